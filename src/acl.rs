@@ -0,0 +1,232 @@
+//! Access control lists: named users, each with the passwords it authenticates with
+//! and the commands it may run, shared by every connection.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+use crate::cmd::COMMAND_TABLE;
+
+/// User every connection starts as, whose password is `requirepass`.
+pub const DEFAULT_USER: &str = "default";
+
+/// A user, as configured with ACL SETUSER.
+#[derive(Clone, Default)]
+struct User {
+    /// Whether the user may authenticate at all
+    enabled: bool,
+    /// Accepts any password, e.g. the default user until `requirepass` is set
+    nopass: bool,
+    passwords: Vec<String>,
+    /// Commands or `@categories` allowed (`true`) or denied, the last rule matching
+    /// a command deciding for it
+    rules: Vec<(bool, String)>,
+}
+
+impl User {
+    /// Applies the ACL SETUSER `rule`, e.g. `on`, `>password` or `+@read`.
+    fn apply(&mut self, rule: &str) -> Result<()> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" => self.rules = vec![(true, "@all".to_string())],
+            "nocommands" => self.rules.clear(),
+            "reset" => *self = User::default(),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.nopass = false;
+                    if !self.passwords.iter().any(|p| p == password) {
+                        self.passwords.push(password.to_string());
+                    }
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.passwords.retain(|p| p != password);
+                } else if let Some((allow, target)) = rule
+                    .strip_prefix('+')
+                    .map(|target| (true, target))
+                    .or_else(|| rule.strip_prefix('-').map(|target| (false, target)))
+                {
+                    let target = target.to_lowercase();
+                    if !is_known_target(&target) {
+                        bail!(
+                            "ERR Error in ACL SETUSER modifier '{rule}': Unknown command or category name in ACL"
+                        );
+                    }
+                    self.rules.push((allow, target));
+                } else {
+                    bail!("ERR Error in ACL SETUSER modifier '{rule}': Syntax error");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the user may run the command `name`, given in lowercase.
+    fn can_run(&self, name: &str) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(_, target)| matches_target(target, name))
+            .is_some_and(|(allow, _)| *allow)
+    }
+}
+
+/// Whether `target` names an implemented command or a category of them.
+fn is_known_target(target: &str) -> bool {
+    match target.strip_prefix('@') {
+        Some(category) => {
+            matches!(category, "all" | "read" | "write" | "fast")
+                || COMMAND_TABLE.iter().any(|spec| spec.group == category)
+        }
+        None => COMMAND_TABLE.iter().any(|spec| spec.name == target),
+    }
+}
+
+/// Whether the rule `target` covers the command `name`. Besides `@all`, the categories
+/// are the `read` (readonly), `write` and `fast` flags and the command groups of
+/// COMMAND DOCS, such as `@string` or `@connection`.
+fn matches_target(target: &str, name: &str) -> bool {
+    let Some(category) = target.strip_prefix('@') else {
+        return target == name;
+    };
+    let Some(spec) = COMMAND_TABLE.iter().find(|spec| spec.name == name) else {
+        return category == "all";
+    };
+    match category {
+        "all" => true,
+        "read" => spec.flags.contains(&"readonly"),
+        "write" | "fast" => spec.flags.contains(&category),
+        group => spec.group == group,
+    }
+}
+
+/// The users of the server, by name.
+pub struct Acl {
+    users: BTreeMap<String, User>,
+}
+
+impl Default for Acl {
+    /// Starts with the default user, which takes no password and may run everything.
+    fn default() -> Self {
+        let default = User {
+            enabled: true,
+            nopass: true,
+            passwords: Vec::new(),
+            rules: vec![(true, "@all".to_string())],
+        };
+        Self {
+            users: BTreeMap::from([(DEFAULT_USER.to_string(), default)]),
+        }
+    }
+}
+
+impl Acl {
+    /// Applies `rules` to the user `name`, creating it disabled and allowed nothing if
+    /// it does not exist yet. Nothing changes if any rule is invalid.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<()> {
+        let mut user = self.users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Makes `password` the only password of the default user, none meaning it takes
+    /// any password, as `requirepass` does.
+    pub fn set_default_password(&mut self, password: &str) {
+        let rule = if password.is_empty() {
+            "nopass".to_string()
+        } else {
+            format!(">{password}")
+        };
+        self.set_user(DEFAULT_USER, &["resetpass".to_string(), rule])
+            .expect("password rules are valid");
+    }
+
+    /// Whether `password` authenticates as the user `name`.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users.get(name).is_some_and(|user| {
+            user.enabled && (user.nopass || user.passwords.iter().any(|p| p == password))
+        })
+    }
+
+    /// Whether connections run as the user `name` without authenticating, i.e. the user
+    /// is enabled and takes any password.
+    pub fn is_open(&self, name: &str) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// Whether the user `name` may run the command `command`, given in lowercase.
+    pub fn can_run(&self, name: &str, command: &str) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|user| user.can_run(command))
+    }
+
+    /// Names of every user, sorted.
+    pub fn users(&self) -> Vec<String> {
+        self.users.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| rule.to_string()).collect()
+    }
+
+    #[test]
+    fn test_last_matching_rule_decides() {
+        let mut acl = Acl::default();
+        acl.set_user(
+            "reader",
+            &rules(&["on", ">pw", "+@read", "-@string", "+get"]),
+        )
+        .unwrap();
+
+        assert!(acl.can_run("reader", "get"));
+        assert!(!acl.can_run("reader", "append"));
+        assert!(acl.can_run("reader", "llen"));
+        assert!(!acl.can_run("reader", "lpush"));
+        assert!(!acl.can_run("nobody", "get"));
+        assert!(acl.can_run(DEFAULT_USER, "set"));
+    }
+
+    #[test]
+    fn test_passwords() {
+        let mut acl = Acl::default();
+        assert!(acl.is_open(DEFAULT_USER));
+        acl.set_default_password("secret");
+        assert!(!acl.is_open(DEFAULT_USER));
+        assert!(acl.authenticate(DEFAULT_USER, "secret"));
+        assert!(!acl.authenticate(DEFAULT_USER, "wrong"));
+
+        // A new user is disabled until turned on
+        acl.set_user("alice", &rules(&[">pw"])).unwrap();
+        assert!(!acl.authenticate("alice", "pw"));
+        acl.set_user("alice", &rules(&["on"])).unwrap();
+        assert!(acl.authenticate("alice", "pw"));
+    }
+
+    #[test]
+    fn test_invalid_rule_changes_nothing() {
+        let mut acl = Acl::default();
+        assert!(acl
+            .set_user("bob", &rules(&["on", "+nosuchcommand"]))
+            .is_err());
+        assert!(acl.set_user("bob", &rules(&["on", "whatever"])).is_err());
+        assert_eq!(acl.users(), vec![DEFAULT_USER]);
+    }
+}
@@ -0,0 +1,151 @@
+//! Append-only file: every write executed by the storage actor is appended to a file
+//! as the RESP array it was received as, and replayed on startup to rebuild the
+//! keyspace. SELECT commands are interleaved whenever the database changes, like on
+//! the replication stream.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use crate::{
+    cmd::Command,
+    resp::{RespCodec, RespDataType},
+    storage::{StorageHandle, NUM_DATABASES},
+};
+
+/// When the appended writes are flushed to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write
+    Always,
+    /// Once per second
+    #[default]
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::Everysec),
+            "no" => Ok(AppendFsync::No),
+            _ => bail!("Invalid appendfsync policy '{s}'"),
+        }
+    }
+}
+
+impl std::fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        })
+    }
+}
+
+/// An open append-only file, owned by the storage actor.
+pub struct Aof {
+    file: File,
+    pub fsync: AppendFsync,
+    /// Database of the last appended write. `None` forces a SELECT before the next one.
+    db: Option<usize>,
+}
+
+impl Aof {
+    /// Opens the file at `path` for appending, creating it if needed.
+    pub fn open(path: &Path, fsync: AppendFsync) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self {
+            file,
+            fsync,
+            db: None,
+        })
+    }
+
+    /// Appends `frame`, executed against database `db`.
+    pub fn append(&mut self, db: usize, frame: &RespDataType) -> Result<()> {
+        let mut buf = Vec::new();
+        if self.db != Some(db) {
+            let select = RespDataType::Array(vec![
+                RespDataType::BulkString("SELECT".into()),
+                RespDataType::BulkString(db.to_string().into()),
+            ]);
+            buf.extend_from_slice(&select.as_bytes());
+        }
+        buf.extend_from_slice(&frame.as_bytes());
+
+        self.file.write_all(&buf)?;
+        self.db = Some(db);
+        if self.fsync == AppendFsync::Always {
+            self.fsync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the appended writes to disk.
+    pub fn fsync(&mut self) -> Result<()> {
+        self.file.sync_data().context("Failed to fsync the AOF")
+    }
+}
+
+/// Replays the writes of the AOF at `path` against `storage`, returning how many
+/// commands were applied. A missing file replays nothing.
+pub async fn replay(path: &Path, storage: &StorageHandle) -> Result<usize> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let mut buf = BytesMut::from(&bytes[..]);
+    let mut codec = RespCodec::default();
+    let mut db = 0;
+    let mut applied = 0;
+
+    while let Some(frame) = codec.decode(&mut buf).context("Invalid AOF")? {
+        match Command::try_from(frame).context("Invalid command in AOF")? {
+            Command::SELECT { index } if index < NUM_DATABASES => db = index,
+            Command::SELECT { index } => bail!("Invalid database {index} in AOF"),
+            cmd => {
+                storage.send(db, cmd).await;
+                applied += 1;
+            }
+        }
+    }
+    if !buf.is_empty() {
+        bail!("AOF ends with a truncated command");
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_appendfsync() {
+        assert_eq!(
+            "EVERYSEC".parse::<AppendFsync>().unwrap(),
+            AppendFsync::Everysec
+        );
+        assert_eq!(AppendFsync::Always.to_string(), "always");
+        assert!("sometimes".parse::<AppendFsync>().is_err());
+    }
+}
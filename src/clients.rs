@@ -0,0 +1,93 @@
+//! Registry of the open client connections, shared by every connection.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// What CLIENT LIST reports about a connection.
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    /// Name set with CLIENT SETNAME
+    pub name: Option<String>,
+    pub connected_at: Instant,
+    /// Name of the last command the connection ran, in lowercase
+    pub last_command: String,
+    /// Cancelled by CLIENT KILL to close the connection
+    kill: CancellationToken,
+}
+
+/// Maps the id of each open connection to its info.
+#[derive(Clone, Default)]
+pub struct Clients {
+    clients: Arc<Mutex<BTreeMap<u64, ClientInfo>>>,
+}
+
+impl Clients {
+    /// Registers the connection `id`, accepted from `addr`, returning the token that
+    /// is cancelled when the connection gets killed.
+    pub fn register(&self, id: u64, addr: SocketAddr) -> CancellationToken {
+        let kill = CancellationToken::new();
+        self.clients.lock().unwrap().insert(
+            id,
+            ClientInfo {
+                addr,
+                name: None,
+                connected_at: Instant::now(),
+                last_command: "NULL".to_string(),
+                kill: kill.clone(),
+            },
+        );
+        kill
+    }
+
+    /// Forgets the connection `id` once closed.
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Applies `update` to the info of the connection `id`, returning what it returns.
+    pub fn update<T>(&self, id: u64, update: impl FnOnce(&mut ClientInfo) -> T) -> Option<T> {
+        self.clients.lock().unwrap().get_mut(&id).map(update)
+    }
+
+    /// Kills the connections matching both `id` and `addr`, when given, except for
+    /// `skip`, returning how many were killed.
+    pub fn kill(&self, id: Option<u64>, addr: Option<&str>, skip: u64) -> usize {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(client_id, info)| {
+                **client_id != skip
+                    && id.is_none_or(|id| id == **client_id)
+                    && addr.is_none_or(|addr| addr == info.addr.to_string())
+                    && !info.kill.is_cancelled()
+            })
+            .map(|(_, info)| info.kill.cancel())
+            .count()
+    }
+
+    /// Describes every open connection on its own line, by increasing id, e.g.
+    /// `id=3 addr=127.0.0.1:52814 name=worker age=12 cmd=get`.
+    pub fn list(&self) -> String {
+        let mut list = String::new();
+        for (id, info) in self.clients.lock().unwrap().iter() {
+            let _ = writeln!(
+                list,
+                "id={} addr={} name={} age={} cmd={}",
+                id,
+                info.addr,
+                info.name.as_deref().unwrap_or_default(),
+                info.connected_at.elapsed().as_secs(),
+                info.last_command
+            );
+        }
+        list
+    }
+}
@@ -1,17 +1,34 @@
-use std::time::Duration;
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, bail, Context};
 
-use crate::resp::RespDataType;
+use bytes::Bytes;
+
+use crate::{data_structures::strings::MAX_STRING_LEN, resp::RespDataType};
+
+pub const WRONGTYPE_ERROR: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Largest bit offset of SETBIT and GETBIT, keeping strings within 512MB like Redis.
+const MAX_BIT_OFFSET: usize = MAX_STRING_LEN * 8 - 1;
 
 #[derive(Debug, Clone)]
 pub enum Command {
-    PING,
-    ECHO(String),
+    /// Replies PONG, or echoes the optional message back.
+    PING(Option<String>),
+    ECHO(Bytes),
     SET {
         key: String,
-        val: String,
+        val: Bytes,
         px: Option<Duration>, // in milliseconds
+        condition: Option<SetCondition>,
+        /// Return the old string stored at key (or nil) instead of OK.
+        get: bool,
+        /// Retain the time to live associated with the key instead of clearing it.
+        keep_ttl: bool,
     },
     GET {
         key: String,
@@ -32,14 +49,39 @@ pub enum Command {
         key: String,
         elements: Vec<String>,
     },
+    /// Replies with the elements of a list sorted as numbers, or as strings with
+    /// `alpha`, greatest first with `desc`. `limit` skips `offset` elements and returns
+    /// at most `count` of them.
+    SORT {
+        key: String,
+        alpha: bool,
+        desc: bool,
+        limit: Option<(usize, usize)>,
+    },
     LLEN {
         key: String,
     },
     LPOP {
         key: String,
-        count: Option<i64>,
+        count: Option<usize>,
+    },
+    /// Pops up to `count` elements from `end` of the first non-empty list among `keys`.
+    LMPOP {
+        keys: Vec<String>,
+        end: ListEnd,
+        count: usize,
     },
-    // NOT implemented
+    /// Replies with the index of `element` in a list. `rank` picks a later match, or
+    /// searches from the tail when negative, while `count` asks for the indexes of that
+    /// many matches, 0 meaning all of them.
+    LPOS {
+        key: String,
+        element: String,
+        rank: Option<i64>,
+        count: Option<usize>,
+    },
+    /// Blocking LPOP: pops from the first non-empty list among `keys`, waiting up to
+    /// `timeout` for an element to be pushed. A zero timeout blocks indefinitely.
     BLPOP {
         keys: Vec<String>,
         timeout: Duration,
@@ -47,22 +89,758 @@ pub enum Command {
     INCR {
         key: String,
     },
+    /// Appends to a string, replying with its new length.
+    APPEND {
+        key: String,
+        value: Bytes,
+    },
+    /// Replies with the length of a string.
+    STRLEN {
+        key: String,
+    },
+    /// Overwrites part of a string from `offset`, replying with its new length.
+    SETRANGE {
+        key: String,
+        offset: usize,
+        value: Bytes,
+    },
+    /// Sets or clears the bit at `offset` of a string, replying with its previous value.
+    SETBIT {
+        key: String,
+        offset: usize,
+        value: bool,
+    },
+    /// Counts the set bits of a string, or of the inclusive `(start, end)` range of
+    /// bytes or bits, negative indexes counting from the end.
+    BITCOUNT {
+        key: String,
+        range: Option<(i64, i64, BitUnit)>,
+    },
+    /// Stores the bitwise operation of the `sources` strings at `dest`, replying with the
+    /// length of the result.
+    BITOP {
+        op: BitOp,
+        dest: String,
+        sources: Vec<String>,
+    },
+    /// Replies with the bit at `offset` of a string, 0 past its end.
+    GETBIT {
+        key: String,
+        offset: usize,
+    },
+    /// Adds members with their scores to a sorted set, or updates the scores of
+    /// existing members, replying with the number of members added.
+    ZADD {
+        key: String,
+        condition: Option<SetCondition>,
+        comparison: Option<ScoreComparison>,
+        members: Vec<(f64, String)>,
+    },
+    /// Adds `delta` to the score of a member of a sorted set, adding the member if
+    /// needed, and replies with its new score.
+    ZINCRBY {
+        key: String,
+        delta: f64,
+        member: String,
+    },
+    /// Removes members from a sorted set, replying with how many were removed.
+    ZREM {
+        key: String,
+        members: Vec<String>,
+    },
+    /// Replies with the score of a member of a sorted set.
+    ZSCORE {
+        key: String,
+        member: String,
+    },
+    /// Replies with the number of members of a sorted set.
+    ZCARD {
+        key: String,
+    },
+    /// Replies with the number of members of a sorted set whose score is between `min`
+    /// and `max`.
+    ZCOUNT {
+        key: String,
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    /// Replies with the members of a sorted set from position `start` to `stop`
+    /// included, lowest score first. Negative positions count from the end.
+    ZRANGE {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    /// Like ZRANGE, but highest score first.
+    ZREVRANGE {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    /// Replies with the members of a sorted set whose score is between `min` and `max`,
+    /// lowest score first. `limit` skips `offset` members and returns at most `count`,
+    /// all of them when negative.
+    ZRANGEBYSCORE {
+        key: String,
+        min: ScoreBound,
+        max: ScoreBound,
+        with_scores: bool,
+        limit: Option<(i64, i64)>,
+    },
+    /// Replies with the position of a member in a sorted set, lowest score first.
+    ZRANK {
+        key: String,
+        member: String,
+    },
+    /// Replies with the position of a member in a sorted set, highest score first.
+    ZREVRANK {
+        key: String,
+        member: String,
+    },
+    /// Removes the given keys, whatever their type, replying with how many existed.
+    DEL {
+        keys: Vec<String>,
+    },
+    /// Like DEL. Redis reclaims the memory in the background, which the single storage
+    /// actor has no use for.
+    UNLINK {
+        keys: Vec<String>,
+    },
+    /// Marks the given keys as accessed, replying with how many existed.
+    TOUCH {
+        keys: Vec<String>,
+    },
+    /// Sets the time to live of a key in seconds, deleting it when not positive.
+    EXPIRE {
+        key: String,
+        seconds: i64,
+    },
+    /// Sets the deadline of a key as a Unix time in milliseconds, deleting it when
+    /// already past. Relative expirations are logged and propagated in this form.
+    PEXPIREAT {
+        key: String,
+        unix_ms: i64,
+    },
+    /// Replies with the remaining time to live of a key in seconds, -1 when it has
+    /// none and -2 when it is missing.
+    TTL {
+        key: String,
+    },
+    /// Replies with the Unix time in seconds at which a key expires, -1 when it has no
+    /// time to live and -2 when it is missing.
+    EXPIRETIME {
+        key: String,
+    },
+    /// Like EXPIRETIME, in milliseconds.
+    PEXPIRETIME {
+        key: String,
+    },
+    /// Copies the value stored at `src` to `dst`, overwriting it only with `replace`.
+    COPY {
+        src: String,
+        dst: String,
+        replace: bool,
+    },
+    /// Changes the logical database the connection operates on.
+    SELECT {
+        index: usize,
+    },
+    /// Returns the number of keys in the keyspace.
+    DBSIZE,
+    /// Returns a random key, or nil when the keyspace is empty.
+    RANDOMKEY,
+    /// Subscribes the connection to the given channels.
+    SUBSCRIBE {
+        channels: Vec<String>,
+    },
+    /// Unsubscribes the connection from the given channels, or from all of them if none
+    /// is given.
+    UNSUBSCRIBE {
+        channels: Vec<String>,
+    },
+    /// Posts `message` to every client subscribed to `channel`.
+    PUBLISH {
+        channel: String,
+        message: String,
+    },
+    /// Removes every key of the current database.
+    FLUSHDB,
+    /// Removes every key of every database.
+    FLUSHALL,
+    /// Incrementally iterates over the keyspace, starting at `cursor`.
+    /// `pattern` filters the returned keys and `count` hints how many to return.
+    SCAN {
+        cursor: u64,
+        pattern: Option<String>,
+        count: Option<usize>,
+    },
     MULTI,
     EXEC,
     DISCARD,
+    /// Marks the given keys to be watched for conditional execution of a transaction.
+    WATCH {
+        keys: Vec<String>,
+    },
+    /// Flushes all the previously watched keys for a transaction.
+    UNWATCH,
+    /// Restores the connection to the state it was accepted in.
+    RESET,
     INFO {
         section: Option<Section>,
     },
-    REPLCONF,
+    REPLCONF(ReplConf),
+    DEBUG {
+        subcommand: DebugSubcommand,
+    },
+    CONFIG(ConfigSubcommand),
+    /// Inspects the internals of the value stored at a key.
+    OBJECT {
+        subcommand: ObjectSubcommand,
+    },
+    /// Authenticates the connection as `username`, or as the default user whose
+    /// password is the `requirepass` config.
+    AUTH {
+        username: Option<String>,
+        password: String,
+    },
+    /// Manages the users of the access control list.
+    ACL {
+        subcommand: AclSubcommand,
+    },
+    /// Inspects or changes the connection issuing it.
+    CLIENT {
+        subcommand: ClientSubcommand,
+    },
+    /// Introspects the commands this server implements.
+    COMMAND {
+        subcommand: CommandSubcommand,
+    },
+    /// Writes the keyspace to the RDB snapshot, replying once it is on disk.
+    SAVE,
+    /// Writes the keyspace to the RDB snapshot in the background.
+    BGSAVE,
+    /// Shuts the server down. `save` forces (SAVE) or skips (NOSAVE) saving the RDB
+    /// snapshot, which is otherwise saved if `save` points are configured.
+    SHUTDOWN {
+        save: Option<bool>,
+    },
     PSYNC {
         replication_id: String,
         offset: i64,
     },
 }
 
-#[derive(Debug, Clone)]
+/// Sections of INFO, in the order a plain INFO lists them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Section {
+    Server,
+    Clients,
+    Memory,
     Replication,
+    Keyspace,
+}
+
+impl Section {
+    pub const ALL: [Section; 5] = [
+        Section::Server,
+        Section::Clients,
+        Section::Memory,
+        Section::Replication,
+        Section::Keyspace,
+    ];
+
+    /// Header of the section, e.g. `Replication` for `# Replication`.
+    pub fn title(self) -> &'static str {
+        match self {
+            Section::Server => "Server",
+            Section::Clients => "Clients",
+            Section::Memory => "Memory",
+            Section::Replication => "Replication",
+            Section::Keyspace => "Keyspace",
+        }
+    }
+}
+
+/// Subcommands of REPLCONF exchanged between a master and its replicas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplConf {
+    /// `REPLCONF listening-port <port>` sent by a replica during the handshake.
+    ListeningPort(u16),
+    /// `REPLCONF capa <capability> [capa <capability> ...]` sent during the handshake.
+    Capa(Vec<String>),
+    /// `REPLCONF GETACK *` sent by the master to request the replica's offset.
+    GetAck,
+    /// `REPLCONF ACK <offset>` sent by a replica to report its processed offset.
+    Ack(usize),
+}
+
+/// Internal/testing subcommands of DEBUG.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugSubcommand {
+    /// Runs one full active-expiration pass and replies with the number of keys reaped.
+    PurgeExpired,
+    /// `DEBUG SLEEP <seconds>` delays the reply of the issuing connection only.
+    Sleep(Duration),
+    /// `DEBUG LISTPACK-ENTRIES` replies with the `list-max-listpack-size` in effect and
+    /// the entry and byte limits of a listpack it implies.
+    ListpackEntries,
+    /// `DEBUG OBJECT <key>` describes the value at `key`: refcount, encoding,
+    /// serialized length and idle time.
+    Object(String),
+    /// `DEBUG SET-ACTIVE-EXPIRE 0|1` turns the active-expiration sweep off or back on,
+    /// keys then expiring only when accessed.
+    SetActiveExpire(bool),
+}
+
+/// Subcommands of OBJECT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectSubcommand {
+    /// `OBJECT ENCODING <key>` replies with the internal representation of the value.
+    Encoding(String),
+    /// `OBJECT IDLETIME <key>` replies with the seconds since the value was last accessed.
+    IdleTime(String),
+    /// `OBJECT REFCOUNT <key>` replies with the number of references to the value,
+    /// always 1 as values are never shared.
+    RefCount(String),
+    /// `OBJECT FREQ <key>` replies with the number of times the value was accessed,
+    /// only tracked under an LFU `maxmemory-policy`.
+    Freq(String),
+}
+
+/// Subcommands of ACL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclSubcommand {
+    /// `ACL SETUSER <name> [rule ...]` creates or modifies a user.
+    SetUser { name: String, rules: Vec<String> },
+    /// `ACL USERS` lists the names of every user.
+    Users,
+    /// `ACL WHOAMI` replies with the user the connection is authenticated as.
+    WhoAmI,
+}
+
+/// Subcommands of CLIENT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientSubcommand {
+    /// `CLIENT ID` replies with the id the connection was given when accepted.
+    Id,
+    /// `CLIENT SETNAME <name>` names the connection, an empty name removing it.
+    SetName(String),
+    /// `CLIENT GETNAME` replies with the name of the connection, or nil.
+    GetName,
+    /// `CLIENT LIST` describes every open connection, one per line.
+    List,
+    /// `CLIENT KILL [ID <id>] [ADDR <ip:port>]` closes the other connections matching
+    /// every given filter, replying with how many were closed.
+    Kill {
+        id: Option<u64>,
+        addr: Option<String>,
+    },
+}
+
+/// Subcommands of COMMAND.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandSubcommand {
+    /// Bare `COMMAND` replies with the arity, flags and key positions of every command.
+    Info,
+    /// `COMMAND COUNT` replies with the number of commands.
+    Count,
+    /// `COMMAND DOCS [command ...]` describes the given commands, or all of them.
+    Docs(Vec<String>),
+    /// `COMMAND GETKEYS <command> [arg ...]` replies with the key arguments of the
+    /// given command line.
+    GetKeys(Vec<String>),
+}
+
+/// Subcommands of CONFIG.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSubcommand {
+    /// `CONFIG GET <pattern>` replies with the parameters whose name matches the glob.
+    Get(String),
+    /// `CONFIG SET <parameter> <value>`
+    Set { parameter: String, value: String },
+}
+
+/// Bitwise operations of BITOP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Unit of the range of BITCOUNT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+/// End of a list to pop from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// Conditional flags for SET.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// Only set the key if it does not already exist.
+    NX,
+    /// Only set the key if it already exists.
+    XX,
+}
+
+/// Conditional flags for ZADD comparing the new score of a member to its current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreComparison {
+    /// Only update the score if the new one is greater.
+    GT,
+    /// Only update the score if the new one is less.
+    LT,
+}
+
+/// A bound of a sorted set score range, exclusive when written as `(score`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl Command {
+    /// Whether the command may run while the dataset is unavailable, i.e. while it is
+    /// being loaded or on a replica whose link with the master is down.
+    pub fn allowed_while_unavailable(&self) -> bool {
+        matches!(self, Command::PING(_) | Command::INFO { .. })
+    }
+
+    /// Whether the command may run on a connection that has not authenticated yet.
+    pub fn allowed_unauthenticated(&self) -> bool {
+        matches!(
+            self,
+            Command::PING(_) | Command::AUTH { .. } | Command::RESET
+        )
+    }
+
+    /// Whether the command may run on a connection subscribed to at least one channel.
+    pub fn allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::SUBSCRIBE { .. } | Command::UNSUBSCRIBE { .. } | Command::PING(_)
+        )
+    }
+
+    /// Whether the command may modify the dataset and must therefore be propagated to
+    /// replicas. BLPOP is excluded: what it pops is propagated as LPOP instead.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::SET { .. }
+                | Command::RPUSH { .. }
+                | Command::LPUSH { .. }
+                | Command::LPOP { .. }
+                | Command::LMPOP { .. }
+                | Command::INCR { .. }
+                | Command::SETBIT { .. }
+                | Command::BITOP { .. }
+                | Command::APPEND { .. }
+                | Command::SETRANGE { .. }
+                | Command::ZADD { .. }
+                | Command::ZINCRBY { .. }
+                | Command::ZREM { .. }
+                | Command::DEL { .. }
+                | Command::UNLINK { .. }
+                | Command::EXPIRE { .. }
+                | Command::PEXPIREAT { .. }
+                | Command::COPY { .. }
+                | Command::FLUSHDB
+                | Command::FLUSHALL
+        )
+    }
+
+    /// Whether the command may take more memory, and must therefore be refused while
+    /// `maxmemory` is exceeded.
+    pub fn denies_oom(&self) -> bool {
+        matches!(
+            self,
+            Command::SET { .. }
+                | Command::RPUSH { .. }
+                | Command::LPUSH { .. }
+                | Command::INCR { .. }
+                | Command::SETBIT { .. }
+                | Command::BITOP { .. }
+                | Command::APPEND { .. }
+                | Command::SETRANGE { .. }
+                | Command::ZADD { .. }
+                | Command::ZINCRBY { .. }
+                | Command::COPY { .. }
+        )
+    }
+}
+
+/// Static description of an implemented command, as reported by COMMAND and COMMAND DOCS.
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Number of arguments including the command name, or minus the minimum number
+    /// when the command is variadic
+    pub arity: i64,
+    /// Any of `write`, `readonly` and `fast`
+    pub flags: &'static [&'static str],
+    /// Positions of the first and last key arguments and the step between keys. The
+    /// last position counts from the end when negative; `(0, 0, 0)` means no keys.
+    pub keys: (i64, i64, i64),
+    pub group: &'static str,
+    pub summary: &'static str,
+}
+
+impl CommandSpec {
+    /// Whether a command line of `argc` arguments, name included, fits the arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        if self.arity < 0 {
+            argc as i64 >= -self.arity
+        } else {
+            argc as i64 == self.arity
+        }
+    }
+}
+
+#[rustfmt::skip]
+pub const COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec { name: "acl", arity: -2, flags: &[], keys: (0, 0, 0), group: "server", summary: "A container for Access List Control commands." },
+    CommandSpec { name: "append", arity: 3, flags: &["write"], keys: (1, 1, 1), group: "string", summary: "Appends a string to the value of a key. Creates the key if it doesn't exist." },
+    CommandSpec { name: "auth", arity: -2, flags: &["fast"], keys: (0, 0, 0), group: "connection", summary: "Authenticates the connection." },
+    CommandSpec { name: "bgsave", arity: -1, flags: &[], keys: (0, 0, 0), group: "server", summary: "Asynchronously saves the database(s) to disk." },
+    CommandSpec { name: "bitcount", arity: -2, flags: &["readonly"], keys: (1, 1, 1), group: "bitmap", summary: "Counts the number of set bits (population counting) in a string." },
+    CommandSpec { name: "bitop", arity: -4, flags: &["write"], keys: (2, -1, 1), group: "bitmap", summary: "Performs bitwise operations on multiple strings, and stores the result." },
+    CommandSpec { name: "blpop", arity: -3, flags: &["write"], keys: (1, -2, 1), group: "list", summary: "Removes and returns the first element in a list. Blocks until an element is available otherwise." },
+    CommandSpec { name: "client", arity: -2, flags: &[], keys: (0, 0, 0), group: "connection", summary: "A container for client connection commands." },
+    CommandSpec { name: "command", arity: -1, flags: &[], keys: (0, 0, 0), group: "server", summary: "Returns detailed information about all commands." },
+    CommandSpec { name: "config", arity: -2, flags: &[], keys: (0, 0, 0), group: "server", summary: "Reads or changes the server's configuration parameters." },
+    CommandSpec { name: "copy", arity: -3, flags: &["write"], keys: (1, 2, 1), group: "generic", summary: "Copies the value of a key to a new key." },
+    CommandSpec { name: "dbsize", arity: 1, flags: &["readonly", "fast"], keys: (0, 0, 0), group: "server", summary: "Returns the number of keys in the database." },
+    CommandSpec { name: "debug", arity: -2, flags: &[], keys: (0, 0, 0), group: "server", summary: "A container for debugging commands." },
+    CommandSpec { name: "del", arity: -2, flags: &["write"], keys: (1, -1, 1), group: "generic", summary: "Deletes one or more keys." },
+    CommandSpec { name: "discard", arity: 1, flags: &["fast"], keys: (0, 0, 0), group: "transactions", summary: "Discards a transaction." },
+    CommandSpec { name: "echo", arity: 2, flags: &["fast"], keys: (0, 0, 0), group: "connection", summary: "Returns the given string." },
+    CommandSpec { name: "exec", arity: 1, flags: &[], keys: (0, 0, 0), group: "transactions", summary: "Executes all commands in a transaction." },
+    CommandSpec { name: "expire", arity: 3, flags: &["write", "fast"], keys: (1, 1, 1), group: "generic", summary: "Sets the expiration time of a key in seconds." },
+    CommandSpec { name: "expiretime", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "generic", summary: "Returns the expiration time of a key as a Unix timestamp." },
+    CommandSpec { name: "flushall", arity: -1, flags: &["write"], keys: (0, 0, 0), group: "server", summary: "Removes all keys from all databases." },
+    CommandSpec { name: "flushdb", arity: -1, flags: &["write"], keys: (0, 0, 0), group: "server", summary: "Removes all keys from the current database." },
+    CommandSpec { name: "get", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "string", summary: "Returns the string value of a key." },
+    CommandSpec { name: "getbit", arity: 3, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "bitmap", summary: "Returns a bit value by offset." },
+    CommandSpec { name: "incr", arity: 2, flags: &["write", "fast"], keys: (1, 1, 1), group: "string", summary: "Increments the integer value of a key by one." },
+    CommandSpec { name: "info", arity: -1, flags: &[], keys: (0, 0, 0), group: "server", summary: "Returns information and statistics about the server." },
+    CommandSpec { name: "llen", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "list", summary: "Returns the length of a list." },
+    CommandSpec { name: "lmpop", arity: -4, flags: &["write"], keys: (0, 0, 0), group: "list", summary: "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped." },
+    CommandSpec { name: "lpop", arity: -2, flags: &["write", "fast"], keys: (1, 1, 1), group: "list", summary: "Returns the first elements in a list after removing it." },
+    CommandSpec { name: "lpos", arity: -3, flags: &["readonly"], keys: (1, 1, 1), group: "list", summary: "Returns the index of matching elements in a list." },
+    CommandSpec { name: "lpush", arity: -3, flags: &["write", "fast"], keys: (1, 1, 1), group: "list", summary: "Prepends one or more elements to a list." },
+    CommandSpec { name: "lrange", arity: 4, flags: &["readonly"], keys: (1, 1, 1), group: "list", summary: "Returns a range of elements from a list." },
+    CommandSpec { name: "multi", arity: 1, flags: &["fast"], keys: (0, 0, 0), group: "transactions", summary: "Starts a transaction." },
+    CommandSpec { name: "object", arity: -2, flags: &["readonly"], keys: (2, 2, 1), group: "generic", summary: "Inspects the internals of Redis objects." },
+    CommandSpec { name: "pexpireat", arity: 3, flags: &["write", "fast"], keys: (1, 1, 1), group: "generic", summary: "Sets the expiration time of a key to a Unix milliseconds timestamp." },
+    CommandSpec { name: "pexpiretime", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "generic", summary: "Returns the expiration time of a key as a Unix milliseconds timestamp." },
+    CommandSpec { name: "ping", arity: -1, flags: &["fast"], keys: (0, 0, 0), group: "connection", summary: "Returns the server's liveliness response." },
+    CommandSpec { name: "psync", arity: -3, flags: &[], keys: (0, 0, 0), group: "server", summary: "An internal command used in replication." },
+    CommandSpec { name: "publish", arity: 3, flags: &["fast"], keys: (0, 0, 0), group: "pubsub", summary: "Posts a message to a channel." },
+    CommandSpec { name: "randomkey", arity: 1, flags: &["readonly"], keys: (0, 0, 0), group: "generic", summary: "Returns a random key name from the database." },
+    CommandSpec { name: "replconf", arity: -1, flags: &[], keys: (0, 0, 0), group: "server", summary: "An internal command for configuring the replication stream." },
+    CommandSpec { name: "reset", arity: 1, flags: &["fast"], keys: (0, 0, 0), group: "connection", summary: "Resets the connection." },
+    CommandSpec { name: "rpush", arity: -3, flags: &["write", "fast"], keys: (1, 1, 1), group: "list", summary: "Appends one or more elements to a list." },
+    CommandSpec { name: "save", arity: 1, flags: &[], keys: (0, 0, 0), group: "server", summary: "Synchronously saves the database(s) to disk." },
+    CommandSpec { name: "scan", arity: -2, flags: &["readonly"], keys: (0, 0, 0), group: "generic", summary: "Iterates over the key names in the database." },
+    CommandSpec { name: "select", arity: 2, flags: &["fast"], keys: (0, 0, 0), group: "connection", summary: "Changes the selected database." },
+    CommandSpec { name: "sort", arity: -2, flags: &["readonly"], keys: (1, 1, 1), group: "generic", summary: "Sorts the elements in a list. Returns the sorted result." },
+    CommandSpec { name: "set", arity: -3, flags: &["write"], keys: (1, 1, 1), group: "string", summary: "Sets the string value of a key, ignoring its type." },
+    CommandSpec { name: "setbit", arity: 4, flags: &["write"], keys: (1, 1, 1), group: "bitmap", summary: "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist." },
+    CommandSpec { name: "setrange", arity: 4, flags: &["write"], keys: (1, 1, 1), group: "string", summary: "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist." },
+    CommandSpec { name: "shutdown", arity: -1, flags: &[], keys: (0, 0, 0), group: "server", summary: "Synchronously saves the database(s) to disk and shuts down the Redis server." },
+    CommandSpec { name: "strlen", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "string", summary: "Returns the length of a string value." },
+    CommandSpec { name: "subscribe", arity: -2, flags: &[], keys: (0, 0, 0), group: "pubsub", summary: "Listens for messages published to channels." },
+    CommandSpec { name: "touch", arity: -2, flags: &["readonly", "fast"], keys: (1, -1, 1), group: "generic", summary: "Returns the number of existing keys out of those specified after updating the time they were last accessed." },
+    CommandSpec { name: "ttl", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "generic", summary: "Returns the expiration time in seconds of a key." },
+    CommandSpec { name: "unlink", arity: -2, flags: &["write", "fast"], keys: (1, -1, 1), group: "generic", summary: "Asynchronously deletes one or more keys." },
+    CommandSpec { name: "unsubscribe", arity: -1, flags: &[], keys: (0, 0, 0), group: "pubsub", summary: "Stops listening to messages posted to channels." },
+    CommandSpec { name: "unwatch", arity: 1, flags: &["fast"], keys: (0, 0, 0), group: "transactions", summary: "Forgets about watched keys of a transaction." },
+    CommandSpec { name: "watch", arity: -2, flags: &["fast"], keys: (1, -1, 1), group: "transactions", summary: "Monitors changes to keys to determine the execution of a transaction." },
+    CommandSpec { name: "zadd", arity: -4, flags: &["write", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Adds one or more members to a sorted set, or updates their scores. Creates the key if it doesn't exist." },
+    CommandSpec { name: "zcard", arity: 2, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns the number of members in a sorted set." },
+    CommandSpec { name: "zcount", arity: 4, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns the count of members in a sorted set that have scores within a range." },
+    CommandSpec { name: "zincrby", arity: 4, flags: &["write", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Increments the score of a member in a sorted set." },
+    CommandSpec { name: "zrange", arity: -4, flags: &["readonly"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns members in a sorted set within a range of indexes." },
+    CommandSpec { name: "zrangebyscore", arity: -4, flags: &["readonly"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns members in a sorted set within a range of scores." },
+    CommandSpec { name: "zrank", arity: 3, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns the index of a member in a sorted set ordered by ascending scores." },
+    CommandSpec { name: "zrem", arity: -3, flags: &["write", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Removes one or more members from a sorted set. Deletes the sorted set if all members were removed." },
+    CommandSpec { name: "zrevrange", arity: -4, flags: &["readonly"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns members in a sorted set within a range of indexes in reverse order." },
+    CommandSpec { name: "zrevrank", arity: 3, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns the index of a member in a sorted set ordered by descending scores." },
+    CommandSpec { name: "zscore", arity: 3, flags: &["readonly", "fast"], keys: (1, 1, 1), group: "sorted_set", summary: "Returns the score of a member in a sorted set." },
+];
+
+/// Characters of the name, and of the arguments, an unknown command error repeats.
+const UNKNOWN_COMMAND_PREVIEW_LEN: usize = 128;
+
+/// Why a frame could not be parsed into a [`Command`], for the failures callers need
+/// to tell apart. Other failures are plain `anyhow` messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// No command is implemented under `name`, which is given as sent, along with its
+    /// arguments.
+    UnknownCommand { name: String, args: Vec<String> },
+    /// The command, named in lowercase, got the wrong number of arguments.
+    WrongArity(String),
+    /// An argument is invalid, as told by the message after `ERR`.
+    InvalidArgument(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand { name, args } => {
+                // As in Redis, arguments are quoted until the preview reaches its limit,
+                // the last one cut to fit
+                let mut preview = String::new();
+                let mut len = 0;
+                for arg in args {
+                    if len >= UNKNOWN_COMMAND_PREVIEW_LEN {
+                        break;
+                    }
+                    let arg: String = arg
+                        .chars()
+                        .take(UNKNOWN_COMMAND_PREVIEW_LEN - len)
+                        .collect();
+                    len += arg.chars().count() + 3;
+                    preview.push_str(&format!("'{arg}' "));
+                }
+                let name: String = name.chars().take(UNKNOWN_COMMAND_PREVIEW_LEN).collect();
+                write!(
+                    f,
+                    "ERR unknown command '{name}', with args beginning with: {preview}"
+                )
+            }
+            CommandError::WrongArity(name) => {
+                write!(f, "ERR wrong number of arguments for '{name}' command")
+            }
+            CommandError::InvalidArgument(message) => write!(f, "ERR {message}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    /// Classifies a parse failure of [`Command::try_from`], failures that are not
+    /// already a `CommandError` being invalid arguments.
+    pub fn classify(error: anyhow::Error) -> Self {
+        error.downcast::<CommandError>().unwrap_or_else(|error| {
+            let message = error.to_string();
+            let message = message.strip_prefix("ERR ").unwrap_or(&message);
+            CommandError::InvalidArgument(message.to_string())
+        })
+    }
+}
+
+/// Replies to a COMMAND subcommand.
+pub fn command_reply(subcommand: &CommandSubcommand) -> RespDataType {
+    match subcommand {
+        CommandSubcommand::Info => command_info(),
+        CommandSubcommand::Count => RespDataType::Integer(COMMAND_TABLE.len() as i64),
+        CommandSubcommand::Docs(names) => command_docs(names),
+        CommandSubcommand::GetKeys(args) => match command_keys(args) {
+            Ok(keys) => RespDataType::Array(
+                keys.into_iter()
+                    .map(|key| RespDataType::BulkString(key.into()))
+                    .collect(),
+            ),
+            Err(e) => RespDataType::SimpleError(e.to_string()),
+        },
+    }
+}
+
+/// Extracts the keys of the command line `args`, name included, from the key
+/// positions of its command in [`COMMAND_TABLE`].
+pub fn command_keys(args: &[String]) -> anyhow::Result<Vec<String>> {
+    let Some(spec) = args.first().and_then(|name| {
+        COMMAND_TABLE
+            .iter()
+            .find(|spec| name.eq_ignore_ascii_case(spec.name))
+    }) else {
+        bail!("ERR Invalid command specified");
+    };
+    if !spec.accepts(args.len()) {
+        bail!("ERR Invalid number of arguments specified for command");
+    }
+
+    let (first, last, step) = spec.keys;
+    if first == 0 {
+        bail!("ERR The command has no key arguments");
+    }
+    let last = if last < 0 {
+        args.len() as i64 + last
+    } else {
+        last
+    };
+    Ok((first..=last)
+        .step_by(step as usize)
+        .filter_map(|i| args.get(i as usize).cloned())
+        .collect())
+}
+
+/// Replies to bare COMMAND: one `[name, arity, flags, first key, last key, step]`
+/// entry per command.
+fn command_info() -> RespDataType {
+    let info = COMMAND_TABLE
+        .iter()
+        .map(|spec| {
+            let (first, last, step) = spec.keys;
+            RespDataType::Array(vec![
+                RespDataType::BulkString(spec.name.into()),
+                RespDataType::Integer(spec.arity),
+                RespDataType::Array(
+                    spec.flags
+                        .iter()
+                        .map(|flag| RespDataType::SimpleString(flag.to_string()))
+                        .collect(),
+                ),
+                RespDataType::Integer(first),
+                RespDataType::Integer(last),
+                RespDataType::Integer(step),
+            ])
+        })
+        .collect();
+    RespDataType::Array(info)
+}
+
+/// Replies to COMMAND DOCS: the name of each known command in `names` (all of them
+/// when empty) followed by its `summary`, `group` and `arity` fields. Unknown names
+/// are skipped.
+fn command_docs(names: &[String]) -> RespDataType {
+    let docs = COMMAND_TABLE
+        .iter()
+        .filter(|spec| {
+            names.is_empty()
+                || names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(spec.name))
+        })
+        .flat_map(|spec| {
+            [
+                RespDataType::BulkString(spec.name.into()),
+                RespDataType::Array(vec![
+                    RespDataType::BulkString("summary".into()),
+                    RespDataType::BulkString(spec.summary.into()),
+                    RespDataType::BulkString("group".into()),
+                    RespDataType::BulkString(spec.group.into()),
+                    RespDataType::BulkString("arity".into()),
+                    RespDataType::Integer(spec.arity),
+                ]),
+            ]
+        })
+        .collect();
+    RespDataType::Array(docs)
 }
 
 impl TryFrom<RespDataType> for Command {
@@ -74,264 +852,1435 @@ impl TryFrom<RespDataType> for Command {
                     bail!("Empty command array");
                 }
 
-                let cmd = match &parts[0] {
-                    RespDataType::BulkString(cmd) | RespDataType::SimpleString(cmd) => {
-                        cmd.to_uppercase()
+                let name = match &parts[0] {
+                    RespDataType::BulkString(_) | RespDataType::SimpleString(_) => {
+                        parts[0].get_str()?
                     }
                     _ => bail!("Command must be a string type"),
                 };
+                if let Some(spec) = COMMAND_TABLE
+                    .iter()
+                    .find(|spec| name.eq_ignore_ascii_case(spec.name))
+                {
+                    if !spec.accepts(parts.len()) {
+                        bail!(CommandError::WrongArity(spec.name.to_string()));
+                    }
+                }
+                let cmd = name.to_uppercase();
 
                 match cmd.as_str() {
-                    "PING" => {
-                        if parts.len() > 1 {
-                            bail!("PING command takes no arguments");
+                    "PING" => match &parts[1..] {
+                        [] => Ok(Command::PING(None)),
+                        [msg @ RespDataType::BulkString(_)] => {
+                            Ok(Command::PING(Some(msg.get_str()?)))
                         }
-                        Ok(Command::PING)
-                    }
-                    "ECHO" => {
-                        if parts.len() != 2 {
-                            bail!("ECHO command requires exactly 1 argument");
-                        }
-                        match &parts[1] {
-                            RespDataType::BulkString(msg) => Ok(Command::ECHO(msg.clone())),
-                            _ => bail!("ECHO message must be a bulk string"),
-                        }
-                    }
-                    "GET" => {
-                        if parts.len() != 2 {
-                            bail!("GET command requires exactly 1 argument");
-                        }
-                        match &parts[1] {
-                            RespDataType::BulkString(key) => Ok(Command::GET { key: key.clone() }),
-                            _ => bail!("GET key must be a bulk string"),
-                        }
-                    }
+                        [_] => bail!("PING message must be a bulk string"),
+                        _ => bail!(CommandError::WrongArity("ping".into())),
+                    },
+                    "ECHO" => match &parts[1] {
+                        msg @ RespDataType::BulkString(_) => Ok(Command::ECHO(msg.get_bytes()?)),
+                        _ => bail!("ECHO message must be a bulk string"),
+                    },
+                    "GET" => Ok(Command::GET {
+                        key: parts[1].get_str()?,
+                    }),
                     "SET" => {
-                        if parts.len() < 3 || parts.len() > 5 {
-                            bail!("SET command requires 2 or 4 arguments (key, value, [PX, milliseconds])");
-                        }
+                        let key = parts[1].get_str()?;
 
-                        let key = match &parts[1] {
-                            RespDataType::BulkString(key) => key.clone(),
-                            _ => bail!("SET key must be a bulk string"),
-                        };
+                        let val = parts[2].get_bytes()?;
 
-                        let val = match &parts[2] {
-                            RespDataType::BulkString(val) => val.clone(),
-                            _ => bail!("SET value must be a bulk string"),
-                        };
+                        let mut px = None;
+                        let mut condition = None;
+                        let mut get = false;
+                        let mut keep_ttl = false;
 
-                        let px = if parts.len() > 3 {
-                            match (&parts[3], parts.get(4)) {
-                                (
-                                    RespDataType::BulkString(opt),
-                                    Some(RespDataType::BulkString(ms)),
-                                ) => {
-                                    if opt.to_uppercase() == "PX" {
-                                        let milliseconds = ms
-                                            .parse::<u64>()
-                                            .context("PX value must be a valid number")?;
-                                        Some(Duration::from_millis(milliseconds))
-                                    } else {
-                                        bail!("Only PX option is supported for SET");
-                                    }
+                        let mut options = parts[3..].iter();
+                        while let Some(opt) = options.next() {
+                            let Ok(opt) = opt.get_str() else {
+                                bail!("Invalid SET options format");
+                            };
+                            match opt.to_uppercase().as_str() {
+                                "PX" if px.is_none() && !keep_ttl => {
+                                    let Some(Ok(ms)) = options.next().map(RespDataType::get_str)
+                                    else {
+                                        bail!("ERR syntax error");
+                                    };
+                                    let milliseconds = ms
+                                        .parse::<u64>()
+                                        .context("PX value must be a valid number")?;
+                                    px = Some(Duration::from_millis(milliseconds));
+                                }
+                                // The deadline is turned into a TTL right away
+                                "PXAT" if px.is_none() && !keep_ttl => {
+                                    let Some(Ok(ms)) = options.next().map(RespDataType::get_str)
+                                    else {
+                                        bail!("ERR syntax error");
+                                    };
+                                    let unix_ms = ms
+                                        .parse::<u64>()
+                                        .context("PXAT value must be a valid number")?;
+                                    let now = SystemTime::now()
+                                        .duration_since(UNIX_EPOCH)
+                                        .unwrap_or_default()
+                                        .as_millis()
+                                        as u64;
+                                    px = Some(Duration::from_millis(unix_ms.saturating_sub(now)));
                                 }
-                                _ => bail!("Invalid SET options format"),
+                                "NX" if condition.is_none() => condition = Some(SetCondition::NX),
+                                "XX" if condition.is_none() => condition = Some(SetCondition::XX),
+                                "GET" => get = true,
+                                "KEEPTTL" if px.is_none() => keep_ttl = true,
+                                _ => bail!("ERR syntax error"),
                             }
-                        } else {
-                            None
-                        };
+                        }
 
-                        Ok(Command::SET { key, val, px })
+                        Ok(Command::SET {
+                            key,
+                            val,
+                            px,
+                            condition,
+                            get,
+                            keep_ttl,
+                        })
                     }
                     "RPUSH" => {
-                        if parts.len() < 3 {
-                            bail!("RPush command requires 3 or more arguments RPUSH key element [element ...]");
-                        }
-
-                        let key = match &parts[1] {
-                            RespDataType::BulkString(key) => key.clone(),
-                            _ => bail!("RPUSH key must be a bulk string"),
-                        };
+                        let key = parts[1].get_str()?;
 
                         let elements = parts[2..]
                             .iter()
-                            .map(|p| match p {
-                                RespDataType::BulkString(s) => Ok(s.clone()),
-                                _ => bail!("RPUSH values must be bulk strings"),
-                            })
+                            .map(RespDataType::get_str)
                             .collect::<Result<Vec<String>, anyhow::Error>>()?;
 
                         Ok(Command::RPUSH { key, elements })
                     }
                     "LRANGE" => {
                         // LRANGE key start stop
-                        if parts.len() != 4 {
-                            bail!("LRANGE LRANGE key start stop");
-                        }
-                        match (&parts[1], &parts[2], &parts[3]) {
-                            (
-                                RespDataType::BulkString(key),
-                                RespDataType::BulkString(start),
-                                RespDataType::BulkString(stop),
-                            ) => Ok(Command::LRANGE{
-                                key: key.clone(),
+                        match (parts[1].get_str(), parts[2].get_str(), parts[3].get_str()) {
+                            (Ok(key), Ok(start), Ok(stop)) => Ok(Command::LRANGE {
+                                key,
                                 start: start.parse().context("Failed to parse Start ")?,
                                 stop: stop.parse().context("Failed to parse Stop")?,
                             }),
-                            (part_1, part_2, part_3) => bail!("LRANGE params must be a bulk string, got ({part_1:#?},{part_2:#?},{part_3:#?})"),
-
+                            _ => bail!(
+                                "LRANGE params must be a bulk string, got ({:#?},{:#?},{:#?})",
+                                parts[1],
+                                parts[2],
+                                parts[3]
+                            ),
                         }
                     }
-                    "LPUSH" => {
-                        if parts.len() < 3 {
-                            bail!("LPush command requires 3 or more arguments RPUSH key element [element ...]");
+                    "SORT" => {
+                        let key = parts[1].get_str()?;
+                        let (mut alpha, mut desc, mut limit) = (false, false, None);
+                        let mut options = parts[2..].iter();
+                        while let Some(opt) = options.next() {
+                            match opt.get_str()?.to_uppercase().as_str() {
+                                "ASC" => desc = false,
+                                "DESC" => desc = true,
+                                "ALPHA" => alpha = true,
+                                "LIMIT" => {
+                                    let (Some(offset), Some(count)) =
+                                        (options.next(), options.next())
+                                    else {
+                                        bail!("ERR syntax error");
+                                    };
+                                    // Like Redis, a negative offset starts at 0 and a
+                                    // negative count takes every element
+                                    let offset = parse_integer(offset)?.max(0) as usize;
+                                    let count = usize::try_from(parse_integer(count)?)
+                                        .unwrap_or(usize::MAX);
+                                    limit = Some((offset, count));
+                                }
+                                _ => bail!("ERR syntax error"),
+                            }
                         }
-
-                        let key = match &parts[1] {
-                            RespDataType::BulkString(key) => key.clone(),
-                            _ => bail!("LPUSH key must be a bulk string"),
-                        };
+                        Ok(Command::SORT {
+                            key,
+                            alpha,
+                            desc,
+                            limit,
+                        })
+                    }
+                    "LPUSH" => {
+                        let key = parts[1].get_str()?;
 
                         let elements = parts[2..]
                             .iter()
-                            .map(|p| match p {
-                                RespDataType::BulkString(s) => Ok(s.clone()),
-                                _ => bail!("LPUSH values must be bulk strings"),
-                            })
+                            .map(RespDataType::get_str)
                             .collect::<Result<Vec<String>, anyhow::Error>>()?;
 
                         Ok(Command::LPUSH { key, elements })
                     }
-                    "LLEN" => {
-                        if parts.len() != 2 {
-                            bail!("LLEN command requires exactly 1 argument");
-                        }
-                        match &parts[1] {
-                            RespDataType::BulkString(key) => Ok(Command::LLEN { key: key.clone() }),
-                            _ => bail!("LLEN key must be a bulk string"),
-                        }
-                    }
+                    "LLEN" => Ok(Command::LLEN {
+                        key: parts[1].get_str()?,
+                    }),
                     "LPOP" => {
-                        if parts.len() < 2 || parts.len() > 3 {
-                            bail!("LPOP command requires 1 or 2 arguments (key, [count])");
+                        if parts.len() > 3 {
+                            bail!(CommandError::WrongArity("lpop".into()));
                         }
 
-                        let key = match &parts[1] {
-                            RespDataType::BulkString(key) => key.clone(),
-                            _ => bail!("LPOP key must be a bulk string"),
-                        };
+                        let key = parts[1].get_str()?;
 
-                        let count = if let Some(RespDataType::BulkString(s)) = parts.get(2) {
-                            Some(
-                                s.parse::<i64>()
-                                    .context("LPOP count mas be a valid integer")?,
-                            )
-                        } else {
-                            None
+                        let count = match parts.get(2) {
+                            Some(count) => {
+                                Some(usize::try_from(parse_integer(count)?).map_err(|_| {
+                                    anyhow!("ERR value is out of range, must be positive")
+                                })?)
+                            }
+                            None => None,
                         };
 
                         Ok(Command::LPOP { key, count })
                     }
 
-                    "BLPOP" => {
-                        if parts.len() < 2 {
-                            bail!("BLPOP requires at least one key and a timeout");
+                    "LPOS" => {
+                        let key = parts[1].get_str()?;
+                        let element = parts[2].get_str()?;
+                        let (mut rank, mut count) = (None, None);
+                        let mut options = parts[3..].iter();
+                        while let Some(opt) = options.next() {
+                            let Some(value) = options.next() else {
+                                bail!("ERR syntax error");
+                            };
+                            match opt.get_str()?.to_uppercase().as_str() {
+                                "RANK" => match parse_integer(value)? {
+                                    0 => bail!("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"),
+                                    i64::MIN => bail!("ERR value is out of range, value must between -9223372036854775807 and 9223372036854775807"),
+                                    value => rank = Some(value),
+                                },
+                                "COUNT" => {
+                                    count = Some(
+                                        usize::try_from(parse_integer(value)?)
+                                            .map_err(|_| anyhow!("ERR COUNT can't be negative"))?,
+                                    )
+                                }
+                                _ => bail!("ERR syntax error"),
+                            }
                         }
-
+                        Ok(Command::LPOS {
+                            key,
+                            element,
+                            rank,
+                            count,
+                        })
+                    }
+                    "LMPOP" => {
+                        let numkeys = usize::try_from(parse_integer(&parts[1])?)
+                            .ok()
+                            .filter(|&numkeys| numkeys > 0)
+                            .ok_or_else(|| anyhow!("ERR numkeys should be greater than 0"))?;
+                        if numkeys > parts.len() - 3 {
+                            bail!("ERR Number of keys can't be greater than number of args");
+                        }
+                        let keys = parts[2..2 + numkeys]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+                        let end = match parts[2 + numkeys].get_str()?.to_uppercase().as_str() {
+                            "LEFT" => ListEnd::Left,
+                            "RIGHT" => ListEnd::Right,
+                            _ => bail!("ERR syntax error"),
+                        };
+                        let count = match &parts[3 + numkeys..] {
+                            [] => 1,
+                            [opt, count] if opt.get_str()?.eq_ignore_ascii_case("COUNT") => {
+                                usize::try_from(parse_integer(count)?)
+                                    .ok()
+                                    .filter(|&count| count > 0)
+                                    .ok_or_else(|| anyhow!("ERR count should be greater than 0"))?
+                            }
+                            _ => bail!("ERR syntax error"),
+                        };
+                        Ok(Command::LMPOP { keys, end, count })
+                    }
+                    "BLPOP" => {
                         // All elements except the last are keys
                         let keys = parts[1..parts.len() - 1]
                             .iter()
-                            .map(|p| match p {
-                                RespDataType::BulkString(key) => Ok(key.clone()),
-                                _ => bail!("BLPOP keys must be bulk strings"),
-                            })
+                            .map(RespDataType::get_str)
                             .collect::<Result<Vec<String>, anyhow::Error>>()?;
 
-                        if keys.is_empty() {
-                            bail!("BLPOP requires at least one key");
+                        // Timeout in seconds, possibly fractional
+                        let timeout = parts[parts.len() - 1]
+                            .get_str()
+                            .context("Timeout must be a bulk string")?
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|secs| secs.is_finite())
+                            .context("ERR timeout is not a float or out of range")?;
+
+                        if timeout < 0.0 {
+                            bail!("ERR timeout is negative");
+                        }
+
+                        let timeout = Duration::from_secs_f64(timeout);
+
+                        Ok(Command::BLPOP { keys, timeout })
+                    }
+                    "APPEND" => Ok(Command::APPEND {
+                        key: parts[1].get_str()?,
+                        value: parts[2].get_bytes()?,
+                    }),
+                    "STRLEN" => Ok(Command::STRLEN {
+                        key: parts[1].get_str()?,
+                    }),
+                    "SETRANGE" => {
+                        let offset = parts[2]
+                            .get_str()?
+                            .parse::<i64>()
+                            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                        let offset = usize::try_from(offset)
+                            .map_err(|_| anyhow!("ERR offset is out of range"))?;
+                        Ok(Command::SETRANGE {
+                            key: parts[1].get_str()?,
+                            offset,
+                            value: parts[3].get_bytes()?,
+                        })
+                    }
+                    "BITCOUNT" => {
+                        let key = parts
+                            .get(1)
+                            .ok_or_else(|| CommandError::WrongArity("bitcount".into()))?
+                            .get_str()?;
+                        let range = match &parts[2..] {
+                            [] => None,
+                            [start, end] => {
+                                Some((parse_integer(start)?, parse_integer(end)?, BitUnit::Byte))
+                            }
+                            [start, end, unit] => {
+                                let unit = match unit.get_str()?.to_uppercase().as_str() {
+                                    "BYTE" => BitUnit::Byte,
+                                    "BIT" => BitUnit::Bit,
+                                    _ => bail!("ERR syntax error"),
+                                };
+                                Some((parse_integer(start)?, parse_integer(end)?, unit))
+                            }
+                            _ => bail!("ERR syntax error"),
+                        };
+                        Ok(Command::BITCOUNT { key, range })
+                    }
+                    "BITOP" => {
+                        let op = match parts[1].get_str()?.to_uppercase().as_str() {
+                            "AND" => BitOp::And,
+                            "OR" => BitOp::Or,
+                            "XOR" => BitOp::Xor,
+                            "NOT" => BitOp::Not,
+                            _ => bail!("ERR syntax error"),
+                        };
+                        let sources = parts[3..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+                        if op == BitOp::Not && sources.len() != 1 {
+                            bail!("ERR BITOP NOT must be called with a single source key.");
                         }
+                        Ok(Command::BITOP {
+                            op,
+                            dest: parts[2].get_str()?,
+                            sources,
+                        })
+                    }
+                    "SETBIT" | "GETBIT" => {
+                        let key = parts[1].get_str()?;
+                        let offset = parts[2]
+                            .get_str()?
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|offset| *offset <= MAX_BIT_OFFSET)
+                            .context("ERR bit offset is not an integer or out of range")?;
 
-                        let timeout = match &parts[parts.len() - 1] {
-                            RespDataType::BulkString(timeout_str) => timeout_str
-                                .parse::<u64>()
-                                .context("Timeout must be a valid unsigned integer")?,
-                            _ => bail!("Timeout must be a bulk string"),
+                        if cmd == "GETBIT" {
+                            return Ok(Command::GETBIT { key, offset });
+                        }
+                        let value = match parts[3].get_str()?.as_str() {
+                            "0" => false,
+                            "1" => true,
+                            _ => bail!("ERR bit is not an integer or out of range"),
                         };
+                        Ok(Command::SETBIT { key, offset, value })
+                    }
+                    "ZADD" => {
+                        let key = parts[1].get_str()?;
 
-                        let timeout = Duration::from_secs(timeout);
+                        let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+                        let mut args = &parts[2..];
+                        while let Some(Ok(flag)) = args.first().map(RespDataType::get_str) {
+                            match flag.to_uppercase().as_str() {
+                                "NX" => nx = true,
+                                "XX" => xx = true,
+                                "GT" => gt = true,
+                                "LT" => lt = true,
+                                _ => break,
+                            }
+                            args = &args[1..];
+                        }
+                        if nx && xx {
+                            bail!("ERR XX and NX options at the same time are not compatible");
+                        }
+                        if [nx, gt, lt].iter().filter(|flag| **flag).count() > 1 {
+                            bail!(
+                                "ERR GT, LT, and/or NX options at the same time are not compatible"
+                            );
+                        }
+                        if args.is_empty() || args.len() % 2 != 0 {
+                            bail!("ERR syntax error");
+                        }
 
-                        Ok(Command::BLPOP { keys, timeout })
+                        let members = args
+                            .chunks_exact(2)
+                            .map(|pair| Ok((parse_score(&pair[0])?, pair[1].get_str()?)))
+                            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+                        let condition = match (nx, xx) {
+                            (true, _) => Some(SetCondition::NX),
+                            (_, true) => Some(SetCondition::XX),
+                            _ => None,
+                        };
+                        let comparison = match (gt, lt) {
+                            (true, _) => Some(ScoreComparison::GT),
+                            (_, true) => Some(ScoreComparison::LT),
+                            _ => None,
+                        };
+                        Ok(Command::ZADD {
+                            key,
+                            condition,
+                            comparison,
+                            members,
+                        })
+                    }
+                    "ZINCRBY" => Ok(Command::ZINCRBY {
+                        key: parts[1].get_str()?,
+                        delta: parse_score(&parts[2])?,
+                        member: parts[3].get_str()?,
+                    }),
+                    "ZREM" => {
+                        let members = parts[2..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+                        Ok(Command::ZREM {
+                            key: parts[1].get_str()?,
+                            members,
+                        })
                     }
-                    "INCR" => {
-                        if parts.len() != 2 {
-                            bail!("INCR command requires exactly 1 argument");
+                    "ZCARD" => Ok(Command::ZCARD {
+                        key: parts[1].get_str()?,
+                    }),
+                    "ZCOUNT" => Ok(Command::ZCOUNT {
+                        key: parts[1].get_str()?,
+                        min: parse_score_bound(&parts[2])?,
+                        max: parse_score_bound(&parts[3])?,
+                    }),
+                    "ZRANGE" | "ZREVRANGE" => {
+                        let with_scores = match &parts[1..] {
+                            [_, _, _] => false,
+                            [_, _, _, option] => {
+                                if !option.get_str()?.eq_ignore_ascii_case("WITHSCORES") {
+                                    bail!("ERR syntax error");
+                                }
+                                true
+                            }
+                            _ => bail!(CommandError::WrongArity(cmd.to_lowercase())),
+                        };
+                        let key = parts[1].get_str()?;
+                        let (start, stop) = (parse_integer(&parts[2])?, parse_integer(&parts[3])?);
+                        Ok(if cmd == "ZRANGE" {
+                            Command::ZRANGE {
+                                key,
+                                start,
+                                stop,
+                                with_scores,
+                            }
+                        } else {
+                            Command::ZREVRANGE {
+                                key,
+                                start,
+                                stop,
+                                with_scores,
+                            }
+                        })
+                    }
+                    "ZRANGEBYSCORE" => {
+                        let key = parts[1].get_str()?;
+                        let min = parse_score_bound(&parts[2])?;
+                        let max = parse_score_bound(&parts[3])?;
+
+                        let mut with_scores = false;
+                        let mut limit = None;
+                        let mut options = parts[4..].iter();
+                        while let Some(opt) = options.next() {
+                            match opt.get_str()?.to_uppercase().as_str() {
+                                "WITHSCORES" => with_scores = true,
+                                "LIMIT" => {
+                                    let (Some(offset), Some(count)) =
+                                        (options.next(), options.next())
+                                    else {
+                                        bail!("ERR syntax error");
+                                    };
+                                    limit = Some((parse_integer(offset)?, parse_integer(count)?));
+                                }
+                                _ => bail!("ERR syntax error"),
+                            }
                         }
-                        match &parts[1] {
-                            RespDataType::BulkString(key) => Ok(Command::INCR { key: key.clone() }),
-                            _ => bail!("GET key must be a bulk string"),
+
+                        Ok(Command::ZRANGEBYSCORE {
+                            key,
+                            min,
+                            max,
+                            with_scores,
+                            limit,
+                        })
+                    }
+                    "ZSCORE" | "ZRANK" | "ZREVRANK" => {
+                        let key = parts[1].get_str()?;
+                        let member = parts[2].get_str()?;
+                        Ok(match cmd.as_str() {
+                            "ZSCORE" => Command::ZSCORE { key, member },
+                            "ZRANK" => Command::ZRANK { key, member },
+                            _ => Command::ZREVRANK { key, member },
+                        })
+                    }
+                    "INCR" => Ok(Command::INCR {
+                        key: parts[1].get_str()?,
+                    }),
+                    "EXPIRE" => {
+                        let seconds = parse_integer(&parts[2])?;
+                        // Like Redis, the TTL must fit in milliseconds
+                        if seconds.checked_mul(1000).is_none() {
+                            bail!("ERR invalid expire time in 'expire' command");
                         }
+                        Ok(Command::EXPIRE {
+                            key: parts[1].get_str()?,
+                            seconds,
+                        })
+                    }
+                    "PEXPIREAT" => Ok(Command::PEXPIREAT {
+                        key: parts[1].get_str()?,
+                        unix_ms: parse_integer(&parts[2])?,
+                    }),
+                    "TTL" => Ok(Command::TTL {
+                        key: parts[1].get_str()?,
+                    }),
+                    "EXPIRETIME" => Ok(Command::EXPIRETIME {
+                        key: parts[1].get_str()?,
+                    }),
+                    "PEXPIRETIME" => Ok(Command::PEXPIRETIME {
+                        key: parts[1].get_str()?,
+                    }),
+                    "DEL" => {
+                        let keys = parts[1..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+
+                        Ok(Command::DEL { keys })
+                    }
+                    "UNLINK" | "TOUCH" => {
+                        let keys = parts[1..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+
+                        Ok(if cmd == "UNLINK" {
+                            Command::UNLINK { keys }
+                        } else {
+                            Command::TOUCH { keys }
+                        })
                     }
-                    "MULTI" => {
-                        if parts.len() > 1 {
-                            bail!("MULTI command takes no arguments");
+                    "COPY" => {
+                        let (Some(Ok(src)), Some(Ok(dst))) = (
+                            parts.get(1).map(RespDataType::get_str),
+                            parts.get(2).map(RespDataType::get_str),
+                        ) else {
+                            bail!("COPY command requires a source and a destination key");
+                        };
+
+                        let mut replace = false;
+                        for opt in &parts[3..] {
+                            match opt.get_str() {
+                                Ok(opt) if opt.eq_ignore_ascii_case("REPLACE") => replace = true,
+                                _ => bail!("ERR syntax error"),
+                            }
                         }
-                        Ok(Command::MULTI)
+
+                        Ok(Command::COPY { src, dst, replace })
                     }
+                    "SELECT" => {
+                        let index = parts[1]
+                            .get_str()?
+                            .parse::<i64>()
+                            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                        let index = usize::try_from(index)
+                            .map_err(|_| anyhow!("ERR DB index is out of range"))?;
+                        Ok(Command::SELECT { index })
+                    }
+                    "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                        let channels = parts[1..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
 
-                    "EXEC" => {
-                        if parts.len() > 1 {
-                            bail!("EXEC command takes no arguments");
+                        Ok(if cmd == "SUBSCRIBE" {
+                            Command::SUBSCRIBE { channels }
+                        } else {
+                            Command::UNSUBSCRIBE { channels }
+                        })
+                    }
+                    "PUBLISH" => match (parts[1].get_str(), parts[2].get_str()) {
+                        (Ok(channel), Ok(message)) => Ok(Command::PUBLISH { channel, message }),
+                        _ => bail!("PUBLISH channel and message must be bulk strings"),
+                    },
+                    "RANDOMKEY" => Ok(Command::RANDOMKEY),
+                    "DBSIZE" => Ok(Command::DBSIZE),
+                    "FLUSHDB" | "FLUSHALL" => {
+                        // The ASYNC/SYNC modifiers only matter for lazy freeing
+                        match &parts[1..] {
+                            [] => {}
+                            [mode]
+                                if mode.get_str().is_ok_and(|mode| {
+                                    mode.eq_ignore_ascii_case("ASYNC")
+                                        || mode.eq_ignore_ascii_case("SYNC")
+                                }) => {}
+                            _ => bail!("ERR syntax error"),
                         }
-                        Ok(Command::EXEC)
+                        Ok(if cmd == "FLUSHDB" {
+                            Command::FLUSHDB
+                        } else {
+                            Command::FLUSHALL
+                        })
                     }
+                    "SCAN" => {
+                        let cursor = parts[1]
+                            .get_str()?
+                            .parse::<u64>()
+                            .map_err(|_| anyhow!("ERR invalid cursor"))?;
+
+                        let mut pattern = None;
+                        let mut count = None;
 
-                    "DISCARD" => {
-                        if parts.len() > 1 {
-                            bail!("DISCARD command takes no arguments");
+                        let mut options = parts[2..].iter();
+                        while let Some(opt) = options.next() {
+                            let (Ok(opt), Some(Ok(arg))) =
+                                (opt.get_str(), options.next().map(RespDataType::get_str))
+                            else {
+                                bail!("ERR syntax error");
+                            };
+                            match opt.to_uppercase().as_str() {
+                                "MATCH" => pattern = Some(arg),
+                                "COUNT" => {
+                                    let n = arg.parse::<usize>().map_err(|_| {
+                                        anyhow!("ERR value is not an integer or out of range")
+                                    })?;
+                                    if n == 0 {
+                                        bail!("ERR syntax error");
+                                    }
+                                    count = Some(n);
+                                }
+                                _ => bail!("ERR syntax error"),
+                            }
                         }
-                        Ok(Command::DISCARD)
+
+                        Ok(Command::SCAN {
+                            cursor,
+                            pattern,
+                            count,
+                        })
                     }
+                    "MULTI" => Ok(Command::MULTI),
+
+                    "EXEC" => Ok(Command::EXEC),
+
+                    "DISCARD" => Ok(Command::DISCARD),
+                    "WATCH" => {
+                        let keys = parts[1..]
+                            .iter()
+                            .map(RespDataType::get_str)
+                            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+
+                        Ok(Command::WATCH { keys })
+                    }
+                    "UNWATCH" => Ok(Command::UNWATCH),
+                    "RESET" => Ok(Command::RESET),
                     "REPLCONF" => {
-                        if let Some(args) = parts.get(1..) {
-                            println!("{args:?}");
-                        }
-                        Ok(Command::REPLCONF)
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let Some(option) = args.first() else {
+                            bail!("REPLCONF command requires arguments");
+                        };
+
+                        let conf = match option.to_lowercase().as_str() {
+                            "listening-port" => {
+                                let port = args
+                                    .get(1)
+                                    .context("REPLCONF listening-port requires a port")?
+                                    .parse::<u16>()
+                                    .context("REPLCONF listening-port must be a valid port")?;
+                                ReplConf::ListeningPort(port)
+                            }
+                            "capa" => {
+                                let capabilities = args
+                                    .chunks(2)
+                                    .map(|pair| match pair {
+                                        [opt, capa] if opt.eq_ignore_ascii_case("capa") => {
+                                            Ok(capa.clone())
+                                        }
+                                        _ => bail!("ERR syntax error"),
+                                    })
+                                    .collect::<Result<Vec<String>, anyhow::Error>>()?;
+                                ReplConf::Capa(capabilities)
+                            }
+                            "getack" => ReplConf::GetAck,
+                            "ack" => {
+                                let offset = args
+                                    .get(1)
+                                    .context("REPLCONF ACK requires an offset")?
+                                    .parse::<usize>()
+                                    .context("REPLCONF ACK offset must be a valid integer")?;
+                                ReplConf::Ack(offset)
+                            }
+                            _ => bail!("ERR Unrecognized REPLCONF option: {}", option),
+                        };
+
+                        Ok(Command::REPLCONF(conf))
                     }
 
-                    "INFO" => match parts.get(2) {
-                        Some(RespDataType::BulkString(param)) => match param.as_str() {
-                            "replication" => Ok(Command::INFO {
-                                section: Some(Section::Replication),
-                            }),
-                            _ => bail!("ERR unsupported INFO section"),
-                        },
+                    "DEBUG" => {
+                        let subcommand = parts[1].get_str()?;
+                        let subcommand = match subcommand.to_uppercase().as_str() {
+                            "PURGE-EXPIRED" => DebugSubcommand::PurgeExpired,
+                            "LISTPACK-ENTRIES" => DebugSubcommand::ListpackEntries,
+                            "SLEEP" => {
+                                let seconds = parts
+                                    .get(2)
+                                    .context("DEBUG SLEEP requires a number of seconds")?
+                                    .get_str()?
+                                    .parse::<f64>()
+                                    .ok()
+                                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                                    .context("ERR value is not a valid float")?;
+                                DebugSubcommand::Sleep(seconds)
+                            }
+                            "OBJECT" => {
+                                let key = parts
+                                    .get(2)
+                                    .context("DEBUG OBJECT requires a key")?
+                                    .get_str()?;
+                                DebugSubcommand::Object(key)
+                            }
+                            "SET-ACTIVE-EXPIRE" => {
+                                let enabled = parts
+                                    .get(2)
+                                    .context("DEBUG SET-ACTIVE-EXPIRE requires 0 or 1")?;
+                                DebugSubcommand::SetActiveExpire(parse_integer(enabled)? != 0)
+                            }
+                            _ => bail!("ERR unknown DEBUG subcommand '{}'", subcommand),
+                        };
+
+                        Ok(Command::DEBUG { subcommand })
+                    }
+                    "OBJECT" => {
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let subcommand = match args.as_slice() {
+                            [sub, key] if sub.eq_ignore_ascii_case("ENCODING") => {
+                                ObjectSubcommand::Encoding(key.clone())
+                            }
+                            [sub, key] if sub.eq_ignore_ascii_case("IDLETIME") => {
+                                ObjectSubcommand::IdleTime(key.clone())
+                            }
+                            [sub, key] if sub.eq_ignore_ascii_case("REFCOUNT") => {
+                                ObjectSubcommand::RefCount(key.clone())
+                            }
+                            [sub, key] if sub.eq_ignore_ascii_case("FREQ") => {
+                                ObjectSubcommand::Freq(key.clone())
+                            }
+                            [sub, ..] => bail!(
+                                "ERR unknown subcommand or wrong number of arguments for 'OBJECT|{}'",
+                                sub
+                            ),
+                            [] => bail!(CommandError::WrongArity("object".into())),
+                        };
+
+                        Ok(Command::OBJECT { subcommand })
+                    }
+                    "AUTH" => match &parts[1..] {
+                        [password] => Ok(Command::AUTH {
+                            username: None,
+                            password: password.get_str()?,
+                        }),
+                        [username, password] => Ok(Command::AUTH {
+                            username: Some(username.get_str()?),
+                            password: password.get_str()?,
+                        }),
+                        _ => bail!("ERR syntax error"),
+                    },
+                    "ACL" => {
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let subcommand = match args.as_slice() {
+                            [sub, name, rules @ ..] if sub.eq_ignore_ascii_case("SETUSER") => {
+                                AclSubcommand::SetUser {
+                                    name: name.clone(),
+                                    rules: rules.to_vec(),
+                                }
+                            }
+                            [sub] if sub.eq_ignore_ascii_case("USERS") => AclSubcommand::Users,
+                            [sub] if sub.eq_ignore_ascii_case("WHOAMI") => AclSubcommand::WhoAmI,
+                            [sub, ..] => bail!(
+                                "ERR unknown subcommand or wrong number of arguments for 'ACL|{}'",
+                                sub
+                            ),
+                            [] => bail!(CommandError::WrongArity("acl".into())),
+                        };
+
+                        Ok(Command::ACL { subcommand })
+                    }
+                    "CLIENT" => {
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let subcommand = match args.as_slice() {
+                            [sub] if sub.eq_ignore_ascii_case("ID") => ClientSubcommand::Id,
+                            [sub, name] if sub.eq_ignore_ascii_case("SETNAME") => {
+                                ClientSubcommand::SetName(name.clone())
+                            }
+                            [sub] if sub.eq_ignore_ascii_case("GETNAME") => {
+                                ClientSubcommand::GetName
+                            }
+                            [sub] if sub.eq_ignore_ascii_case("LIST") => ClientSubcommand::List,
+                            [sub, filters @ ..]
+                                if sub.eq_ignore_ascii_case("KILL") && !filters.is_empty() =>
+                            {
+                                let (mut id, mut addr) = (None, None);
+                                for filter in filters.chunks(2) {
+                                    match filter {
+                                        [name, value] if name.eq_ignore_ascii_case("ID") => {
+                                            let value = value.parse::<u64>().ok().filter(|id| *id > 0);
+                                            id = Some(value.context(
+                                                "ERR client-id should be greater than 0",
+                                            )?);
+                                        }
+                                        [name, value] if name.eq_ignore_ascii_case("ADDR") => {
+                                            addr = Some(value.clone());
+                                        }
+                                        _ => bail!("ERR syntax error"),
+                                    }
+                                }
+                                ClientSubcommand::Kill { id, addr }
+                            }
+                            [sub, ..] => bail!(
+                                "ERR unknown subcommand or wrong number of arguments for 'CLIENT|{}'",
+                                sub
+                            ),
+                            [] => bail!(CommandError::WrongArity("client".into())),
+                        };
+
+                        Ok(Command::CLIENT { subcommand })
+                    }
+                    "COMMAND" => {
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let subcommand = match args.split_first() {
+                            None => CommandSubcommand::Info,
+                            Some((sub, [])) if sub.eq_ignore_ascii_case("COUNT") => {
+                                CommandSubcommand::Count
+                            }
+                            Some((sub, names)) if sub.eq_ignore_ascii_case("DOCS") => {
+                                CommandSubcommand::Docs(names.to_vec())
+                            }
+                            Some((sub, args)) if sub.eq_ignore_ascii_case("GETKEYS") => {
+                                if args.is_empty() {
+                                    bail!(CommandError::WrongArity("command|getkeys".into()));
+                                }
+                                CommandSubcommand::GetKeys(args.to_vec())
+                            }
+                            Some((sub, _)) => {
+                                bail!("ERR unknown subcommand '{}'. Try COMMAND HELP.", sub)
+                            }
+                        };
+                        Ok(Command::COMMAND { subcommand })
+                    }
+                    "SAVE" => Ok(Command::SAVE),
+                    "BGSAVE" => Ok(Command::BGSAVE),
+                    "SHUTDOWN" => {
+                        let save = match &parts[1..] {
+                            [] => None,
+                            [opt] => match opt.get_str()?.to_uppercase().as_str() {
+                                "SAVE" => Some(true),
+                                "NOSAVE" => Some(false),
+                                _ => bail!("ERR syntax error"),
+                            },
+                            _ => bail!("ERR syntax error"),
+                        };
+                        Ok(Command::SHUTDOWN { save })
+                    }
+                    "CONFIG" => {
+                        let args = parts[1..].iter().map(|p| p.get_str()).collect::<Result<
+                            Vec<String>,
+                            anyhow::Error,
+                        >>(
+                        )?;
+
+                        let subcommand = match args.as_slice() {
+                            [sub, pattern] if sub.eq_ignore_ascii_case("GET") => {
+                                ConfigSubcommand::Get(pattern.clone())
+                            }
+                            [sub, parameter, value] if sub.eq_ignore_ascii_case("SET") => {
+                                ConfigSubcommand::Set {
+                                    parameter: parameter.clone(),
+                                    value: value.clone(),
+                                }
+                            }
+                            [sub, ..] => bail!(
+                                "ERR unknown subcommand or wrong number of arguments for 'CONFIG|{}'",
+                                sub
+                            ),
+                            [] => bail!(CommandError::WrongArity("config".into())),
+                        };
+
+                        Ok(Command::CONFIG(subcommand))
+                    }
+                    "INFO" => match parts.get(1) {
+                        Some(param @ RespDataType::BulkString(_)) => {
+                            let name = param.get_str()?.to_lowercase();
+                            let section = Section::ALL
+                                .into_iter()
+                                .find(|section| section.title().to_lowercase() == name)
+                                .ok_or_else(|| anyhow!("ERR unsupported INFO section"))?;
+                            Ok(Command::INFO {
+                                section: Some(section),
+                            })
+                        }
                         Some(_) => bail!("ERR expected BulkString for section"),
                         None => Ok(Command::INFO { section: None }),
                     },
-                    "PSYNC" => {
-                        if parts.len() != 3 {
-                            bail!("expected 3 parameters in psync");
-                        }
-
-                        match (&parts[1], &parts[2]) {
-                            (
-                                RespDataType::BulkString(replica_id),
-                                RespDataType::BulkString(master_offset),
-                            ) => {
-                                let offset = master_offset.parse::<i64>().map_err(|e| anyhow!("Failed to parse offset as i64: {}", e))?;
-                                Ok(Command::PSYNC {
-                                    replication_id: replica_id.clone(),
-                                    offset,
-                                })
-                            }
-                            _ => bail!("PSYNC expects two BulkString parameters: replication_id and offset"),
+                    "PSYNC" => match (parts[1].get_str(), parts[2].get_str()) {
+                        (Ok(replica_id), Ok(master_offset)) => {
+                            let offset = master_offset
+                                .parse::<i64>()
+                                .map_err(|e| anyhow!("Failed to parse offset as i64: {}", e))?;
+                            Ok(Command::PSYNC {
+                                replication_id: replica_id,
+                                offset,
+                            })
                         }
-                    }
-                    _ => bail!("Unknown command: {}", cmd),
+                        _ => bail!(
+                            "PSYNC expects two BulkString parameters: replication_id and offset"
+                        ),
+                    },
+                    _ => bail!(CommandError::UnknownCommand {
+                        name,
+                        args: parts[1..]
+                            .iter()
+                            .map(|part| part.get_str().unwrap_or_default())
+                            .collect(),
+                    }),
                 }
             }
             _ => bail!("Command must be an array of RESP types"),
         }
     }
 }
+
+/// Parses an integer argument.
+fn parse_integer(part: &RespDataType) -> anyhow::Result<i64> {
+    part.get_str()?.parse::<i64>().map_err(|_| {
+        CommandError::InvalidArgument("value is not an integer or out of range".into()).into()
+    })
+}
+
+/// Parses a bound of a sorted set score range: a score, exclusive when prefixed
+/// with `(`.
+fn parse_score_bound(part: &RespDataType) -> anyhow::Result<ScoreBound> {
+    let bound = part.get_str()?;
+    let (score, exclusive) = match bound.strip_prefix('(') {
+        Some(score) => (score, true),
+        None => (bound.as_str(), false),
+    };
+    match score.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(ScoreBound { score, exclusive }),
+        _ => bail!("ERR min or max is not a float"),
+    }
+}
+
+/// Parses a sorted set score: a float, `inf`, `+inf` or `-inf`, but never NaN.
+fn parse_score(part: &RespDataType) -> anyhow::Result<f64> {
+    match part.get_str()?.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => bail!("ERR value is not a valid float"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Command> {
+        let parts = args
+            .iter()
+            .map(|arg| RespDataType::BulkString(arg.to_string().into()))
+            .collect();
+        Command::try_from(RespDataType::Array(parts))
+    }
+
+    #[test]
+    fn test_set_options_are_case_insensitive() {
+        let cmd = parse(&["set", "k", "v", "px", "100", "nx", "get"]).unwrap();
+        assert!(matches!(
+            cmd,
+            Command::SET {
+                px: Some(px),
+                condition: Some(SetCondition::NX),
+                get: true,
+                keep_ttl: false,
+                ..
+            } if px == Duration::from_millis(100)
+        ));
+
+        let cmd = parse(&["SET", "k", "v", "KeepTtl", "Xx"]).unwrap();
+        assert!(matches!(
+            cmd,
+            Command::SET {
+                px: None,
+                condition: Some(SetCondition::XX),
+                keep_ttl: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_zadd_flags_and_scores() {
+        let cmd = parse(&["zadd", "z", "xx", "gt", "1.5", "a", "-inf", "b"]).unwrap();
+        assert!(matches!(
+            cmd,
+            Command::ZADD {
+                condition: Some(SetCondition::XX),
+                comparison: Some(ScoreComparison::GT),
+                ref members,
+                ..
+            } if *members == [(1.5, "a".to_string()), (f64::NEG_INFINITY, "b".to_string())]
+        ));
+
+        assert!(parse(&["ZADD", "z", "NX", "XX", "1", "a"]).is_err());
+        assert!(parse(&["ZADD", "z", "NX", "GT", "1", "a"]).is_err());
+        assert!(parse(&["ZADD", "z", "1", "a", "2"]).is_err());
+        assert!(parse(&["ZADD", "z", "nan", "a"]).is_err());
+    }
+
+    #[test]
+    fn test_zrangebyscore_bounds_and_options() {
+        let cmd = parse(&[
+            "zrangebyscore",
+            "z",
+            "(5",
+            "+inf",
+            "limit",
+            "1",
+            "2",
+            "withscores",
+        ]);
+        assert!(matches!(
+            cmd.unwrap(),
+            Command::ZRANGEBYSCORE {
+                min: ScoreBound {
+                    score: 5.0,
+                    exclusive: true
+                },
+                max: ScoreBound {
+                    exclusive: false,
+                    ..
+                },
+                with_scores: true,
+                limit: Some((1, 2)),
+                ..
+            }
+        ));
+
+        assert!(parse(&["ZRANGEBYSCORE", "z", "(x", "1"]).is_err());
+        assert!(parse(&["ZRANGEBYSCORE", "z", "0", "1", "LIMIT", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands_and_options_are_case_insensitive() {
+        assert!(matches!(
+            parse(&["scan", "0", "match", "user:*", "count", "5"]).unwrap(),
+            Command::SCAN {
+                cursor: 0,
+                pattern: Some(ref pattern),
+                count: Some(5),
+            } if pattern == "user:*"
+        ));
+        assert!(matches!(
+            parse(&["debug", "purge-EXPIRED"]).unwrap(),
+            Command::DEBUG {
+                subcommand: DebugSubcommand::PurgeExpired
+            }
+        ));
+        assert!(matches!(
+            parse(&["replconf", "GETACK", "*"]).unwrap(),
+            Command::REPLCONF(ReplConf::GetAck)
+        ));
+        assert!(matches!(
+            parse(&["replconf", "CAPA", "psync2"]).unwrap(),
+            Command::REPLCONF(ReplConf::Capa(ref capa)) if capa == &["psync2"]
+        ));
+        assert!(matches!(
+            parse(&["config", "set", "appendonly", "yes"]).unwrap(),
+            Command::CONFIG(ConfigSubcommand::Set { ref parameter, ref value })
+                if parameter == "appendonly" && value == "yes"
+        ));
+        assert!(parse(&["CONFIG", "GET"]).is_err());
+    }
+
+    #[test]
+    fn test_command_docs_describes_known_commands() {
+        let Command::COMMAND { subcommand } = parse(&["command", "docs", "GET", "nosuch"]).unwrap()
+        else {
+            panic!("expected COMMAND DOCS");
+        };
+        let RespDataType::Array(docs) = command_reply(&subcommand) else {
+            panic!("expected an array");
+        };
+
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0], RespDataType::BulkString("get".into()));
+        let RespDataType::Array(fields) = &docs[1] else {
+            panic!("expected the fields of get");
+        };
+        let arity = fields
+            .chunks(2)
+            .find(|pair| pair[0] == RespDataType::BulkString("arity".into()))
+            .map(|pair| pair[1].clone());
+        assert_eq!(arity, Some(RespDataType::Integer(2)));
+    }
+
+    #[test]
+    fn test_command_count_and_flags() {
+        let Command::COMMAND { subcommand } = parse(&["COMMAND", "COUNT"]).unwrap() else {
+            panic!("expected COMMAND COUNT");
+        };
+        assert_eq!(
+            command_reply(&subcommand),
+            RespDataType::Integer(COMMAND_TABLE.len() as i64)
+        );
+
+        let Command::COMMAND { subcommand } = parse(&["COMMAND"]).unwrap() else {
+            panic!("expected COMMAND");
+        };
+        let RespDataType::Array(info) = command_reply(&subcommand) else {
+            panic!("expected an array");
+        };
+        assert_eq!(info.len(), COMMAND_TABLE.len());
+        let set = info
+            .iter()
+            .find(|entry| {
+                matches!(entry, RespDataType::Array(fields) if fields[0] == RespDataType::BulkString("set".into()))
+            })
+            .expect("set is registered");
+        let RespDataType::Array(fields) = set else {
+            unreachable!()
+        };
+        assert_eq!(
+            fields[2],
+            RespDataType::Array(vec![RespDataType::SimpleString("write".into())])
+        );
+        assert_eq!(fields[3..], [1, 1, 1].map(RespDataType::Integer));
+    }
+
+    #[test]
+    fn test_ping_takes_an_optional_message() {
+        assert!(matches!(parse(&["PING"]).unwrap(), Command::PING(None)));
+        assert!(matches!(
+            parse(&["ping", "hello world"]).unwrap(),
+            Command::PING(Some(msg)) if msg == "hello world"
+        ));
+        assert_eq!(
+            parse(&["PING", "a", "b"]).unwrap_err().to_string(),
+            "ERR wrong number of arguments for 'ping' command"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_are_classified() {
+        let error = |args: &[&str]| CommandError::classify(parse(args).unwrap_err());
+
+        assert_eq!(
+            error(&["FOO", "bar"]),
+            CommandError::UnknownCommand {
+                name: "FOO".into(),
+                args: vec!["bar".into()],
+            }
+        );
+        assert_eq!(error(&["GET"]), CommandError::WrongArity("get".into()));
+        assert_eq!(
+            error(&["get", "a", "b"]).to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            error(&["DEBUG", "SET-ACTIVE-EXPIRE", "x"]),
+            CommandError::InvalidArgument("value is not an integer or out of range".into())
+        );
+        assert_eq!(error(&["DEBUG"]), CommandError::WrongArity("debug".into()));
+        assert_eq!(
+            error(&["DEBUG", "NOSUCH"]).to_string(),
+            "ERR unknown DEBUG subcommand 'NOSUCH'"
+        );
+        assert_eq!(
+            error(&["LPOP", "k", "1", "2"]),
+            CommandError::WrongArity("lpop".into())
+        );
+    }
+
+    #[test]
+    fn test_unknown_command_message() {
+        let message = |args: &[&str]| parse(args).unwrap_err().to_string();
+
+        assert_eq!(
+            message(&["FOO", "bar", "baz"]),
+            "ERR unknown command 'FOO', with args beginning with: 'bar' 'baz' "
+        );
+        assert_eq!(
+            message(&["foo"]),
+            "ERR unknown command 'foo', with args beginning with: "
+        );
+
+        // The preview stops once 128 characters long, cutting the argument reaching it
+        let long = "x".repeat(200);
+        let first = "a".repeat(100);
+        assert_eq!(
+            message(&["FOO", &first, &long, "never"]),
+            format!(
+                "ERR unknown command 'FOO', with args beginning with: '{first}' '{}' ",
+                "x".repeat(25)
+            )
+        );
+        assert_eq!(
+            message(&[&long]),
+            format!(
+                "ERR unknown command '{}', with args beginning with: ",
+                "x".repeat(128)
+            )
+        );
+    }
+
+    #[test]
+    fn test_expire_rejects_ttls_overflowing_milliseconds() {
+        assert_eq!(
+            parse(&["EXPIRE", "k", "9223372036854775807"])
+                .unwrap_err()
+                .to_string(),
+            "ERR invalid expire time in 'expire' command"
+        );
+        assert!(matches!(
+            parse(&["EXPIRE", "k", "9223372036854775"]).unwrap(),
+            Command::EXPIRE {
+                seconds: 9223372036854775,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_info_takes_the_section_as_first_argument() {
+        assert!(matches!(
+            parse(&["INFO", "replication"]).unwrap(),
+            Command::INFO {
+                section: Some(Section::Replication)
+            }
+        ));
+        assert!(matches!(
+            parse(&["info", "KeySpace"]).unwrap(),
+            Command::INFO {
+                section: Some(Section::Keyspace)
+            }
+        ));
+        assert!(matches!(
+            parse(&["INFO"]).unwrap(),
+            Command::INFO { section: None }
+        ));
+        assert!(parse(&["INFO", "nonexistent"]).is_err());
+    }
+
+    #[test]
+    fn test_sort_options() {
+        assert!(matches!(
+            parse(&["SORT", "k", "limit", "-1", "-1", "desc", "ALPHA"]).unwrap(),
+            Command::SORT {
+                alpha: true,
+                desc: true,
+                limit: Some((0, usize::MAX)),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(&["SORT", "k"]).unwrap(),
+            Command::SORT {
+                alpha: false,
+                desc: false,
+                limit: None,
+                ..
+            }
+        ));
+        assert!(parse(&["SORT", "k", "LIMIT", "1"]).is_err());
+        assert!(parse(&["SORT", "k", "BY", "weight_*"]).is_err());
+    }
+
+    #[test]
+    fn test_lmpop_arguments() {
+        assert!(matches!(
+            parse(&["LMPOP", "2", "a", "b", "right", "count", "3"]).unwrap(),
+            Command::LMPOP { keys, end: ListEnd::Right, count: 3 } if keys == ["a", "b"]
+        ));
+        assert_eq!(
+            parse(&["LMPOP", "0", "a", "LEFT"]).unwrap_err().to_string(),
+            "ERR numkeys should be greater than 0"
+        );
+        assert_eq!(
+            parse(&["LMPOP", "3", "a", "LEFT"]).unwrap_err().to_string(),
+            "ERR Number of keys can't be greater than number of args"
+        );
+        assert_eq!(
+            parse(&["LMPOP", "1", "a", "LEFT", "COUNT", "0"])
+                .unwrap_err()
+                .to_string(),
+            "ERR count should be greater than 0"
+        );
+        assert!(parse(&["LMPOP", "1", "a", "UP"]).is_err());
+    }
+
+    #[test]
+    fn test_lpos_options() {
+        assert!(matches!(
+            parse(&["LPOS", "k", "b", "rank", "-2", "COUNT", "0"]).unwrap(),
+            Command::LPOS {
+                rank: Some(-2),
+                count: Some(0),
+                ..
+            }
+        ));
+        assert!(parse(&["LPOS", "k", "b", "RANK", "0"])
+            .unwrap_err()
+            .to_string()
+            .starts_with("ERR RANK can't be zero"));
+        assert_eq!(
+            parse(&["LPOS", "k", "b", "COUNT", "-1"])
+                .unwrap_err()
+                .to_string(),
+            "ERR COUNT can't be negative"
+        );
+        assert!(parse(&["LPOS", "k", "b", "RANK"]).is_err());
+    }
+
+    #[test]
+    fn test_lpop_count_must_not_be_negative() {
+        assert!(matches!(
+            parse(&["LPOP", "k", "0"]).unwrap(),
+            Command::LPOP { count: Some(0), .. }
+        ));
+        assert_eq!(
+            parse(&["LPOP", "k", "-1"]).unwrap_err().to_string(),
+            "ERR value is out of range, must be positive"
+        );
+        assert_eq!(
+            parse(&["LPOP", "k", "many"]).unwrap_err().to_string(),
+            "ERR value is not an integer or out of range"
+        );
+    }
+
+    #[test]
+    fn test_command_getkeys() {
+        let keys = |args: &[&str]| {
+            let Command::COMMAND { subcommand } = parse(args).unwrap() else {
+                panic!("expected COMMAND GETKEYS");
+            };
+            command_reply(&subcommand)
+        };
+        let bulk = |keys: &[&str]| {
+            RespDataType::Array(
+                keys.iter()
+                    .map(|key| RespDataType::BulkString(key.to_string().into()))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            keys(&["COMMAND", "GETKEYS", "SET", "k", "v", "PX", "100"]),
+            bulk(&["k"])
+        );
+        assert_eq!(
+            keys(&["command", "getkeys", "del", "a", "b", "c"]),
+            bulk(&["a", "b", "c"])
+        );
+        // BLPOP ends with its timeout, which is not a key
+        assert_eq!(
+            keys(&["COMMAND", "GETKEYS", "BLPOP", "a", "b", "0"]),
+            bulk(&["a", "b"])
+        );
+        assert_eq!(
+            keys(&["COMMAND", "GETKEYS", "PING"]),
+            RespDataType::SimpleError("ERR The command has no key arguments".into())
+        );
+        assert_eq!(
+            keys(&["COMMAND", "GETKEYS", "GET"]),
+            RespDataType::SimpleError(
+                "ERR Invalid number of arguments specified for command".into()
+            )
+        );
+        assert_eq!(
+            keys(&["COMMAND", "GETKEYS", "NOSUCH", "k"]),
+            RespDataType::SimpleError("ERR Invalid command specified".into())
+        );
+    }
+
+    #[test]
+    fn test_command_table_covers_every_parsed_command() {
+        for spec in COMMAND_TABLE {
+            let err = parse(&[spec.name]).err().map(|e| e.to_string());
+            assert!(
+                err.is_none_or(|e| !e.starts_with("ERR unknown command")),
+                "{} is documented but not parsed",
+                spec.name
+            );
+        }
+
+        // The verbs are the arms of the outermost match of `Command::try_from`
+        let source = include_str!("cmd.rs");
+        let arms = source.lines().filter_map(|line| {
+            let arm = line.strip_prefix(&" ".repeat(20))?.split(" =>").next()?;
+            arm.starts_with('"').then_some(arm)
+        });
+        let verbs: Vec<&str> = arms
+            .flat_map(|arm| arm.split(" | "))
+            .map(|verb| verb.trim_matches('"'))
+            .filter(|verb| !verb.is_empty() && verb.chars().all(|c| c.is_ascii_uppercase()))
+            .collect();
+        assert!(verbs.contains(&"PING") && verbs.contains(&"PSYNC"));
+        for verb in verbs {
+            assert!(
+                COMMAND_TABLE
+                    .iter()
+                    .any(|spec| verb.eq_ignore_ascii_case(spec.name)),
+                "{verb} is parsed but not documented"
+            );
+        }
+    }
+}
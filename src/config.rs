@@ -1,9 +1,37 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
 use clap::{Arg, Command};
 
+use crate::{
+    aof::AppendFsync,
+    data_structures::list::DEFAULT_LIST_MAX_LISTPACK_SIZE,
+    glob::glob_match,
+    pubsub::KeyspaceEvents,
+    storage::{MaxmemoryPolicy, DEFAULT_MAXMEMORY_SAMPLES},
+};
+
+/// Parameters exposed through CONFIG GET / CONFIG SET without a typed field in
+/// [`Config`], with their default values.
+const UNTYPED_PARAMETERS: &[(&str, &str)] = &[("save", "3600 1 300 100 60 10000")];
+
 pub struct ServerConfig {
     pub bind_addr: String,
     pub port: u16,
     pub replica_of: Option<String>,
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// Directory of the RDB snapshot
+    pub dir: String,
+    /// File name of the RDB snapshot, inside `dir`
+    pub dbfilename: String,
+    /// Whether writes are logged to the AOF, which is then loaded instead of the snapshot
+    pub appendonly: bool,
+    pub appendfsync: AppendFsync,
+    /// File name of the AOF, inside `dir`
+    pub appendfilename: String,
     // pub replication_id: String,
     // pub replication_offset: u64,
 }
@@ -25,6 +53,48 @@ impl ServerConfig {
                     .help("Make this server a replica of the specified master")
                     .num_args(1),
             )
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .value_name("DIR")
+                    .help("Directory of the RDB snapshot")
+                    .default_value("."),
+            )
+            .arg(
+                Arg::new("dbfilename")
+                    .long("dbfilename")
+                    .value_name("FILE")
+                    .help("File name of the RDB snapshot")
+                    .default_value("dump.rdb"),
+            )
+            .arg(
+                Arg::new("appendonly")
+                    .long("appendonly")
+                    .value_name("yes|no")
+                    .help("Log every write to the append-only file")
+                    .default_value("no"),
+            )
+            .arg(
+                Arg::new("appendfsync")
+                    .long("appendfsync")
+                    .value_name("always|everysec|no")
+                    .help("When the append-only file is flushed to disk")
+                    .default_value("everysec"),
+            )
+            .arg(
+                Arg::new("appendfilename")
+                    .long("appendfilename")
+                    .value_name("FILE")
+                    .help("File name of the append-only file")
+                    .default_value("appendonly.aof"),
+            )
+            .arg(
+                Arg::new("notify-keyspace-events")
+                    .long("notify-keyspace-events")
+                    .value_name("FLAGS")
+                    .help("Keyspace events to publish over Pub/Sub, e.g. KEA")
+                    .default_value(""),
+            )
             .get_matches();
 
         let port = matches
@@ -50,10 +120,321 @@ impl ServerConfig {
             (host, port)
         });
 
+        let notify_keyspace_events = matches
+            .get_one::<String>("notify-keyspace-events")
+            .expect("default always present")
+            .parse()
+            .expect("Invalid --notify-keyspace-events flags");
+
         Self {
             bind_addr: addr,
             port: port.parse().expect("default port should be valid"),
             replica_of: replica_of.map(|(host, port)| format!("{}:{}", host, port)),
+            notify_keyspace_events,
+            dir: matches
+                .get_one::<String>("dir")
+                .expect("default always present")
+                .clone(),
+            dbfilename: matches
+                .get_one::<String>("dbfilename")
+                .expect("default always present")
+                .clone(),
+            appendonly: parse_yes_no(
+                matches
+                    .get_one::<String>("appendonly")
+                    .expect("default always present"),
+            )
+            .expect("Invalid --appendonly, expected yes or no"),
+            appendfsync: matches
+                .get_one::<String>("appendfsync")
+                .expect("default always present")
+                .parse()
+                .expect("Invalid --appendfsync policy"),
+            appendfilename: matches
+                .get_one::<String>("appendfilename")
+                .expect("default always present")
+                .clone(),
+        }
+    }
+}
+
+/// Parameters that can be read and changed at runtime, shared by every connection.
+pub struct Config {
+    /// Memory limit in bytes, 0 meaning no limit
+    pub maxmemory: u64,
+    /// How keys are evicted once `maxmemory` is exceeded
+    pub maxmemory_policy: MaxmemoryPolicy,
+    /// Keys sampled per database to pick the next eviction victims among
+    pub maxmemory_samples: usize,
+    /// The AOF parameters are applied at startup, changing them takes a restart
+    pub appendonly: bool,
+    pub appendfsync: AppendFsync,
+    /// File name of the AOF, inside `dir`
+    pub appendfilename: String,
+    /// Directory of the RDB snapshot and the AOF
+    pub dir: String,
+    /// File name of the RDB snapshot, inside `dir`
+    pub dbfilename: String,
+    /// Largest list kept as a single listpack: a number of entries when positive, a
+    /// size from 4KB (-1) to 64KB (-5) when negative
+    pub list_max_listpack_size: i64,
+    /// Most elements a list may hold, pushes past it failing, 0 meaning no limit
+    pub list_max_len: usize,
+    /// Seconds after which a connection that sent nothing is closed, 0 meaning never
+    pub timeout: u64,
+    /// Most connections served at once, the next ones being turned away
+    pub maxclients: usize,
+    /// Password connections must AUTH with before running commands, empty meaning none
+    pub requirepass: String,
+    /// Parameters without a typed field, stored as given
+    other: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::default(),
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            appendonly: false,
+            appendfsync: AppendFsync::default(),
+            appendfilename: "appendonly.aof".to_string(),
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            list_max_len: 0,
+            timeout: 0,
+            maxclients: 10000,
+            requirepass: String::new(),
+            other: UNTYPED_PARAMETERS
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the name/value pairs of the parameters whose name matches `pattern`,
+    /// sorted by name. Parameter names are case-insensitive.
+    pub fn get(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        let mut params: Vec<(String, String)> = [
+            ("maxmemory".to_string(), self.maxmemory.to_string()),
+            (
+                "maxmemory-policy".to_string(),
+                self.maxmemory_policy.to_string(),
+            ),
+            (
+                "maxmemory-samples".to_string(),
+                self.maxmemory_samples.to_string(),
+            ),
+            (
+                "appendonly".to_string(),
+                yes_no(self.appendonly).to_string(),
+            ),
+            ("appendfsync".to_string(), self.appendfsync.to_string()),
+            ("appendfilename".to_string(), self.appendfilename.clone()),
+            ("dir".to_string(), self.dir.clone()),
+            ("dbfilename".to_string(), self.dbfilename.clone()),
+            (
+                "list-max-listpack-size".to_string(),
+                self.list_max_listpack_size.to_string(),
+            ),
+            ("list-max-len".to_string(), self.list_max_len.to_string()),
+            ("timeout".to_string(), self.timeout.to_string()),
+            ("maxclients".to_string(), self.maxclients.to_string()),
+            ("requirepass".to_string(), self.requirepass.clone()),
+        ]
+        .into_iter()
+        .chain(self.other.clone())
+        .filter(|(name, _)| glob_match(pattern.as_bytes(), name.as_bytes()))
+        .collect();
+        params.sort();
+        params
+    }
+
+    /// Path of the RDB snapshot
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    /// Path of the AOF
+    pub fn aof_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appendfilename)
+    }
+
+    /// Whether `save` lists any snapshotting point, in which case the RDB snapshot is
+    /// saved on shutdown
+    pub fn has_save_points(&self) -> bool {
+        self.other
+            .get("save")
+            .is_some_and(|points| !points.trim().is_empty())
+    }
+
+    /// Sets the parameter `name` to `value`, failing if the parameter is unknown or the
+    /// value is invalid for it.
+    pub fn set(&mut self, name: &str, value: String) -> Result<()> {
+        let name = name.to_lowercase();
+        let invalid = |reason: &str| {
+            anyhow!("ERR CONFIG SET failed (possibly related to argument '{name}') - {reason}")
+        };
+
+        match name.as_str() {
+            "maxmemory" => {
+                self.maxmemory = parse_memory(&value)
+                    .ok_or_else(|| invalid("argument must be a memory value"))?
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy = value.parse().map_err(|_| {
+                    invalid(
+                        "argument(s) must be one of the following: noeviction, allkeys-lru, allkeys-lfu",
+                    )
+                })?
+            }
+            "maxmemory-samples" => {
+                self.maxmemory_samples = value
+                    .parse()
+                    .ok()
+                    .filter(|samples| (1..=64).contains(samples))
+                    .ok_or_else(|| invalid("argument must be between 1 and 64 inclusive"))?
+            }
+            "appendonly" => {
+                self.appendonly =
+                    parse_yes_no(&value).ok_or_else(|| invalid("argument must be 'yes' or 'no'"))?
+            }
+            "appendfsync" => {
+                self.appendfsync = value.parse().map_err(|_| {
+                    invalid("argument(s) must be one of the following: always, everysec, no")
+                })?
+            }
+            "appendfilename" => self.appendfilename = value,
+            "dir" => self.dir = value,
+            "dbfilename" => self.dbfilename = value,
+            "requirepass" => self.requirepass = value,
+            "list-max-listpack-size" => {
+                self.list_max_listpack_size = value
+                    .parse()
+                    .ok()
+                    .filter(|size| (-5..=i32::MAX as i64).contains(size))
+                    .ok_or_else(|| {
+                        invalid("argument must be between -5 and 2147483647 inclusive")
+                    })?
+            }
+            "list-max-len" => {
+                self.list_max_len = value
+                    .parse()
+                    .map_err(|_| invalid("argument couldn't be parsed into an integer"))?
+            }
+            "timeout" => {
+                self.timeout = value
+                    .parse()
+                    .map_err(|_| invalid("argument couldn't be parsed into an integer"))?
+            }
+            "maxclients" => {
+                self.maxclients = value.parse().ok().filter(|&max| max >= 1).ok_or_else(|| {
+                    invalid("argument must be between 1 and 18446744073709551615 inclusive")
+                })?
+            }
+            _ => match self.other.get_mut(&name) {
+                Some(current) => *current = value,
+                None => {
+                    bail!("ERR Unknown option or number of arguments for CONFIG SET - '{name}'")
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Parses a byte count such as `1024`, `100mb` or `2GB`. `k`, `m` and `g` are powers
+/// of 1000, `kb`, `mb` and `gb` powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits_end);
+
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1_000,
+        "kb" => 1 << 10,
+        "m" => 1_000_000,
+        "mb" => 1 << 20,
+        "g" => 1_000_000_000,
+        "gb" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_matches_parameter_names() {
+        let config = Config::default();
+        assert_eq!(
+            config.get("MAXMEMORY"),
+            vec![("maxmemory".to_string(), "0".to_string())]
+        );
+        assert_eq!(config.get("append*").len(), 3);
+        assert!(config.get("nonexistent").is_empty());
+    }
+
+    #[test]
+    fn test_set_maxmemory_parses_units() {
+        let mut config = Config::default();
+        config.set("maxmemory", "100mb".into()).unwrap();
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        config.set("maxmemory", "2k".into()).unwrap();
+        assert_eq!(config.maxmemory, 2000);
+        assert_eq!(config.get("maxmemory")[0].1, "2000");
+        assert!(config.set("maxmemory", "10tb".into()).is_err());
+    }
+
+    #[test]
+    fn test_set_maxmemory_samples_checks_the_range() {
+        let mut config = Config::default();
+        assert_eq!(config.get("maxmemory-samples")[0].1, "5");
+        config.set("maxmemory-samples", "10".into()).unwrap();
+        assert_eq!(config.maxmemory_samples, 10);
+        for invalid in ["0", "65", "many"] {
+            assert!(config.set("maxmemory-samples", invalid.into()).is_err());
         }
     }
+
+    #[test]
+    fn test_set_rejects_invalid_boolean() {
+        let mut config = Config::default();
+        let err = config.set("appendonly", "maybe".into()).unwrap_err();
+        assert!(err.to_string().contains("'yes' or 'no'"));
+        assert!(!config.appendonly);
+    }
+
+    #[test]
+    fn test_set_rejects_unknown_parameter() {
+        let mut config = Config::default();
+        config.set("save", "60 1".into()).unwrap();
+        assert_eq!(config.get("save")[0].1, "60 1");
+        assert!(config.set("nonexistent", "1".into()).is_err());
+    }
 }
@@ -0,0 +1,111 @@
+//! A map from keys to values that can also hand out an entry by position.
+
+use std::{collections::HashMap, ops::Index};
+
+/// Entries are kept in a vector indexed by a hash map, so that a key picked at random,
+/// e.g. to sample eviction candidates or for RANDOMKEY, costs as much as a lookup. The
+/// order of the entries is not meaningful: removing one moves the last in its place.
+pub struct KeyMap<V> {
+    entries: Vec<(String, V)>,
+    /// Position of every key in `entries`
+    positions: HashMap<String, usize>,
+}
+
+impl<V> Default for KeyMap<V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+}
+
+impl<V> KeyMap<V> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        let &position = self.positions.get(key)?;
+        Some(&self.entries[position].1)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let &position = self.positions.get(key)?;
+        Some(&mut self.entries[position].1)
+    }
+
+    /// Returns the entry at `position`, in `0..len()`.
+    pub fn get_index(&self, position: usize) -> Option<(&String, &V)> {
+        self.entries.get(position).map(|(key, value)| (key, value))
+    }
+
+    /// Stores `value` at `key`, returning the value it replaced.
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(&position) = self.positions.get(&key) {
+            return Some(std::mem::replace(&mut self.entries[position].1, value));
+        }
+        self.positions.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        None
+    }
+
+    /// Removes `key`, returning its value.
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let position = self.positions.remove(key)?;
+        let (_, value) = self.entries.swap_remove(position);
+        if let Some((moved, _)) = self.entries.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.positions.clear();
+    }
+}
+
+impl<V> Index<&str> for KeyMap<V> {
+    type Output = V;
+
+    /// Returns the value at `key`, panicking if it is missing.
+    fn index(&self, key: &str) -> &V {
+        self.get(key).expect("key not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_keeps_every_other_key_reachable() {
+        let mut map = KeyMap::default();
+        for (i, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            assert_eq!(map.insert(key.to_string(), i), None);
+        }
+        assert_eq!(map.insert("b".to_string(), 10), Some(1));
+
+        assert_eq!(map.remove("a"), Some(0));
+        assert_eq!(map.remove("a"), None);
+        assert_eq!(map.len(), 3);
+        for (key, value) in [("b", 10), ("c", 2), ("d", 3)] {
+            assert_eq!(map.get(key), Some(&value));
+        }
+        let mut keys: Vec<&String> = (0..map.len())
+            .filter_map(|i| map.get_index(i).map(|(key, _)| key))
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["b", "c", "d"]);
+        assert_eq!(map.get_index(3), None);
+    }
+}
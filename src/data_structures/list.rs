@@ -1,22 +1,108 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    time::Duration,
+};
 
-use crate::resp::RespDataType;
+use tokio::{sync::oneshot, time::Instant};
+
+use super::{keymap::KeyMap, len_reply, resolve_index_range};
+use crate::{cmd::ListEnd, resp::RespDataType};
+
+/// Default `list-max-listpack-size`: lists whose elements take up to 8KB are kept as a
+/// single `listpack`. Bigger lists become a `quicklist`.
+pub const DEFAULT_LIST_MAX_LISTPACK_SIZE: i64 = -2;
+
+/// Returns the most entries and the most bytes a single listpack holds under the
+/// `list-max-listpack-size` `size`. A positive size caps the number of entries, while
+/// -1 to -5 cap the total size of the elements at 4KB to 64KB.
+pub fn listpack_limits(size: i64) -> (Option<usize>, Option<usize>) {
+    if size >= 0 {
+        (Some(size.max(1) as usize), None)
+    } else {
+        (None, Some(4096 << (size.unsigned_abs().min(5) - 1)))
+    }
+}
 
 /// A thread-safe Redis-like list data structure implementation.
 ///
 /// `Lists` provides operations for managing named lists of strings, similar to Redis lists.
 /// Each list is identified by a string key and supports operations like push, pop, and range queries.
 /// All operations are thread-safe through the use of `RwLock`.
-#[derive(Default)]
 pub struct Lists {
     /// Internal storage mapping list names to their contents.
     /// Uses `VecDeque` for efficient operations at both ends of the list.
-    inner: HashMap<String, BlockingList>,
+    inner: KeyMap<BlockingList>,
+    /// Bytes taken by the keys and elements, expired lists included until they are
+    /// reaped
+    used_memory: usize,
+    /// Deadlines of the lists given a TTL, earliest first, used by the active expiry
+    /// sweep. Like for strings, stale entries are discarded once their deadline passes.
+    expirations: BTreeSet<(Instant, String)>,
+    /// Clients blocked in BLPOP, in the order they started waiting.
+    blocked: VecDeque<BlockedPop>,
+    /// `list-max-listpack-size`, deciding the encoding OBJECT ENCODING reports
+    max_listpack_size: i64,
+    /// `list-max-len`: most elements a list may hold, 0 meaning no limit
+    max_len: usize,
 }
 
-#[derive(Default)]
+impl Default for Lists {
+    fn default() -> Self {
+        Self {
+            inner: KeyMap::default(),
+            used_memory: 0,
+            expirations: BTreeSet::new(),
+            blocked: VecDeque::new(),
+            max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            max_len: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
 struct BlockingList {
     inner: VecDeque<String>,
+    /// Last time the list was read or written, for OBJECT IDLETIME
+    last_accessed: Instant,
+    /// Number of times the list was read or written, for OBJECT FREQ and LFU eviction
+    accesses: u64,
+    expires_at: Option<Instant>,
+}
+
+impl Default for BlockingList {
+    fn default() -> Self {
+        Self {
+            inner: VecDeque::new(),
+            last_accessed: Instant::now(),
+            accesses: 0,
+            expires_at: None,
+        }
+    }
+}
+
+impl BlockingList {
+    /// Whether the list holds elements and its TTL, if any, has not elapsed. Other
+    /// lists are treated as missing.
+    fn is_live(&self, now: Instant) -> bool {
+        !self.inner.is_empty() && self.expires_at.is_none_or(|expiry| now <= expiry)
+    }
+
+    /// Records a read or write of the list at `now`.
+    fn accessed(&mut self, now: Instant) {
+        self.last_accessed = now;
+        self.accesses += 1;
+    }
+
+    /// Returns the bytes taken by the elements.
+    fn elements_len(&self) -> usize {
+        self.inner.iter().map(String::len).sum()
+    }
+}
+
+/// A client waiting for an element to be pushed to any of `keys`.
+struct BlockedPop {
+    keys: Vec<String>,
+    response_tx: oneshot::Sender<RespDataType>,
 }
 
 impl Lists {
@@ -30,14 +116,290 @@ impl Lists {
     ///
     /// * `RespDataType::Integer` - The length of the list, or 0 if the key doesn't exist
     ///
-    pub fn get_list_len(&self, key: &str) -> RespDataType {
-        let len = self
+    pub fn get_list_len(&mut self, key: &str) -> RespDataType {
+        len_reply(self.touch(key).map_or(0, |list| list.inner.len()))
+    }
+
+    /// Iterates over the keys of the live lists.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, list)| list.is_live(now))
+            .map(|(key, _)| key)
+    }
+
+    /// Returns whether a live list is stored at `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.live(key).is_some()
+    }
+
+    /// Returns the live list at `key`.
+    fn live(&self, key: &str) -> Option<&BlockingList> {
+        self.inner
+            .get(key)
+            .filter(|list| list.is_live(Instant::now()))
+    }
+
+    /// Stores a copy of the list at `src`, TTL included, under `dst`, overwriting it.
+    /// Returns whether `src` held a live list.
+    pub fn copy(&mut self, src: &str, dst: &str) -> bool {
+        let Some(list) = self.live(src).cloned() else {
+            return false;
+        };
+        self.insert(dst.to_string(), list);
+        true
+    }
+
+    /// Iterates over the live lists with their elements, head first, and deadlines.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &VecDeque<String>, Option<Instant>)> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, list)| list.is_live(now))
+            .map(|(key, list)| (key, &list.inner, list.expires_at))
+    }
+
+    /// Stores `elements` at `key` with the given deadline, as loaded from a snapshot.
+    pub fn restore(&mut self, key: String, elements: Vec<String>, expires_at: Option<Instant>) {
+        self.insert(
+            key,
+            BlockingList {
+                inner: elements.into(),
+                expires_at,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Stores `list` at `key`, indexing its deadline for the active expiry sweep.
+    fn insert(&mut self, key: String, list: BlockingList) {
+        if let Some(deadline) = list.expires_at {
+            self.expirations.insert((deadline, key.clone()));
+        }
+        let key_len = key.len();
+        self.used_memory += key_len + list.elements_len();
+        if let Some(old) = self.inner.insert(key, list) {
+            self.used_memory -= key_len + old.elements_len();
+        }
+    }
+
+    /// Removes the list at `key`, live or not, returning it.
+    fn take(&mut self, key: &str) -> Option<BlockingList> {
+        let list = self.inner.remove(key)?;
+        self.used_memory -= key.len() + list.elements_len();
+        Some(list)
+    }
+
+    /// Accounts for `popped`, just taken from the list at `key`, deleting the list once
+    /// empty.
+    fn popped(&mut self, key: &str, popped: &[String]) {
+        self.used_memory -= popped.iter().map(String::len).sum::<usize>();
+        if self
+            .inner
+            .get(key)
+            .is_some_and(|list| list.inner.is_empty())
+        {
+            self.take(key);
+        }
+    }
+
+    /// Returns the bytes taken by the keys and elements, overhead excluded. Lists whose
+    /// TTL elapsed count until they are reaped.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// Returns the number of lists, including those whose TTL elapsed but that were
+    /// not reaped yet.
+    pub fn key_count(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns the key at `position`, in `0..key_count()`, live or not.
+    pub fn key_at(&self, position: usize) -> Option<&String> {
+        self.inner.get_index(position).map(|(key, _)| key)
+    }
+
+    /// Returns the elements of the live list at `key` without marking it as accessed.
+    pub fn peek(&self, key: &str) -> Option<&VecDeque<String>> {
+        self.live(key).map(|list| &list.inner)
+    }
+
+    /// Returns how long the live list at `key` has gone without being accessed.
+    pub fn idle_time(&self, key: &str) -> Option<Duration> {
+        self.live(key).map(|list| list.last_accessed.elapsed())
+    }
+
+    /// Returns how many times the live list at `key` was read or written.
+    pub fn frequency(&self, key: &str) -> Option<u64> {
+        self.live(key).map(|list| list.accesses)
+    }
+
+    /// Returns the access count and last access time of the live list at `key`.
+    pub fn access(&self, key: &str) -> Option<(u64, Instant)> {
+        self.live(key)
+            .map(|list| (list.accesses, list.last_accessed))
+    }
+
+    /// Returns the deadline of the live list at `key`, `Some(None)` if it has no TTL.
+    pub fn expiry(&self, key: &str) -> Option<Option<Instant>> {
+        self.live(key).map(|list| list.expires_at)
+    }
+
+    /// Sets the deadline of the live list at `key`, `None` removing its TTL. Returns
+    /// whether there was such a list.
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let now = Instant::now();
+        let Some(list) = self.inner.get_mut(key).filter(|list| list.is_live(now)) else {
+            return false;
+        };
+        list.expires_at = expires_at;
+        if let Some(deadline) = expires_at {
+            self.expirations.insert((deadline, key.to_string()));
+        }
+        true
+    }
+
+    /// Marks the live list at `key` as accessed, returning whether there is one.
+    pub fn mark_accessed(&mut self, key: &str) -> bool {
+        self.touch(key).is_some()
+    }
+
+    /// Returns the live list at `key`, marking it as accessed.
+    fn touch(&mut self, key: &str) -> Option<&mut BlockingList> {
+        let now = Instant::now();
+        let list = self.inner.get_mut(key).filter(|list| list.is_live(now))?;
+        list.accessed(now);
+        Some(list)
+    }
+
+    /// Returns the live list at `key` marked as accessed, replacing a missing, emptied
+    /// or expired list with an empty one without TTL.
+    fn live_or_empty(&mut self, key: String) -> &mut BlockingList {
+        let now = Instant::now();
+        if !self.contains(&key) {
+            self.insert(key.clone(), BlockingList::default());
+        }
+        // safety: inserted above when missing
+        let list = self.inner.get_mut(&key).unwrap();
+        list.accessed(now);
+        list
+    }
+
+    /// Removes the list at `key` if its TTL has elapsed, returning whether it was removed.
+    pub fn remove_if_expired(&mut self, key: &str) -> bool {
+        let now = Instant::now();
+        let expired = self
             .inner
             .get(key)
-            .map(|list| list.inner.len() as i64)
-            .unwrap_or(0);
+            .is_some_and(|list| !list.inner.is_empty() && !list.is_live(now));
+        if expired {
+            self.take(key);
+        }
+        expired
+    }
 
-        RespDataType::Integer(len)
+    /// Removes every list whose TTL has elapsed and returns the reaped keys.
+    pub fn purge_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .inner
+            .iter()
+            .filter(|(_, list)| !list.inner.is_empty() && !list.is_live(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.take(key);
+        }
+        expired
+    }
+
+    /// Examines up to `sample_size` of the deadlines that have passed, earliest first,
+    /// removing the lists still set to expire at them. Returns the removed keys.
+    pub fn sweep_expired(&mut self, sample_size: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+
+        for _ in 0..sample_size {
+            if self
+                .expirations
+                .first()
+                .is_none_or(|(deadline, _)| *deadline >= now)
+            {
+                break;
+            }
+
+            // safety: checked to be non-empty above
+            let (deadline, key) = self.expirations.pop_first().unwrap();
+            // The list may have been deleted, emptied or given another TTL since
+            if self
+                .inner
+                .get(&key)
+                .is_some_and(|list| !list.inner.is_empty() && list.expires_at == Some(deadline))
+            {
+                self.take(&key);
+                reaped.push(key);
+            }
+        }
+        reaped
+    }
+
+    /// Returns the encoding Redis would report for the live list at `key`.
+    pub fn encoding(&self, key: &str) -> Option<&'static str> {
+        let list = self.live(key)?;
+        let (max_entries, max_bytes) = listpack_limits(self.max_listpack_size);
+        let fits_entries = max_entries.is_none_or(|max| list.inner.len() <= max);
+        let fits_bytes =
+            max_bytes.is_none_or(|max| list.inner.iter().map(String::len).sum::<usize>() <= max);
+        Some(if fits_entries && fits_bytes {
+            "listpack"
+        } else {
+            "quicklist"
+        })
+    }
+
+    /// Returns the `list-max-listpack-size` in effect.
+    pub fn max_listpack_size(&self) -> i64 {
+        self.max_listpack_size
+    }
+
+    /// Changes the `list-max-listpack-size`, which applies to existing lists right away.
+    pub fn set_max_listpack_size(&mut self, size: i64) {
+        self.max_listpack_size = size;
+    }
+
+    /// Changes the `list-max-len`. Lists already longer keep their elements but take no
+    /// more.
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// Returns the error replied when pushing `count` elements to the list at `key`
+    /// would make it longer than `list-max-len`.
+    fn check_max_len(&self, key: &str, count: usize) -> Result<(), RespDataType> {
+        let len = self.live(key).map_or(0, |list| list.inner.len());
+        if self.max_len > 0 && len + count > self.max_len {
+            return Err(RespDataType::SimpleError(format!(
+                "ERR list would exceed list-max-len of {} elements",
+                self.max_len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Removes the list stored at `key`, returning whether it was live.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.take(key)
+            .is_some_and(|list| list.is_live(Instant::now()))
+    }
+
+    /// Removes every list. Clients blocked in BLPOP keep waiting.
+    pub fn clear(&mut self) {
+        self.inner.clear();
+        self.expirations.clear();
+        self.used_memory = 0;
     }
 
     /// Prepends one or more values to the head of the list stored at key.
@@ -55,13 +417,19 @@ impl Lists {
     /// * `RespDataType::Integer` - The length of the list after the push operation
     ///
     pub fn lpush(&mut self, key: String, values: Vec<String>) -> RespDataType {
-        let list = self.inner.entry(key).or_default();
+        if let Err(error) = self.check_max_len(&key, values.len()) {
+            return error;
+        }
+        let added: usize = values.iter().map(String::len).sum();
+        let list = self.live_or_empty(key);
 
         for v in values {
             list.inner.push_front(v);
         }
 
-        RespDataType::Integer(list.inner.len() as i64)
+        let len = list.inner.len();
+        self.used_memory += added;
+        len_reply(len)
     }
 
     /// Removes and returns elements from the head of the list stored at key.
@@ -78,31 +446,175 @@ impl Lists {
     ///   - `RespDataType::BulkString` - The popped element
     ///   - `RespDataType::NullBulkString` - If the key doesn't exist or list is empty
     /// * When `count` is `Some(n)`:
-    ///   - `RespDataType::Array` - Array of popped elements (may be empty)
+    ///   - `RespDataType::Array` - Array of popped elements, empty when `n` is 0
+    ///   - `RespDataType::NullArray` - If the key doesn't exist or list is empty
     ///
-    pub fn left_pop(&mut self, key: &str, count: Option<i64>) -> RespDataType {
-        let list = match self.inner.get_mut(key) {
-            Some(list) if !list.inner.is_empty() => list,
-            Some(_) => return RespDataType::NullBulkString,
-            None => return RespDataType::NullBulkString,
+    pub fn left_pop(&mut self, key: &str, count: Option<usize>) -> RespDataType {
+        let Some(list) = self.touch(key) else {
+            return match count {
+                Some(_) => RespDataType::NullArray,
+                None => RespDataType::NullBulkString,
+            };
+        };
+
+        let popped: Vec<String> = match count {
+            Some(n) => list.inner.drain(..n.min(list.inner.len())).collect(),
+            None => list.inner.pop_front().into_iter().collect(),
         };
+        self.popped(key, &popped);
 
         match count {
-            Some(n) => {
-                let n = n.max(0) as usize;
-                let elements = list
-                    .inner
-                    .drain(..n.min(list.inner.len()))
-                    .map(RespDataType::BulkString)
-                    .collect();
-                RespDataType::Array(elements)
-            }
+            Some(_) => RespDataType::Array(
+                popped
+                    .into_iter()
+                    .map(|element| RespDataType::BulkString(element.into()))
+                    .collect(),
+            ),
             None => {
                 // safety: list has been checked that is not emtpy
-                let val = list.inner.pop_front().unwrap();
-                RespDataType::BulkString(val)
+                let val = popped.into_iter().next().unwrap();
+                RespDataType::BulkString(val.into())
+            }
+        }
+    }
+
+    /// Replies with the index of the `rank`-th match of `element`, counting from the
+    /// tail when `rank` is negative, or nil if there is none. With `count`, replies with
+    /// the indexes of up to `count` matches from there instead, all of them when 0.
+    pub fn lpos(
+        &mut self,
+        key: &str,
+        element: &str,
+        rank: Option<i64>,
+        count: Option<usize>,
+    ) -> RespDataType {
+        let rank = rank.unwrap_or(1);
+        let matches: Vec<usize> = match self.touch(key) {
+            Some(list) => {
+                let indexes = list
+                    .inner
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, candidate)| *candidate == element)
+                    .map(|(index, _)| index);
+                let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                    Box::new(indexes)
+                } else {
+                    Box::new(indexes.rev())
+                };
+                let take = match count {
+                    Some(0) => usize::MAX,
+                    Some(count) => count,
+                    None => 1,
+                };
+                indexes
+                    .skip(rank.unsigned_abs() as usize - 1)
+                    .take(take)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        match count {
+            Some(_) => RespDataType::Array(matches.into_iter().map(len_reply).collect()),
+            None => matches
+                .first()
+                .map_or(RespDataType::NullBulkString, |&index| len_reply(index)),
+        }
+    }
+
+    /// Pops the head of the first non-empty list among `keys`, or registers the
+    /// client to be served by a later push if they are all empty.
+    ///
+    /// The reply, sent on `response_tx`, is a two-element array with the key the
+    /// element was popped from and the element itself.
+    ///
+    /// Returns the key that was popped from when the client was served immediately.
+    pub fn blocking_left_pop(
+        &mut self,
+        keys: Vec<String>,
+        response_tx: oneshot::Sender<RespDataType>,
+    ) -> Option<String> {
+        for key in keys.iter() {
+            if let Some(value) = self.pop_front(key) {
+                let _ = response_tx.send(pop_reply(key, value));
+                return Some(key.clone());
+            }
+        }
+
+        self.blocked.push_back(BlockedPop { keys, response_tx });
+        None
+    }
+
+    /// Hands elements of the list at `key` to the clients blocked on it, one element
+    /// per client in the order they started waiting, until either the list or the
+    /// waiters run out. Meant to be called after every push to `key`.
+    ///
+    /// Returns the number of elements handed out.
+    pub fn serve_blocked(&mut self, key: &str) -> usize {
+        let mut served = 0;
+        let mut i = 0;
+        while i < self.blocked.len() {
+            if !self.contains(key) {
+                break;
+            }
+            if !self.blocked[i].keys.iter().any(|k| k == key) {
+                i += 1;
+                continue;
+            }
+
+            // safety: index checked by the loop condition
+            let waiter = self.blocked.remove(i).unwrap();
+            if waiter.response_tx.is_closed() {
+                // The client timed out or went away
+                continue;
             }
+
+            // safety: list checked to be non-empty above
+            let value = self.pop_front(key).unwrap();
+            let Err(RespDataType::Array(mut reply)) =
+                waiter.response_tx.send(pop_reply(key, value))
+            else {
+                served += 1;
+                continue;
+            };
+            // The client went away in the meantime, keep the element
+            if let Some(Ok(value)) = reply.pop().as_ref().map(RespDataType::get_str) {
+                self.used_memory += value.len();
+                self.live_or_empty(key.to_string()).inner.push_front(value);
+            }
+        }
+        served
+    }
+
+    /// Pops up to `count` elements from `end` of the first non-empty list among `keys`,
+    /// returning its key along with the elements in the order they were popped.
+    pub fn multi_pop(
+        &mut self,
+        keys: &[String],
+        end: ListEnd,
+        count: usize,
+    ) -> Option<(String, Vec<String>)> {
+        for key in keys {
+            let Some(list) = self.touch(key) else {
+                continue;
+            };
+            let len = list.inner.len();
+            let count = count.min(len);
+            let popped: Vec<String> = match end {
+                ListEnd::Left => list.inner.drain(..count).collect(),
+                ListEnd::Right => list.inner.drain(len - count..).rev().collect(),
+            };
+            self.popped(key, &popped);
+            return Some((key.clone(), popped));
         }
+        None
+    }
+
+    fn pop_front(&mut self, key: &str) -> Option<String> {
+        let value = self.touch(key)?.inner.pop_front()?;
+        self.popped(key, std::slice::from_ref(&value));
+        Some(value)
     }
 
     /// Appends one or more values to the tail of the list stored at key.
@@ -120,9 +632,15 @@ impl Lists {
     /// * `RespDataType::Integer` - The length of the list after the push operation
     ///
     pub fn rpush(&mut self, key: String, values: Vec<String>) -> RespDataType {
-        let list = self.inner.entry(key).or_default();
+        if let Err(error) = self.check_max_len(&key, values.len()) {
+            return error;
+        }
+        let added: usize = values.iter().map(String::len).sum();
+        let list = self.live_or_empty(key);
         list.inner.extend(values);
-        RespDataType::Integer(list.inner.len() as i64)
+        let len = list.inner.len();
+        self.used_memory += added;
+        len_reply(len)
     }
 
     // Returns the specified elements of the list stored at key.
@@ -142,53 +660,312 @@ impl Lists {
     ///
     /// * `RespDataType::Array` - Array containing the elements in the specified range.
     ///   Returns an empty array if the key doesn't exist, the list is empty,
-    ///   or the range is invalid (start > stop).
+    ///   or the range selects nothing (start > stop, or start past the end).
     ///
-    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> RespDataType {
-        let Some(list) = self.inner.get(key) else {
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> RespDataType {
+        let Some(list) = self.touch(key) else {
             return RespDataType::Array(vec![]);
         };
 
-        let len = list.inner.len() as i64;
-        if len == 0 {
-            return RespDataType::Array(vec![]);
-        }
-
-        // Convert negative indices to positive
-        let start_idx = normalize_index(start, len);
-        let stop_idx = normalize_index(stop, len);
-
-        // Check if range is valid
-        if start_idx > stop_idx {
+        let Some((start_idx, stop_idx)) = resolve_index_range(start, stop, list.inner.len()) else {
             return RespDataType::Array(vec![]);
-        }
+        };
 
         let elements: Vec<RespDataType> = list
             .inner
             .range(start_idx..=stop_idx)
             .cloned()
-            .map(RespDataType::BulkString)
+            .map(|element| RespDataType::BulkString(element.into()))
             .collect();
 
         RespDataType::Array(elements)
     }
+
+    /// Replies with the elements of the list at `key` sorted as numbers, or byte-wise
+    /// with `alpha`, greatest first with `desc`, leaving the list untouched. `limit`
+    /// skips `offset` elements and returns at most `count` of them. Sorting as numbers
+    /// fails if any element is not one.
+    pub fn sort(
+        &mut self,
+        key: &str,
+        alpha: bool,
+        desc: bool,
+        limit: Option<(usize, usize)>,
+    ) -> RespDataType {
+        let mut elements: Vec<String> = self
+            .touch(key)
+            .map(|list| list.inner.iter().cloned().collect())
+            .unwrap_or_default();
+
+        if alpha {
+            elements.sort();
+        } else {
+            let scores: Option<Vec<f64>> = elements
+                .iter()
+                .map(|element| element.parse::<f64>().ok().filter(|score| !score.is_nan()))
+                .collect();
+            let Some(scores) = scores else {
+                return RespDataType::SimpleError(
+                    "ERR One or more scores can't be converted into double".into(),
+                );
+            };
+            let mut scored: Vec<(f64, String)> = scores.into_iter().zip(elements).collect();
+            scored.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+            elements = scored.into_iter().map(|(_, element)| element).collect();
+        }
+        if desc {
+            elements.reverse();
+        }
+
+        let (offset, count) = limit.unwrap_or((0, usize::MAX));
+        RespDataType::Array(
+            elements
+                .into_iter()
+                .skip(offset)
+                .take(count)
+                .map(|element| RespDataType::BulkString(element.into()))
+                .collect(),
+        )
+    }
 }
 
-/// Converts a potentially negative index to a valid positive index within bounds.
-///
-/// Negative indices are converted by adding them to the length (e.g., -1 becomes len-1).
-/// The result is clamped to the valid range [0, len-1].
-///
-/// # Arguments
-///
-/// * `index` - The index to normalize (can be negative)
-/// * `len` - The length of the collection
-///
-/// # Returns
-///
-/// * `usize` - A valid index within [0, len-1]
-///
-fn normalize_index(index: i64, len: i64) -> usize {
-    let normalized = if index < 0 { len + index } else { index };
-    normalized.clamp(0, len - 1) as usize
+fn pop_reply(key: &str, value: String) -> RespDataType {
+    RespDataType::Array(vec![
+        RespDataType::BulkString(key.to_string().into()),
+        RespDataType::BulkString(value.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_switches_to_quicklist_when_large() {
+        let mut lists = Lists::default();
+        lists.rpush("small".into(), vec!["a".into(), "b".into()]);
+        lists.rpush("large".into(), vec!["x".repeat(1024); 9]);
+
+        assert_eq!(lists.encoding("small"), Some("listpack"));
+        assert_eq!(lists.encoding("large"), Some("quicklist"));
+        assert_eq!(lists.encoding("missing"), None);
+
+        lists.set_max_listpack_size(1);
+        assert_eq!(lists.encoding("small"), Some("quicklist"));
+        lists.set_max_listpack_size(-3);
+        assert_eq!(lists.encoding("large"), Some("listpack"));
+    }
+
+    #[test]
+    fn test_lpos() {
+        let mut lists = Lists::default();
+        lists.rpush(
+            "k".into(),
+            ["a", "b", "c", "b", "d", "b"].map(String::from).to_vec(),
+        );
+        let indexes = |indexes: &[i64]| {
+            RespDataType::Array(indexes.iter().map(|&i| RespDataType::Integer(i)).collect())
+        };
+
+        assert_eq!(lists.lpos("k", "b", None, None), RespDataType::Integer(1));
+        assert_eq!(
+            lists.lpos("k", "b", Some(2), None),
+            RespDataType::Integer(3)
+        );
+        assert_eq!(
+            lists.lpos("k", "b", Some(-1), None),
+            RespDataType::Integer(5)
+        );
+        assert_eq!(
+            lists.lpos("k", "b", Some(-3), None),
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            lists.lpos("k", "b", Some(4), None),
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            lists.lpos("k", "z", None, None),
+            RespDataType::NullBulkString
+        );
+
+        assert_eq!(lists.lpos("k", "b", None, Some(2)), indexes(&[1, 3]));
+        assert_eq!(lists.lpos("k", "b", None, Some(0)), indexes(&[1, 3, 5]));
+        assert_eq!(lists.lpos("k", "b", Some(-1), Some(2)), indexes(&[5, 3]));
+        assert_eq!(lists.lpos("k", "z", None, Some(0)), indexes(&[]));
+        assert_eq!(lists.lpos("missing", "b", None, Some(1)), indexes(&[]));
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut lists = Lists::default();
+        lists.rpush(
+            "numbers".into(),
+            ["10", "2", "-1.5", "3"].map(String::from).to_vec(),
+        );
+        lists.rpush(
+            "words".into(),
+            ["pear", "apple", "fig"].map(String::from).to_vec(),
+        );
+        let bulk = |elements: &[&str]| {
+            RespDataType::Array(
+                elements
+                    .iter()
+                    .map(|element| RespDataType::BulkString(element.to_string().into()))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            lists.sort("numbers", false, false, None),
+            bulk(&["-1.5", "2", "3", "10"])
+        );
+        assert_eq!(
+            lists.sort("numbers", false, true, Some((1, 2))),
+            bulk(&["3", "2"])
+        );
+        assert_eq!(
+            lists.sort("numbers", true, false, None),
+            bulk(&["-1.5", "10", "2", "3"])
+        );
+        assert_eq!(
+            lists.sort("words", true, true, None),
+            bulk(&["pear", "fig", "apple"])
+        );
+        assert_eq!(
+            lists.sort("words", false, false, None),
+            RespDataType::SimpleError(
+                "ERR One or more scores can't be converted into double".into()
+            )
+        );
+        assert_eq!(lists.sort("missing", false, false, None), bulk(&[]));
+        // The list itself keeps its order
+        assert_eq!(lists.lrange("numbers", 0, 0), bulk(&["10"]));
+    }
+
+    #[test]
+    fn test_push_past_max_len_fails() {
+        let mut lists = Lists::default();
+        lists.set_max_len(3);
+        assert_eq!(
+            lists.rpush("k".into(), vec!["a".into(), "b".into()]),
+            RespDataType::Integer(2)
+        );
+        assert!(matches!(
+            lists.lpush("k".into(), vec!["c".into(), "d".into()]),
+            RespDataType::SimpleError(_)
+        ));
+        // Nothing was pushed by the failed push
+        assert_eq!(lists.get_list_len("k"), RespDataType::Integer(2));
+        assert_eq!(
+            lists.lpush("k".into(), vec!["c".into()]),
+            RespDataType::Integer(3)
+        );
+
+        lists.set_max_len(0);
+        assert_eq!(
+            lists.rpush("k".into(), vec!["d".into()]),
+            RespDataType::Integer(4)
+        );
+    }
+
+    #[test]
+    fn test_lrange_handles_negative_and_out_of_range_indexes() {
+        let mut lists = Lists::default();
+        lists.rpush("k".into(), vec!["a".into(), "b".into(), "c".into()]);
+        let range = |lists: &mut Lists, start, stop| {
+            let RespDataType::Array(elements) = lists.lrange("k", start, stop) else {
+                panic!("expected an array");
+            };
+            elements
+                .into_iter()
+                .map(|element| element.get_str().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(range(&mut lists, 0, -1), vec!["a", "b", "c"]);
+        assert_eq!(range(&mut lists, -2, -1), vec!["b", "c"]);
+        assert_eq!(range(&mut lists, -100, 100), vec!["a", "b", "c"]);
+        assert_eq!(range(&mut lists, 1, 1), vec!["b"]);
+        assert!(range(&mut lists, 3, 10).is_empty());
+        assert!(range(&mut lists, 2, 1).is_empty());
+        assert!(range(&mut lists, 0, -4).is_empty());
+    }
+
+    #[test]
+    fn test_left_pop_with_count() {
+        let mut lists = Lists::default();
+        lists.rpush("k".into(), vec!["a".into(), "b".into(), "c".into()]);
+
+        assert_eq!(lists.left_pop("k", Some(0)), RespDataType::Array(vec![]));
+        assert_eq!(
+            lists.left_pop("k", Some(2)),
+            RespDataType::Array(vec![
+                RespDataType::BulkString("a".into()),
+                RespDataType::BulkString("b".into()),
+            ])
+        );
+        assert_eq!(
+            lists.left_pop("k", Some(5)),
+            RespDataType::Array(vec![RespDataType::BulkString("c".into())])
+        );
+        assert_eq!(lists.left_pop("k", Some(0)), RespDataType::NullArray);
+        assert_eq!(lists.left_pop("k", None), RespDataType::NullBulkString);
+    }
+
+    #[test]
+    fn test_len_reply_never_goes_negative() {
+        assert_eq!(len_reply(3), RespDataType::Integer(3));
+        assert_eq!(
+            len_reply(i64::MAX as usize),
+            RespDataType::Integer(i64::MAX)
+        );
+        assert_eq!(len_reply(usize::MAX), RespDataType::Integer(i64::MAX));
+    }
+
+    #[test]
+    fn test_push_serves_multiple_waiters_in_order() {
+        let mut lists = Lists::default();
+        let (first_tx, mut first_rx) = oneshot::channel();
+        let (second_tx, mut second_rx) = oneshot::channel();
+
+        lists.blocking_left_pop(vec!["key".into()], first_tx);
+        lists.blocking_left_pop(vec!["key".into()], second_tx);
+
+        lists.rpush("key".into(), vec!["a".into(), "b".into()]);
+        lists.serve_blocked("key");
+
+        assert_eq!(first_rx.try_recv().unwrap(), pop_reply("key", "a".into()));
+        assert_eq!(second_rx.try_recv().unwrap(), pop_reply("key", "b".into()));
+        assert_eq!(lists.get_list_len("key"), RespDataType::Integer(0));
+    }
+
+    #[test]
+    fn test_push_skips_waiters_that_went_away() {
+        let mut lists = Lists::default();
+        let (gone_tx, gone_rx) = oneshot::channel();
+        let (waiting_tx, mut waiting_rx) = oneshot::channel();
+
+        lists.blocking_left_pop(vec!["key".into()], gone_tx);
+        lists.blocking_left_pop(vec!["other".into(), "key".into()], waiting_tx);
+        drop(gone_rx);
+
+        lists.rpush("key".into(), vec!["a".into()]);
+        lists.serve_blocked("key");
+
+        assert_eq!(waiting_rx.try_recv().unwrap(), pop_reply("key", "a".into()));
+        assert!(lists.blocked.is_empty());
+    }
+
+    #[test]
+    fn test_blocking_pop_is_served_immediately_when_list_has_elements() {
+        let mut lists = Lists::default();
+        lists.rpush("second".into(), vec!["x".into()]);
+        let (tx, mut rx) = oneshot::channel();
+
+        lists.blocking_left_pop(vec!["first".into(), "second".into()], tx);
+
+        assert_eq!(rx.try_recv().unwrap(), pop_reply("second", "x".into()));
+        assert!(lists.blocked.is_empty());
+    }
 }
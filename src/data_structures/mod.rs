@@ -1,4 +1,39 @@
 //! building blocks for different types of data storage
 
+use crate::resp::RespDataType;
+
+pub mod keymap;
 pub mod list;
 pub mod strings;
+pub mod zset;
+
+/// Replies with a length. Lengths beyond `i64::MAX` cannot be held in memory, but
+/// saturate rather than wrap to a negative integer all the same.
+fn len_reply(len: usize) -> RespDataType {
+    RespDataType::Integer(i64::try_from(len).unwrap_or(i64::MAX))
+}
+
+/// Resolves the inclusive range `start..=end` over `len` items, negative indexes
+/// counting from the end, and clamps it to the items. `None` when it selects nothing.
+fn resolve_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let resolve = |index: i64| {
+        if index < 0 {
+            (len + index).max(0)
+        } else {
+            index
+        }
+    };
+    let (start, end) = (resolve(start), resolve(end).min(len - 1));
+    (start <= end).then_some((start as usize, end as usize))
+}
+
+/// Like [`resolve_range`] for LRANGE and ZRANGE, where an end still negative once
+/// counted from the end selects nothing instead of the first item.
+fn resolve_index_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len_i64 = i64::try_from(len).unwrap_or(i64::MAX);
+    if end < 0 && len_i64 + end < 0 {
+        return None;
+    }
+    resolve_range(start, end, len)
+}
@@ -1,75 +1,897 @@
-use std::{
-    collections::HashMap,
-    time::{Duration, Instant},
-};
+use std::{collections::BTreeSet, time::Duration};
+
+use bytes::Bytes;
+use tokio::time::Instant;
 
-use crate::resp::RespDataType;
+use super::{keymap::KeyMap, len_reply, resolve_range};
+use crate::{
+    cmd::{BitOp, BitUnit, SetCondition},
+    resp::RespDataType,
+};
 
 #[derive(Default)]
 pub struct Strings {
-    inner: HashMap<String, Value>,
+    inner: KeyMap<Value>,
+    /// Bytes taken by the keys and values, expired ones included until they are reaped
+    used_memory: usize,
+    /// Deadlines of the keys that were stored with a TTL, earliest first, used by the
+    /// active expiry sweep. Entries are not removed when their key is deleted or
+    /// overwritten; the sweep discards them once their deadline passes.
+    expirations: BTreeSet<(Instant, String)>,
 }
 
+#[derive(Clone)]
 struct Value {
-    data: String,
+    /// Binary-safe contents, not necessarily UTF-8
+    data: Vec<u8>,
     expires_at: Option<Instant>,
+    /// Last time the value was read or written, for OBJECT IDLETIME
+    last_accessed: Instant,
+    /// Number of times the value was read or written, for OBJECT FREQ and LFU eviction
+    accesses: u64,
 }
 
 impl Value {
-    pub fn new(data: String, expiry: Option<Duration>) -> Self {
+    pub fn new(data: Vec<u8>, expiry: Option<Duration>) -> Self {
+        let now = Instant::now();
         Self {
             data,
-            expires_at: expiry.map(|expiry| Instant::now() + expiry),
+            expires_at: expiry.map(|expiry| now + expiry),
+            last_accessed: now,
+            accesses: 0,
         }
     }
+
+    /// Records a read or write of the value at `now`.
+    fn accessed(&mut self, now: Instant) {
+        self.last_accessed = now;
+        self.accesses += 1;
+    }
     pub fn is_expired(&self, now: Instant) -> bool {
         self.expires_at.is_some_and(|expiry| now > expiry)
     }
+
+    /// Returns the value as a 64-bit integer, if it is the decimal form of one.
+    fn as_int(&self) -> Option<i64> {
+        std::str::from_utf8(&self.data).ok()?.parse().ok()
+    }
 }
 
 const NON_VALID_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
 
+/// Longest string value, Redis' default `proto-max-bulk-len` of 512MB.
+pub const MAX_STRING_LEN: usize = 512 << 20;
+
+const MAX_STRING_LEN_ERROR: &str = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+
+/// Longest string Redis embeds in the object header (`embstr`) instead of allocating
+/// it separately (`raw`).
+const EMBSTR_MAX_LEN: usize = 44;
+
 impl Strings {
-    pub fn set(&mut self, key: String, value: String, expiry: Option<Duration>) -> RespDataType {
-        self.inner.insert(key, Value::new(value, expiry));
-        RespDataType::SimpleString("OK".into())
+    /// Stores `value` at `key`, honouring the NX/XX condition and the GET flag.
+    ///
+    /// Overwriting a key discards its previous TTL in favour of `expiry` (which may be
+    /// none), unless `keep_ttl` is set, in which case the existing deadline is retained.
+    ///
+    /// # Returns
+    ///
+    /// * With `get`: the previous value (or `NullBulkString` when the key was missing),
+    ///   whether or not the write happened.
+    /// * Without `get`: `OK` when the value was stored, `NullBulkString` when the
+    ///   condition prevented the write.
+    pub fn set(
+        &mut self,
+        key: String,
+        value: Bytes,
+        expiry: Option<Duration>,
+        condition: Option<SetCondition>,
+        get: bool,
+        keep_ttl: bool,
+    ) -> RespDataType {
+        let now = Instant::now();
+        let existing = self.inner.get(&key).filter(|entry| !entry.is_expired(now));
+        let old = existing.map(|entry| entry.data.clone());
+        let old_expiry = existing.and_then(|entry| entry.expires_at);
+
+        let should_set = match condition {
+            Some(SetCondition::NX) => old.is_none(),
+            Some(SetCondition::XX) => old.is_some(),
+            None => true,
+        };
+
+        if should_set {
+            let mut entry = Value::new(value.into(), expiry);
+            entry.accessed(now);
+            if keep_ttl {
+                entry.expires_at = old_expiry;
+            }
+            self.insert(key, entry);
+        }
+
+        match (get, old) {
+            (true, Some(old)) => RespDataType::BulkString(old.into()),
+            (true, None) => RespDataType::NullBulkString,
+            (false, _) if should_set => RespDataType::SimpleString("OK".into()),
+            (false, _) => RespDataType::NullBulkString,
+        }
     }
 
     pub fn increment(&mut self, key: String) -> RespDataType {
         match self.inner.get_mut(&key) {
             Some(entry) if !entry.is_expired(Instant::now()) => {
+                entry.accessed(Instant::now());
                 // Try to parse the current value as an integer
-                match entry.data.parse::<i64>() {
-                    Ok(current_value) => {
+                match entry.as_int() {
+                    Some(current_value) => {
                         let new_value = current_value + 1;
-                        entry.data = new_value.to_string();
+                        let data = new_value.to_string().into_bytes();
+                        self.used_memory = self.used_memory + data.len() - entry.data.len();
+                        entry.data = data;
                         RespDataType::Integer(new_value)
                     }
-                    Err(_) => RespDataType::SimpleError(NON_VALID_INTEGER_ERROR.into()),
+                    None => RespDataType::SimpleError(NON_VALID_INTEGER_ERROR.into()),
                 }
             }
             Some(_) | None => {
-                let default_value = Value::new(1.to_string(), None);
-                self.inner.insert(key, default_value);
+                let default_value = Value::new(b"1".to_vec(), None);
+                self.insert(key, default_value);
                 RespDataType::Integer(1)
             }
         }
     }
 
-    pub fn get(&mut self, key: &str) -> RespDataType {
-        match self.inner.get(key) {
-            Some(entry) if !entry.is_expired(Instant::now()) => {
-                RespDataType::BulkString(entry.data.clone())
+    /// Appends `value` to the value at `key`, creating it if missing, and replies with
+    /// the new length.
+    pub fn append(&mut self, key: String, value: &[u8]) -> RespDataType {
+        let current_len = self.peek(&key).map_or(0, <[u8]>::len);
+        if current_len + value.len() > MAX_STRING_LEN {
+            return RespDataType::SimpleError(MAX_STRING_LEN_ERROR.into());
+        }
+        let entry = self.live_or_empty(key);
+        entry.data.extend_from_slice(value);
+        let len = entry.data.len();
+        self.used_memory += value.len();
+        len_reply(len)
+    }
+
+    /// Overwrites the value at `key` with `value` starting at byte `offset`, zero-padding
+    /// it up to `offset` if shorter, and replies with the new length. A missing key is
+    /// only created when `value` is not empty.
+    pub fn set_range(&mut self, key: String, offset: usize, value: &[u8]) -> RespDataType {
+        let current_len = self.peek(&key).map_or(0, <[u8]>::len);
+        if value.is_empty() {
+            return len_reply(current_len);
+        }
+        let Some(end) = offset
+            .checked_add(value.len())
+            .filter(|end| *end <= MAX_STRING_LEN)
+        else {
+            return RespDataType::SimpleError(MAX_STRING_LEN_ERROR.into());
+        };
+
+        let entry = self.live_or_empty(key);
+        let before = entry.data.len();
+        if before < end {
+            entry.data.resize(end, 0);
+        }
+        entry.data[offset..end].copy_from_slice(value);
+        let len = entry.data.len();
+        self.used_memory += len - before;
+        len_reply(len)
+    }
+
+    /// Replies with the length of the value at `key`, 0 if missing.
+    pub fn len(&mut self, key: &str) -> RespDataType {
+        let now = Instant::now();
+        match self
+            .inner
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+        {
+            Some(entry) => {
+                entry.accessed(now);
+                len_reply(entry.data.len())
             }
-            Some(_) => {
-                if let Some(entry) = self.inner.get(key) {
-                    if entry.is_expired(Instant::now()) {
-                        self.inner.remove(key);
+            None => RespDataType::Integer(0),
+        }
+    }
+
+    /// Returns the live value at `key` marked as accessed, replacing a missing or expired
+    /// value with an empty one without TTL.
+    fn live_or_empty(&mut self, key: String) -> &mut Value {
+        let now = Instant::now();
+        if !self.contains(&key) {
+            self.insert(key.clone(), Value::new(Vec::new(), None));
+        }
+        // safety: inserted above when missing
+        let entry = self.inner.get_mut(&key).unwrap();
+        entry.accessed(now);
+        entry
+    }
+
+    /// Sets or clears the bit at `offset` of the value at `key`, bit 0 being the most
+    /// significant bit of the first byte, and replies with the previous bit. The value
+    /// is zero-padded up to the byte holding `offset`, and created if missing.
+    pub fn set_bit(&mut self, key: String, offset: usize, bit: bool) -> RespDataType {
+        let entry = self.live_or_empty(key);
+        let (byte, mask) = (offset / 8, 0x80 >> (offset % 8));
+        let before = entry.data.len();
+        if before <= byte {
+            entry.data.resize(byte + 1, 0);
+        }
+        let old = entry.data[byte] & mask != 0;
+        if bit {
+            entry.data[byte] |= mask;
+        } else {
+            entry.data[byte] &= !mask;
+        }
+        let grown = entry.data.len() - before;
+        self.used_memory += grown;
+        RespDataType::Integer(old as i64)
+    }
+
+    /// Replies with the bit at `offset` of the value at `key`, 0 past its end or for a
+    /// missing key.
+    pub fn get_bit(&mut self, key: &str, offset: usize) -> RespDataType {
+        let now = Instant::now();
+        let Some(entry) = self
+            .inner
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+        else {
+            return RespDataType::Integer(0);
+        };
+        entry.accessed(now);
+
+        let bit = entry
+            .data
+            .get(offset / 8)
+            .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0);
+        RespDataType::Integer(bit as i64)
+    }
+
+    /// Stores the bitwise `op` of the values at `sources` at `dest`, shorter and missing
+    /// values being zero-extended, and replies with the length of the result. An empty
+    /// result deletes `dest`.
+    pub fn bit_op(&mut self, op: BitOp, dest: String, sources: &[String]) -> RespDataType {
+        let values: Vec<&[u8]> = sources
+            .iter()
+            .map(|key| self.peek(key).unwrap_or_default())
+            .collect();
+        let len = values.iter().map(|value| value.len()).max().unwrap_or(0);
+        let byte = |value: &[u8], i: usize| value.get(i).copied().unwrap_or(0);
+
+        let result: Vec<u8> = (0..len)
+            .map(|i| {
+                let mut bytes = values.iter().map(|value| byte(value, i));
+                match op {
+                    BitOp::And => bytes.fold(0xff, |acc, b| acc & b),
+                    BitOp::Or => bytes.fold(0, |acc, b| acc | b),
+                    BitOp::Xor => bytes.fold(0, |acc, b| acc ^ b),
+                    BitOp::Not => !bytes.next().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        if result.is_empty() {
+            self.take(&dest);
+        } else {
+            self.insert(dest, Value::new(result, None));
+        }
+        len_reply(len)
+    }
+
+    /// Replies with the number of set bits of the value at `key`, or of its inclusive
+    /// `(start, end)` range of bytes or bits. 0 for a missing key.
+    pub fn bit_count(&mut self, key: &str, range: Option<(i64, i64, BitUnit)>) -> RespDataType {
+        let now = Instant::now();
+        let Some(entry) = self
+            .inner
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+        else {
+            return RespDataType::Integer(0);
+        };
+        entry.accessed(now);
+
+        let data = &entry.data;
+        let popcount = |bytes: &[u8]| bytes.iter().map(|b| b.count_ones() as i64).sum::<i64>();
+        let count = match range {
+            None => popcount(data),
+            Some((start, end, BitUnit::Byte)) => match resolve_range(start, end, data.len()) {
+                Some((start, end)) => popcount(&data[start..=end]),
+                None => 0,
+            },
+            Some((start, end, BitUnit::Bit)) => match resolve_range(start, end, data.len() * 8) {
+                Some((start, end)) => {
+                    // Mask out the bits before `start` and after `end` in the edge bytes
+                    let (first, last) = (start / 8, end / 8);
+                    let head = data[first] & (0xff >> (start % 8));
+                    if first == last {
+                        (head & (0xff << (7 - end % 8))).count_ones() as i64
+                    } else {
+                        let tail = data[last] & (0xff << (7 - end % 8));
+                        head.count_ones() as i64
+                            + popcount(&data[first + 1..last])
+                            + tail.count_ones() as i64
                     }
                 }
+                None => 0,
+            },
+        };
+        RespDataType::Integer(count)
+    }
+
+    /// Stores `entry` at `key`, indexing its deadline for the active expiry sweep.
+    fn insert(&mut self, key: String, entry: Value) {
+        if let Some(deadline) = entry.expires_at {
+            self.expirations.insert((deadline, key.clone()));
+        }
+        let key_len = key.len();
+        self.used_memory += key_len + entry.data.len();
+        if let Some(old) = self.inner.insert(key, entry) {
+            self.used_memory -= key_len + old.data.len();
+        }
+    }
+
+    /// Removes `key`, live or not, returning its value.
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let entry = self.inner.remove(key)?;
+        self.used_memory -= key.len() + entry.data.len();
+        Some(entry)
+    }
+
+    /// Returns the bytes taken by the keys and values, overhead excluded. Values whose
+    /// TTL elapsed count until they are reaped.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// Returns the number of keys, including those whose TTL elapsed but that were not
+    /// reaped yet.
+    pub fn key_count(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns the key at `position`, in `0..key_count()`, live or not.
+    pub fn key_at(&self, position: usize) -> Option<&String> {
+        self.inner.get_index(position).map(|(key, _)| key)
+    }
+
+    /// Iterates over the keys that have not expired yet.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key)
+    }
+
+    /// Iterates over the live (non-expired) keys with their values and deadlines.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &[u8], Option<Instant>)> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key, entry.data.as_slice(), entry.expires_at))
+    }
+
+    /// Stores `data` at `key` with the given deadline, as loaded from a snapshot.
+    pub fn restore(&mut self, key: String, data: Vec<u8>, expires_at: Option<Instant>) {
+        self.insert(
+            key,
+            Value {
+                data,
+                expires_at,
+                last_accessed: Instant::now(),
+                accesses: 0,
+            },
+        );
+    }
+
+    /// Returns the encoding Redis would report for the live value at `key`: `int` for
+    /// canonical 64-bit integers, `embstr` for short strings and `raw` otherwise.
+    pub fn encoding(&self, key: &str) -> Option<&'static str> {
+        let entry = self
+            .inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))?;
+        let is_int = entry
+            .as_int()
+            .is_some_and(|n| n.to_string().as_bytes() == entry.data);
+
+        Some(if is_int {
+            "int"
+        } else if entry.data.len() <= EMBSTR_MAX_LEN {
+            "embstr"
+        } else {
+            "raw"
+        })
+    }
+
+    /// Returns the live value at `key` without marking it as accessed.
+    pub fn peek(&self, key: &str) -> Option<&[u8]> {
+        self.inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| entry.data.as_slice())
+    }
+
+    /// Returns how long the live value at `key` has gone without being accessed.
+    pub fn idle_time(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| now - entry.last_accessed)
+    }
+
+    /// Returns how many times the live value at `key` was read or written.
+    pub fn frequency(&self, key: &str) -> Option<u64> {
+        self.inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| entry.accesses)
+    }
+
+    /// Returns the access count and last access time of the live value at `key`.
+    pub fn access(&self, key: &str) -> Option<(u64, Instant)> {
+        self.inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| (entry.accesses, entry.last_accessed))
+    }
+
+    /// Removes `key`, returning whether a live (non-expired) value was stored there.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.take(key)
+            .is_some_and(|entry| !entry.is_expired(Instant::now()))
+    }
+
+    /// Removes every key.
+    pub fn clear(&mut self) {
+        self.inner.clear();
+        self.expirations.clear();
+        self.used_memory = 0;
+    }
+
+    /// Returns whether a live (non-expired) value is stored at `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.inner
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(Instant::now()))
+    }
+
+    /// Stores a copy of the value at `src`, TTL included, under `dst`, overwriting it.
+    /// Returns whether `src` held a live value.
+    pub fn copy(&mut self, src: &str, dst: &str) -> bool {
+        let Some(entry) = self
+            .inner
+            .get(src)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .cloned()
+        else {
+            return false;
+        };
+        self.insert(dst.to_string(), entry);
+        true
+    }
+
+    /// Marks the live value at `key` as accessed, returning whether there is one.
+    pub fn mark_accessed(&mut self, key: &str) -> bool {
+        let now = Instant::now();
+        match self
+            .inner
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+        {
+            Some(entry) => {
+                entry.accessed(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the deadline of the live value at `key`, `Some(None)` if it has no TTL.
+    pub fn expiry(&self, key: &str) -> Option<Option<Instant>> {
+        self.inner
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| entry.expires_at)
+    }
+
+    /// Sets the deadline of the live value at `key`, `None` removing its TTL. Returns
+    /// whether there was such a value.
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let now = Instant::now();
+        let Some(entry) = self
+            .inner
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+        else {
+            return false;
+        };
+        entry.expires_at = expires_at;
+        if let Some(deadline) = expires_at {
+            self.expirations.insert((deadline, key.to_string()));
+        }
+        true
+    }
+
+    /// Removes `key` if its TTL has elapsed, returning whether it was removed.
+    pub fn remove_if_expired(&mut self, key: &str) -> bool {
+        let expired = self
+            .inner
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(Instant::now()));
+        if expired {
+            self.take(key);
+        }
+        expired
+    }
+
+    /// Removes every key whose TTL has elapsed and returns the reaped keys.
+    pub fn purge_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .inner
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.take(key);
+        }
+        expired
+    }
+
+    /// Examines up to `sample_size` of the deadlines that have passed, earliest first,
+    /// removing the keys still set to expire at them. Returns the removed keys.
+    pub fn sweep_expired(&mut self, sample_size: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+
+        for _ in 0..sample_size {
+            if self
+                .expirations
+                .first()
+                .is_none_or(|(deadline, _)| *deadline >= now)
+            {
+                break;
+            }
+
+            // safety: checked to be non-empty above
+            let (deadline, key) = self.expirations.pop_first().unwrap();
+            // The key may have been deleted, overwritten or given another TTL since
+            if self
+                .inner
+                .get(&key)
+                .is_some_and(|entry| entry.expires_at == Some(deadline))
+            {
+                self.take(&key);
+                reaped.push(key);
+            }
+        }
+        reaped
+    }
+
+    /// Returns the live value at `key`, marking it as accessed. An expired value is
+    /// removed on the way.
+    pub fn get(&mut self, key: &str) -> RespDataType {
+        let now = Instant::now();
+        match self.inner.get_mut(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.accessed(now);
+                RespDataType::BulkString(entry.data.clone().into())
+            }
+            Some(_) => {
+                self.take(key);
                 RespDataType::NullBulkString
             }
             None => RespDataType::NullBulkString,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_follows_value_shape() {
+        let mut strings = Strings::default();
+        for (key, value) in [("int", "-42"), ("padded", "042"), ("short", "hello")] {
+            strings.set(key.into(), value.into(), None, None, false, false);
+        }
+        strings.set(
+            "long".into(),
+            "x".repeat(45).into(),
+            None,
+            None,
+            false,
+            false,
+        );
+
+        assert_eq!(strings.encoding("int"), Some("int"));
+        assert_eq!(strings.encoding("padded"), Some("embstr"));
+        assert_eq!(strings.encoding("short"), Some("embstr"));
+        assert_eq!(strings.encoding("long"), Some("raw"));
+        assert_eq!(strings.encoding("missing"), None);
+    }
+
+    #[test]
+    fn test_set_bit_grows_the_value() {
+        let mut strings = Strings::default();
+        assert_eq!(
+            strings.set_bit("k".into(), 17, true),
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            strings.get("k"),
+            RespDataType::BulkString(vec![0, 0, 0x40].into())
+        );
+        assert_eq!(strings.get_bit("k", 17), RespDataType::Integer(1));
+        assert_eq!(strings.get_bit("k", 16), RespDataType::Integer(0));
+        assert_eq!(strings.get_bit("k", 1000), RespDataType::Integer(0));
+        assert_eq!(strings.get_bit("missing", 0), RespDataType::Integer(0));
+
+        // Setting a bit inside the value keeps its length and replies the old bit
+        assert_eq!(
+            strings.set_bit("k".into(), 17, false),
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            strings.set_bit("k".into(), 0, true),
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            strings.get("k"),
+            RespDataType::BulkString(vec![0x80, 0, 0].into())
+        );
+    }
+
+    #[test]
+    fn test_append_and_set_range_reply_the_new_length() {
+        let mut strings = Strings::default();
+        let chunk = vec![b'x'; 1 << 16];
+        let mut expected = 0;
+        for _ in 0..64 {
+            expected += chunk.len();
+            assert_eq!(
+                strings.append("k".into(), &chunk),
+                RespDataType::Integer(expected as i64)
+            );
+        }
+        assert_eq!(strings.len("k"), RespDataType::Integer(1 << 22));
+
+        // Writing past the end zero-pads the gap
+        assert_eq!(
+            strings.set_range("k".into(), (1 << 22) + 10, b"end"),
+            RespDataType::Integer((1 << 22) + 13)
+        );
+        assert_eq!(
+            strings.set_range("missing".into(), 5, b""),
+            RespDataType::Integer(0)
+        );
+        assert!(!strings.contains("missing"));
+
+        // One byte over the limit is refused without allocating anything
+        assert_eq!(
+            strings.set_range("k".into(), MAX_STRING_LEN, b"x"),
+            RespDataType::SimpleError(MAX_STRING_LEN_ERROR.into())
+        );
+        assert_eq!(
+            strings.set_range("k".into(), usize::MAX, b"x"),
+            RespDataType::SimpleError(MAX_STRING_LEN_ERROR.into())
+        );
+    }
+
+    #[test]
+    fn test_bit_count_ranges() {
+        let mut strings = Strings::default();
+        strings.set("k".into(), "foobar".into(), None, None, false, false);
+
+        assert_eq!(strings.bit_count("k", None), RespDataType::Integer(26));
+        assert_eq!(
+            strings.bit_count("k", Some((0, 0, BitUnit::Byte))),
+            RespDataType::Integer(4)
+        );
+        assert_eq!(
+            strings.bit_count("k", Some((1, 1, BitUnit::Byte))),
+            RespDataType::Integer(6)
+        );
+        assert_eq!(
+            strings.bit_count("k", Some((-2, -1, BitUnit::Byte))),
+            RespDataType::Integer(7)
+        );
+        // 'f' is 0b01100110 and 'o' 0b01101111
+        assert_eq!(
+            strings.bit_count("k", Some((5, 30, BitUnit::Bit))),
+            RespDataType::Integer(17)
+        );
+        assert_eq!(
+            strings.bit_count("k", Some((1, 2, BitUnit::Bit))),
+            RespDataType::Integer(2)
+        );
+        assert_eq!(
+            strings.bit_count("k", Some((3, 1, BitUnit::Byte))),
+            RespDataType::Integer(0)
+        );
+        assert_eq!(strings.bit_count("missing", None), RespDataType::Integer(0));
+    }
+
+    #[test]
+    fn test_bit_op() {
+        let mut strings = Strings::default();
+        strings.set(
+            "a".into(),
+            vec![0b1111_0000, 0xff].into(),
+            None,
+            None,
+            false,
+            false,
+        );
+        strings.set(
+            "b".into(),
+            vec![0b1010_1010].into(),
+            None,
+            None,
+            false,
+            false,
+        );
+
+        // The shorter source is zero-extended
+        assert_eq!(
+            strings.bit_op(BitOp::And, "dest".into(), &["a".into(), "b".into()]),
+            RespDataType::Integer(2)
+        );
+        assert_eq!(
+            strings.get("dest"),
+            RespDataType::BulkString(vec![0b1010_0000, 0].into())
+        );
+
+        assert_eq!(
+            strings.bit_op(BitOp::Not, "dest".into(), &["b".into()]),
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            strings.get("dest"),
+            RespDataType::BulkString(vec![0b0101_0101].into())
+        );
+
+        assert_eq!(
+            strings.bit_op(BitOp::Or, "dest".into(), &["missing".into()]),
+            RespDataType::Integer(0)
+        );
+        assert!(!strings.contains("dest"));
+    }
+
+    #[test]
+    fn test_set_get_on_missing_key() {
+        let mut strings = Strings::default();
+        let reply = strings.set("k".into(), "v".into(), None, None, true, false);
+        assert_eq!(reply, RespDataType::NullBulkString);
+        assert_eq!(strings.get("k"), RespDataType::BulkString("v".into()));
+    }
+
+    #[test]
+    fn test_set_nx_get_on_missing_key() {
+        let mut strings = Strings::default();
+        let reply = strings.set(
+            "k".into(),
+            "v".into(),
+            None,
+            Some(SetCondition::NX),
+            true,
+            false,
+        );
+        assert_eq!(reply, RespDataType::NullBulkString);
+        assert_eq!(strings.get("k"), RespDataType::BulkString("v".into()));
+    }
+
+    #[test]
+    fn test_set_xx_get_on_missing_key() {
+        let mut strings = Strings::default();
+        let reply = strings.set(
+            "k".into(),
+            "v".into(),
+            None,
+            Some(SetCondition::XX),
+            true,
+            false,
+        );
+        assert_eq!(reply, RespDataType::NullBulkString);
+        assert_eq!(strings.get("k"), RespDataType::NullBulkString);
+    }
+
+    #[test]
+    fn test_set_get_returns_old_value() {
+        let mut strings = Strings::default();
+        strings.set("k".into(), "old".into(), None, None, false, false);
+        let reply = strings.set("k".into(), "new".into(), None, None, true, false);
+        assert_eq!(reply, RespDataType::BulkString("old".into()));
+        assert_eq!(strings.get("k"), RespDataType::BulkString("new".into()));
+    }
+
+    #[test]
+    fn test_purge_expired_only_reaps_expired_keys() {
+        let mut strings = Strings::default();
+        let ttl = Some(Duration::from_millis(1));
+        strings.set("a".into(), "1".into(), ttl, None, false, false);
+        strings.set("b".into(), "2".into(), ttl, None, false, false);
+        strings.set("c".into(), "3".into(), None, None, false, false);
+        std::thread::sleep(Duration::from_millis(5));
+
+        let mut reaped = strings.purge_expired();
+        reaped.sort();
+        assert_eq!(reaped, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(strings.inner.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweep_expired_reaps_due_keys_without_access() {
+        let mut strings = Strings::default();
+        let ttl = Some(Duration::from_millis(10));
+        strings.set("a".into(), "1".into(), ttl, None, false, false);
+        strings.set("b".into(), "2".into(), ttl, None, false, false);
+        // Overwritten without a TTL, so its deadline no longer applies
+        strings.set("b".into(), "3".into(), None, None, false, false);
+        strings.set(
+            "c".into(),
+            "4".into(),
+            Some(Duration::from_secs(60)),
+            None,
+            false,
+            false,
+        );
+
+        tokio::time::advance(Duration::from_millis(20)).await;
+
+        assert_eq!(strings.sweep_expired(10), vec!["a".to_string()]);
+        assert_eq!(strings.inner.len(), 2);
+        assert_eq!(strings.expirations.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweep_expired_honours_sample_size() {
+        let mut strings = Strings::default();
+        for i in 0..5 {
+            let ttl = Some(Duration::from_millis(10 + i));
+            strings.set(format!("k{i}"), "v".into(), ttl, None, false, false);
+        }
+
+        tokio::time::advance(Duration::from_millis(20)).await;
+
+        assert_eq!(
+            strings.sweep_expired(2),
+            vec!["k0".to_string(), "k1".to_string()]
+        );
+        assert_eq!(strings.inner.len(), 3);
+        assert_eq!(strings.sweep_expired(10).len(), 3);
+        assert!(strings.inner.is_empty());
+    }
+
+    #[test]
+    fn test_set_clears_existing_ttl() {
+        let mut strings = Strings::default();
+        let ttl = Some(Duration::from_secs(100));
+        strings.set("k".into(), "v1".into(), ttl, None, false, false);
+        strings.set("k".into(), "v2".into(), None, None, false, false);
+        assert!(strings.inner["k"].expires_at.is_none());
+    }
+
+    #[test]
+    fn test_set_keepttl_retains_existing_ttl() {
+        let mut strings = Strings::default();
+        let ttl = Some(Duration::from_secs(100));
+        strings.set("k".into(), "v1".into(), ttl, None, false, false);
+        let deadline = strings.inner["k"].expires_at;
+        strings.set("k".into(), "v2".into(), None, None, false, true);
+        assert!(deadline.is_some());
+        assert_eq!(strings.inner["k"].expires_at, deadline);
+        assert_eq!(strings.get("k"), RespDataType::BulkString("v2".into()));
+    }
+}
@@ -0,0 +1,902 @@
+//! Sorted sets: collections of unique members ordered by a floating point score.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::{keymap::KeyMap, len_reply, resolve_index_range};
+use crate::{
+    cmd::{ScoreBound, ScoreComparison, SetCondition},
+    resp::RespDataType,
+};
+
+/// Sorted sets with up to this many members are reported as a `listpack`.
+const MAX_LISTPACK_ENTRIES: usize = 128;
+/// Sorted sets whose members are all up to this long are reported as a `listpack`.
+const MAX_LISTPACK_VALUE: usize = 64;
+
+/// The sorted sets of a database, by key.
+#[derive(Default)]
+pub struct SortedSets {
+    inner: KeyMap<SortedSet>,
+    /// Bytes taken by the keys, members and scores, expired sorted sets included until
+    /// they are reaped
+    used_memory: usize,
+    /// Deadlines of the sorted sets given a TTL, earliest first, used by the active
+    /// expiry sweep. Like for lists, stale entries are discarded once their deadline
+    /// passes.
+    expirations: BTreeSet<(Instant, String)>,
+}
+
+#[derive(Clone)]
+struct SortedSet {
+    /// Score of every member
+    scores: HashMap<String, f64>,
+    /// Members ordered by score, ties broken lexicographically
+    ordered: BTreeSet<(Score, String)>,
+    /// Last time the sorted set was read or written, for OBJECT IDLETIME
+    last_accessed: Instant,
+    /// Number of times the sorted set was read or written, for OBJECT FREQ and LFU
+    /// eviction
+    accesses: u64,
+    expires_at: Option<Instant>,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        Self {
+            scores: HashMap::new(),
+            ordered: BTreeSet::new(),
+            last_accessed: Instant::now(),
+            accesses: 0,
+            expires_at: None,
+        }
+    }
+}
+
+/// A score totally ordered by `f64::total_cmp`. Scores are never NaN and -0 is
+/// stored as 0, so the order matches the numeric one.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl SortedSet {
+    /// Whether the sorted set still exists at `now`, i.e. its TTL has not elapsed.
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|expiry| now <= expiry)
+    }
+
+    /// Records a read or write of the sorted set at `now`.
+    fn accessed(&mut self, now: Instant) {
+        self.last_accessed = now;
+        self.accesses += 1;
+    }
+
+    /// Adds `member` or moves it to its new `score`, returning whether it was added.
+    fn insert(&mut self, member: String, score: f64) -> bool {
+        // Adding 0 turns -0 into 0
+        let score = score + 0.0;
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old.is_none()
+    }
+
+    /// Returns the bytes taken by the members and scores.
+    fn members_len(&self) -> usize {
+        self.scores.keys().map(|member| member_len(member)).sum()
+    }
+
+    /// Iterates over the members whose score is within `min` and `max`, lowest score
+    /// first.
+    fn within(&self, min: ScoreBound, max: ScoreBound) -> impl Iterator<Item = &(Score, String)> {
+        // The empty string sorts before any member of the same score
+        self.ordered
+            .range((Score(min.score), String::new())..)
+            .skip_while(move |(score, _)| min.exclusive && score.0 == min.score)
+            .take_while(move |(score, _)| {
+                score.0 < max.score || (!max.exclusive && score.0 == max.score)
+            })
+    }
+
+    /// Returns the 0-based position of `member`, lowest score first.
+    fn rank(&self, member: &str) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(
+            self.ordered
+                .range(..(Score(score), member.to_string()))
+                .count(),
+        )
+    }
+}
+
+impl SortedSets {
+    /// Adds the `(score, member)` pairs to the sorted set at `key`, creating it if
+    /// needed, and replies with the number of members that were not in it yet, along
+    /// with whether any member was added or got a new score.
+    ///
+    /// `condition` restricts the pairs to new (NX) or existing (XX) members, while
+    /// `comparison` only lets the score of an existing member grow (GT) or shrink (LT).
+    pub fn add(
+        &mut self,
+        key: String,
+        members: Vec<(f64, String)>,
+        condition: Option<SetCondition>,
+        comparison: Option<ScoreComparison>,
+    ) -> (RespDataType, bool) {
+        let set = self.live_or_empty(key.clone());
+
+        let (mut added, mut updated, mut grown) = (0, 0, 0);
+        for (score, member) in members {
+            match set.scores.get(&member) {
+                None if condition != Some(SetCondition::XX) => {
+                    grown += member_len(&member);
+                    set.insert(member, score);
+                    added += 1;
+                }
+                None => {}
+                Some(&current) => {
+                    let update = condition != Some(SetCondition::NX)
+                        && match comparison {
+                            Some(ScoreComparison::GT) => score > current,
+                            Some(ScoreComparison::LT) => score < current,
+                            None => true,
+                        };
+                    if update && score != current {
+                        set.insert(member, score);
+                        updated += 1;
+                    }
+                }
+            }
+        }
+
+        // XX on a missing key must not leave an empty sorted set behind
+        let empty = set.scores.is_empty();
+        self.used_memory += grown;
+        if empty {
+            self.take(&key);
+        }
+        (len_reply(added), added + updated > 0)
+    }
+
+    /// Adds `delta` to the score of `member`, adding it with a score of `delta` if it is
+    /// missing, and replies with the new score.
+    pub fn increment(&mut self, key: String, delta: f64, member: String) -> RespDataType {
+        let current = self
+            .live(&key)
+            .and_then(|set| set.scores.get(&member))
+            .copied()
+            .unwrap_or(0.0);
+        // inf and -inf cancel out into NaN, which cannot be ordered
+        let score = current + delta;
+        if score.is_nan() {
+            return RespDataType::SimpleError("ERR resulting score is not a number (NaN)".into());
+        }
+
+        let len = member_len(&member);
+        if self.live_or_empty(key).insert(member, score) {
+            self.used_memory += len;
+        }
+        score_reply(score)
+    }
+
+    /// Removes `members` from the sorted set at `key`, deleting it once empty, and
+    /// replies with the number of members that were removed.
+    pub fn remove_members(&mut self, key: &str, members: &[String]) -> RespDataType {
+        let Some(set) = self.touch(key) else {
+            return RespDataType::Integer(0);
+        };
+
+        let (mut removed, mut shrunk) = (0, 0);
+        for member in members {
+            if let Some(score) = set.scores.remove(member) {
+                set.ordered.remove(&(Score(score), member.clone()));
+                removed += 1;
+                shrunk += member_len(member);
+            }
+        }
+
+        let empty = set.scores.is_empty();
+        self.used_memory -= shrunk;
+        if empty {
+            self.take(key);
+        }
+        len_reply(removed)
+    }
+
+    /// Replies with the score of `member`, or nil if it is missing.
+    pub fn score(&mut self, key: &str, member: &str) -> RespDataType {
+        match self.touch(key).and_then(|set| set.scores.get(member)) {
+            Some(&score) => score_reply(score),
+            None => RespDataType::NullBulkString,
+        }
+    }
+
+    /// Replies with the number of members of the sorted set at `key`, 0 if missing.
+    pub fn card(&mut self, key: &str) -> RespDataType {
+        len_reply(self.touch(key).map_or(0, |set| set.scores.len()))
+    }
+
+    /// Replies with the 0-based position of `member`, lowest score first or highest
+    /// score first when `reverse` is set, or nil if it is missing.
+    pub fn rank(&mut self, key: &str, member: &str, reverse: bool) -> RespDataType {
+        let Some(set) = self.touch(key) else {
+            return RespDataType::NullBulkString;
+        };
+        match set.rank(member) {
+            Some(rank) if reverse => len_reply(set.scores.len() - 1 - rank),
+            Some(rank) => len_reply(rank),
+            None => RespDataType::NullBulkString,
+        }
+    }
+
+    /// Replies with the members from position `start` to `stop` included, lowest score
+    /// first or highest score first when `reverse` is set. Negative positions count from
+    /// the end. With `with_scores`, each member is followed by its score.
+    pub fn range(
+        &mut self,
+        key: &str,
+        start: i64,
+        stop: i64,
+        reverse: bool,
+        with_scores: bool,
+    ) -> RespDataType {
+        let Some(set) = self.touch(key) else {
+            return RespDataType::Array(vec![]);
+        };
+        let Some((start, stop)) = resolve_index_range(start, stop, set.ordered.len()) else {
+            return RespDataType::Array(vec![]);
+        };
+
+        let count = stop - start + 1;
+        if reverse {
+            range_reply(
+                set.ordered.iter().rev().skip(start).take(count),
+                with_scores,
+            )
+        } else {
+            range_reply(set.ordered.iter().skip(start).take(count), with_scores)
+        }
+    }
+
+    /// Replies with the members whose score is within `min` and `max`, lowest score
+    /// first. `limit` skips `offset` members and then returns at most `count` of them,
+    /// or all of them when `count` is negative. With `with_scores`, each member is
+    /// followed by its score.
+    pub fn range_by_score(
+        &mut self,
+        key: &str,
+        min: ScoreBound,
+        max: ScoreBound,
+        with_scores: bool,
+        limit: Option<(i64, i64)>,
+    ) -> RespDataType {
+        let Some(set) = self.touch(key) else {
+            return RespDataType::Array(vec![]);
+        };
+        let (offset, count) = match limit {
+            Some((offset, _)) if offset < 0 => return RespDataType::Array(vec![]),
+            Some((offset, count)) => (
+                offset as usize,
+                usize::try_from(count).unwrap_or(usize::MAX),
+            ),
+            None => (0, usize::MAX),
+        };
+
+        let members = set.within(min, max).skip(offset).take(count);
+        range_reply(members, with_scores)
+    }
+
+    /// Replies with the number of members whose score is within `min` and `max`, 0 if
+    /// the sorted set is missing.
+    pub fn count(&mut self, key: &str, min: ScoreBound, max: ScoreBound) -> RespDataType {
+        len_reply(
+            self.touch(key)
+                .map_or(0, |set| set.within(min, max).count()),
+        )
+    }
+
+    /// Iterates over the keys of the live sorted sets.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, set)| set.is_live(now))
+            .map(|(key, _)| key)
+    }
+
+    /// Returns whether a live sorted set is stored at `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.live(key).is_some()
+    }
+
+    /// Returns the live sorted set at `key`.
+    fn live(&self, key: &str) -> Option<&SortedSet> {
+        self.inner
+            .get(key)
+            .filter(|set| set.is_live(Instant::now()))
+    }
+
+    /// Returns the live sorted set at `key` marked as accessed, replacing a missing or
+    /// expired one with an empty one without TTL.
+    fn live_or_empty(&mut self, key: String) -> &mut SortedSet {
+        let now = Instant::now();
+        if !self.contains(&key) {
+            self.insert(key.clone(), SortedSet::default());
+        }
+        // safety: inserted above when missing
+        let set = self.inner.get_mut(&key).unwrap();
+        set.accessed(now);
+        set
+    }
+
+    /// Stores a copy of the sorted set at `src`, TTL included, under `dst`, overwriting
+    /// it. Returns whether `src` held a live sorted set.
+    pub fn copy(&mut self, src: &str, dst: &str) -> bool {
+        let Some(set) = self.live(src).cloned() else {
+            return false;
+        };
+        self.insert(dst.to_string(), set);
+        true
+    }
+
+    /// Iterates over the live sorted sets with their members and scores, lowest score
+    /// first, and deadlines.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, Vec<(String, f64)>, Option<Instant>)> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(move |(_, set)| set.is_live(now))
+            .map(|(key, set)| (key, Self::members(set), set.expires_at))
+    }
+
+    /// Stores the `(member, score)` pairs at `key` with the given deadline, as loaded
+    /// from a snapshot.
+    pub fn restore(
+        &mut self,
+        key: String,
+        members: Vec<(String, f64)>,
+        expires_at: Option<Instant>,
+    ) {
+        let mut set = SortedSet {
+            expires_at,
+            ..Default::default()
+        };
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        self.insert(key, set);
+    }
+
+    /// Stores `set` at `key`, indexing its deadline for the active expiry sweep.
+    fn insert(&mut self, key: String, set: SortedSet) {
+        if let Some(deadline) = set.expires_at {
+            self.expirations.insert((deadline, key.clone()));
+        }
+        let key_len = key.len();
+        self.used_memory += key_len + set.members_len();
+        if let Some(old) = self.inner.insert(key, set) {
+            self.used_memory -= key_len + old.members_len();
+        }
+    }
+
+    /// Removes the sorted set at `key`, live or not, returning it.
+    fn take(&mut self, key: &str) -> Option<SortedSet> {
+        let set = self.inner.remove(key)?;
+        self.used_memory -= key.len() + set.members_len();
+        Some(set)
+    }
+
+    /// Returns the bytes taken by the keys, members and scores, overhead excluded.
+    /// Sorted sets whose TTL elapsed count until they are reaped.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// Returns the number of sorted sets, including those whose TTL elapsed but that
+    /// were not reaped yet.
+    pub fn key_count(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns the key at `position`, in `0..key_count()`, live or not.
+    pub fn key_at(&self, position: usize) -> Option<&String> {
+        self.inner.get_index(position).map(|(key, _)| key)
+    }
+
+    /// Returns the members and scores of the live sorted set at `key`, lowest score
+    /// first, without marking it as accessed.
+    pub fn peek(&self, key: &str) -> Option<Vec<(String, f64)>> {
+        self.live(key).map(Self::members)
+    }
+
+    /// Returns the deadline of the live sorted set at `key`, `Some(None)` if it has no
+    /// TTL.
+    pub fn expiry(&self, key: &str) -> Option<Option<Instant>> {
+        self.live(key).map(|set| set.expires_at)
+    }
+
+    /// Sets the deadline of the live sorted set at `key`, `None` removing its TTL.
+    /// Returns whether there was such a sorted set.
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let now = Instant::now();
+        let Some(set) = self.inner.get_mut(key).filter(|set| set.is_live(now)) else {
+            return false;
+        };
+        set.expires_at = expires_at;
+        if let Some(deadline) = expires_at {
+            self.expirations.insert((deadline, key.to_string()));
+        }
+        true
+    }
+
+    /// Removes the sorted set at `key` if its TTL has elapsed, returning whether it was
+    /// removed.
+    pub fn remove_if_expired(&mut self, key: &str) -> bool {
+        let expired = self
+            .inner
+            .get(key)
+            .is_some_and(|set| !set.is_live(Instant::now()));
+        if expired {
+            self.take(key);
+        }
+        expired
+    }
+
+    /// Removes every sorted set whose TTL has elapsed and returns the reaped keys.
+    pub fn purge_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .inner
+            .iter()
+            .filter(|(_, set)| !set.is_live(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.take(key);
+        }
+        expired
+    }
+
+    /// Examines up to `sample_size` of the deadlines that have passed, earliest first,
+    /// removing the sorted sets still set to expire at them. Returns the removed keys.
+    pub fn sweep_expired(&mut self, sample_size: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+
+        for _ in 0..sample_size {
+            if self
+                .expirations
+                .first()
+                .is_none_or(|(deadline, _)| *deadline >= now)
+            {
+                break;
+            }
+
+            // safety: checked to be non-empty above
+            let (deadline, key) = self.expirations.pop_first().unwrap();
+            // The sorted set may have been deleted or given another TTL since
+            if self
+                .inner
+                .get(&key)
+                .is_some_and(|set| set.expires_at == Some(deadline))
+            {
+                self.take(&key);
+                reaped.push(key);
+            }
+        }
+        reaped
+    }
+
+    fn members(set: &SortedSet) -> Vec<(String, f64)> {
+        set.ordered
+            .iter()
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+
+    /// Returns how long the sorted set at `key` has gone without being accessed.
+    pub fn idle_time(&self, key: &str) -> Option<Duration> {
+        self.live(key).map(|set| set.last_accessed.elapsed())
+    }
+
+    /// Returns how many times the sorted set at `key` was read or written.
+    pub fn frequency(&self, key: &str) -> Option<u64> {
+        self.live(key).map(|set| set.accesses)
+    }
+
+    /// Returns the access count and last access time of the sorted set at `key`.
+    pub fn access(&self, key: &str) -> Option<(u64, Instant)> {
+        self.live(key).map(|set| (set.accesses, set.last_accessed))
+    }
+
+    /// Marks the sorted set at `key` as accessed, returning whether there is one.
+    pub fn mark_accessed(&mut self, key: &str) -> bool {
+        self.touch(key).is_some()
+    }
+
+    /// Returns the sorted set at `key`, marking it as accessed.
+    fn touch(&mut self, key: &str) -> Option<&mut SortedSet> {
+        let now = Instant::now();
+        let set = self.inner.get_mut(key).filter(|set| set.is_live(now))?;
+        set.accessed(now);
+        Some(set)
+    }
+
+    /// Returns the encoding Redis would report for the sorted set at `key`.
+    pub fn encoding(&self, key: &str) -> Option<&'static str> {
+        let set = self.live(key)?;
+        let small = set.scores.len() <= MAX_LISTPACK_ENTRIES
+            && set
+                .scores
+                .keys()
+                .all(|member| member.len() <= MAX_LISTPACK_VALUE);
+        Some(if small { "listpack" } else { "skiplist" })
+    }
+
+    /// Removes the sorted set stored at `key`, returning whether it was live.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.take(key)
+            .is_some_and(|set| set.is_live(Instant::now()))
+    }
+
+    /// Removes every sorted set.
+    pub fn clear(&mut self) {
+        self.inner.clear();
+        self.expirations.clear();
+        self.used_memory = 0;
+    }
+}
+
+/// Returns the bytes taken by `member` and its score.
+fn member_len(member: &str) -> usize {
+    member.len() + size_of::<f64>()
+}
+
+/// Replies with a score the way Redis formats doubles, e.g. `1`, `2.5` or `-inf`.
+fn score_reply(score: f64) -> RespDataType {
+    RespDataType::BulkString(score.to_string().into())
+}
+
+/// Replies with `members`, each followed by its score with `with_scores`.
+fn range_reply<'a>(
+    members: impl Iterator<Item = &'a (Score, String)>,
+    with_scores: bool,
+) -> RespDataType {
+    let mut reply = Vec::new();
+    for (score, member) in members {
+        reply.push(RespDataType::BulkString(member.clone().into()));
+        if with_scores {
+            reply.push(score_reply(score.0));
+        }
+    }
+    RespDataType::Array(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(f64, &str)]) -> Vec<(f64, String)> {
+        pairs
+            .iter()
+            .map(|(score, member)| (*score, member.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_add_replies_with_new_members_only() {
+        let mut zsets = SortedSets::default();
+        assert_eq!(
+            zsets.add("z".into(), pairs(&[(1.0, "a"), (2.0, "b")]), None, None),
+            (RespDataType::Integer(2), true)
+        );
+        assert_eq!(
+            zsets.add("z".into(), pairs(&[(3.0, "a"), (4.0, "c")]), None, None),
+            (RespDataType::Integer(1), true)
+        );
+        assert_eq!(
+            zsets.add("z".into(), pairs(&[(3.0, "a")]), None, None),
+            (RespDataType::Integer(0), false)
+        );
+        assert_eq!(zsets.card("z"), RespDataType::Integer(3));
+        assert_eq!(zsets.score("z", "a"), RespDataType::BulkString("3".into()));
+        assert_eq!(zsets.score("z", "x"), RespDataType::NullBulkString);
+        assert_eq!(zsets.card("missing"), RespDataType::Integer(0));
+    }
+
+    #[test]
+    fn test_add_conditions_and_comparisons() {
+        let mut zsets = SortedSets::default();
+        zsets.add("z".into(), pairs(&[(5.0, "a")]), None, None);
+
+        // XX never adds, NX never updates
+        let xx = Some(SetCondition::XX);
+        let nx = Some(SetCondition::NX);
+        assert_eq!(
+            zsets.add("z".into(), pairs(&[(1.0, "a"), (1.0, "b")]), xx, None),
+            (RespDataType::Integer(0), true)
+        );
+        assert_eq!(zsets.score("z", "a"), RespDataType::BulkString("1".into()));
+        assert_eq!(
+            zsets.add("z".into(), pairs(&[(9.0, "a"), (2.5, "b")]), nx, None),
+            (RespDataType::Integer(1), true)
+        );
+        assert_eq!(zsets.score("z", "a"), RespDataType::BulkString("1".into()));
+        assert_eq!(
+            zsets.score("z", "b"),
+            RespDataType::BulkString("2.5".into())
+        );
+
+        // GT and LT only move existing members one way but still add new ones
+        let gt = Some(ScoreComparison::GT);
+        let lt = Some(ScoreComparison::LT);
+        zsets.add("z".into(), pairs(&[(0.0, "a"), (7.0, "c")]), None, gt);
+        assert_eq!(zsets.score("z", "a"), RespDataType::BulkString("1".into()));
+        assert_eq!(zsets.score("z", "c"), RespDataType::BulkString("7".into()));
+        zsets.add("z".into(), pairs(&[(0.0, "a"), (8.0, "b")]), None, lt);
+        assert_eq!(zsets.score("z", "a"), RespDataType::BulkString("0".into()));
+        assert_eq!(
+            zsets.score("z", "b"),
+            RespDataType::BulkString("2.5".into())
+        );
+
+        // XX on a missing key creates nothing
+        zsets.add("other".into(), pairs(&[(1.0, "a")]), xx, None);
+        assert!(!zsets.contains("other"));
+    }
+
+    #[test]
+    fn test_rank_breaks_ties_lexicographically() {
+        let mut zsets = SortedSets::default();
+        zsets.add(
+            "z".into(),
+            pairs(&[(2.0, "b"), (1.0, "z"), (2.0, "a"), (f64::INFINITY, "top")]),
+            None,
+            None,
+        );
+
+        assert_eq!(zsets.rank("z", "z", false), RespDataType::Integer(0));
+        assert_eq!(zsets.rank("z", "a", false), RespDataType::Integer(1));
+        assert_eq!(zsets.rank("z", "b", false), RespDataType::Integer(2));
+        assert_eq!(zsets.rank("z", "top", false), RespDataType::Integer(3));
+        assert_eq!(zsets.rank("z", "top", true), RespDataType::Integer(0));
+        assert_eq!(zsets.rank("z", "b", true), RespDataType::Integer(1));
+        assert_eq!(zsets.rank("z", "x", false), RespDataType::NullBulkString);
+
+        // Updating a score moves the member
+        zsets.add("z".into(), pairs(&[(0.5, "b")]), None, None);
+        assert_eq!(zsets.rank("z", "b", false), RespDataType::Integer(0));
+        assert_eq!(zsets.rank("z", "z", false), RespDataType::Integer(1));
+    }
+
+    fn bulk(items: &[&str]) -> RespDataType {
+        RespDataType::Array(
+            items
+                .iter()
+                .map(|item| RespDataType::BulkString(item.to_string().into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_range_by_position() {
+        let mut zsets = SortedSets::default();
+        zsets.add(
+            "z".into(),
+            pairs(&[(3.0, "c"), (1.0, "a"), (2.0, "b"), (4.0, "d")]),
+            None,
+            None,
+        );
+
+        assert_eq!(
+            zsets.range("z", 0, -1, false, false),
+            bulk(&["a", "b", "c", "d"])
+        );
+        assert_eq!(zsets.range("z", 1, 2, false, false), bulk(&["b", "c"]));
+        assert_eq!(zsets.range("z", -2, 100, false, false), bulk(&["c", "d"]));
+        assert_eq!(zsets.range("z", 3, 1, false, false), bulk(&[]));
+        assert_eq!(zsets.range("z", 5, 10, false, false), bulk(&[]));
+        assert_eq!(zsets.range("missing", 0, -1, false, false), bulk(&[]));
+
+        assert_eq!(
+            zsets.range("z", 0, -1, true, false),
+            bulk(&["d", "c", "b", "a"])
+        );
+        assert_eq!(zsets.range("z", 0, 1, true, false), bulk(&["d", "c"]));
+    }
+
+    #[test]
+    fn test_range_with_scores_interleaves_members_and_scores() {
+        let mut zsets = SortedSets::default();
+        zsets.add("z".into(), pairs(&[(1.5, "a"), (-2.0, "b")]), None, None);
+
+        assert_eq!(
+            zsets.range("z", 0, -1, false, true),
+            bulk(&["b", "-2", "a", "1.5"])
+        );
+        assert_eq!(zsets.range("z", 0, 0, true, true), bulk(&["a", "1.5"]));
+    }
+
+    fn bound(score: f64, exclusive: bool) -> ScoreBound {
+        ScoreBound { score, exclusive }
+    }
+
+    #[test]
+    fn test_count() {
+        let mut zsets = SortedSets::default();
+        zsets.add(
+            "z".into(),
+            pairs(&[(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d"), (5.0, "e")]),
+            None,
+            None,
+        );
+
+        assert_eq!(
+            zsets.count("z", bound(2.0, false), bound(3.0, false)),
+            RespDataType::Integer(3)
+        );
+        assert_eq!(
+            zsets.count("z", bound(2.0, true), bound(5.0, false)),
+            RespDataType::Integer(2)
+        );
+        assert_eq!(
+            zsets.count(
+                "z",
+                bound(f64::NEG_INFINITY, false),
+                bound(f64::INFINITY, false)
+            ),
+            RespDataType::Integer(5)
+        );
+        assert_eq!(
+            zsets.count("missing", bound(0.0, false), bound(1.0, false)),
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let mut zsets = SortedSets::default();
+        zsets.add(
+            "z".into(),
+            pairs(&[(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d"), (5.0, "e")]),
+            None,
+            None,
+        );
+        let range = |zsets: &mut SortedSets, min, max, limit| {
+            zsets.range_by_score("z", min, max, false, limit)
+        };
+
+        // Inclusive bounds
+        assert_eq!(
+            range(&mut zsets, bound(2.0, false), bound(3.0, false), None),
+            bulk(&["b", "c", "d"])
+        );
+        assert_eq!(
+            range(
+                &mut zsets,
+                bound(f64::NEG_INFINITY, false),
+                bound(f64::INFINITY, false),
+                None
+            ),
+            bulk(&["a", "b", "c", "d", "e"])
+        );
+        assert_eq!(
+            zsets.range_by_score("z", bound(4.0, false), bound(5.0, false), true, None),
+            bulk(&["e", "5"])
+        );
+
+        // Exclusive bounds
+        assert_eq!(
+            range(&mut zsets, bound(2.0, true), bound(5.0, true), None),
+            bulk(&["d"])
+        );
+        assert_eq!(
+            range(&mut zsets, bound(1.0, true), bound(2.0, false), None),
+            bulk(&["b", "c"])
+        );
+        assert_eq!(
+            range(&mut zsets, bound(3.0, false), bound(1.0, false), None),
+            bulk(&[])
+        );
+
+        // LIMIT offset count
+        let all = (bound(f64::NEG_INFINITY, false), bound(f64::INFINITY, false));
+        assert_eq!(
+            range(&mut zsets, all.0, all.1, Some((1, 2))),
+            bulk(&["b", "c"])
+        );
+        assert_eq!(
+            range(&mut zsets, all.0, all.1, Some((3, -1))),
+            bulk(&["d", "e"])
+        );
+        assert_eq!(range(&mut zsets, all.0, all.1, Some((-1, 2))), bulk(&[]));
+    }
+
+    #[test]
+    fn test_increment_updates_or_creates_members() {
+        let mut zsets = SortedSets::default();
+        zsets.add("z".into(), pairs(&[(1.0, "a"), (3.0, "b")]), None, None);
+
+        assert_eq!(
+            zsets.increment("z".into(), 2.5, "a".into()),
+            RespDataType::BulkString("3.5".into())
+        );
+        assert_eq!(zsets.rank("z", "a", false), RespDataType::Integer(1));
+
+        assert_eq!(
+            zsets.increment("z".into(), -4.0, "c".into()),
+            RespDataType::BulkString("-4".into())
+        );
+        assert_eq!(
+            zsets.increment("new".into(), 1.0, "x".into()),
+            RespDataType::BulkString("1".into())
+        );
+        assert_eq!(zsets.card("z"), RespDataType::Integer(3));
+
+        zsets.increment("z".into(), f64::INFINITY, "b".into());
+        assert!(matches!(
+            zsets.increment("z".into(), f64::NEG_INFINITY, "b".into()),
+            RespDataType::SimpleError(_)
+        ));
+        assert_eq!(
+            zsets.score("z", "b"),
+            RespDataType::BulkString("inf".into())
+        );
+    }
+
+    #[test]
+    fn test_remove_members_counts_existing_ones() {
+        let mut zsets = SortedSets::default();
+        zsets.add(
+            "z".into(),
+            pairs(&[(1.0, "a"), (2.0, "b"), (3.0, "c")]),
+            None,
+            None,
+        );
+
+        let members = ["a", "missing", "c", "a"].map(String::from);
+        assert_eq!(
+            zsets.remove_members("z", &members),
+            RespDataType::Integer(2)
+        );
+        assert_eq!(zsets.range("z", 0, -1, false, false), bulk(&["b"]));
+
+        // Removing the last member deletes the key
+        assert_eq!(
+            zsets.remove_members("z", &["b".to_string()]),
+            RespDataType::Integer(1)
+        );
+        assert!(!zsets.contains("z"));
+        assert_eq!(
+            zsets.remove_members("z", &["b".to_string()]),
+            RespDataType::Integer(0)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Redis-style glob matching used by pattern-based commands (SCAN MATCH, CONFIG GET, ...)
+//!
+//! Matching is done on raw bytes rather than UTF-8 strings so binary key names
+//! can be matched, and `[...]` classes compare individual bytes.
+//!
+//! Supported syntax:
+//! * `*` matches any sequence of bytes, including the empty one
+//! * `?` matches exactly one byte
+//! * `[abc]`, `[a-z]` and `[^a]` match a single byte from (or not from) a class
+//! * `\x` matches `x` literally
+
+/// Returns whether `string` matches the glob `pattern`.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Position right after the last `*` seen and the string index it is currently
+    // assumed to have consumed up to. Used to backtrack on a mismatch.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+
+        if let Some(next) = match_single(pattern, p, string[s]) {
+            p = next;
+            s += 1;
+            continue;
+        }
+
+        // Let the last `*` swallow one more byte and retry from there
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Matches the single-byte token of `pattern` starting at `p` against `byte`,
+/// returning the index of the next token on success.
+fn match_single(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let (matched, next) = match_class(pattern, p + 1, byte);
+            matched.then_some(next)
+        }
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
+
+/// Matches `byte` against the class starting at `p` (just past the `[`), returning
+/// whether it matched and the index just past the closing `]`.
+/// An unterminated class extends to the end of the pattern, like in Redis.
+fn match_class(pattern: &[u8], mut p: usize, byte: u8) -> (bool, usize) {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (start, end) = (pattern[p], pattern[p + 2]);
+            let (low, high) = if start <= end {
+                (start, end)
+            } else {
+                (end, start)
+            };
+            matched |= (low..=high).contains(&byte);
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+
+    // Skip the closing bracket, if any
+    let next = (p + 1).min(pattern.len());
+    (matched != negate, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_and_wildcards() {
+        assert!(glob_match(b"hello", b"hello"));
+        assert!(!glob_match(b"hello", b"hell"));
+        assert!(glob_match(b"h?llo", b"hallo"));
+        assert!(glob_match(b"h*o", b"ho"));
+        assert!(glob_match(b"h*o", b"heeello"));
+        assert!(!glob_match(b"h*o", b"help"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*:name", b"user:42:name"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h[c-a]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+    }
+
+    #[test]
+    fn test_matches_non_utf8_bytes() {
+        let key = b"key:\xff\x00end";
+        assert!(glob_match(b"key:*", key));
+        assert!(glob_match(b"key:?\x00end", key));
+        assert!(glob_match(b"key:[\xf0-\xff]*", key));
+        assert!(!glob_match(b"key:[^\xff]*", key));
+    }
+}
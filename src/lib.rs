@@ -1,7 +1,14 @@
+pub mod acl;
+pub mod aof;
+pub mod clients;
 pub mod cmd;
 pub mod config;
 pub mod data_structures;
+pub mod glob;
+pub mod pubsub;
+pub mod rdb;
 pub mod resp;
+pub mod rng;
 pub mod server;
 pub mod storage;
 
@@ -9,4 +16,4 @@ pub mod storage;
 // pub use crate::cmd::Command;
 // pub use crate::resp::{RespCodec, RespDataType};
 // pub use crate::server::{Connection, RedisServer};
-// pub use crate::storage::StorageHandle;
+pub use crate::storage::StorageHandle;
@@ -7,5 +7,13 @@ use codecrafters_redis::{config::ServerConfig, server::RedisServer};
 async fn main() -> Result<()> {
     let config = ServerConfig::from_cli();
     let server = RedisServer::new(config).await?;
+
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown();
+        }
+    });
+
     server.run().await
 }
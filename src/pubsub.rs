@@ -0,0 +1,242 @@
+//! Pub/Sub channel registry, shared by every connection and the storage actor.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::bail;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::resp::RespDataType;
+
+/// The connections subscribed to a channel, identified by their peer address.
+type Subscribers = HashMap<SocketAddr, Subscriber>;
+
+/// Where the `message` frames of a subscribed connection are pushed to, and how to
+/// close it when it does not read them fast enough.
+struct Subscriber {
+    tx: Sender<Vec<RespDataType>>,
+    killed: CancellationToken,
+}
+
+/// Maps each channel to its subscribers.
+#[derive(Clone, Default)]
+pub struct PubSub {
+    channels: Arc<Mutex<HashMap<String, Subscribers>>>,
+}
+
+impl PubSub {
+    /// Subscribes the connection `id` to `channel`. Subscribing twice is a no-op.
+    /// `killed` is cancelled when the connection's output buffer fills up.
+    pub fn subscribe(
+        &self,
+        channel: String,
+        id: SocketAddr,
+        tx: Sender<Vec<RespDataType>>,
+        killed: CancellationToken,
+    ) {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel)
+            .or_default()
+            .entry(id)
+            .or_insert(Subscriber { tx, killed });
+    }
+
+    /// Unsubscribes the connection `id` from `channel`.
+    pub fn unsubscribe(&self, channel: &str, id: SocketAddr) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Sends `message` to every subscriber of `channel`, returning how many received it.
+    /// Subscribers whose output buffer is full are disconnected instead, since waiting
+    /// for them would hold up the publisher.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get(channel) else {
+            return 0;
+        };
+
+        subscribers
+            .iter()
+            .filter(|(id, subscriber)| {
+                let frame = RespDataType::Array(vec![
+                    RespDataType::BulkString("message".into()),
+                    RespDataType::BulkString(channel.to_string().into()),
+                    RespDataType::BulkString(message.to_string().into()),
+                ]);
+                match subscriber.tx.try_send(vec![frame]) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        info!("Closing subscriber {} over its output buffer limit", id);
+                        subscriber.killed.cancel();
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                }
+            })
+            .count()
+    }
+}
+
+/// Classes of keyspace events, as selected by the `notify-keyspace-events` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// Type-independent commands such as DEL or COPY (`g`)
+    Generic,
+    /// String commands (`$`)
+    String,
+    /// List commands (`l`)
+    List,
+    /// Sorted set commands (`z`)
+    SortedSet,
+    /// Keys reaped because their TTL elapsed (`x`)
+    Expired,
+    /// Keys evicted to stay under `maxmemory` (`e`)
+    Evicted,
+}
+
+/// Which keyspace notifications are published. Nothing is published by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents {
+    /// Publish `<event>` on `__keyspace@<db>__:<key>` (`K`)
+    keyspace: bool,
+    /// Publish `<key>` on `__keyevent@<db>__:<event>` (`E`)
+    keyevent: bool,
+    generic: bool,
+    string: bool,
+    list: bool,
+    sorted_set: bool,
+    expired: bool,
+    evicted: bool,
+}
+
+impl KeyspaceEvents {
+    /// Whether events of `class` are published on at least one kind of channel.
+    pub fn is_enabled(&self, class: EventClass) -> bool {
+        let class_enabled = match class {
+            EventClass::Generic => self.generic,
+            EventClass::String => self.string,
+            EventClass::List => self.list,
+            EventClass::SortedSet => self.sorted_set,
+            EventClass::Expired => self.expired,
+            EventClass::Evicted => self.evicted,
+        };
+        class_enabled && (self.keyspace || self.keyevent)
+    }
+
+    /// Publishes `event` on `key` of database `db` to the enabled channels.
+    pub fn notify(&self, pubsub: &PubSub, class: EventClass, event: &str, key: &str, db: usize) {
+        if !self.is_enabled(class) {
+            return;
+        }
+        if self.keyspace {
+            pubsub.publish(&format!("__keyspace@{db}__:{key}"), event);
+        }
+        if self.keyevent {
+            pubsub.publish(&format!("__keyevent@{db}__:{event}"), key);
+        }
+    }
+}
+
+impl FromStr for KeyspaceEvents {
+    type Err = anyhow::Error;
+
+    /// Parses Redis' flag syntax, e.g. `KEA` or `Ex`.
+    fn from_str(flags: &str) -> Result<Self, Self::Err> {
+        let mut events = KeyspaceEvents::default();
+        for flag in flags.chars() {
+            match flag {
+                'K' => events.keyspace = true,
+                'E' => events.keyevent = true,
+                'g' => events.generic = true,
+                '$' => events.string = true,
+                'l' => events.list = true,
+                'z' => events.sorted_set = true,
+                'x' => events.expired = true,
+                'e' => events.evicted = true,
+                'A' => {
+                    events.generic = true;
+                    events.string = true;
+                    events.list = true;
+                    events.sorted_set = true;
+                    events.expired = true;
+                    events.evicted = true;
+                }
+                _ => bail!("Invalid keyspace event flag '{flag}'"),
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[test]
+    fn test_parse_keyspace_event_flags() {
+        let events: KeyspaceEvents = "Ex".parse().unwrap();
+        assert!(events.is_enabled(EventClass::Expired));
+        assert!(!events.is_enabled(EventClass::String));
+
+        let events: KeyspaceEvents = "KA".parse().unwrap();
+        assert!(events.is_enabled(EventClass::List));
+
+        // Classes alone publish nothing without K or E
+        let events: KeyspaceEvents = "g$".parse().unwrap();
+        assert!(!events.is_enabled(EventClass::Generic));
+
+        assert!("Kz?".parse::<KeyspaceEvents>().is_err());
+    }
+
+    #[test]
+    fn test_publish_counts_receivers() {
+        let pubsub = PubSub::default();
+        let (tx, mut rx) = channel(4);
+        let killed = CancellationToken::new();
+        pubsub.subscribe("news".into(), "127.0.0.1:1".parse().unwrap(), tx, killed);
+
+        assert_eq!(pubsub.publish("news", "hello"), 1);
+        assert_eq!(pubsub.publish("other", "hello"), 0);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            vec![RespDataType::Array(vec![
+                RespDataType::BulkString("message".into()),
+                RespDataType::BulkString("news".into()),
+                RespDataType::BulkString("hello".into()),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_publish_disconnects_subscribers_that_fall_behind() {
+        let pubsub = PubSub::default();
+        let (tx, _rx) = channel(1);
+        let killed = CancellationToken::new();
+        pubsub.subscribe(
+            "news".into(),
+            "127.0.0.1:1".parse().unwrap(),
+            tx,
+            killed.clone(),
+        );
+
+        assert_eq!(pubsub.publish("news", "first"), 1);
+        assert!(!killed.is_cancelled());
+        assert_eq!(pubsub.publish("news", "second"), 0);
+        assert!(killed.is_cancelled());
+    }
+}
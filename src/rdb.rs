@@ -0,0 +1,370 @@
+//! Reading and writing of RDB snapshots, Redis' binary dump format.
+//!
+//! A snapshot is the `REDIS` magic and a 4-digit version, followed by one section per
+//! non-empty database and an end marker:
+//!
+//! ```text
+//! REDIS0011
+//! FE <db>  FB <keys> <keys with TTL>      select db, resize hint
+//! [FC <unix ms, u64 LE>] <type> <key> <value>
+//! ...
+//! FF <checksum, u64 LE>
+//! ```
+//!
+//! Only string, list and sorted set values can be stored. The checksum is written as 0, which
+//! Redis reads as "checksum disabled".
+
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+const MAGIC: &[u8] = b"REDIS";
+const VERSION: &[u8] = b"0011";
+
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+/// Sorted set with binary double scores
+const TYPE_ZSET_2: u8 = 5;
+
+/// A value stored in a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<String>),
+    /// Members with their scores
+    SortedSet(Vec<(String, f64)>),
+}
+
+/// A key of a snapshot along with its database, value and deadline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdbEntry {
+    pub db: usize,
+    pub key: String,
+    pub value: RdbValue,
+    /// Unix time in milliseconds at which the key expires
+    pub expires_at_ms: Option<u64>,
+}
+
+/// Serializes `entries` into an RDB snapshot. Entries of the same database must be
+/// contiguous.
+pub fn encode(entries: &[RdbEntry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(VERSION);
+
+    for section in entries.chunk_by(|a, b| a.db == b.db) {
+        let expiring = section.iter().filter(|e| e.expires_at_ms.is_some()).count();
+        buf.push(OPCODE_SELECTDB);
+        write_length(&mut buf, section[0].db as u64);
+        buf.push(OPCODE_RESIZEDB);
+        write_length(&mut buf, section.len() as u64);
+        write_length(&mut buf, expiring as u64);
+
+        for entry in section {
+            if let Some(at) = entry.expires_at_ms {
+                buf.push(OPCODE_EXPIRETIME_MS);
+                buf.extend_from_slice(&at.to_le_bytes());
+            }
+            buf.push(match entry.value {
+                RdbValue::String(_) => TYPE_STRING,
+                RdbValue::List(_) => TYPE_LIST,
+                RdbValue::SortedSet(_) => TYPE_ZSET_2,
+            });
+            write_string(&mut buf, &entry.key);
+            write_value(&mut buf, &entry.value);
+        }
+    }
+
+    buf.push(OPCODE_EOF);
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    buf
+}
+
+/// Returns the number of bytes `value` takes in a snapshot, as DEBUG OBJECT reports.
+pub fn serialized_len(value: &RdbValue) -> usize {
+    let mut buf = Vec::new();
+    write_value(&mut buf, value);
+    buf.len()
+}
+
+/// Parses an RDB snapshot back into its entries.
+pub fn decode(bytes: &[u8]) -> Result<Vec<RdbEntry>> {
+    let mut reader = Reader { buf: bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("Not an RDB file");
+    }
+    let version = reader.take(VERSION.len())?;
+    if !version.iter().all(u8::is_ascii_digit) {
+        bail!("Invalid RDB version");
+    }
+
+    let mut entries = Vec::new();
+    let mut db = 0;
+    let mut expires_at_ms = None;
+
+    loop {
+        match reader.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => db = reader.length()? as usize,
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expires_at_ms = Some(u64::from_le_bytes(reader.take(8)?.try_into()?))
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.take(4)?.try_into()?);
+                expires_at_ms = Some(secs as u64 * 1000);
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FREQ => {
+                reader.u8()?;
+            }
+            OPCODE_MODULE_AUX => bail!("RDB module data is not supported"),
+            value_type => {
+                let key = reader.string()?;
+                let value = match value_type {
+                    TYPE_STRING => RdbValue::String(reader.bytes()?),
+                    TYPE_LIST => {
+                        let len = reader.length()?;
+                        let elements = (0..len)
+                            .map(|_| reader.string())
+                            .collect::<Result<Vec<String>>>()?;
+                        RdbValue::List(elements)
+                    }
+                    TYPE_ZSET_2 => {
+                        let len = reader.length()?;
+                        let members = (0..len)
+                            .map(|_| {
+                                let member = reader.string()?;
+                                let score = f64::from_le_bytes(reader.take(8)?.try_into()?);
+                                Ok((member, score))
+                            })
+                            .collect::<Result<Vec<(String, f64)>>>()?;
+                        RdbValue::SortedSet(members)
+                    }
+                    _ => bail!("Unsupported RDB value type {value_type}"),
+                };
+                entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expires_at_ms: expires_at_ms.take(),
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Writes `entries` to the snapshot at `path`. The snapshot is written to a temporary
+/// file first so that a crash never leaves a truncated file behind.
+pub fn save(path: &Path, entries: &[RdbEntry]) -> Result<()> {
+    let tmp = path.with_extension("rdb.tmp");
+    let mut file =
+        fs::File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(&encode(entries))?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Reads the snapshot at `path`, returning no entries if the file does not exist.
+pub fn load(path: &Path) -> Result<Vec<RdbEntry>> {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes).with_context(|| format!("Invalid RDB file {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&(len as u16 | 0x4000).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &RdbValue) {
+    match value {
+        RdbValue::String(value) => write_bytes(buf, value),
+        RdbValue::List(elements) => {
+            write_length(buf, elements.len() as u64);
+            for element in elements {
+                write_string(buf, element);
+            }
+        }
+        RdbValue::SortedSet(members) => {
+            write_length(buf, members.len() as u64);
+            for (member, score) in members {
+                write_string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_bytes(buf, s.as_bytes());
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_length(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// A length-prefixed field: either a length or, for strings, an integer encoding.
+enum Length {
+    Len(u64),
+    /// The string is an integer stored in this many little-endian bytes
+    Int(usize),
+    Compressed,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.buf.get(self.pos..self.pos + n) else {
+            bail!("Unexpected end of RDB file");
+        };
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0b00 => Length::Len((first & 0x3F) as u64),
+            0b01 => Length::Len(((first & 0x3F) as u64) << 8 | self.u8()? as u64),
+            0b10 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.take(4)?.try_into()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.take(8)?.try_into()?)),
+                _ => bail!("Invalid RDB length encoding {first:#x}"),
+            },
+            _ => match first & 0x3F {
+                0 => Length::Int(1),
+                1 => Length::Int(2),
+                2 => Length::Int(4),
+                3 => Length::Compressed,
+                _ => bail!("Invalid RDB string encoding {first:#x}"),
+            },
+        })
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            _ => bail!("Expected an RDB length"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).context("RDB string is not UTF-8")
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(self.take(len as usize)?.to_vec()),
+            Length::Int(width) => {
+                let bytes = self.take(width)?;
+                let value = match width {
+                    1 => bytes[0] as i8 as i64,
+                    2 => i16::from_le_bytes(bytes.try_into()?) as i64,
+                    _ => i32::from_le_bytes(bytes.try_into()?) as i64,
+                };
+                Ok(value.to_string().into_bytes())
+            }
+            Length::Compressed => bail!("LZF-compressed RDB strings are not supported"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: "greeting".into(),
+                value: RdbValue::String(b"hello".to_vec()),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: "session".into(),
+                value: RdbValue::String(vec![0xff; 20_000]),
+                expires_at_ms: Some(1_956_528_000_000),
+            },
+            RdbEntry {
+                db: 3,
+                key: "queue".into(),
+                value: RdbValue::List(vec!["a".into(), "b".into()]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 3,
+                key: "leaderboard".into(),
+                value: RdbValue::SortedSet(vec![
+                    ("ann".into(), -1.5),
+                    ("bob".into(), f64::INFINITY),
+                ]),
+                expires_at_ms: None,
+            },
+        ];
+
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_decode_empty_snapshot_with_aux_fields() {
+        let empty = include_bytes!("../empty.rdb");
+        assert_eq!(decode(empty).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_file() {
+        let entries = vec![RdbEntry {
+            db: 0,
+            key: "k".into(),
+            value: RdbValue::String(b"v".to_vec()),
+            expires_at_ms: None,
+        }];
+        let bytes = encode(&entries);
+        assert!(decode(&bytes[..bytes.len() - 10]).is_err());
+    }
+}
@@ -7,18 +7,34 @@ use anyhow::{bail, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-pub struct RespCodec;
+#[derive(Default)]
+pub struct RespCodec {
+    /// Set by a replica after FULLRESYNC: the next frame is an RDB payload
+    /// (`$<length>\r\n<contents>` with no trailing CRLF) rather than a RESP value.
+    expect_rdb: bool,
+}
 
-impl RespCodec {}
+impl RespCodec {
+    /// Makes the next decoded frame an [`RespDataType::RdbFile`], after which
+    /// decoding resumes as regular RESP.
+    pub fn expect_rdb(&mut self) {
+        self.expect_rdb = true;
+    }
+}
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RespDataType {
-    BulkString(String),
+    /// A binary-safe string
+    BulkString(Bytes),
     NullBulkString,
+    NullArray,
     SimpleError(String),
     Array(Vec<RespDataType>),
     SimpleString(String),
     Integer(i64),
+    /// An RDB snapshot sent to a replica after FULLRESYNC.
+    /// Framed like a bulk string but without the trailing CRLF: `$<length>\r\n<contents>`
+    RdbFile(Bytes),
 }
 
 const SIMPLE_STRING_BYTE: u8 = b'+';
@@ -42,13 +58,27 @@ impl Decoder for RespCodec {
             return Ok(None);
         }
 
+        if self.expect_rdb {
+            let rdb = parse_rdb_file(src)?;
+            if rdb.is_some() {
+                self.expect_rdb = false;
+            }
+            return Ok(rdb);
+        }
+
         match src[0] {
             SIMPLE_STRING_BYTE => parse_simple_string(src),
-            ARRAY_BYTE => parse_array(src),
+            // Arrays are parsed element by element, so only start once the whole
+            // frame is buffered to avoid consuming a partial command.
+            ARRAY_BYTE => match complete_frame_len(src)? {
+                Some(_) => parse_array(src),
+                None => Ok(None),
+            },
             BULK_STRING_BYTE => parse_bulk_string(src),
             INTEGER_BYTE => parse_integer(src),
+            ERROR_BYTE => parse_simple_errors(src),
 
-            _ => Err(Error::new(ErrorKind::InvalidData, "Unknown RESP type byte")),
+            byte => Err(unexpected_byte(ARRAY_BYTE, byte)),
         }
     }
 }
@@ -78,10 +108,61 @@ fn parse_integer(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Er
     }
 }
 
-fn find_crlf(src: &BytesMut) -> Option<usize> {
+fn find_crlf(src: &[u8]) -> Option<usize> {
     src.windows(2).position(|window| window == CRLF)
 }
 
+/// Returns the length in bytes of the frame at the start of `src` if it has been
+/// fully received, or `None` if more data is needed. Nothing is consumed.
+fn complete_frame_len(src: &[u8]) -> Result<Option<usize>, std::io::Error> {
+    let Some(crlf_pos) = find_crlf(src) else {
+        return Ok(None);
+    };
+    let header_len = crlf_pos + CRLF.len();
+
+    let parse_len = || -> Result<isize, std::io::Error> {
+        from_utf8(&src[1..crlf_pos])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid length format"))
+    };
+
+    match src[0] {
+        SIMPLE_STRING_BYTE | ERROR_BYTE | INTEGER_BYTE => Ok(Some(header_len)),
+        BULK_STRING_BYTE => match parse_len()? {
+            len if len < 0 => Ok(Some(header_len)),
+            len => {
+                let frame_len = header_len + len as usize + CRLF.len();
+                Ok((src.len() >= frame_len).then_some(frame_len))
+            }
+        },
+        ARRAY_BYTE => {
+            let num_elements = parse_len()?;
+            let mut frame_len = header_len;
+            for _ in 0..num_elements.max(0) {
+                match complete_frame_len(&src[frame_len..])? {
+                    Some(element_len) => frame_len += element_len,
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(frame_len))
+        }
+        byte => Err(unexpected_byte(BULK_STRING_BYTE, byte)),
+    }
+}
+
+/// Redis' protocol error for a frame starting with `got` where `expected` was due.
+fn unexpected_byte(expected: u8, got: u8) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "ERR Protocol error: expected '{}', got '{}'",
+            expected as char,
+            got.escape_ascii()
+        ),
+    )
+}
+
 fn parse_simple_string(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Error> {
     if let Some(crlf_pos) = find_crlf(src) {
         // A simple string like "+\r\n" should be an error because it has no content.
@@ -105,7 +186,6 @@ fn parse_simple_string(src: &mut BytesMut) -> Result<Option<RespDataType>, std::
     }
 }
 
-#[allow(dead_code)]
 fn parse_simple_errors(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Error> {
     if let Some(crlf_pos) = find_crlf(src) {
         // A simple string like "+\r\n" should be an error because it has no content.
@@ -157,6 +237,7 @@ fn parse_bulk_string(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io
             .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid bulk string length format"))?;
 
         if length == -1 {
+            src.advance(crlf_pos + CRLF.len());
             return Ok(Some(RespDataType::NullBulkString));
         }
 
@@ -164,24 +245,51 @@ fn parse_bulk_string(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io
         if src.len() < (crlf_pos + CRLF.len()) + data_len + CRLF.len() {
             return Ok(None);
         }
+        let data_end = crlf_pos + CRLF.len() + data_len;
+        if &src[data_end..data_end + CRLF.len()] != CRLF {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Bulk string data not terminated by CRLF",
+            ));
+        }
         src.advance(crlf_pos + CRLF.len());
 
-        let content = from_utf8(&src[0..data_len])
-            .map_err(|_| {
-                Error::new(
-                    ErrorKind::InvalidData,
-                    "Invalid UTF-8 in bulk string length",
-                )
-            })?
-            .to_string();
-
-        src.advance(data_len + 2);
+        let content = src.split_to(data_len).freeze();
+        src.advance(CRLF.len());
         Ok(Some(RespDataType::BulkString(content)))
     } else {
         Ok(None)
     }
 }
 
+// After FULLRESYNC the master sends its snapshot framed as $<length>\r\n<contents>,
+// like a bulk string but without the final CRLF, so exactly <length> bytes are consumed
+// and whatever follows is the start of the replication command stream.
+fn parse_rdb_file(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Error> {
+    if src[0] != BULK_STRING_BYTE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Expected '$' at the start of the RDB payload",
+        ));
+    }
+
+    if let Some(crlf_pos) = find_crlf(src) {
+        let length: usize = from_utf8(&src[1..crlf_pos])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid RDB payload length"))?;
+
+        if src.len() < crlf_pos + CRLF.len() + length {
+            return Ok(None);
+        }
+        src.advance(crlf_pos + CRLF.len());
+        let contents = src.split_to(length).freeze();
+        Ok(Some(RespDataType::RdbFile(contents)))
+    } else {
+        Ok(None)
+    }
+}
+
 // Clients send commands to the Redis server as RESP arrays. Similarly, some Redis commands that return collections of elements use arrays as their replies. An example is the LRANGE command that returns elements of a list.
 //
 // RESP Arrays' encoding uses the following format:
@@ -211,7 +319,8 @@ fn parse_array(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Erro
             .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid bulk string length format"))?;
 
         if num_elements == -1 {
-            todo!("implement null array data type");
+            src.advance(crlf_pos + CRLF.len());
+            return Ok(Some(RespDataType::NullArray));
         }
 
         let num_elements = num_elements as usize;
@@ -251,7 +360,21 @@ fn parse_array(src: &mut BytesMut) -> Result<Option<RespDataType>, std::io::Erro
                         return Ok(None);
                     }
                 }
-                _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid RESP data type")),
+                INTEGER_BYTE => {
+                    if let Some(integer) = parse_integer(src)? {
+                        array.push(integer);
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                ERROR_BYTE => {
+                    if let Some(error) = parse_simple_errors(src)? {
+                        array.push(error);
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                byte => return Err(unexpected_byte(BULK_STRING_BYTE, byte)),
             }
         }
 
@@ -274,7 +397,20 @@ impl Encoder<RespDataType> for RespCodec {
 impl RespDataType {
     pub fn get_str(&self) -> anyhow::Result<String> {
         match self {
-            RespDataType::BulkString(s) | RespDataType::SimpleString(s) => Ok(s.clone()),
+            RespDataType::BulkString(s) => match std::str::from_utf8(s) {
+                Ok(s) => Ok(s.to_string()),
+                Err(_) => bail!("Expected a UTF-8 string"),
+            },
+            RespDataType::SimpleString(s) => Ok(s.clone()),
+            _ => bail!("Expected string type"),
+        }
+    }
+
+    /// Returns the raw contents of a string, which need not be UTF-8.
+    pub fn get_bytes(&self) -> anyhow::Result<Bytes> {
+        match self {
+            RespDataType::BulkString(s) => Ok(s.clone()),
+            RespDataType::SimpleString(s) => Ok(Bytes::from(s.clone())),
             _ => bail!("Expected string type"),
         }
     }
@@ -304,7 +440,7 @@ impl RespDataType {
                 buf.put_u8(BULK_STRING_BYTE);
                 buf.put_slice(len_bytes.as_bytes());
                 buf.put_slice(CRLF);
-                buf.put_slice(s.as_bytes());
+                buf.put_slice(s);
                 buf.put_slice(CRLF);
                 buf.freeze()
             }
@@ -337,6 +473,23 @@ impl RespDataType {
                 buf.put_slice(CRLF);
                 buf.freeze()
             }
+            RespDataType::NullArray => {
+                let mut buf = BytesMut::with_capacity(1 + 2 + CRLF.len());
+                buf.put_u8(ARRAY_BYTE);
+                buf.put_slice(b"-1");
+                buf.put_slice(CRLF);
+                buf.freeze()
+            }
+            RespDataType::RdbFile(contents) => {
+                let len_bytes = contents.len().to_string();
+                let len = 1 + len_bytes.len() + CRLF.len() + contents.len(); // '$' + len + \r\n + data
+                let mut buf = BytesMut::with_capacity(len);
+                buf.put_u8(BULK_STRING_BYTE);
+                buf.put_slice(len_bytes.as_bytes());
+                buf.put_slice(CRLF);
+                buf.put_slice(contents);
+                buf.freeze()
+            }
             Self::Integer(int) => {
                 let int_str = int.to_string();
                 let len = 1 + int_str.len() + CRLF.len();
@@ -448,13 +601,73 @@ mod tests {
         assert!(buf.is_empty());
     }
 
+    #[test]
+    fn test_parse_bulk_string_bad_terminator() {
+        let mut buf = bytes_from_str("$3\r\nhey!!");
+        let result = parse_bulk_string(&mut buf);
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_empty_bulk_string() {
+        let mut buf = bytes_from_str("$0\r\n\r\n");
+        let result = parse_bulk_string(&mut buf).unwrap();
+        assert_eq!(result, Some(RespDataType::BulkString("".into())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_empty_bulk_string_not_enough_data() {
+        let mut buf = bytes_from_str("$0\r\n");
+        let result = parse_bulk_string(&mut buf).unwrap();
+        assert!(result.is_none());
+        // Buffer should not be consumed
+        assert_eq!(buf.to_vec(), b"$0\r\n");
+    }
+
+    #[test]
+    fn test_decode_partial_array_keeps_buffer() {
+        let mut codec = RespCodec::default();
+        let mut buf = bytes_from_str("*2\r\n$4\r\nECHO\r\n$0\r\n");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.to_vec(), b"*2\r\n$4\r\nECHO\r\n$0\r\n");
+
+        buf.extend_from_slice(b"\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespDataType::Array(vec![
+                RespDataType::BulkString("ECHO".into()),
+                RespDataType::BulkString("".into()),
+            ]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_names_unexpected_byte() {
+        let mut codec = RespCodec::default();
+        let mut buf = bytes_from_str("*1\r\n$4\r\nPING\r\nX");
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "ERR Protocol error: expected '*', got 'X'");
+
+        let mut buf = bytes_from_str("*1\r\n\x01\r\n");
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR Protocol error: expected '$', got '\\x01'"
+        );
+    }
+
     #[test]
     fn test_parse_array() {
         let mut buf = bytes_from_str("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n");
         let result = parse_array(&mut buf).unwrap();
         if let Some(RespDataType::Array(array)) = result {
-            assert_eq!(array[0], RespDataType::BulkString("ECHO".to_string()));
-            assert_eq!(array[1], RespDataType::BulkString("hey".to_string()));
+            assert_eq!(array[0], RespDataType::BulkString("ECHO".into()));
+            assert_eq!(array[1], RespDataType::BulkString("hey".into()));
         } else {
             panic!("Expected array");
         }
@@ -467,8 +680,8 @@ mod tests {
         let mut buf = bytes_from_str("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n");
         let result = parse_array(&mut buf).unwrap();
         if let Some(RespDataType::Array(array)) = result {
-            assert_eq!(array[0], RespDataType::BulkString("ECHO".to_string()));
-            assert_eq!(array[1], RespDataType::BulkString("hey".to_string()));
+            assert_eq!(array[0], RespDataType::BulkString("ECHO".into()));
+            assert_eq!(array[1], RespDataType::BulkString("hey".into()));
         } else {
             panic!("Expected array");
         }
@@ -491,7 +704,7 @@ mod tests {
     #[test]
     fn test_encoded_bulk_str() {
         let expected_bytes = bytes_from_str("$4\r\nECHO\r\n");
-        let resp_data_type = RespDataType::BulkString("ECHO".to_string());
+        let resp_data_type = RespDataType::BulkString("ECHO".into());
 
         assert_eq!(resp_data_type.as_bytes(), expected_bytes)
     }
@@ -499,8 +712,8 @@ mod tests {
     fn test_encoded_array() {
         let expected_bytes = bytes_from_str("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n");
         let resp_data_type = RespDataType::Array(vec![
-            RespDataType::BulkString("ECHO".to_string()),
-            RespDataType::BulkString("hey".to_string()),
+            RespDataType::BulkString("ECHO".into()),
+            RespDataType::BulkString("hey".into()),
         ]);
 
         assert_eq!(resp_data_type.as_bytes(), expected_bytes)
@@ -522,6 +735,65 @@ mod tests {
         assert_eq!(resp_data_type.as_bytes(), expected_bytes)
     }
 
+    #[test]
+    fn test_encoded_rdb_file() {
+        let resp_data_type = RespDataType::RdbFile(Bytes::from_static(b"REDIS0011"));
+
+        assert_eq!(resp_data_type.as_bytes(), bytes_from_str("$9\r\nREDIS0011"))
+    }
+
+    #[test]
+    fn test_null_array_round_trip() {
+        let encoded = RespDataType::NullArray.as_bytes();
+        assert_eq!(encoded, bytes_from_str("*-1\r\n"));
+
+        let mut buf = BytesMut::from(&encoded[..]);
+        assert_eq!(
+            parse_array(&mut buf).unwrap(),
+            Some(RespDataType::NullArray)
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_rdb_file_after_expect_rdb() {
+        let mut codec = RespCodec::default();
+        codec.expect_rdb();
+
+        let mut buf = bytes_from_str("$5\r\nREDIS");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespDataType::RdbFile(Bytes::from_static(b"REDIS")))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_command_right_after_rdb_file() {
+        let mut codec = RespCodec::default();
+        codec.expect_rdb();
+
+        // The payload may itself contain CRLFs, only its length marks its end
+        let mut buf = bytes_from_str("$9\r\nREDIS\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 11, "a partial payload must not be consumed");
+
+        buf.extend_from_slice(b"00*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespDataType::RdbFile(Bytes::from_static(b"REDIS\r\n00")))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespDataType::Array(vec![
+                RespDataType::BulkString("SET".into()),
+                RespDataType::BulkString("k".into()),
+                RespDataType::BulkString("v".into()),
+            ]))
+        );
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_encoded_integer() {
         let expected_bytes = bytes_from_str(":-1\r\n");
@@ -1,21 +1,89 @@
-use crate::config::ServerConfig;
+use crate::acl::{Acl, DEFAULT_USER};
+use crate::aof::{self, Aof};
+use crate::clients::Clients;
+use crate::config::{Config, ServerConfig};
+use crate::rdb;
 use crate::resp::{RespCodec, RespDataType};
-use crate::{cmd::Command, storage::StorageHandle};
+use crate::{
+    cmd::{
+        command_reply, AclSubcommand, ClientSubcommand, Command, CommandError, ConfigSubcommand,
+        DebugSubcommand, ListEnd, ReplConf, Section,
+    },
+    pubsub::PubSub,
+    storage::{StorageConfig, StorageHandle, NUM_DATABASES},
+};
 use anyhow::{Context, Result};
-use futures::{SinkExt, StreamExt};
-use std::collections::VecDeque;
-use std::fmt;
-use std::sync::{Arc, RwLock};
-use tokio::io::AsyncWriteExt;
+use bytes::Bytes;
+use futures::stream::{Peekable, SplitSink, SplitStream};
+use futures::{FutureExt, SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Receiver, Sender, UnboundedReceiver};
+use tokio::task::JoinSet;
 use tokio_util::codec::Framed;
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// How long shutting down waits for the open connections to finish their current
+/// command before dropping them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Batches of frames that may wait for the writer task of a connection, and replies
+/// held in a batch at most. Replies wait for room, which stops the connection from
+/// reading more commands, while a Pub/Sub subscriber that falls this far behind is
+/// disconnected.
+const OUTPUT_BUFFER_FRAMES: usize = 1024;
+
+/// Shuts a running [`RedisServer`] down, from the outside or through SHUTDOWN.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    /// Whether the RDB snapshot is saved on the way out, overriding the `save` config
+    save: Arc<Mutex<Option<bool>>>,
+}
+
+impl ShutdownHandle {
+    /// Shuts the server down, saving the RDB snapshot if `save` points are configured.
+    pub fn shutdown(&self) {
+        self.shutdown_with(None);
+    }
+
+    /// Shuts the server down, saving the RDB snapshot if `save` is set, never if it is
+    /// unset, and as configured when `None`.
+    pub fn shutdown_with(&self, save: Option<bool>) {
+        *self.save.lock().unwrap() = save;
+        self.token.cancel();
+    }
+
+    /// Completes once the server starts shutting down.
+    async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+}
 
 /// Represents a Redis server that handles client connections
 pub struct RedisServer {
     listener: TcpListener,
+    /// Port announced to the master with REPLCONF listening-port
+    port: u16,
     storage: StorageHandle,
+    pubsub: PubSub,
+    config: Arc<RwLock<Config>>,
     server_info: Arc<RwLock<ServerInfo>>,
+    /// Makes [`run`](Self::run) stop accepting and return
+    shutdown: ShutdownHandle,
+    /// Id handed to the next accepted connection
+    next_client_id: AtomicU64,
+    clients: Clients,
+    /// Number of connections being served, checked against `maxclients`
+    connected_clients: Arc<AtomicUsize>,
+    acl: Arc<RwLock<Acl>>,
 }
 
 impl RedisServer {
@@ -25,13 +93,56 @@ impl RedisServer {
             .await
             .context("Failed to bind to address")?;
 
-        let storage = StorageHandle::new();
-        let server_info = Arc::new(RwLock::new(ServerInfo::from(config)));
+        let mut runtime_config = Config::default();
+        runtime_config.dir = config.dir.clone();
+        runtime_config.dbfilename = config.dbfilename.clone();
+        runtime_config.appendonly = config.appendonly;
+        runtime_config.appendfsync = config.appendfsync;
+        runtime_config.appendfilename = config.appendfilename.clone();
+
+        let aof = if config.appendonly {
+            Some(Aof::open(&runtime_config.aof_path(), config.appendfsync)?)
+        } else {
+            None
+        };
+
+        let server_info = Arc::new(RwLock::new(ServerInfo::from(&config)));
+        let pubsub = PubSub::default();
+        let storage = StorageHandle::with_config(StorageConfig {
+            pubsub: pubsub.clone(),
+            keyspace_events: config.notify_keyspace_events,
+            aof,
+            server_info: Some(server_info.clone()),
+            ..Default::default()
+        });
+
+        // A replica gets its dataset from its master instead
+        if config.replica_of.is_none() {
+            // The AOF holds every write, so it takes precedence over the snapshot
+            if config.appendonly {
+                let path = runtime_config.aof_path();
+                let applied = aof::replay(&path, &storage).await?;
+                info!("Replayed {} writes from {}", applied, path.display());
+            } else {
+                let path = runtime_config.rdb_path();
+                let entries = rdb::load(&path)?;
+                info!("Loaded {} keys from {}", entries.len(), path.display());
+                storage.restore(entries).await;
+            }
+        }
 
         Ok(Self {
             listener,
+            port: config.port,
             storage,
+            pubsub,
+            config: Arc::new(RwLock::new(runtime_config)),
             server_info,
+            shutdown: ShutdownHandle::default(),
+            next_client_id: AtomicU64::new(1),
+            clients: Clients::default(),
+            connected_clients: Arc::default(),
+            acl: Arc::default(),
         })
     }
 
@@ -42,11 +153,17 @@ impl RedisServer {
     /// 2. REPLCONF listening-port <port> - Inform master of our listening port
     /// 3. REPLCONF capa psync2 - Announce PSYNC2 capability
     /// 4. PSYNC ? -1 - Request full synchronization
-    async fn perform_replication_handshake(&self, addr: &str) -> Result<()> {
+    ///
+    /// Returns the connection to the master, positioned right after the RDB snapshot,
+    /// from which the replicated command stream is read.
+    async fn perform_replication_handshake(
+        &self,
+        addr: &str,
+    ) -> Result<Framed<TcpStream, RespCodec>> {
         let stream = TcpStream::connect(addr)
             .await
             .context("Failed to connect to master")?;
-        let mut framed = Framed::new(stream, RespCodec);
+        let mut framed = Framed::new(stream, RespCodec::default());
 
         info!("Starting replication handshake with master at {}", addr);
 
@@ -66,7 +183,7 @@ impl RedisServer {
         debug!("Received PING response: {:?}", response);
 
         // Step 2: Send REPLCONF commands
-        self.send_replconf(&mut framed, "listening-port", "6380")
+        self.send_replconf(&mut framed, "listening-port", &self.port.to_string())
             .await
             .context("Failed to send listening-port REPLCONF")?;
 
@@ -80,7 +197,7 @@ impl RedisServer {
             .context("Failed to send PSYNC")?;
 
         info!("Replication handshake completed successfully");
-        Ok(())
+        Ok(framed)
     }
 
     /// Sends a PSYNC command to request synchronization with the master
@@ -89,9 +206,9 @@ impl RedisServer {
     /// previous replication state (? for unknown replication ID, -1 for unknown offset).
     async fn send_psync(&self, framed: &mut Framed<TcpStream, RespCodec>) -> Result<()> {
         let psync = RespDataType::Array(vec![
-            RespDataType::BulkString("PSYNC".to_string()),
-            RespDataType::BulkString("?".to_string()),
-            RespDataType::BulkString("-1".to_string()),
+            RespDataType::BulkString("PSYNC".into()),
+            RespDataType::BulkString("?".into()),
+            RespDataType::BulkString("-1".into()),
         ]);
 
         framed
@@ -106,6 +223,18 @@ impl RedisServer {
             .context("Failed to decode PSYNC response")?;
 
         info!("Connected to master, PSYNC response: {:?}", response);
+
+        // The FULLRESYNC reply is followed by the master's RDB snapshot
+        framed.codec_mut().expect_rdb();
+        let rdb = framed
+            .next()
+            .await
+            .context("No RDB payload from master after FULLRESYNC")?
+            .context("Failed to decode RDB payload")?;
+
+        if let RespDataType::RdbFile(contents) = rdb {
+            info!("Received RDB file ({} bytes) from master", contents.len());
+        }
         Ok(())
     }
 
@@ -120,9 +249,9 @@ impl RedisServer {
         value: &str,
     ) -> Result<()> {
         let replconf = RespDataType::Array(vec![
-            RespDataType::BulkString("REPLCONF".to_string()),
-            RespDataType::BulkString(key.to_string()),
-            RespDataType::BulkString(value.to_string()),
+            RespDataType::BulkString("REPLCONF".into()),
+            RespDataType::BulkString(key.to_string().into()),
+            RespDataType::BulkString(value.to_string().into()),
         ]);
 
         framed
@@ -140,7 +269,22 @@ impl RedisServer {
         Ok(())
     }
 
-    /// Starts the server and begins accepting connections
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Returns a handle to shut the server down with
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Starts the server and accepts connections until shut down through
+    /// [`shutdown_handle`](Self::shutdown_handle).
+    ///
+    /// Shutting down stops accepting right away. Open connections are closed once done
+    /// with their current command, or dropped after `SHUTDOWN_TIMEOUT`. The AOF is then
+    /// flushed and the RDB snapshot saved if configured.
     pub async fn run(self) -> Result<()> {
         {
             println!(
@@ -150,27 +294,138 @@ impl RedisServer {
             );
         }
 
-        let info = self.server_info.read().unwrap();
-        if let ServerRole::Slave { addr } = &info.role {
-            self.perform_replication_handshake(addr).await?
+        let master_addr = match &self.server_info.read().unwrap().role {
+            ServerRole::Slave { addr } => Some(addr.clone()),
+            ServerRole::Master => None,
+        };
+        if let Some(addr) = master_addr {
+            let master = self.perform_replication_handshake(&addr).await?;
+            let storage = self.storage.clone();
+            let server_info = self.server_info.clone();
+            server_info.write().unwrap().master_link_up = true;
+            tokio::spawn(async move {
+                if let Err(e) = apply_replication_stream(master, storage).await {
+                    eprintln!("Replication link with master {} failed: {:?}", addr, e);
+                }
+                server_info.write().unwrap().master_link_up = false;
+            });
         }
 
+        let mut connections = JoinSet::new();
         loop {
-            let (socket, peer_addr) = self.listener.accept().await?;
+            let (socket, peer_addr) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                // Reap the connections that closed so they do not pile up
+                Some(_) = connections.join_next() => continue,
+                _ = self.shutdown.triggered() => break,
+            };
             println!("Accepted new connection from: {}", peer_addr);
 
-            let storage = self.storage.clone();
-            // server_info could not be shared and be asked via cmd
-            let server_info = self.server_info.clone();
+            let maxclients = self.config.read().unwrap().maxclients;
+            if self.connected_clients.load(Ordering::Relaxed) >= maxclients {
+                info!(
+                    "Rejecting {}, {} clients already connected",
+                    peer_addr, maxclients
+                );
+                connections.spawn(reject_client(socket));
+                continue;
+            }
 
-            tokio::spawn(async move {
-                let mut connection = Connection::new(socket, storage, server_info);
+            let mut connection = Connection::new(socket, peer_addr, &self);
+            connections.spawn(async move {
                 if let Err(e) = connection.handle().await {
                     eprintln!("Error handling connection from {}: {:?}", peer_addr, e);
                 }
             });
         }
+
+        info!(
+            "Shutting down, waiting for {} connections",
+            connections.len()
+        );
+        drop(self.listener);
+        let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            info!("Dropping {} connections still busy", connections.len());
+            connections.abort_all();
+        }
+
+        let save = *self.shutdown.save.lock().unwrap();
+        persist_on_shutdown(&self.storage, &self.config, save).await
+    }
+}
+
+/// Flushes the AOF and saves the RDB snapshot so that no acknowledged write is lost
+/// when the server exits. The snapshot is saved when `save` is set or, when it is
+/// `None`, if the `save` config lists any snapshotting point.
+async fn persist_on_shutdown(
+    storage: &StorageHandle,
+    config: &RwLock<Config>,
+    save: Option<bool>,
+) -> Result<()> {
+    storage.fsync_aof().await;
+
+    let (save, path) = {
+        let config = config.read().unwrap();
+        (save.unwrap_or(config.has_save_points()), config.rdb_path())
+    };
+    if save {
+        let entries = storage.snapshot().await;
+        tokio::task::spawn_blocking(move || rdb::save(&path, &entries))
+            .await
+            .context("Saving task failed")??;
+        info!("DB saved on disk");
+    }
+    Ok(())
+}
+
+/// Applies the commands propagated by the master to the local storage.
+///
+/// Commands received over the replication link are executed silently: the master
+/// expects no replies. The replication offset advances by the encoded size of every
+/// command consumed from the stream.
+async fn apply_replication_stream(
+    mut master: Framed<TcpStream, RespCodec>,
+    storage: StorageHandle,
+) -> Result<()> {
+    let mut offset = 0;
+    let mut db = 0;
+
+    while let Some(frame) = master.next().await {
+        let frame = frame.context("Failed to decode replication stream")?;
+        let frame_len = frame.as_bytes().len();
+
+        match Command::try_from(frame) {
+            // The ACK reports the bytes processed before this GETACK, which is
+            // itself accounted for once the reply has been sent.
+            Ok(Command::REPLCONF(ReplConf::GetAck)) => {
+                let ack = RespDataType::Array(vec![
+                    RespDataType::BulkString("REPLCONF".into()),
+                    RespDataType::BulkString("ACK".into()),
+                    RespDataType::BulkString(offset.to_string().into()),
+                ]);
+                master
+                    .send(ack)
+                    .await
+                    .context("Failed to send REPLCONF ACK to master")?;
+            }
+            Ok(Command::PING(_)) | Ok(Command::REPLCONF(_)) => {}
+            Ok(Command::SELECT { index }) if index < NUM_DATABASES => db = index,
+            Ok(cmd) => {
+                storage.send(db, cmd).await;
+            }
+            Err(e) => error!("Invalid command from master: {}", e),
+        }
+
+        offset += frame_len;
+        debug!("Replication offset is now {}", offset);
     }
+
+    info!("Master closed the replication link");
+    Ok(())
 }
 
 pub struct ServerInfo {
@@ -179,22 +434,51 @@ pub struct ServerInfo {
     pub connected_slaves: usize,
     //The replication ID of the master (we'll get to this in later stages)
     pub master_replid: String,
-    // The replication offset of the master (we'll get to this in later stages)
+    // The replication offset of the master: bytes of commands propagated to replicas so far
     pub master_repl_offset: usize,
+    // The last offset acknowledged by each connected replica
+    pub replica_acks: HashMap<SocketAddr, usize>,
+    // Whether the dataset is being loaded into memory
+    pub loading: bool,
+    // Whether a replica is connected to its master and applying its stream
+    pub master_link_up: bool,
+    // Whether a replica keeps serving (possibly stale) data while its master link is down
+    pub serve_stale_data: bool,
+    // Whether a BGSAVE is writing the RDB snapshot
+    pub rdb_bgsave_in_progress: bool,
+    // Port the server listens on
+    pub tcp_port: u16,
+    // When the server started, for its uptime
+    pub started_at: Instant,
 }
 impl ServerInfo {
     pub fn is_slave(&self) -> bool {
         matches!(self.role, ServerRole::Slave { addr: _ })
     }
+
+    /// Returns the error to reply with when the dataset cannot currently be served
+    pub fn unavailable_error(&self) -> Option<RespDataType> {
+        if self.loading {
+            return Some(RespDataType::SimpleError(
+                "LOADING Redis is loading the dataset in memory".into(),
+            ));
+        }
+        if self.is_slave() && !self.master_link_up && !self.serve_stale_data {
+            return Some(RespDataType::SimpleError(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                    .into(),
+            ));
+        }
+        None
+    }
 }
 
 impl fmt::Display for ServerInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.role {
-            ServerRole::Master => write!(f, "role:master")?,
-            ServerRole::Slave { .. } => write!(f, "role:slave")?,
+            ServerRole::Master => writeln!(f, "role:master")?,
+            ServerRole::Slave { .. } => writeln!(f, "role:slave")?,
         }
-        // Add other replication info fields
         writeln!(f, "connected_slaves:{}", self.connected_slaves)?;
         writeln!(f, "master_replid:{}", self.master_replid)?;
         writeln!(f, "master_repl_offset:{}", self.master_repl_offset)?;
@@ -204,15 +488,26 @@ impl fmt::Display for ServerInfo {
 
 const DEFAULT_MASTER_ID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
 
-impl From<ServerConfig> for ServerInfo {
-    fn from(cfg: ServerConfig) -> Self {
+/// Empty RDB snapshot sent to replicas after FULLRESYNC
+const EMPTY_RDB: &[u8] = include_bytes!("../empty.rdb");
+
+impl From<&ServerConfig> for ServerInfo {
+    fn from(cfg: &ServerConfig) -> Self {
         Self {
             role: cfg
                 .replica_of
+                .clone()
                 .map_or(ServerRole::Master, |addr| ServerRole::Slave { addr }),
             connected_slaves: 0,
             master_replid: DEFAULT_MASTER_ID.to_string(),
             master_repl_offset: 0,
+            replica_acks: HashMap::new(),
+            loading: false,
+            master_link_up: false,
+            serve_stale_data: true,
+            rdb_bgsave_in_progress: false,
+            tcp_port: cfg.port,
+            started_at: Instant::now(),
         }
     }
 }
@@ -225,44 +520,142 @@ pub enum ServerRole {
 
 /// Represents an individual client connection
 pub struct Connection {
-    framed: Framed<TcpStream, RespCodec>,
+    frames: Peekable<SplitStream<Framed<TcpStream, RespCodec>>>,
+    /// Frames to send to the peer, written in order by the connection's writer task.
+    /// Replies and Pub/Sub messages all go through it so they never interleave.
+    writer_tx: Sender<Vec<RespDataType>>,
+    /// Replies held until no pipelined command is waiting, to be written in one flush
+    replies: Vec<RespDataType>,
+    peer_addr: SocketAddr,
     storage: StorageHandle,
-    transaction_queue: Option<VecDeque<Command>>,
+    /// Index of the logical database selected with SELECT
+    db: usize,
+    /// Commands queued by MULTI, along with the frames they were parsed from
+    transaction_queue: Option<VecDeque<(Command, RespDataType)>>,
+    /// Set when a command failed to queue, so EXEC must abort the transaction
+    transaction_dirty: bool,
+    /// Keys marked with WATCH, with the database they belong to, along with their
+    /// version at the time they were watched
+    watched_keys: Vec<((usize, String), u64)>,
+    /// Writes to forward, set once the peer turned out to be a replica through PSYNC
+    replication_rx: Option<UnboundedReceiver<RespDataType>>,
+    pubsub: PubSub,
+    /// Channels the connection is subscribed to. While there is any, only
+    /// (un)subscribe commands are accepted.
+    subscriptions: HashSet<String>,
+    config: Arc<RwLock<Config>>,
     server_info: Arc<RwLock<ServerInfo>>,
+    /// Triggered when the server shuts down, closing the connection before its next
+    /// command
+    shutdown: ShutdownHandle,
+    /// Unique id given when the connection was accepted, for CLIENT ID
+    id: u64,
+    /// Where the connection is registered, along with its name, for CLIENT
+    clients: Clients,
+    /// Cancelled by CLIENT KILL from another connection, closing this one
+    killed: CancellationToken,
+    /// Live connection count of the server, decremented when this one is dropped
+    connected_clients: Arc<AtomicUsize>,
+    /// Whether the connection may run commands, which takes an AUTH when the default
+    /// user had a password when it was accepted
+    authenticated: bool,
+    acl: Arc<RwLock<Acl>>,
+    /// User the connection runs commands as, whose permissions are checked first
+    user: String,
 }
 
 impl Connection {
-    /// Creates a new connection with the given socket and storage handle
-    pub fn new(
-        socket: TcpStream,
-        storage: StorageHandle,
-        server_info: Arc<RwLock<ServerInfo>>,
-    ) -> Self {
-        let framed = Framed::new(socket, RespCodec);
+    /// Creates a new connection with the given socket, sharing the state of `server`
+    /// and taking the next client id
+    pub fn new(socket: TcpStream, peer_addr: SocketAddr, server: &RedisServer) -> Self {
+        let (sink, frames) = Framed::new(socket, RespCodec::default()).split();
+        let (writer_tx, writer_rx) = channel(OUTPUT_BUFFER_FRAMES);
+        tokio::spawn(write_frames(sink, writer_rx));
+
+        let id = server.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let killed = server.clients.register(id, peer_addr);
+        server.connected_clients.fetch_add(1, Ordering::Relaxed);
+        let authenticated = server.acl.read().unwrap().is_open(DEFAULT_USER);
 
         Self {
-            framed,
-            storage,
+            frames: frames.peekable(),
+            writer_tx,
+            replies: Vec::new(),
+            peer_addr,
+            storage: server.storage.clone(),
+            db: 0,
             transaction_queue: None,
-            server_info,
+            transaction_dirty: false,
+            watched_keys: Vec::new(),
+            replication_rx: None,
+            pubsub: server.pubsub.clone(),
+            subscriptions: HashSet::new(),
+            config: server.config.clone(),
+            // server_info could not be shared and be asked via cmd
+            server_info: server.server_info.clone(),
+            shutdown: server.shutdown.clone(),
+            id,
+            clients: server.clients.clone(),
+            killed,
+            connected_clients: server.connected_clients.clone(),
+            authenticated,
+            acl: server.acl.clone(),
+            user: DEFAULT_USER.to_string(),
         }
     }
 
     /// Handles the connection lifecycle, processing commands until the connection closes
     pub async fn handle(&mut self) -> Result<()> {
-        while let Some(resp_result) = self.framed.next().await {
-            let resp_data = resp_result.context("Decoding failed")?;
-            let cmd = Command::try_from(resp_data);
+        let result = self.serve().await;
+        self.flush_replies().await;
+        result
+    }
+
+    /// Runs the commands read from the peer until the connection closes
+    async fn serve(&mut self) -> Result<()> {
+        loop {
+            if !self.command_ready() {
+                self.flush_replies().await;
+            }
+            let idle_timeout = self.idle_timeout();
+            let resp_result = tokio::select! {
+                resp_result = self.frames.next() => resp_result,
+                Some(write) = recv_write(&mut self.replication_rx) => {
+                    self.send(write).await;
+                    continue;
+                }
+                _ = self.shutdown.triggered() => break,
+                _ = self.killed.cancelled() => break,
+                _ = tokio::time::sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                    info!("Closing connection from {} idle for {:?}", self.peer_addr, idle_timeout);
+                    break;
+                }
+            };
+            let Some(resp_result) = resp_result else {
+                break;
+            };
+
+            let resp_data = match resp_result {
+                Ok(resp_data) => resp_data,
+                Err(e) => {
+                    // The stream cannot be resynchronized, so report why and hang up
+                    self.send(RespDataType::SimpleError(e.to_string())).await;
+                    return Err(e).context("Decoding failed");
+                }
+            };
+            let cmd = Command::try_from(resp_data.clone());
 
             match cmd {
                 Ok(cmd) => {
-                    self.process_command(cmd).await?;
+                    self.process_command(cmd, resp_data).await?;
                 }
                 Err(e) => {
-                    eprintln!("Command error: {}", e);
-                    let _ = self
-                        .framed
-                        .send(RespDataType::SimpleError(e.to_string()))
+                    let error = CommandError::classify(e);
+                    debug!("Command error: {}", error);
+                    if self.transaction_queue.is_some() {
+                        self.transaction_dirty = true;
+                    }
+                    self.send(RespDataType::SimpleError(error.to_string()))
                         .await;
                 }
             }
@@ -271,51 +664,284 @@ impl Connection {
         Ok(())
     }
 
+    /// Returns how long the connection may stay silent before being closed, per the
+    /// `timeout` config. Replicas and subscribers wait for the server, so they never
+    /// time out.
+    fn idle_timeout(&self) -> Option<Duration> {
+        let timeout = self.config.read().unwrap().timeout;
+        let exempt = self.replication_rx.is_some() || !self.subscriptions.is_empty();
+        (timeout > 0 && !exempt).then(|| Duration::from_secs(timeout))
+    }
+
     /// Processes a single command and responds to client
-    async fn process_command(&mut self, cmd: Command) -> Result<()> {
+    async fn process_command(&mut self, cmd: Command, frame: RespDataType) -> Result<()> {
+        let name = frame_name(&frame);
+        self.clients
+            .update(self.id, |info| info.last_command = name);
+
+        if !self.authenticated && !cmd.allowed_unauthenticated() {
+            if self.transaction_queue.is_some() {
+                self.transaction_dirty = true;
+            }
+            self.send(RespDataType::SimpleError(
+                "NOAUTH Authentication required.".into(),
+            ))
+            .await;
+            return Ok(());
+        }
+
+        let permitted = matches!(cmd, Command::AUTH { .. })
+            || self
+                .acl
+                .read()
+                .unwrap()
+                .can_run(&self.user, &frame_name(&frame));
+        if !permitted {
+            if self.transaction_queue.is_some() {
+                self.transaction_dirty = true;
+            }
+            self.send(RespDataType::SimpleError(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.user,
+                frame_name(&frame)
+            )))
+            .await;
+            return Ok(());
+        }
+
+        // RESET gets the connection out of whatever context it is in, so it is never
+        // queued nor refused
+        if let Command::RESET = cmd {
+            self.reset();
+            self.send(RespDataType::SimpleString("RESET".into())).await;
+            return Ok(());
+        }
+
+        // Replicas report their offset without expecting a reply
+        if let Command::REPLCONF(ReplConf::Ack(offset)) = cmd {
+            self.server_info
+                .write()
+                .unwrap()
+                .replica_acks
+                .insert(self.peer_addr, offset);
+            return Ok(());
+        }
+
+        if !self.subscriptions.is_empty() && !cmd.allowed_while_subscribed() {
+            let name = frame_name(&frame);
+            self.send(RespDataType::SimpleError(format!(
+                "ERR Can't execute '{name}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context"
+            ))).await;
+            return Ok(());
+        }
+
+        // A subscriber tells replies from messages by their shape, so PING replies as
+        // a message would
+        if let (false, Command::PING(msg)) = (self.subscriptions.is_empty(), &cmd) {
+            self.send(RespDataType::Array(vec![
+                RespDataType::BulkString("pong".into()),
+                RespDataType::BulkString(msg.clone().unwrap_or_default().into()),
+            ]))
+            .await;
+            return Ok(());
+        }
+
+        if !cmd.allowed_while_unavailable() {
+            let unavailable = self.server_info.read().unwrap().unavailable_error();
+            if let Some(error) = unavailable {
+                if self.transaction_queue.is_some() {
+                    self.transaction_dirty = true;
+                }
+                self.send(error).await;
+                return Ok(());
+            }
+        }
+
+        // Writes only reach a replica's dataset through its master link, which does not
+        // go through connections
+        let writes = cmd.is_write() || matches!(cmd, Command::BLPOP { .. });
+        if writes && self.server_info.read().unwrap().is_slave() {
+            if self.transaction_queue.is_some() {
+                self.transaction_dirty = true;
+            }
+            self.send(RespDataType::SimpleError(
+                "READONLY You can't write against a read only replica.".into(),
+            ))
+            .await;
+            return Ok(());
+        }
+
+        if let Command::SUBSCRIBE { .. } | Command::UNSUBSCRIBE { .. } | Command::SHUTDOWN { .. } =
+            cmd
+        {
+            if self.transaction_queue.is_some() {
+                self.transaction_dirty = true;
+                self.send(RespDataType::SimpleError(
+                    "ERR Command not allowed inside a transaction".into(),
+                ))
+                .await;
+                return Ok(());
+            }
+        }
+
+        match cmd {
+            Command::SUBSCRIBE { .. } | Command::UNSUBSCRIBE { .. } => {
+                self.handle_subscription_command(cmd).await;
+                return Ok(());
+            }
+            // There is no reply: the connection closes along with the server
+            Command::SHUTDOWN { save } => {
+                self.shutdown.shutdown_with(save);
+                return Ok(());
+            }
+            _ => {}
+        }
+
         let mut resync_flag = false;
         let response = if self.transaction_queue.is_some() {
-            self.handle_transaction_command(cmd).await
+            self.handle_transaction_command(cmd, frame).await
         } else {
             if let Command::PSYNC { .. } = cmd {
                 resync_flag = true;
             };
-            self.handle_regular_command(cmd).await
+            self.handle_regular_command(cmd, frame).await
         };
 
-        self.framed.send(response).await?;
+        self.send(response).await;
 
         if resync_flag {
-            self.send_rdb_file().await?;
+            self.send_rdb_file().await;
         }
 
         Ok(())
     }
 
-    /// Sends the RDB file after PSYNC response
-    async fn send_rdb_file(&mut self) -> Result<()> {
-        // Empty RDB file as hex bytes
-        let empty_rdb = include_bytes!("../empty.rdb");
+    /// Discards the transaction and watched keys, leaves every channel, selects the
+    /// first database, drops the client name and goes back to the default user, which
+    /// takes a new AUTH if it has a password.
+    fn reset(&mut self) {
+        self.transaction_queue = None;
+        self.transaction_dirty = false;
+        self.unwatch();
+        for channel in self.subscriptions.drain() {
+            self.pubsub.unsubscribe(&channel, self.peer_addr);
+        }
+        self.db = 0;
+        self.clients.update(self.id, |info| info.name = None);
+        self.user = DEFAULT_USER.to_string();
+        self.authenticated = self.acl.read().unwrap().is_open(DEFAULT_USER);
+    }
+
+    /// Handles SUBSCRIBE and UNSUBSCRIBE, which reply with one confirmation per channel
+    /// carrying the number of channels the connection remains subscribed to.
+    async fn handle_subscription_command(&mut self, cmd: Command) {
+        let confirmation = |kind: &str, channel: Option<String>, count: usize| {
+            RespDataType::Array(vec![
+                RespDataType::BulkString(kind.to_string().into()),
+                channel.map_or(RespDataType::NullBulkString, |channel| {
+                    RespDataType::BulkString(channel.into())
+                }),
+                RespDataType::Integer(count as i64),
+            ])
+        };
+
+        match cmd {
+            Command::SUBSCRIBE { channels } => {
+                for channel in channels {
+                    let subscribed = self.subscriptions.insert(channel.clone());
+                    let reply =
+                        confirmation("subscribe", Some(channel.clone()), self.subscriptions.len());
+                    // Messages skip the held replies, so the confirmation must be written
+                    // before the first of them can be published
+                    self.send(reply).await;
+                    self.flush_replies().await;
+                    if subscribed {
+                        self.pubsub.subscribe(
+                            channel,
+                            self.peer_addr,
+                            self.writer_tx.clone(),
+                            self.killed.clone(),
+                        );
+                    }
+                }
+            }
+            Command::UNSUBSCRIBE { mut channels } => {
+                if channels.is_empty() {
+                    channels = self.subscriptions.iter().cloned().collect();
+                }
+                if channels.is_empty() {
+                    let reply = confirmation("unsubscribe", None, 0);
+                    self.send(reply).await;
+                }
+                for channel in channels {
+                    if self.subscriptions.remove(&channel) {
+                        self.pubsub.unsubscribe(&channel, self.peer_addr);
+                    }
+                    let reply =
+                        confirmation("unsubscribe", Some(channel), self.subscriptions.len());
+                    self.send(reply).await;
+                }
+            }
+            _ => unreachable!("only (un)subscribe commands are handled here"),
+        }
+    }
+
+    /// Holds `frame` to be written to the peer along with the replies to the other
+    /// pipelined commands.
+    async fn send(&mut self, frame: RespDataType) {
+        self.replies.push(frame);
+        if self.replies.len() >= OUTPUT_BUFFER_FRAMES {
+            self.flush_replies().await;
+        }
+    }
+
+    /// Hands the held replies to the writer task, waiting while the output buffer is
+    /// full. They are dropped once the peer went away, which the read side notices on
+    /// its own.
+    async fn flush_replies(&mut self) {
+        if self.replies.is_empty() {
+            return;
+        }
+        let replies = std::mem::take(&mut self.replies);
+        let _ = self.writer_tx.send(replies).await;
+    }
 
-        // Get the underlying TCP stream
-        let stream = self.framed.get_mut();
+    /// Whether the next command was received already, without waiting for it
+    fn command_ready(&mut self) -> bool {
+        matches!(
+            Pin::new(&mut self.frames).peek().now_or_never(),
+            Some(Some(_))
+        )
+    }
 
-        // Send RDB file in the format: $<length>\r\n<binary_contents>
-        let rdb_response = format!("${}\r\n", empty_rdb.len());
-        stream.write_all(rdb_response.as_bytes()).await?;
-        stream.write_all(empty_rdb).await?;
-        stream.flush().await?;
+    /// Sends the RDB file after PSYNC response
+    async fn send_rdb_file(&mut self) {
+        let rdb = Bytes::from_static(EMPTY_RDB);
+        let len = rdb.len();
 
-        info!("Sent RDB file ({} bytes) to replica", empty_rdb.len());
-        Ok(())
+        // Sent in the format: $<length>\r\n<binary_contents>
+        self.send(RespDataType::RdbFile(rdb)).await;
+
+        info!("Sent RDB file ({} bytes) to replica", len);
     }
 
     /// Handles commands when in transaction mode
-    async fn handle_transaction_command(&mut self, cmd: Command) -> RespDataType {
+    async fn handle_transaction_command(
+        &mut self,
+        cmd: Command,
+        frame: RespDataType,
+    ) -> RespDataType {
         match cmd {
             Command::EXEC => {
                 if let Some(mut queued_cmds) = self.transaction_queue.take() {
-                    if queued_cmds.is_empty() {
+                    if std::mem::take(&mut self.transaction_dirty) {
+                        self.unwatch();
+                        RespDataType::SimpleError(
+                            "EXECABORT Transaction discarded because of previous errors.".into(),
+                        )
+                    } else if self.watched_keys_modified().await {
+                        RespDataType::NullArray
+                    } else if queued_cmds.is_empty() {
                         RespDataType::Array(vec![])
                     } else {
                         self.execute_transaction(&mut queued_cmds).await
@@ -326,11 +952,18 @@ impl Connection {
             }
             Command::DISCARD => {
                 self.transaction_queue = None;
+                self.transaction_dirty = false;
+                self.unwatch();
                 RespDataType::SimpleString("OK".into())
             }
+            Command::MULTI => RespDataType::SimpleError("ERR MULTI calls can not be nested".into()),
+            Command::WATCH { .. } => {
+                self.transaction_dirty = true;
+                RespDataType::SimpleError("ERR WATCH inside MULTI is not allowed".into())
+            }
             _ => {
                 if let Some(ref mut queued_cmds) = self.transaction_queue {
-                    queued_cmds.push_back(cmd);
+                    queued_cmds.push_back((cmd, frame));
                 }
                 RespDataType::SimpleString("QUEUED".into())
             }
@@ -338,64 +971,2867 @@ impl Connection {
     }
 
     /// Handles commands when not in transaction mode
-    async fn handle_regular_command(&mut self, cmd: Command) -> RespDataType {
+    async fn handle_regular_command(&mut self, cmd: Command, frame: RespDataType) -> RespDataType {
         match cmd {
-            Command::PING => RespDataType::SimpleString("PONG".to_string()),
-            Command::ECHO(msg) => RespDataType::BulkString(msg),
             Command::MULTI => {
                 self.transaction_queue = Some(VecDeque::new());
                 RespDataType::SimpleString("OK".into())
             }
             Command::EXEC => RespDataType::SimpleError("ERR EXEC without MULTI".into()),
             Command::DISCARD => RespDataType::SimpleError("ERR DISCARD without MULTI".into()),
-            Command::INFO { section: _ } => self.retrieve_info(),
-            Command::REPLCONF => RespDataType::SimpleString("OK".into()),
+            Command::WATCH { keys } => {
+                let keys: Vec<(usize, String)> =
+                    keys.into_iter().map(|key| (self.db, key)).collect();
+                let versions = self.storage.watch(keys.clone()).await;
+                self.watched_keys.extend(keys.into_iter().zip(versions));
+                RespDataType::SimpleString("OK".into())
+            }
             Command::PSYNC {
-                replication_id,
+                replication_id: _,
                 offset: _,
             } => {
-                let current_offset = 0;
+                // Writes from now on are streamed after the RDB snapshot
+                self.replication_rx = Some(self.storage.add_replica());
+                self.server_info.write().unwrap().connected_slaves += 1;
+
+                let current_offset = self.server_info.read().unwrap().master_repl_offset;
                 let my_id = DEFAULT_MASTER_ID;
                 RespDataType::SimpleString(format!("FULLRESYNC {} {}", my_id, current_offset))
             }
-            _ => self.storage.send(cmd).await,
+            _ => self.dispatch(cmd, frame).await,
+        }
+    }
+
+    /// Runs a command that does not change the transaction or replication state of the
+    /// connection, the same way whether it was sent on its own or queued by MULTI.
+    async fn dispatch(&mut self, cmd: Command, frame: RespDataType) -> RespDataType {
+        match cmd {
+            Command::PING(None) => RespDataType::SimpleString("PONG".to_string()),
+            Command::PING(Some(msg)) => RespDataType::BulkString(msg.into()),
+            Command::ECHO(msg) => RespDataType::BulkString(msg),
+            Command::UNWATCH => {
+                self.unwatch();
+                RespDataType::SimpleString("OK".into())
+            }
+            Command::SELECT { index } => self.select(index),
+            Command::PUBLISH { channel, message } => {
+                RespDataType::Integer(self.pubsub.publish(&channel, &message) as i64)
+            }
+            Command::INFO { section } => self.retrieve_info(section).await,
+            Command::CONFIG(subcommand) => self.config(subcommand),
+            Command::AUTH { username, password } => self.auth(username, password),
+            Command::ACL { subcommand } => self.acl(subcommand),
+            Command::CLIENT { subcommand } => self.client(subcommand),
+            Command::COMMAND { subcommand } => command_reply(&subcommand),
+            Command::SAVE => self.save().await,
+            Command::BGSAVE => self.bgsave().await,
+            Command::DEBUG {
+                subcommand: DebugSubcommand::Sleep(duration),
+            } => {
+                tokio::time::sleep(duration).await;
+                RespDataType::SimpleString("OK".into())
+            }
+            Command::REPLCONF(_) => RespDataType::SimpleString("OK".into()),
+            // Only reached from EXEC, as they are handled before being queued
+            Command::MULTI
+            | Command::EXEC
+            | Command::DISCARD
+            | Command::WATCH { .. }
+            | Command::PSYNC { .. } => RespDataType::SimpleError(format!(
+                "ERR Command not allowed inside a transaction: '{}'",
+                frame_name(&frame)
+            )),
+            Command::BLPOP { timeout, .. } => {
+                // The replies to the commands pipelined before must not wait for it
+                self.flush_replies().await;
+                let reply = self.storage.send_from_client(self.db, cmd, frame);
+                if timeout.is_zero() {
+                    reply.await
+                } else {
+                    tokio::time::timeout(timeout, reply)
+                        .await
+                        .unwrap_or(RespDataType::NullArray)
+                }
+            }
+            _ => self.storage.send_from_client(self.db, cmd, frame).await,
+        }
+    }
+
+    /// Switches the connection to database `index`
+    fn select(&mut self, index: usize) -> RespDataType {
+        if index >= NUM_DATABASES {
+            return RespDataType::SimpleError("ERR DB index is out of range".into());
+        }
+        self.db = index;
+        RespDataType::SimpleString("OK".into())
+    }
+
+    /// Reads or changes the runtime configuration
+    fn config(&self, subcommand: ConfigSubcommand) -> RespDataType {
+        match subcommand {
+            ConfigSubcommand::Get(pattern) => RespDataType::Array(
+                self.config
+                    .read()
+                    .unwrap()
+                    .get(&pattern)
+                    .into_iter()
+                    .flat_map(|(name, value)| {
+                        [
+                            RespDataType::BulkString(name.into()),
+                            RespDataType::BulkString(value.into()),
+                        ]
+                    })
+                    .collect(),
+            ),
+            ConfigSubcommand::Set { parameter, value } => {
+                let mut config = self.config.write().unwrap();
+                match config.set(&parameter, value) {
+                    Ok(()) => {
+                        // The storage actor owns the lists, so it keeps its own copy
+                        if parameter.eq_ignore_ascii_case("list-max-listpack-size") {
+                            self.storage
+                                .set_list_max_listpack_size(config.list_max_listpack_size);
+                        }
+                        if parameter.eq_ignore_ascii_case("maxmemory")
+                            || parameter.eq_ignore_ascii_case("maxmemory-policy")
+                        {
+                            self.storage
+                                .set_maxmemory(config.maxmemory, config.maxmemory_policy);
+                        }
+                        if parameter.eq_ignore_ascii_case("maxmemory-samples") {
+                            self.storage.set_maxmemory_samples(config.maxmemory_samples);
+                        }
+                        if parameter.eq_ignore_ascii_case("list-max-len") {
+                            self.storage.set_list_max_len(config.list_max_len);
+                        }
+                        // requirepass is the password of the default user
+                        if parameter.eq_ignore_ascii_case("requirepass") {
+                            self.acl
+                                .write()
+                                .unwrap()
+                                .set_default_password(&config.requirepass);
+                        }
+                        RespDataType::SimpleString("OK".into())
+                    }
+                    Err(e) => RespDataType::SimpleError(e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Authenticates this connection if `password` matches `requirepass`
+    fn auth(&mut self, username: Option<String>, password: String) -> RespDataType {
+        let acl = self.acl.read().unwrap();
+        if username.is_none() && acl.is_open(DEFAULT_USER) {
+            return RespDataType::SimpleError(
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".into(),
+            );
+        }
+        let username = username.unwrap_or_else(|| DEFAULT_USER.to_string());
+        if !acl.authenticate(&username, &password) {
+            return RespDataType::SimpleError(
+                "WRONGPASS invalid username-password pair or user is disabled.".into(),
+            );
+        }
+        drop(acl);
+        self.authenticated = true;
+        self.user = username;
+        RespDataType::SimpleString("OK".into())
+    }
+
+    /// Manages the users of the access control list
+    fn acl(&self, subcommand: AclSubcommand) -> RespDataType {
+        match subcommand {
+            AclSubcommand::SetUser { name, rules } => {
+                match self.acl.write().unwrap().set_user(&name, &rules) {
+                    Ok(()) => RespDataType::SimpleString("OK".into()),
+                    Err(e) => RespDataType::SimpleError(e.to_string()),
+                }
+            }
+            AclSubcommand::Users => RespDataType::Array(
+                self.acl
+                    .read()
+                    .unwrap()
+                    .users()
+                    .into_iter()
+                    .map(|name| RespDataType::BulkString(name.into()))
+                    .collect(),
+            ),
+            AclSubcommand::WhoAmI => RespDataType::BulkString(self.user.clone().into()),
+        }
+    }
+
+    /// Inspects or changes this connection
+    fn client(&mut self, subcommand: ClientSubcommand) -> RespDataType {
+        match subcommand {
+            ClientSubcommand::Id => RespDataType::Integer(self.id as i64),
+            ClientSubcommand::SetName(name) => {
+                if name.chars().any(|c| !('!'..='~').contains(&c)) {
+                    return RespDataType::SimpleError(
+                        "ERR Client names cannot contain spaces, newlines or special characters."
+                            .into(),
+                    );
+                }
+                self.clients.update(self.id, |info| {
+                    info.name = Some(name).filter(|name| !name.is_empty());
+                });
+                RespDataType::SimpleString("OK".into())
+            }
+            ClientSubcommand::GetName => self
+                .clients
+                .update(self.id, |info| info.name.clone())
+                .flatten()
+                .map_or(RespDataType::NullBulkString, |name| {
+                    RespDataType::BulkString(name.into())
+                }),
+            ClientSubcommand::List => RespDataType::BulkString(self.clients.list().into()),
+            ClientSubcommand::Kill { id, addr } => {
+                let killed = self.clients.kill(id, addr.as_deref(), self.id);
+                RespDataType::Integer(killed as i64)
+            }
+        }
+    }
+
+    /// Writes the keyspace to the RDB snapshot, replying once it is on disk
+    async fn save(&self) -> RespDataType {
+        if self.server_info.read().unwrap().rdb_bgsave_in_progress {
+            return RespDataType::SimpleError("ERR Background save already in progress".into());
+        }
+
+        let entries = self.storage.snapshot().await;
+        let path = self.config.read().unwrap().rdb_path();
+        match tokio::task::spawn_blocking(move || rdb::save(&path, &entries)).await {
+            Ok(Ok(())) => RespDataType::SimpleString("OK".into()),
+            Ok(Err(e)) => {
+                error!("SAVE failed: {:#}", e);
+                RespDataType::SimpleError("ERR".into())
+            }
+            Err(e) => {
+                error!("SAVE task failed: {}", e);
+                RespDataType::SimpleError("ERR".into())
+            }
+        }
+    }
+
+    /// Writes the keyspace to the RDB snapshot from a blocking task, replying as soon
+    /// as the keyspace has been captured
+    async fn bgsave(&self) -> RespDataType {
+        {
+            let mut server_info = self.server_info.write().unwrap();
+            if server_info.rdb_bgsave_in_progress {
+                return RespDataType::SimpleError("ERR Background save already in progress".into());
+            }
+            server_info.rdb_bgsave_in_progress = true;
         }
+
+        let entries = self.storage.snapshot().await;
+        let path = self.config.read().unwrap().rdb_path();
+        let server_info = self.server_info.clone();
+        tokio::task::spawn_blocking(move || {
+            match rdb::save(&path, &entries) {
+                Ok(()) => info!("Background saving terminated with success"),
+                Err(e) => error!("Background saving failed: {:#}", e),
+            }
+            server_info.write().unwrap().rdb_bgsave_in_progress = false;
+        });
+
+        RespDataType::SimpleString("Background saving started".into())
     }
 
-    /// retrieves a BulkString like
-    /// $ redis-cli INFO replication
-    /// # Replication
-    /// role:master
-    /// connected_slaves:0
-    /// master_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb
-    /// master_repl_offset:0
-    /// second_repl_offset:-1
-    /// repl_backlog_active:0
-    /// repl_backlog_size:1048576
-    /// repl_backlog_first_byte_offset:0
-    /// repl_backlog_histlen:
-    fn retrieve_info(&self) -> RespDataType {
+    /// Renders `section` of INFO, or every section if none is given, each under its
+    /// `# Title` header.
+    async fn retrieve_info(&self, section: Option<Section>) -> RespDataType {
+        let stats = self.storage.stats().await;
         let server_info = self.server_info.read().unwrap();
-        RespDataType::BulkString(server_info.to_string())
+        let sections = section.map_or(Section::ALL.to_vec(), |section| vec![section]);
+
+        let mut info = String::new();
+        for section in sections {
+            if !info.is_empty() {
+                info.push('\n');
+            }
+            let _ = writeln!(info, "# {}", section.title());
+            let _ = match section {
+                Section::Server => writeln!(
+                    info,
+                    "redis_version:{}\nprocess_id:{}\ntcp_port:{}\nuptime_in_seconds:{}",
+                    env!("CARGO_PKG_VERSION"),
+                    std::process::id(),
+                    server_info.tcp_port,
+                    server_info.started_at.elapsed().as_secs()
+                ),
+                Section::Clients => writeln!(
+                    info,
+                    "connected_clients:{}",
+                    self.connected_clients.load(Ordering::Relaxed)
+                ),
+                Section::Memory => {
+                    let config = self.config.read().unwrap();
+                    writeln!(
+                        info,
+                        "used_memory:{}\nmaxmemory:{}\nmaxmemory_policy:{}",
+                        stats.used_memory, config.maxmemory, config.maxmemory_policy
+                    )
+                }
+                Section::Replication => write!(info, "{server_info}"),
+                Section::Keyspace => stats
+                    .keyspace
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (keys, _))| *keys > 0)
+                    .try_for_each(|(db, (keys, expires))| {
+                        writeln!(info, "db{db}:keys={keys},expires={expires},avg_ttl=0")
+                    }),
+            };
+        }
+        RespDataType::BulkString(info.into())
+    }
+
+    /// Consumes the watched keys and reports whether any of them was written,
+    /// deleted or expired since it was watched
+    async fn watched_keys_modified(&mut self) -> bool {
+        if self.watched_keys.is_empty() {
+            return false;
+        }
+
+        let (keys, versions): (Vec<(usize, String)>, Vec<u64>) =
+            std::mem::take(&mut self.watched_keys).into_iter().unzip();
+        let modified = self.storage.versions(keys.clone()).await != versions;
+        self.storage.unwatch(keys);
+        modified
+    }
+
+    /// Forgets the watched keys, so that the storage stops tracking their writes.
+    fn unwatch(&mut self) {
+        if self.watched_keys.is_empty() {
+            return;
+        }
+        let keys = std::mem::take(&mut self.watched_keys)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        self.storage.unwatch(keys);
     }
 
     /// Executes a transaction by processing all queued commands
-    async fn execute_transaction(&self, queued_cmds: &mut VecDeque<Command>) -> RespDataType {
+    async fn execute_transaction(
+        &mut self,
+        queued_cmds: &mut VecDeque<(Command, RespDataType)>,
+    ) -> RespDataType {
         let mut results = Vec::with_capacity(queued_cmds.len());
 
-        while let Some(cmd) = queued_cmds.pop_front() {
+        while let Some((cmd, frame)) = queued_cmds.pop_front() {
             let result = match cmd {
-                Command::PING => RespDataType::SimpleString("PONG".to_string()),
-                Command::ECHO(msg) => RespDataType::BulkString(msg),
-                Command::EXEC | Command::MULTI => {
-                    panic!("MULTI or EXEC should not be queued in a transaction")
-                }
-                _ => self.storage.send(cmd).await,
+                // A transaction never blocks, BLPOP replies nil when every list is empty
+                Command::BLPOP { keys, .. } => self.pop_without_blocking(keys).await,
+                cmd => self.dispatch(cmd, frame).await,
             };
-
             results.push(result);
         }
 
         RespDataType::Array(results)
     }
+
+    /// Runs BLPOP as `LMPOP numkeys key... LEFT`, which never blocks, and shapes the
+    /// reply like BLPOP's: the key and the element popped, or a nil array.
+    async fn pop_without_blocking(&mut self, keys: Vec<String>) -> RespDataType {
+        let mut frame = vec!["LMPOP".to_string(), keys.len().to_string()];
+        frame.extend(keys.iter().cloned());
+        frame.push("LEFT".to_string());
+        let frame = RespDataType::Array(
+            frame
+                .into_iter()
+                .map(|part| RespDataType::BulkString(part.into()))
+                .collect(),
+        );
+        let cmd = Command::LMPOP {
+            keys,
+            end: ListEnd::Left,
+            count: 1,
+        };
+        match self.storage.send_from_client(self.db, cmd, frame).await {
+            RespDataType::Array(mut reply) => {
+                if let Some(RespDataType::Array(mut elements)) = reply.pop() {
+                    reply.append(&mut elements);
+                }
+                RespDataType::Array(reply)
+            }
+            reply => reply,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+        self.unwatch();
+
+        for channel in &self.subscriptions {
+            self.pubsub.unsubscribe(channel, self.peer_addr);
+        }
+
+        if self.replication_rx.is_some() {
+            let mut server_info = self.server_info.write().unwrap();
+            server_info.connected_slaves -= 1;
+            server_info.replica_acks.remove(&self.peer_addr);
+        }
+
+        self.clients.unregister(self.id);
+    }
+}
+
+/// Returns the lowercase name of the command in `frame`, for error messages and
+/// CLIENT LIST.
+fn frame_name(frame: &RespDataType) -> String {
+    match frame {
+        RespDataType::Array(parts) => parts
+            .first()
+            .and_then(|name| name.get_str().ok())
+            .unwrap_or_default()
+            .to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/// Writes the batches of frames queued on `rx` to the peer, in order, until the
+/// connection is dropped or the peer goes away. Every batch queued by then is written in
+/// the same flush.
+async fn write_frames(
+    mut sink: SplitSink<Framed<TcpStream, RespCodec>, RespDataType>,
+    mut rx: Receiver<Vec<RespDataType>>,
+) {
+    while let Some(mut frames) = rx.recv().await {
+        while let Ok(more) = rx.try_recv() {
+            frames.extend(more);
+        }
+        let written = async {
+            for frame in frames {
+                sink.feed(frame).await?;
+            }
+            sink.flush().await
+        };
+        if let Err(e) = written.await {
+            debug!("Failed to write to peer: {}", e);
+            break;
+        }
+    }
+}
+
+/// Turns away a connection accepted past `maxclients`, telling the peer why before
+/// closing the socket.
+async fn reject_client(socket: TcpStream) {
+    let mut framed = Framed::new(socket, RespCodec::default());
+    let reply = RespDataType::SimpleError("ERR max number of clients reached".to_string());
+    if let Err(e) = framed.send(reply).await {
+        debug!("Failed to write to peer: {}", e);
+    }
+}
+
+/// Waits for the next write to forward to a replica, or forever if the connection is
+/// not a replica.
+async fn recv_write(
+    replication_rx: &mut Option<UnboundedReceiver<RespDataType>>,
+) -> Option<RespDataType> {
+    match replication_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aof::AppendFsync;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    /// Starts a master on an ephemeral port and returns its address
+    async fn spawn_server() -> SocketAddr {
+        spawn_server_with_info().await.0
+    }
+
+    /// Like [`spawn_server`], also handing out the server's shared state
+    async fn spawn_server_with_info() -> (SocketAddr, Arc<RwLock<ServerInfo>>) {
+        spawn_server_with_config(test_config(None)).await
+    }
+
+    /// Config of a server on an ephemeral port, with its own snapshot file
+    fn test_config(replica_of: Option<String>) -> ServerConfig {
+        static NEXT_SNAPSHOT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT_SNAPSHOT.fetch_add(1, Ordering::Relaxed);
+        ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            port: 0,
+            replica_of,
+            notify_keyspace_events: Default::default(),
+            dir: std::env::temp_dir().to_string_lossy().into_owned(),
+            dbfilename: format!("redis-rs-test-{}-{n}.rdb", std::process::id()),
+            appendonly: false,
+            appendfsync: Default::default(),
+            appendfilename: format!("redis-rs-test-{}-{n}.aof", std::process::id()),
+        }
+    }
+
+    async fn spawn_server_with_config(
+        config: ServerConfig,
+    ) -> (SocketAddr, Arc<RwLock<ServerInfo>>) {
+        let server = RedisServer::new(config).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let server_info = server.server_info.clone();
+        tokio::spawn(server.run());
+        (addr, server_info)
+    }
+
+    async fn connect(addr: SocketAddr) -> Framed<TcpStream, RespCodec> {
+        Framed::new(
+            TcpStream::connect(addr).await.unwrap(),
+            RespCodec::default(),
+        )
+    }
+
+    /// Sends a command built from `args` without waiting for a reply
+    async fn send_only(client: &mut Framed<TcpStream, RespCodec>, args: &[&str]) {
+        let cmd = args
+            .iter()
+            .map(|arg| RespDataType::BulkString(arg.to_string().into()))
+            .collect();
+        client.send(RespDataType::Array(cmd)).await.unwrap();
+    }
+
+    /// Sends a command built from `args` and waits for its reply
+    async fn send_cmd(client: &mut Framed<TcpStream, RespCodec>, args: &[&str]) -> RespDataType {
+        send_only(client, args).await;
+        client.next().await.unwrap().unwrap()
+    }
+
+    fn ok() -> RespDataType {
+        RespDataType::SimpleString("OK".into())
+    }
+
+    fn queued() -> RespDataType {
+        RespDataType::SimpleString("QUEUED".into())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_reply_lists_its_args() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["FOO", "bar"]).await,
+            RespDataType::SimpleError(
+                "ERR unknown command 'FOO', with args beginning with: 'bar' ".into()
+            )
+        );
+        // Parse failures without a prefix of their own are reported as ERR
+        assert_eq!(
+            send_cmd(&mut client, &["DEBUG", "SLEEP"]).await,
+            RespDataType::SimpleError("ERR DEBUG SLEEP requires a number of seconds".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_runs_every_command_despite_failures() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["INCR", "k"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["LLEN", "k"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["SET", "other", "1"]).await,
+            queued()
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![
+                ok(),
+                RespDataType::SimpleError("ERR value is not an integer or out of range".into()),
+                RespDataType::SimpleError(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+                ),
+                ok(),
+            ])
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "other"]).await,
+            RespDataType::BulkString("1".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_reply_the_same_inside_a_transaction() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        let commands: &[&[&str]] = &[
+            &["PING"],
+            &["ECHO", "hi"],
+            &["SET", "k", "v"],
+            &["INCR", "k"],
+            &["RPUSH", "k", "a"],
+            &["CONFIG", "GET", "maxclients"],
+            &["CLIENT", "GETNAME"],
+            &["COMMAND", "COUNT"],
+            &["INFO", "replication"],
+            &["DEL", "k"],
+        ];
+
+        let mut outside = Vec::new();
+        for cmd in commands {
+            outside.push(send_cmd(&mut client, cmd).await);
+        }
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        for cmd in commands {
+            assert_eq!(send_cmd(&mut client, cmd).await, queued());
+        }
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(outside)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_aborts_exec_after_list_write() {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", "k"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut writer, &["RPUSH", "k", "a"]).await,
+            RespDataType::Integer(1)
+        );
+
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["SET", "k", "v"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::NullArray
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_aborts_exec_after_concurrent_set() {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut watcher, &["SET", "k", "1"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", "k"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["INCR", "k"]).await, queued());
+
+        assert_eq!(send_cmd(&mut writer, &["SET", "k", "10"]).await, ok());
+
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::NullArray
+        );
+        assert_eq!(
+            send_cmd(&mut watcher, &["GET", "k"]).await,
+            RespDataType::BulkString("10".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unwatch_forgets_watched_keys() {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", "k"]).await, ok());
+        assert_eq!(send_cmd(&mut writer, &["SET", "k", "1"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["UNWATCH"]).await, ok());
+
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["INCR", "k"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::Array(vec![RespDataType::Integer(2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_continues_after_runtime_error() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "abc"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["INCR", "k"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["GET", "k"]).await, queued());
+
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![
+                ok(),
+                RespDataType::SimpleError("ERR value is not an integer or out of range".into()),
+                RespDataType::BulkString("abc".into()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queuing_error_aborts_exec() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert!(matches!(
+            send_cmd(&mut client, &["FOO", "bar"]).await,
+            RespDataType::SimpleError(_)
+        ));
+        assert!(matches!(
+            send_cmd(&mut client, &["GET"]).await,
+            RespDataType::SimpleError(_)
+        ));
+
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::SimpleError(
+                "EXECABORT Transaction discarded because of previous errors.".into()
+            )
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discard_clears_dirty_transaction() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert!(matches!(
+            send_cmd(&mut client, &["FOO"]).await,
+            RespDataType::SimpleError(_)
+        ));
+        assert_eq!(send_cmd(&mut client, &["DISCARD"]).await, ok());
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![ok()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpush_wakes_every_blocked_client() {
+        let addr = spawn_server().await;
+        let mut first = connect(addr).await;
+        let mut second = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        send_only(&mut first, &["BLPOP", "key", "0"]).await;
+        // Make sure the first client is queued before the second one
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        send_only(&mut second, &["BLPOP", "key", "0"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        assert_eq!(
+            send_cmd(&mut writer, &["RPUSH", "key", "a", "b"]).await,
+            RespDataType::Integer(2)
+        );
+
+        let popped = |value: &str| {
+            RespDataType::Array(vec![
+                RespDataType::BulkString("key".into()),
+                RespDataType::BulkString(value.to_string().into()),
+            ])
+        };
+        assert_eq!(first.next().await.unwrap().unwrap(), popped("a"));
+        assert_eq!(second.next().await.unwrap().unwrap(), popped("b"));
+        assert_eq!(
+            send_cmd(&mut writer, &["LLEN", "key"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blpop_queued_by_multi_does_not_block() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["RPUSH", "full", "a"]).await;
+        send_cmd(&mut client, &["MULTI"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["BLPOP", "empty", "full", "0"]).await,
+            queued()
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["BLPOP", "empty", "full", "0"]).await,
+            queued()
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![
+                RespDataType::Array(vec![
+                    RespDataType::BulkString("full".into()),
+                    RespDataType::BulkString("a".into()),
+                ]),
+                RespDataType::NullArray,
+            ])
+        );
+        // The connection is still served
+        assert_eq!(
+            send_cmd(&mut client, &["LLEN", "full"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blpop_times_out_with_null_array() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["BLPOP", "key", "0.05"]).await,
+            RespDataType::NullArray
+        );
+
+        // A push after the timeout must not be swallowed by the stale waiter
+        assert_eq!(
+            send_cmd(&mut client, &["RPUSH", "key", "a"]).await,
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["LLEN", "key"]).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nested_multi_errors_and_keeps_queue() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["MULTI"]).await,
+            RespDataType::SimpleError("ERR MULTI calls can not be nested".into())
+        );
+        assert_eq!(send_cmd(&mut client, &["GET", "k"]).await, queued());
+
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![ok(), RespDataType::BulkString("v".into())])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_loading_blocks_commands_but_not_ping() {
+        let (addr, server_info) = spawn_server_with_info().await;
+        let mut client = connect(addr).await;
+        server_info.write().unwrap().loading = true;
+
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::SimpleError("LOADING Redis is loading the dataset in memory".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+        assert!(matches!(
+            send_cmd(&mut client, &["INFO"]).await,
+            RespDataType::BulkString(_)
+        ));
+
+        server_info.write().unwrap().loading = false;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replica_with_master_down_refuses_stale_reads() {
+        let (addr, server_info) = spawn_server_with_info().await;
+        let mut client = connect(addr).await;
+        {
+            let mut info = server_info.write().unwrap();
+            info.role = ServerRole::Slave {
+                addr: "127.0.0.1:1".into(),
+            };
+            info.master_link_up = false;
+            info.serve_stale_data = false;
+        }
+
+        assert!(matches!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::SimpleError(e) if e.starts_with("MASTERDOWN")
+        ));
+        assert_eq!(
+            send_cmd(&mut client, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+
+        // Serving stale data is the default
+        server_info.write().unwrap().serve_stale_data = true;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_a_set_nx_that_writes_nothing() {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        send_cmd(&mut writer, &["SET", "k", "v"]).await;
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", "k"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut writer, &["SET", "k", "other", "NX"]).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["GET", "k"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::Array(vec![RespDataType::BulkString("v".into())])
+        );
+    }
+
+    /// Runs `setup`, then `noop` from another client while `key` is watched, and
+    /// checks that EXEC still runs. Returns the reply to `noop`.
+    async fn watch_around(setup: &[&[&str]], key: &str, noop: &[&str]) -> RespDataType {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        for args in setup {
+            send_cmd(&mut writer, args).await;
+        }
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", key]).await, ok());
+        let reply = send_cmd(&mut writer, noop).await;
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["PING"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::Array(vec![RespDataType::SimpleString("PONG".into())]),
+            "{noop:?} aborted the transaction"
+        );
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_lpop_on_a_missing_list() {
+        assert_eq!(
+            watch_around(&[], "list", &["LPOP", "list"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_incr_on_a_non_integer() {
+        let reply = watch_around(&[&["SET", "k", "abc"]], "k", &["INCR", "k"]).await;
+        assert!(matches!(reply, RespDataType::SimpleError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_zadd_nx_on_an_existing_member() {
+        let setup: &[&[&str]] = &[&["ZADD", "z", "1", "a"]];
+        assert_eq!(
+            watch_around(setup, "z", &["ZADD", "z", "NX", "2", "a"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_bitop_storing_nothing() {
+        assert_eq!(
+            watch_around(&[], "dest", &["BITOP", "OR", "dest", "missing"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_without_writes_executes() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["WATCH", "k"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["INCR", "k"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![RespDataType::Integer(1)])
+        );
+    }
+
+    /// Accepts a single replica, answers its handshake, then streams `commands` to it.
+    /// Frames the replica sends, the handshake included, are forwarded on the returned
+    /// channel.
+    async fn spawn_fake_master(
+        commands: Vec<Vec<&'static str>>,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<RespDataType>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut replica = Framed::new(socket, RespCodec::default());
+
+            // PING, REPLCONF listening-port, REPLCONF capa, PSYNC
+            for reply in ["PONG", "OK", "OK"] {
+                let _ = replies_tx.send(replica.next().await.unwrap().unwrap());
+                replica
+                    .send(RespDataType::SimpleString(reply.into()))
+                    .await
+                    .unwrap();
+            }
+            let _ = replies_tx.send(replica.next().await.unwrap().unwrap());
+            replica
+                .send(RespDataType::SimpleString(format!(
+                    "FULLRESYNC {} 0",
+                    DEFAULT_MASTER_ID
+                )))
+                .await
+                .unwrap();
+            replica
+                .send(RespDataType::RdbFile(Bytes::from_static(EMPTY_RDB)))
+                .await
+                .unwrap();
+
+            for args in commands {
+                let cmd = args
+                    .iter()
+                    .map(|arg| RespDataType::BulkString(arg.to_string().into()))
+                    .collect();
+                replica.send(RespDataType::Array(cmd)).await.unwrap();
+            }
+
+            // Keep the link open until the replica goes away
+            while let Some(Ok(frame)) = replica.next().await {
+                let _ = replies_tx.send(frame);
+            }
+        });
+
+        (addr, replies_rx)
+    }
+
+    /// Starts a replica of `master_addr` on an ephemeral port and returns its address
+    async fn spawn_replica(master_addr: SocketAddr) -> SocketAddr {
+        let config = test_config(Some(master_addr.to_string()));
+        let replica = RedisServer::new(config).await.unwrap();
+        let replica_addr = replica.local_addr().unwrap();
+        tokio::spawn(replica.run());
+        replica_addr
+    }
+
+    /// Skips the PING, REPLCONF and PSYNC the replica sent a fake master.
+    async fn skip_handshake(replies: &mut mpsc::UnboundedReceiver<RespDataType>) {
+        for _ in 0..4 {
+            replies.recv().await.unwrap();
+        }
+    }
+
+    fn replconf_ack(offset: usize) -> RespDataType {
+        RespDataType::Array(vec![
+            RespDataType::BulkString("REPLCONF".into()),
+            RespDataType::BulkString("ACK".into()),
+            RespDataType::BulkString(offset.to_string().into()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_replica_announces_its_listening_port() {
+        let (master_addr, mut replies) = spawn_fake_master(Vec::new()).await;
+        // A free port, which the replica then binds
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = test_config(Some(master_addr.to_string()));
+        config.bind_addr = format!("127.0.0.1:{port}");
+        config.port = port;
+        let replica = RedisServer::new(config).await.unwrap();
+        tokio::spawn(replica.run());
+
+        replies.recv().await.unwrap();
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            RespDataType::Array(vec![
+                RespDataType::BulkString("REPLCONF".into()),
+                RespDataType::BulkString("listening-port".into()),
+                RespDataType::BulkString(port.to_string().into()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replica_rejects_writes_from_clients() {
+        let (master_addr, _replies) = spawn_fake_master(vec![vec!["SET", "k", "v"]]).await;
+        let replica_addr = spawn_replica(master_addr).await;
+        let mut client = connect(replica_addr).await;
+
+        // Writes from the master still apply
+        let mut value = RespDataType::NullBulkString;
+        for _ in 0..100 {
+            value = send_cmd(&mut client, &["GET", "k"]).await;
+            if value != RespDataType::NullBulkString {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(value, RespDataType::BulkString("v".into()));
+
+        let readonly = RespDataType::SimpleError(
+            "READONLY You can't write against a read only replica.".into(),
+        );
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "w"]).await, readonly);
+        assert_eq!(send_cmd(&mut client, &["DEL", "k"]).await, readonly);
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replica_answers_getack_with_offset() {
+        let set = vec!["SET", "foo", "bar"];
+        let getack = vec!["REPLCONF", "GETACK", "*"];
+        let (master_addr, mut replies) = spawn_fake_master(vec![getack.clone(), set, getack]).await;
+        spawn_replica(master_addr).await;
+        skip_handshake(&mut replies).await;
+
+        // *3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n
+        let set_len = 31;
+        // *3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n
+        let getack_len = 37;
+
+        assert_eq!(replies.recv().await.unwrap(), replconf_ack(0));
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            replconf_ack(getack_len + set_len)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replica_ack_matches_master_offset_before_getack() {
+        let getack = vec!["REPLCONF", "GETACK", "*"];
+        let stream = vec![
+            vec!["PING"],
+            vec!["SELECT", "1"],
+            vec!["SET", "foo", "bar"],
+            getack.clone(),
+            getack,
+        ];
+        let (master_addr, mut replies) = spawn_fake_master(stream).await;
+        spawn_replica(master_addr).await;
+        skip_handshake(&mut replies).await;
+
+        // *1\r\n$4\r\nPING\r\n
+        let ping_len = 14;
+        // *2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n
+        let select_len = 23;
+        let set_len = 31;
+        let getack_len = 37;
+
+        // Every command the master sent before the GETACK counts, the GETACK itself
+        // only once the ACK has been sent
+        let master_offset = ping_len + select_len + set_len;
+        assert_eq!(replies.recv().await.unwrap(), replconf_ack(master_offset));
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            replconf_ack(master_offset + getack_len)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_master_does_not_reply_to_ack() {
+        let addr = spawn_server().await;
+        let mut replica = connect(addr).await;
+
+        replica
+            .send(RespDataType::Array(vec![
+                RespDataType::BulkString("REPLCONF".into()),
+                RespDataType::BulkString("ACK".into()),
+                RespDataType::BulkString("42".into()),
+            ]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            send_cmd(&mut replica, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_master_repl_offset_grows_with_propagated_writes() {
+        let addr = spawn_server().await;
+        let mut replica = connect(addr).await;
+        let fullresync = send_cmd(&mut replica, &["PSYNC", "?", "-1"]).await;
+        assert_eq!(
+            fullresync,
+            RespDataType::SimpleString(format!("FULLRESYNC {} 0", DEFAULT_MASTER_ID))
+        );
+        replica.codec_mut().expect_rdb();
+        replica.next().await.unwrap().unwrap();
+
+        let mut client = connect(addr).await;
+        let mut offsets = Vec::new();
+        for key in ["a", "b", "c"] {
+            assert_eq!(send_cmd(&mut client, &["SET", key, "1"]).await, ok());
+            let RespDataType::BulkString(info) =
+                send_cmd(&mut client, &["INFO", "replication"]).await
+            else {
+                panic!("INFO did not reply with a bulk string");
+            };
+            let offset: usize = String::from_utf8_lossy(&info)
+                .lines()
+                .find_map(|line| line.strip_prefix("master_repl_offset:"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            offsets.push(offset);
+        }
+
+        assert!(offsets[0] > 0);
+        assert!(
+            offsets.windows(2).all(|pair| pair[0] < pair[1]),
+            "{offsets:?}"
+        );
+    }
+
+    /// Sends INFO with `args` and returns its text
+    async fn info(client: &mut Framed<TcpStream, RespCodec>, args: &[&str]) -> String {
+        let cmd: Vec<&str> = std::iter::once("INFO")
+            .chain(args.iter().copied())
+            .collect();
+        match send_cmd(client, &cmd).await {
+            RespDataType::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("INFO replied {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_info_renders_the_requested_section() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        send_cmd(&mut client, &["SET", "k", "v"]).await;
+
+        let server = info(&mut client, &["server"]).await;
+        assert!(server.starts_with("# Server\n"), "{server}");
+        assert!(server.contains("redis_version:"), "{server}");
+        assert!(!server.contains("# Replication"), "{server}");
+
+        let replication = info(&mut client, &["REPLICATION"]).await;
+        assert!(replication.starts_with("# Replication\nrole:master\n"));
+        assert!(!replication.contains("redis_version:"));
+
+        let keyspace = info(&mut client, &["keyspace"]).await;
+        assert_eq!(keyspace, "# Keyspace\ndb0:keys=1,expires=0,avg_ttl=0\n");
+
+        assert!(matches!(
+            send_cmd(&mut client, &["INFO", "nonexistent"]).await,
+            RespDataType::SimpleError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_info_keyspace_counts_keys_per_database() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        assert_eq!(info(&mut client, &["keyspace"]).await, "# Keyspace\n");
+
+        send_cmd(&mut client, &["SET", "a", "1"]).await;
+        send_cmd(&mut client, &["SET", "b", "1", "PX", "100000"]).await;
+        send_cmd(&mut client, &["RPUSH", "list", "x"]).await;
+        send_cmd(&mut client, &["EXPIRE", "list", "100"]).await;
+        send_cmd(&mut client, &["ZADD", "zset", "1", "m"]).await;
+        send_cmd(&mut client, &["SELECT", "3"]).await;
+        send_cmd(&mut client, &["SET", "c", "1", "PX", "100000"]).await;
+
+        assert_eq!(
+            info(&mut client, &["keyspace"]).await,
+            "# Keyspace\n\
+             db0:keys=4,expires=2,avg_ttl=0\n\
+             db3:keys=1,expires=1,avg_ttl=0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_info_without_section_renders_all_of_them() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        let all = info(&mut client, &[]).await;
+        let headers: Vec<&str> = all.lines().filter(|line| line.starts_with("# ")).collect();
+        assert_eq!(
+            headers,
+            [
+                "# Server",
+                "# Clients",
+                "# Memory",
+                "# Replication",
+                "# Keyspace"
+            ]
+        );
+        assert!(all.contains("connected_clients:1\n"), "{all}");
+        assert!(all.contains("used_memory:0\n"), "{all}");
+    }
+
+    #[tokio::test]
+    async fn test_replica_applies_propagated_set() {
+        let (master_addr, _replies) = spawn_fake_master(vec![vec!["SET", "foo", "bar"]]).await;
+        let replica_addr = spawn_replica(master_addr).await;
+
+        let mut client = connect(replica_addr).await;
+        let value = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let reply = send_cmd(&mut client, &["GET", "foo"]).await;
+                if reply != RespDataType::NullBulkString {
+                    return reply;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replica never applied the propagated SET");
+
+        assert_eq!(value, RespDataType::BulkString("bar".into()));
+    }
+
+    #[tokio::test]
+    async fn test_debug_purge_expired_counts_reaped_keys() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                send_cmd(&mut client, &["SET", key, "v", "PX", "10"]).await,
+                ok()
+            );
+        }
+        assert_eq!(send_cmd(&mut client, &["SET", "keep", "v"]).await, ok());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["DEBUG", "PURGE-EXPIRED"]).await,
+            RespDataType::Integer(3)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["DEBUG", "PURGE-EXPIRED"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_psync_sends_rdb_after_fullresync() {
+        let addr = spawn_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+
+        let fullresync = format!("+FULLRESYNC {} 0\r\n", DEFAULT_MASTER_ID);
+        let rdb_header = format!("${}\r\n", EMPTY_RDB.len());
+        let expected_len = fullresync.len() + rdb_header.len() + EMPTY_RDB.len();
+
+        let mut received = Vec::new();
+        while received.len() < expected_len {
+            let mut chunk = [0u8; 256];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the RDB payload arrived");
+            received.extend_from_slice(&chunk[..n]);
+        }
+
+        let (reply, rest) = received.split_at(fullresync.len());
+        assert_eq!(reply, fullresync.as_bytes());
+        let (header, payload) = rest.split_at(rdb_header.len());
+        assert_eq!(header, rdb_header.as_bytes());
+        assert!(payload.starts_with(b"REDIS"));
+        assert_eq!(payload, EMPTY_RDB);
+    }
+
+    /// Iterates SCAN until the cursor wraps back to 0, returning every key seen.
+    async fn scan_all(client: &mut Framed<TcpStream, RespCodec>, options: &[&str]) -> Vec<String> {
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        loop {
+            let mut args = vec!["SCAN", cursor.as_str()];
+            args.extend_from_slice(options);
+            let RespDataType::Array(reply) = send_cmd(client, &args).await else {
+                panic!("SCAN must reply with an array");
+            };
+            let [RespDataType::BulkString(next), RespDataType::Array(keys)] = &reply[..] else {
+                panic!("unexpected SCAN reply: {reply:?}");
+            };
+            seen.extend(keys.iter().map(|key| key.get_str().unwrap()));
+            if next == "0" {
+                break;
+            }
+            cursor = reply[0].get_str().unwrap();
+        }
+        seen.sort();
+        seen
+    }
+
+    #[tokio::test]
+    async fn test_scan_visits_every_key() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        let mut expected: Vec<String> = (0..25).map(|i| format!("key:{i}")).collect();
+        for key in &expected {
+            assert_eq!(send_cmd(&mut client, &["SET", key, "v"]).await, ok());
+        }
+        send_cmd(&mut client, &["RPUSH", "list", "a"]).await;
+        expected.push("list".to_string());
+        expected.sort();
+
+        assert_eq!(scan_all(&mut client, &["COUNT", "3"]).await, expected);
+        assert_eq!(scan_all(&mut client, &[]).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_scan_keeps_cursor_across_writes() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        for i in 0..10 {
+            send_cmd(&mut client, &["SET", &format!("old:{i}"), "v"]).await;
+        }
+
+        let RespDataType::Array(reply) = send_cmd(&mut client, &["SCAN", "0", "COUNT", "4"]).await
+        else {
+            panic!("SCAN must reply with an array");
+        };
+        let RespDataType::Array(first_batch) = &reply[1] else {
+            panic!("unexpected SCAN reply: {reply:?}");
+        };
+        let mut seen: Vec<String> = first_batch.iter().map(|k| k.get_str().unwrap()).collect();
+
+        // Keys added mid-scan must not make the iteration skip keys that were there all along
+        for i in 0..10 {
+            send_cmd(&mut client, &["SET", &format!("new:{i}"), "v"]).await;
+        }
+        let cursor = reply[0].get_str().unwrap();
+        if cursor != "0" {
+            let RespDataType::Array(rest) =
+                send_cmd(&mut client, &["SCAN", &cursor, "COUNT", "100"]).await
+            else {
+                panic!("SCAN must reply with an array");
+            };
+            assert_eq!(rest[0], RespDataType::BulkString("0".into()));
+            let RespDataType::Array(keys) = &rest[1] else {
+                panic!("unexpected SCAN reply: {rest:?}");
+            };
+            seen.extend(keys.iter().map(|k| k.get_str().unwrap()));
+        }
+
+        for i in 0..10 {
+            assert!(seen.contains(&format!("old:{i}")), "old:{i} was skipped");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_returns_stable_keys_once_while_growing() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        for i in 0..20 {
+            send_cmd(&mut client, &["SET", &format!("old:{i}"), "v"]).await;
+        }
+
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        let mut added = 0;
+        loop {
+            let RespDataType::Array(reply) =
+                send_cmd(&mut client, &["SCAN", &cursor, "COUNT", "3"]).await
+            else {
+                panic!("SCAN must reply with an array");
+            };
+            let [RespDataType::BulkString(next), RespDataType::Array(keys)] = &reply[..] else {
+                panic!("unexpected SCAN reply: {reply:?}");
+            };
+            seen.extend(keys.iter().map(|key| key.get_str().unwrap()));
+            if next == "0" {
+                break;
+            }
+            cursor = reply[0].get_str().unwrap();
+
+            // Grow the keyspace, and so the number of buckets, between calls
+            for _ in 0..5 {
+                send_cmd(&mut client, &["SET", &format!("new:{added}"), "v"]).await;
+                added += 1;
+            }
+        }
+
+        for i in 0..20 {
+            let key = format!("old:{i}");
+            assert_eq!(
+                seen.iter().filter(|seen| **seen == key).count(),
+                1,
+                "{key} must be returned exactly once"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_match_filters_keys() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        for key in ["user:1", "user:2", "session:1", "user:admin"] {
+            send_cmd(&mut client, &["SET", key, "v"]).await;
+        }
+
+        assert_eq!(
+            scan_all(&mut client, &["MATCH", "user:[0-9]", "COUNT", "1"]).await,
+            vec!["user:1".to_string(), "user:2".to_string()]
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["SCAN", "0", "COUNT", "0"]).await,
+            RespDataType::SimpleError("ERR syntax error".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dbsize_tracks_writes_and_deletes() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(0)
+        );
+
+        send_cmd(&mut client, &["SET", "a", "1"]).await;
+        send_cmd(&mut client, &["SET", "b", "2"]).await;
+        send_cmd(&mut client, &["RPUSH", "list", "x"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(3)
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["DEL", "a", "list", "missing"]).await,
+            RespDataType::Integer(2)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(1)
+        );
+
+        // Popping the last element deletes the list
+        send_cmd(&mut client, &["RPUSH", "list", "x"]).await;
+        send_cmd(&mut client, &["LPOP", "list"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dbsize_skips_expired_keys() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "short", "v", "PX", "20"]).await;
+        send_cmd(&mut client, &["SET", "long", "v"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(2)
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_reply_in_order() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        // Written in a single flush, so the server decodes many frames per read
+        for i in 0..1000 {
+            let ping = RespDataType::Array(vec![
+                RespDataType::BulkString("PING".into()),
+                RespDataType::BulkString(i.to_string().into()),
+            ]);
+            client.feed(ping).await.unwrap();
+        }
+        client.flush().await.unwrap();
+
+        for i in 0..1000 {
+            assert_eq!(
+                client.next().await.unwrap().unwrap(),
+                RespDataType::BulkString(i.to_string().into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_are_written_before_blpop_blocks() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        for args in [["SET", "k", "v"].as_slice(), &["BLPOP", "empty", "0"]] {
+            let frame = RespDataType::Array(
+                args.iter()
+                    .map(|arg| RespDataType::BulkString(arg.to_string().into()))
+                    .collect(),
+            );
+            client.feed(frame).await.unwrap();
+        }
+        client.flush().await.unwrap();
+
+        let reply = tokio::time::timeout(Duration::from_secs(1), client.next()).await;
+        assert_eq!(reply.unwrap().unwrap().unwrap(), ok());
+    }
+
+    #[tokio::test]
+    async fn test_rpush_on_a_string_and_get_on_a_list_are_refused() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        let wrongtype = RespDataType::SimpleError(crate::cmd::WRONGTYPE_ERROR.into());
+
+        send_cmd(&mut client, &["SET", "string", "v"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["RPUSH", "string", "a"]).await,
+            wrongtype
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "string"]).await,
+            RespDataType::BulkString("v".into())
+        );
+
+        send_cmd(&mut client, &["RPUSH", "list", "a"]).await;
+        assert_eq!(send_cmd(&mut client, &["GET", "list"]).await, wrongtype);
+        assert_eq!(
+            send_cmd(&mut client, &["LLEN", "list"]).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_echo_is_binary_safe() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        let payload = Bytes::from_static(b"a\x00b\xffc");
+        client
+            .send(RespDataType::Array(vec![
+                RespDataType::BulkString("ECHO".into()),
+                RespDataType::BulkString(payload.clone()),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            RespDataType::BulkString(payload)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_ping_with_message_echoes_it() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["PING", "hello"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["PING"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("hello".into()),
+                RespDataType::SimpleString("PONG".into()),
+            ])
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["PING", "hi"]).await,
+            RespDataType::BulkString("hi".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flushdb_removes_every_key() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "k", "v"]).await;
+        send_cmd(&mut client, &["RPUSH", "list", "a", "b"]).await;
+
+        assert_eq!(send_cmd(&mut client, &["FLUSHDB"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["LLEN", "list"]).await,
+            RespDataType::Integer(0)
+        );
+
+        send_cmd(&mut client, &["SET", "k", "v"]).await;
+        assert_eq!(send_cmd(&mut client, &["FLUSHALL", "async"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flushall_aborts_watching_transaction() {
+        let addr = spawn_server().await;
+        let mut watcher = connect(addr).await;
+        let mut writer = connect(addr).await;
+
+        send_cmd(&mut watcher, &["SET", "k", "1"]).await;
+        assert_eq!(send_cmd(&mut watcher, &["WATCH", "k"]).await, ok());
+        assert_eq!(send_cmd(&mut writer, &["FLUSHALL"]).await, ok());
+
+        assert_eq!(send_cmd(&mut watcher, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut watcher, &["INCR", "k"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut watcher, &["EXEC"]).await,
+            RespDataType::NullArray
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_list_is_independent() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["RPUSH", "src", "a", "b"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["COPY", "src", "dst"]).await,
+            RespDataType::Integer(1)
+        );
+
+        send_cmd(&mut client, &["RPUSH", "src", "c"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["LRANGE", "dst", "0", "-1"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("a".into()),
+                RespDataType::BulkString("b".into()),
+            ])
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["COPY", "missing", "other"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_requires_replace_to_overwrite() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "src", "new", "PX", "100000"]).await;
+        send_cmd(&mut client, &["SET", "dst", "old"]).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["COPY", "src", "dst"]).await,
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "dst"]).await,
+            RespDataType::BulkString("old".into())
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["COPY", "src", "dst", "REPLACE"]).await,
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "dst"]).await,
+            RespDataType::BulkString("new".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_carries_over_ttl() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "src", "v", "PX", "20"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["COPY", "src", "dst"]).await,
+            RespDataType::Integer(1)
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "dst"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_isolates_databases() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        let mut other = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["SELECT", "1"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+
+        // Other connections start on database 0
+        assert_eq!(
+            send_cmd(&mut other, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            send_cmd(&mut other, &["DBSIZE"]).await,
+            RespDataType::Integer(0)
+        );
+
+        assert_eq!(send_cmd(&mut client, &["SELECT", "0"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["SELECT", "16"]).await,
+            RespDataType::SimpleError("ERR DB index is out of range".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flushdb_only_clears_selected_database() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "k", "0"]).await;
+        send_cmd(&mut client, &["SELECT", "2"]).await;
+        send_cmd(&mut client, &["SET", "k", "2"]).await;
+
+        assert_eq!(send_cmd(&mut client, &["FLUSHDB"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(0)
+        );
+
+        send_cmd(&mut client, &["SELECT", "0"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("0".into())
+        );
+
+        assert_eq!(send_cmd(&mut client, &["FLUSHALL"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["DBSIZE"]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_inside_transaction() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SELECT", "3"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::Array(vec![ok(), ok()])
+        );
+
+        // The selection outlives the transaction
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+        send_cmd(&mut client, &["SELECT", "0"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    /// Waits until `n` replicas have completed their handshake with the master
+    async fn wait_for_replicas(server_info: &Arc<RwLock<ServerInfo>>, n: usize) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while server_info.read().unwrap().connected_slaves < n {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replica never connected");
+    }
+
+    /// Polls `GET key` until it returns a value
+    async fn wait_for_key(client: &mut Framed<TcpStream, RespCodec>, key: &str) -> RespDataType {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let reply = send_cmd(client, &["GET", key]).await;
+                if reply != RespDataType::NullBulkString {
+                    return reply;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{key} never reached the replica"))
+    }
+
+    #[tokio::test]
+    async fn test_master_propagates_writes_to_replica() {
+        let (master_addr, server_info) = spawn_server_with_info().await;
+        let replica_addr = spawn_replica(master_addr).await;
+        wait_for_replicas(&server_info, 1).await;
+
+        let mut client = connect(master_addr).await;
+        let mut blocked = connect(master_addr).await;
+        assert_eq!(send_cmd(&mut client, &["SET", "foo", "bar"]).await, ok());
+
+        // An element handed to a blocked client must be popped on the replica too
+        send_only(&mut blocked, &["BLPOP", "list", "0"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        send_cmd(&mut client, &["RPUSH", "list", "a", "b"]).await;
+        blocked.next().await.unwrap().unwrap();
+
+        assert_eq!(send_cmd(&mut client, &["SELECT", "2"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, ok());
+
+        let mut replica = connect(replica_addr).await;
+        assert_eq!(
+            wait_for_key(&mut replica, "foo").await,
+            RespDataType::BulkString("bar".into())
+        );
+        assert_eq!(send_cmd(&mut replica, &["SELECT", "2"]).await, ok());
+        assert_eq!(
+            wait_for_key(&mut replica, "k").await,
+            RespDataType::BulkString("v".into())
+        );
+        assert_eq!(send_cmd(&mut replica, &["SELECT", "0"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut replica, &["LRANGE", "list", "0", "-1"]).await,
+            RespDataType::Array(vec![RespDataType::BulkString("b".into())])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_replica_does_not_delay_writes() {
+        let (master_addr, server_info) = spawn_server_with_info().await;
+
+        // A replica that completes PSYNC and then stops reading from the socket
+        let mut stream = TcpStream::connect(master_addr).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+        wait_for_replicas(&server_info, 1).await;
+
+        // Enough data to fill the socket buffers several times over
+        let value = "x".repeat(64 * 1024);
+        let writes = 100;
+        let mut client = connect(master_addr).await;
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            for i in 0..writes {
+                assert_eq!(
+                    send_cmd(&mut client, &["SET", &format!("key:{i}"), &value]).await,
+                    ok()
+                );
+            }
+        })
+        .await
+        .expect("writes were held up by the replica");
+
+        let mut replica = Framed::new(stream, RespCodec::default());
+        let fullresync = replica.next().await.unwrap().unwrap();
+        assert!(matches!(fullresync, RespDataType::SimpleString(s) if s.starts_with("FULLRESYNC")));
+        replica.codec_mut().expect_rdb();
+        assert!(matches!(
+            replica.next().await.unwrap().unwrap(),
+            RespDataType::RdbFile(_)
+        ));
+
+        let mut received = 0;
+        while received < writes {
+            let frame = replica.next().await.unwrap().unwrap();
+            match Command::try_from(frame).unwrap() {
+                Command::SELECT { index: 0 } => {}
+                Command::SET { key, val, .. } => {
+                    assert_eq!(key, format!("key:{received}"));
+                    assert_eq!(val.len(), value.len());
+                    received += 1;
+                }
+                cmd => panic!("unexpected propagated command: {cmd:?}"),
+            }
+        }
+    }
+
+    /// Builds a `[kind, channel, last]` Pub/Sub confirmation or message frame
+    fn pubsub_reply(kind: &str, channel: &str, last: RespDataType) -> RespDataType {
+        RespDataType::Array(vec![
+            RespDataType::BulkString(kind.to_string().into()),
+            RespDataType::BulkString(channel.to_string().into()),
+            last,
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscribers() {
+        let addr = spawn_server().await;
+        let mut subscriber = connect(addr).await;
+        let mut publisher = connect(addr).await;
+
+        send_only(&mut subscriber, &["SUBSCRIBE", "news", "sport"]).await;
+        for (channel, count) in [("news", 1), ("sport", 2)] {
+            assert_eq!(
+                subscriber.next().await.unwrap().unwrap(),
+                pubsub_reply("subscribe", channel, RespDataType::Integer(count))
+            );
+        }
+
+        assert_eq!(
+            send_cmd(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            send_cmd(&mut publisher, &["PUBLISH", "weather", "rain"]).await,
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            subscriber.next().await.unwrap().unwrap(),
+            pubsub_reply("message", "news", RespDataType::BulkString("hello".into()))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_subscribe_confirmation_precedes_messages_when_pipelined() {
+        let addr = spawn_server().await;
+        let mut subscriber = connect(addr).await;
+
+        // Floods the channel with pipelined PUBLISH, fewer than a subscriber may fall
+        // behind by
+        let (mut replies, mut publishes) = TcpStream::connect(addr).await.unwrap().into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut replies, &mut tokio::io::sink()).await });
+        let publishing = tokio::spawn(async move {
+            let publish = b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$5\r\nhello\r\n".repeat(10);
+            for _ in 0..100 {
+                publishes.write_all(&publish).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        // The PINGs keep the connection busy while messages are published
+        let pings = 500;
+        let commands = std::iter::once(["SUBSCRIBE", "news"].as_slice())
+            .chain(std::iter::repeat_n(["PING"].as_slice(), pings));
+        for args in commands {
+            let frame = RespDataType::Array(
+                args.iter()
+                    .map(|arg| RespDataType::BulkString(arg.to_string().into()))
+                    .collect(),
+            );
+            subscriber.feed(frame).await.unwrap();
+        }
+        subscriber.flush().await.unwrap();
+
+        assert_eq!(
+            subscriber.next().await.unwrap().unwrap(),
+            pubsub_reply("subscribe", "news", RespDataType::Integer(1))
+        );
+        let pong = RespDataType::Array(vec![
+            RespDataType::BulkString("pong".into()),
+            RespDataType::BulkString("".into()),
+        ]);
+        let mut pongs = 0;
+        while pongs < pings {
+            if subscriber.next().await.unwrap().unwrap() == pong {
+                pongs += 1;
+            }
+        }
+        publishing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replies_and_messages_do_not_interleave_on_the_wire() {
+        let addr = spawn_server().await;
+        let mut subscriber = connect(addr).await;
+        let mut publisher = connect(addr).await;
+        send_cmd(&mut subscriber, &["SUBSCRIBE", "big"]).await;
+
+        // Large messages take several writes each, racing the confirmations below
+        let rounds = 20;
+        let payload = "x".repeat(64 * 1024);
+        let publishing = tokio::spawn({
+            let payload = payload.clone();
+            async move {
+                for _ in 0..rounds {
+                    send_cmd(&mut publisher, &["PUBLISH", "big", &payload]).await;
+                }
+            }
+        });
+        for i in 0..rounds {
+            send_only(&mut subscriber, &["SUBSCRIBE", &format!("channel:{i}")]).await;
+        }
+
+        let (mut messages, mut confirmations) = (0, 0);
+        while messages + confirmations < 2 * rounds {
+            match subscriber.next().await.unwrap().unwrap() {
+                RespDataType::Array(frame)
+                    if frame[0] == RespDataType::BulkString("message".into()) =>
+                {
+                    assert_eq!(frame[2], RespDataType::BulkString(payload.clone().into()));
+                    messages += 1;
+                }
+                confirmation => {
+                    let channel = format!("channel:{confirmations}");
+                    let count = RespDataType::Integer(confirmations as i64 + 2);
+                    assert_eq!(confirmation, pubsub_reply("subscribe", &channel, count));
+                    confirmations += 1;
+                }
+            }
+        }
+        publishing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_connection_rejects_regular_commands() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SUBSCRIBE", "news"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::SimpleError(
+                "ERR Can't execute 'get': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context"
+                    .into()
+            )
+        );
+
+        let pong = |msg: &str| {
+            RespDataType::Array(vec![
+                RespDataType::BulkString("pong".into()),
+                RespDataType::BulkString(msg.to_string().into()),
+            ])
+        };
+        assert_eq!(send_cmd(&mut client, &["PING"]).await, pong(""));
+        assert_eq!(send_cmd(&mut client, &["PING", "hi"]).await, pong("hi"));
+
+        // Leaving the last channel restores the regular command set
+        assert_eq!(
+            send_cmd(&mut client, &["UNSUBSCRIBE"]).await,
+            pubsub_reply("unsubscribe", "news", RespDataType::Integer(0))
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribed_connection_stops_receiving() {
+        let addr = spawn_server().await;
+        let mut subscriber = connect(addr).await;
+        let mut publisher = connect(addr).await;
+
+        send_cmd(&mut subscriber, &["SUBSCRIBE", "news"]).await;
+        send_cmd(&mut subscriber, &["UNSUBSCRIBE", "news"]).await;
+        assert_eq!(
+            send_cmd(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+            RespDataType::Integer(0)
+        );
+
+        // Disconnecting drops the subscriptions too
+        send_cmd(&mut subscriber, &["SUBSCRIBE", "news"]).await;
+        drop(subscriber);
+        let mut receivers = 1;
+        for _ in 0..50 {
+            receivers = match send_cmd(&mut publisher, &["PUBLISH", "news", "hello"]).await {
+                RespDataType::Integer(n) => n,
+                other => panic!("unexpected reply: {other:?}"),
+            };
+            if receivers == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(receivers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_config_get_known_parameter() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(&mut client, &["CONFIG", "GET", "maxmem*"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("maxmemory".into()),
+                RespDataType::BulkString("0".into()),
+                RespDataType::BulkString("maxmemory-policy".into()),
+                RespDataType::BulkString("noeviction".into()),
+                RespDataType::BulkString("maxmemory-samples".into()),
+                RespDataType::BulkString("5".into()),
+            ])
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["CONFIG", "GET", "nonexistent"]).await,
+            RespDataType::Array(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_set_is_seen_by_other_connections() {
+        let addr = spawn_server().await;
+        let mut writer = connect(addr).await;
+        let mut reader = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(&mut writer, &["CONFIG", "SET", "appendonly", "yes"]).await,
+            ok()
+        );
+        assert_eq!(
+            send_cmd(&mut reader, &["CONFIG", "GET", "appendonly"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("appendonly".into()),
+                RespDataType::BulkString("yes".into()),
+            ])
+        );
+        assert!(matches!(
+            send_cmd(&mut writer, &["CONFIG", "SET", "nonexistent", "1"]).await,
+            RespDataType::SimpleError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_saved_keyspace_is_loaded_on_startup() {
+        let config = test_config(None);
+        let path = Path::new(&config.dir).join(&config.dbfilename);
+        let dbfilename = config.dbfilename.clone();
+        let (addr, _) = spawn_server_with_config(config).await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "greeting", "hello"]).await;
+        send_cmd(&mut client, &["SET", "session", "abc", "PX", "100000"]).await;
+        send_cmd(&mut client, &["RPUSH", "queue", "a", "b"]).await;
+        send_cmd(&mut client, &["SELECT", "2"]).await;
+        send_cmd(&mut client, &["SET", "other", "db"]).await;
+        assert_eq!(send_cmd(&mut client, &["SAVE"]).await, ok());
+        send_cmd(&mut client, &["FLUSHALL"]).await;
+
+        let restarted = ServerConfig {
+            dbfilename,
+            ..test_config(None)
+        };
+        let (addr, _) = spawn_server_with_config(restarted).await;
+        let mut client = connect(addr).await;
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "greeting"]).await,
+            RespDataType::BulkString("hello".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "session"]).await,
+            RespDataType::BulkString("abc".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["LRANGE", "queue", "0", "-1"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("a".into()),
+                RespDataType::BulkString("b".into()),
+            ])
+        );
+        send_cmd(&mut client, &["SELECT", "2"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "other"]).await,
+            RespDataType::BulkString("db".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections_and_saves() {
+        let config = test_config(None);
+        let path = Path::new(&config.dir).join(&config.dbfilename);
+        let server = RedisServer::new(config).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let run = tokio::spawn(server.run());
+
+        let mut client = connect(addr).await;
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, ok());
+
+        shutdown.shutdown();
+        let result = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("run returns once shut down")
+            .unwrap();
+        assert!(result.is_ok());
+
+        // The idle connection was closed and the listener no longer accepts
+        assert!(client.next().await.is_none());
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        let entries = rdb::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "k");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_nosave_exits_without_saving() {
+        let config = test_config(None);
+        let path = Path::new(&config.dir).join(&config.dbfilename);
+        let server = RedisServer::new(config).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let run = tokio::spawn(server.run());
+
+        let mut client = connect(addr).await;
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, ok());
+        send_only(&mut client, &["SHUTDOWN", "NOSAVE"]).await;
+
+        let result = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("run returns once shut down")
+            .unwrap();
+        assert!(result.is_ok());
+        // No reply: the connection just closes
+        assert!(client.next().await.is_none());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_client_id_and_name() {
+        let addr = spawn_server().await;
+        let mut first = connect(addr).await;
+        let mut second = connect(addr).await;
+
+        let RespDataType::Integer(first_id) = send_cmd(&mut first, &["CLIENT", "ID"]).await else {
+            panic!("expected an integer id");
+        };
+        let RespDataType::Integer(second_id) = send_cmd(&mut second, &["client", "id"]).await
+        else {
+            panic!("expected an integer id");
+        };
+        assert_ne!(first_id, second_id);
+
+        assert_eq!(
+            send_cmd(&mut first, &["CLIENT", "GETNAME"]).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            send_cmd(&mut first, &["CLIENT", "SETNAME", "worker-1"]).await,
+            ok()
+        );
+        assert_eq!(
+            send_cmd(&mut first, &["CLIENT", "GETNAME"]).await,
+            RespDataType::BulkString("worker-1".into())
+        );
+        assert_eq!(
+            send_cmd(&mut second, &["CLIENT", "GETNAME"]).await,
+            RespDataType::NullBulkString
+        );
+        assert!(matches!(
+            send_cmd(&mut first, &["CLIENT", "SETNAME", "has space"]).await,
+            RespDataType::SimpleError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_list_shows_every_connection() {
+        let addr = spawn_server().await;
+        let mut first = connect(addr).await;
+        let mut second = connect(addr).await;
+        let mut third = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut first, &["CLIENT", "SETNAME", "first"]).await,
+            ok()
+        );
+        send_cmd(&mut second, &["PING"]).await;
+
+        let RespDataType::BulkString(list) = send_cmd(&mut third, &["CLIENT", "LIST"]).await else {
+            panic!("expected a bulk string");
+        };
+        let list = String::from_utf8(list.to_vec()).unwrap();
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains(" name=first ") && lines[0].ends_with("cmd=client"));
+        assert!(lines[1].contains(" name= ") && lines[1].ends_with("cmd=ping"));
+        assert!(lines[2].ends_with("cmd=client"));
+        for line in lines {
+            assert!(line.starts_with("id=") && line.contains(" addr=127.0.0.1:"));
+            assert!(line.contains(" age=0 "));
+        }
+
+        // Closed connections leave the registry
+        drop(first);
+        drop(second);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let RespDataType::BulkString(list) = send_cmd(&mut third, &["CLIENT", "LIST"]).await else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(list.iter().filter(|b| **b == b'\n').count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_kill_closes_the_connection() {
+        let addr = spawn_server().await;
+        let mut killer = connect(addr).await;
+        let mut by_id = connect(addr).await;
+        let mut by_addr = connect(addr).await;
+
+        let RespDataType::Integer(id) = send_cmd(&mut by_id, &["CLIENT", "ID"]).await else {
+            panic!("expected an integer id");
+        };
+        let id = id.to_string();
+        assert_eq!(
+            send_cmd(&mut killer, &["CLIENT", "KILL", "ID", &id]).await,
+            RespDataType::Integer(1)
+        );
+        assert!(by_id.next().await.is_none());
+        assert_eq!(
+            send_cmd(&mut killer, &["CLIENT", "KILL", "ID", &id]).await,
+            RespDataType::Integer(0)
+        );
+
+        let peer = by_addr.get_ref().local_addr().unwrap().to_string();
+        assert_eq!(
+            send_cmd(&mut killer, &["CLIENT", "KILL", "ADDR", &peer]).await,
+            RespDataType::Integer(1)
+        );
+        assert!(by_addr.next().await.is_none());
+
+        // A connection never kills itself
+        let own = killer.get_ref().local_addr().unwrap().to_string();
+        assert_eq!(
+            send_cmd(&mut killer, &["CLIENT", "KILL", "ADDR", &own]).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_is_closed_after_timeout() {
+        let addr = spawn_server().await;
+        let mut idle = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut idle, &["CONFIG", "SET", "timeout", "10"]).await,
+            ok()
+        );
+        let started = tokio::time::Instant::now();
+
+        // Activity restarts the countdown
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert_eq!(
+            send_cmd(&mut idle, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+
+        // Still open past the first deadline, closed once 10s passed since the PING
+        let waited = tokio::time::timeout(Duration::from_millis(9900), idle.next()).await;
+        assert!(waited.is_err(), "closed before its timeout");
+        assert!(idle.next().await.is_none());
+        assert!(started.elapsed() >= Duration::from_secs(18));
+    }
+
+    #[tokio::test]
+    async fn test_maxclients_rejects_extra_connections() {
+        let addr = spawn_server().await;
+        let mut first = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut first, &["CONFIG", "SET", "maxclients", "2"]).await,
+            ok()
+        );
+        let mut second = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut second, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+
+        let mut third = connect(addr).await;
+        assert_eq!(
+            third.next().await.unwrap().unwrap(),
+            RespDataType::SimpleError("ERR max number of clients reached".into())
+        );
+        assert!(third.next().await.is_none());
+        assert_eq!(
+            send_cmd(&mut second, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_requirepass() {
+        let addr = spawn_server().await;
+        let mut admin = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut admin, &["AUTH", "secret"]).await,
+            RespDataType::SimpleError("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".into())
+        );
+        assert_eq!(
+            send_cmd(&mut admin, &["CONFIG", "SET", "requirepass", "secret"]).await,
+            ok()
+        );
+        // Connections accepted before the password was set stay authenticated
+        assert_eq!(send_cmd(&mut admin, &["SET", "k", "v"]).await, ok());
+
+        let mut client = connect(addr).await;
+        let noauth = RespDataType::SimpleError("NOAUTH Authentication required.".into());
+        assert_eq!(send_cmd(&mut client, &["GET", "k"]).await, noauth);
+        assert_eq!(
+            send_cmd(&mut client, &["PING"]).await,
+            RespDataType::SimpleString("PONG".into())
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["AUTH", "wrong"]).await,
+            RespDataType::SimpleError(
+                "WRONGPASS invalid username-password pair or user is disabled.".into()
+            )
+        );
+        assert_eq!(send_cmd(&mut client, &["GET", "k"]).await, noauth);
+
+        assert_eq!(send_cmd(&mut client, &["AUTH", "secret"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_clears_the_connection_state() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+        let reset = RespDataType::SimpleString("RESET".into());
+
+        assert_eq!(send_cmd(&mut client, &["SELECT", "1"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["MULTI"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v"]).await, queued());
+        assert_eq!(send_cmd(&mut client, &["RESET"]).await, reset);
+        assert_eq!(
+            send_cmd(&mut client, &["EXEC"]).await,
+            RespDataType::SimpleError("ERR EXEC without MULTI".into())
+        );
+        // Back on database 0, where nothing was written
+        assert_eq!(send_cmd(&mut client, &["SET", "k", "v0"]).await, ok());
+        assert_eq!(send_cmd(&mut client, &["SELECT", "1"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::NullBulkString
+        );
+
+        send_cmd(&mut client, &["SUBSCRIBE", "news"]).await;
+        assert_eq!(send_cmd(&mut client, &["RESET"]).await, reset);
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v0".into())
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["CONFIG", "SET", "requirepass", "secret"]).await,
+            ok()
+        );
+        assert_eq!(send_cmd(&mut client, &["RESET"]).await, reset);
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::SimpleError("NOAUTH Authentication required.".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acl_user_denied_writes() {
+        let addr = spawn_server().await;
+        let mut admin = connect(addr).await;
+        assert_eq!(send_cmd(&mut admin, &["SET", "k", "v"]).await, ok());
+        assert_eq!(
+            send_cmd(
+                &mut admin,
+                &["ACL", "SETUSER", "reader", "on", ">pw", "+@read", "+acl"]
+            )
+            .await,
+            ok()
+        );
+
+        let mut client = connect(addr).await;
+        assert_eq!(
+            send_cmd(&mut client, &["AUTH", "reader", "wrong"]).await,
+            RespDataType::SimpleError(
+                "WRONGPASS invalid username-password pair or user is disabled.".into()
+            )
+        );
+        assert_eq!(send_cmd(&mut client, &["AUTH", "reader", "pw"]).await, ok());
+        assert_eq!(
+            send_cmd(&mut client, &["ACL", "WHOAMI"]).await,
+            RespDataType::BulkString("reader".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["SET", "k", "w"]).await,
+            RespDataType::SimpleError(
+                "NOPERM User reader has no permissions to run the 'set' command".into()
+            )
+        );
+        assert_eq!(
+            send_cmd(&mut admin, &["GET", "k"]).await,
+            RespDataType::BulkString("v".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bgsave_replies_before_writing() {
+        let config = test_config(None);
+        let path = Path::new(&config.dir).join(&config.dbfilename);
+        let (addr, server_info) = spawn_server_with_config(config).await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "k", "v"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["BGSAVE"]).await,
+            RespDataType::SimpleString("Background saving started".into())
+        );
+
+        for _ in 0..100 {
+            if !server_info.read().unwrap().rdb_bgsave_in_progress {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let entries = rdb::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "k");
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_recovered_from_the_aof() {
+        let config = ServerConfig {
+            appendonly: true,
+            appendfsync: AppendFsync::Always,
+            ..test_config(None)
+        };
+        let path = Path::new(&config.dir).join(&config.appendfilename);
+        let appendfilename = config.appendfilename.clone();
+        let (addr, _) = spawn_server_with_config(config).await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "greeting", "hello"]).await;
+        send_cmd(&mut client, &["SET", "gone", "soon"]).await;
+        send_cmd(&mut client, &["DEL", "gone"]).await;
+        send_cmd(&mut client, &["SELECT", "1"]).await;
+        send_cmd(&mut client, &["RPUSH", "queue", "a", "b", "c"]).await;
+        send_cmd(&mut client, &["LPOP", "queue"]).await;
+        // Reads are not logged
+        send_cmd(&mut client, &["GET", "greeting"]).await;
+
+        let restarted = ServerConfig {
+            appendonly: true,
+            appendfilename,
+            ..test_config(None)
+        };
+        let (addr, _) = spawn_server_with_config(restarted).await;
+        let mut client = connect(addr).await;
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "greeting"]).await,
+            RespDataType::BulkString("hello".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "gone"]).await,
+            RespDataType::NullBulkString
+        );
+        send_cmd(&mut client, &["SELECT", "1"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["LRANGE", "queue", "0", "-1"]).await,
+            RespDataType::Array(vec![
+                RespDataType::BulkString("b".into()),
+                RespDataType::BulkString("c".into()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expiries_survive_an_aof_replay() {
+        let config = ServerConfig {
+            appendonly: true,
+            appendfsync: AppendFsync::Always,
+            ..test_config(None)
+        };
+        let path = Path::new(&config.dir).join(&config.appendfilename);
+        let appendfilename = config.appendfilename.clone();
+        let (addr, _) = spawn_server_with_config(config).await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "short", "v", "PX", "50"]).await;
+        send_cmd(&mut client, &["SET", "lapsing", "v", "PX", "200"]).await;
+        send_cmd(&mut client, &["SET", "kept", "v"]).await;
+        send_cmd(&mut client, &["EXPIRE", "kept", "100"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Finding the key expired logs its deletion
+        assert_eq!(
+            send_cmd(&mut client, &["GET", "short"]).await,
+            RespDataType::NullBulkString
+        );
+
+        let logged = std::fs::read_to_string(&path).unwrap();
+        assert!(logged.contains("PXAT"));
+        assert!(logged.contains("PEXPIREAT"));
+        assert!(logged.contains("DEL"));
+        assert!(!logged.contains("EXPIRE\r\n"));
+
+        // `lapsing` expires while the server is down
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let restarted = ServerConfig {
+            appendonly: true,
+            appendfilename,
+            ..test_config(None)
+        };
+        let (addr, _) = spawn_server_with_config(restarted).await;
+        let mut client = connect(addr).await;
+        std::fs::remove_file(path).unwrap();
+
+        for key in ["short", "lapsing"] {
+            assert_eq!(
+                send_cmd(&mut client, &["GET", key]).await,
+                RespDataType::NullBulkString
+            );
+        }
+        let RespDataType::Integer(ttl) = send_cmd(&mut client, &["TTL", "kept"]).await else {
+            panic!("TTL must reply an integer");
+        };
+        assert!((99..=100).contains(&ttl));
+    }
+
+    #[tokio::test]
+    async fn test_debug_sleep_only_delays_its_connection() {
+        let addr = spawn_server().await;
+        let mut sleeper = connect(addr).await;
+        let mut other = connect(addr).await;
+
+        send_only(&mut sleeper, &["DEBUG", "SLEEP", "0.5"]).await;
+        let started = tokio::time::Instant::now();
+        assert_eq!(send_cmd(&mut other, &["SET", "k", "v"]).await, ok());
+        assert!(started.elapsed() < std::time::Duration::from_millis(250));
+
+        assert_eq!(sleeper.next().await.unwrap().unwrap(), ok());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_debug_object() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["SET", "k", "hello"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["DEBUG", "OBJECT", "k"]).await,
+            RespDataType::SimpleString(
+                "refcount:1 encoding:embstr serializedlength:6 lru_seconds_idle:0".into()
+            )
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["DEBUG", "OBJECT", "missing"]).await,
+            RespDataType::SimpleError("ERR no such key".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_set_list_max_listpack_size_changes_encoding() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        send_cmd(&mut client, &["RPUSH", "list", "a", "b", "c"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["OBJECT", "ENCODING", "list"]).await,
+            RespDataType::BulkString("listpack".into())
+        );
+
+        assert_eq!(
+            send_cmd(
+                &mut client,
+                &["CONFIG", "SET", "list-max-listpack-size", "3"]
+            )
+            .await,
+            ok()
+        );
+        let RespDataType::Array(limits) =
+            send_cmd(&mut client, &["DEBUG", "LISTPACK-ENTRIES"]).await
+        else {
+            panic!("DEBUG LISTPACK-ENTRIES must reply with an array");
+        };
+        assert_eq!(limits[1], RespDataType::Integer(3));
+        assert_eq!(limits[3], RespDataType::Integer(3));
+
+        send_cmd(&mut client, &["RPUSH", "list", "d"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["OBJECT", "ENCODING", "list"]).await,
+            RespDataType::BulkString("quicklist".into())
+        );
+        assert!(matches!(
+            send_cmd(
+                &mut client,
+                &["CONFIG", "SET", "list-max-listpack-size", "-6"]
+            )
+            .await,
+            RespDataType::SimpleError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_config_set_list_max_len_caps_pushes() {
+        let addr = spawn_server().await;
+        let mut client = connect(addr).await;
+
+        assert_eq!(
+            send_cmd(
+                &mut client,
+                &["CONFIG", "SET", "list-max-listpack-size", "2"]
+            )
+            .await,
+            ok()
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["CONFIG", "SET", "list-max-len", "4"]).await,
+            ok()
+        );
+        send_cmd(&mut client, &["RPUSH", "list", "a", "b"]).await;
+        assert_eq!(
+            send_cmd(&mut client, &["OBJECT", "ENCODING", "list"]).await,
+            RespDataType::BulkString("listpack".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["LPUSH", "list", "c", "d"]).await,
+            RespDataType::Integer(4)
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["OBJECT", "ENCODING", "list"]).await,
+            RespDataType::BulkString("quicklist".into())
+        );
+
+        assert_eq!(
+            send_cmd(&mut client, &["RPUSH", "list", "e"]).await,
+            RespDataType::SimpleError("ERR list would exceed list-max-len of 4 elements".into())
+        );
+        assert_eq!(
+            send_cmd(&mut client, &["LLEN", "list"]).await,
+            RespDataType::Integer(4)
+        );
+    }
 }
@@ -1,85 +1,1469 @@
-use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    oneshot,
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::{Instant, MissedTickBehavior},
+};
+
+use anyhow::bail;
+use tracing::error;
+
 use crate::{
-    cmd::Command,
-    data_structures::{list::Lists, strings::Strings},
+    aof::{Aof, AppendFsync},
+    cmd::{Command, DebugSubcommand, ListEnd, ObjectSubcommand, SetCondition, WRONGTYPE_ERROR},
+    data_structures::{
+        list::{listpack_limits, Lists, DEFAULT_LIST_MAX_LISTPACK_SIZE},
+        strings::Strings,
+        zset::SortedSets,
+    },
+    glob::glob_match,
+    pubsub::{EventClass, KeyspaceEvents, PubSub},
+    rdb::{self, RdbEntry, RdbValue},
     resp::RespDataType,
+    rng::Rng,
+    server::ServerInfo,
 };
 
+const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Number of keys SCAN returns per call when no COUNT is given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Number of logical databases, selectable with SELECT.
+pub const NUM_DATABASES: usize = 16;
+
+/// How often the actor looks for expired keys that were never accessed again.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+/// How many expired keys the actor reaps at most per database and sweep.
+pub const DEFAULT_SWEEP_SAMPLE_SIZE: usize = 20;
+
+/// How often writes are flushed to the AOF under the `everysec` policy.
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes a key is assumed to take besides its name and contents, for `maxmemory`.
+const KEY_OVERHEAD: usize = 64;
+
+/// Default `maxmemory-samples`: keys of each database sampled per eviction.
+pub const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+/// Best eviction candidates kept between evictions, like Redis' `EVPOOL_SIZE`.
+const EVICTION_POOL_SIZE: usize = 16;
+
+/// Which keys are evicted once `maxmemory` is exceeded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// Nothing is evicted, commands that would take more memory are refused instead
+    #[default]
+    NoEviction,
+    /// The least recently accessed keys of any database go first
+    AllkeysLru,
+    /// The least frequently accessed keys of any database go first
+    AllkeysLfu,
+}
+
+impl FromStr for MaxmemoryPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "noeviction" => Ok(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Ok(MaxmemoryPolicy::AllkeysLru),
+            "allkeys-lfu" => Ok(MaxmemoryPolicy::AllkeysLfu),
+            _ => bail!("Invalid maxmemory policy '{s}'"),
+        }
+    }
+}
+
+impl std::fmt::Display for MaxmemoryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+        })
+    }
+}
+
+/// Settings of the storage actor.
+pub struct StorageConfig {
+    /// How often keys whose TTL elapsed are looked for
+    pub sweep_interval: Duration,
+    /// How many expired keys are reaped at most per database and sweep
+    pub sweep_sample_size: usize,
+    /// Where keyspace notifications are published
+    pub pubsub: PubSub,
+    /// Which keyspace notifications are published
+    pub keyspace_events: KeyspaceEvents,
+    /// Where writes are appended, if the AOF is enabled
+    pub aof: Option<Aof>,
+    /// Initial `list-max-listpack-size`
+    pub list_max_listpack_size: i64,
+    /// Seed of the random numbers behind RANDOMKEY, taken from the clock if unset
+    pub random_seed: Option<u64>,
+    /// Where the replication offset is advanced as writes are propagated, if anywhere
+    pub server_info: Option<Arc<RwLock<ServerInfo>>>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            sweep_sample_size: DEFAULT_SWEEP_SAMPLE_SIZE,
+            pubsub: PubSub::default(),
+            keyspace_events: KeyspaceEvents::default(),
+            aof: None,
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            random_seed: None,
+            server_info: None,
+        }
+    }
+}
+
+/// Figures INFO reports about the dataset.
+pub struct StorageStats {
+    /// Bytes the live keys of every database are estimated to take
+    pub used_memory: usize,
+    /// Number of live keys, and of those with a TTL, of each database
+    pub keyspace: Vec<(usize, usize)>,
+}
+
 struct StorageActor {
-    string_store: Strings,
-    list_store: Lists,
-    cmd_rx: UnboundedReceiver<StorageCommand>,
+    databases: Vec<Keyspace>,
+    /// Streams of the connected replicas, fed with every write in execution order
+    replicas: Vec<UnboundedSender<RespDataType>>,
+    /// Database last selected on the replication stream. `None` forces a SELECT
+    /// before the next propagated write, e.g. after a replica joins.
+    replication_db: Option<usize>,
+    /// Holds `master_repl_offset`, advanced by the size of every propagated frame
+    server_info: Option<Arc<RwLock<ServerInfo>>>,
+    sweep_interval: Duration,
+    sweep_sample_size: usize,
+    /// Whether the sweep runs, turned off by DEBUG SET-ACTIVE-EXPIRE 0
+    active_expire: bool,
+    pubsub: PubSub,
+    keyspace_events: KeyspaceEvents,
+    aof: Option<Aof>,
+    /// Bytes the keys may take before some get evicted, 0 meaning no limit
+    maxmemory: u64,
+    maxmemory_policy: MaxmemoryPolicy,
+    /// Keys of each database sampled to refill `eviction_pool`
+    maxmemory_samples: usize,
+    /// Best eviction candidates sampled so far, with their rank and database, best
+    /// first. Ranks go stale as keys are accessed, so they are checked before evicting.
+    eviction_pool: Vec<((u64, Instant), usize, String)>,
+    cmd_rx: UnboundedReceiver<StorageRequest>,
 }
 
 impl StorageActor {
-    pub fn new(cmd_rx: UnboundedReceiver<StorageCommand>) -> Self {
+    pub fn new(cmd_rx: UnboundedReceiver<StorageRequest>, config: StorageConfig) -> Self {
+        let keyspace = |db: usize| {
+            let mut keyspace = Keyspace {
+                keyspace_events: config.keyspace_events,
+                rng: config
+                    .random_seed
+                    .map(|seed| Rng::new(seed.wrapping_add(db as u64)))
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            keyspace
+                .list_store
+                .set_max_listpack_size(config.list_max_listpack_size);
+            keyspace
+        };
+
         Self {
-            string_store: Strings::default(),
-            list_store: Lists::default(),
+            databases: (0..NUM_DATABASES).map(keyspace).collect(),
+            replicas: Vec::new(),
+            replication_db: None,
+            server_info: config.server_info,
+            sweep_interval: config.sweep_interval,
+            sweep_sample_size: config.sweep_sample_size,
+            active_expire: true,
+            pubsub: config.pubsub,
+            keyspace_events: config.keyspace_events,
+            aof: config.aof,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::default(),
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            eviction_pool: Vec::new(),
             cmd_rx,
         }
     }
 
+    /// Reaps keys whose TTL elapsed, so that keys nobody reads again still free memory.
+    fn sweep_expired(&mut self) {
+        for db in 0..self.databases.len() {
+            self.databases[db].sweep_expired(self.sweep_sample_size);
+            self.propagate_expired(db);
+            self.publish_events(db);
+        }
+    }
+
+    /// Publishes the keyspace events raised in database `db`.
+    fn publish_events(&mut self, db: usize) {
+        for (class, event, key) in std::mem::take(&mut self.databases[db].events) {
+            self.keyspace_events
+                .notify(&self.pubsub, class, event, &key, db);
+        }
+    }
+
+    /// Evicts keys until they take less than `maxmemory`, if the policy allows it, each
+    /// eviction being propagated as a DEL. Returns whether the keys fit in `maxmemory`.
+    /// Like Redis, the victim is the best of a pool of sampled keys, not of every key.
+    fn evict_to_maxmemory(&mut self) -> bool {
+        if self.maxmemory == 0 {
+            return true;
+        }
+        let policy = self.maxmemory_policy;
+        while self
+            .databases
+            .iter()
+            .map(Keyspace::used_memory)
+            .sum::<usize>() as u64
+            > self.maxmemory
+        {
+            // The budget alone answers noeviction, nothing is sampled
+            if policy == MaxmemoryPolicy::NoEviction {
+                return false;
+            }
+            if self.eviction_pool.is_empty() {
+                self.fill_eviction_pool();
+                // Reaping the expired keys sampled may have made enough room
+                continue;
+            }
+
+            let (rank, db, key) = self.eviction_pool.remove(0);
+            // The key may have been accessed, deleted or overwritten since it was sampled
+            if self.databases[db].eviction_rank(&key, policy) != Some(rank) {
+                continue;
+            }
+            self.databases[db].evict(&key);
+            self.propagate(db, del_frame(key));
+            self.publish_events(db);
+        }
+        true
+    }
+
+    /// Adds `maxmemory-samples` keys of every database to the eviction pool, ranked by
+    /// the policy, keeping the best `EVICTION_POOL_SIZE` candidates. Sampled keys whose
+    /// TTL elapsed are reaped instead.
+    fn fill_eviction_pool(&mut self) {
+        let policy = self.maxmemory_policy;
+        for db in 0..self.databases.len() {
+            for key in self.databases[db].sample_keys(self.maxmemory_samples) {
+                self.databases[db].remove_if_expired(&key);
+                let Some(rank) = self.databases[db].eviction_rank(&key, policy) else {
+                    continue;
+                };
+                if self
+                    .eviction_pool
+                    .iter()
+                    .any(|(_, pooled_db, pooled)| *pooled_db == db && *pooled == key)
+                {
+                    continue;
+                }
+                let position = self
+                    .eviction_pool
+                    .partition_point(|(pooled, ..)| *pooled <= rank);
+                if position < EVICTION_POOL_SIZE {
+                    self.eviction_pool.insert(position, (rank, db, key));
+                    self.eviction_pool.truncate(EVICTION_POOL_SIZE);
+                }
+            }
+            self.propagate_expired(db);
+            self.publish_events(db);
+        }
+    }
+
+    /// Propagates a DEL for every key of database `db` whose TTL elapsed since last
+    /// time, so that the AOF and the replicas drop them too.
+    fn propagate_expired(&mut self, db: usize) {
+        for key in std::mem::take(&mut self.databases[db].expired_keys) {
+            self.propagate(db, del_frame(key));
+        }
+    }
+
+    /// Appends `frame`, executed against database `db`, to the AOF and hands it to
+    /// every replica without waiting for them, dropping the replicas that went away.
+    fn propagate(&mut self, db: usize, frame: RespDataType) {
+        if let Some(aof) = &mut self.aof {
+            if let Err(e) = aof.append(db, &frame) {
+                error!("Failed to append to the AOF: {:#}", e);
+            }
+        }
+
+        if self.replicas.is_empty() {
+            return;
+        }
+
+        if self.replication_db != Some(db) {
+            let select = RespDataType::Array(vec![
+                RespDataType::BulkString("SELECT".into()),
+                RespDataType::BulkString(db.to_string().into()),
+            ]);
+            self.advance_repl_offset(&select);
+            self.replicas.retain(|tx| tx.send(select.clone()).is_ok());
+            self.replication_db = Some(db);
+        }
+        self.advance_repl_offset(&frame);
+        self.replicas.retain(|tx| tx.send(frame.clone()).is_ok());
+    }
+
+    /// Moves the replication offset past `frame`, as encoded on the replication stream.
+    fn advance_repl_offset(&self, frame: &RespDataType) {
+        if let Some(server_info) = &self.server_info {
+            server_info.write().unwrap().master_repl_offset += frame.as_bytes().len();
+        }
+    }
+
     async fn run(mut self) {
-        while let Some((cmd, response_tx)) = self.cmd_rx.recv().await {
-            match cmd {
-                Command::SET { key, val, px } => {
-                    let response = self.string_store.set(key, val, px);
-                    let _ = response_tx.send(response);
+        let mut sweep = tokio::time::interval(self.sweep_interval);
+        sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut aof_fsync = tokio::time::interval(AOF_FSYNC_INTERVAL);
+        let fsync_every_second = self
+            .aof
+            .as_ref()
+            .is_some_and(|aof| aof.fsync == AppendFsync::Everysec);
+
+        loop {
+            let request = tokio::select! {
+                request = self.cmd_rx.recv() => request,
+                _ = sweep.tick(), if self.active_expire => {
+                    self.sweep_expired();
+                    continue;
                 }
-                Command::GET { key } => {
-                    let response = self.string_store.get(&key);
-                    let _ = response_tx.send(response);
+                _ = aof_fsync.tick(), if fsync_every_second => {
+                    if let Some(Err(e)) = self.aof.as_mut().map(Aof::fsync) {
+                        error!("{:#}", e);
+                    }
+                    continue;
                 }
-                Command::LLEN { key } => {
-                    let response = self.list_store.get_list_len(&key);
-                    let _ = response_tx.send(response);
+            };
+            let Some(request) = request else {
+                break;
+            };
+
+            match request {
+                StorageRequest::Command(db, cmd, frame, response_tx) => {
+                    if cmd.denies_oom() && !self.evict_to_maxmemory() {
+                        let _ = response_tx.send(RespDataType::SimpleError(OOM_ERROR.into()));
+                        continue;
+                    }
+                    let written = cmd.is_write();
+                    let frame = frame
+                        .filter(|_| written)
+                        .map(|frame| with_absolute_expiry(&cmd, frame));
+                    match cmd {
+                        Command::FLUSHALL => {
+                            self.databases.iter_mut().for_each(Keyspace::clear_all);
+                            let _ = response_tx.send(RespDataType::SimpleString("OK".into()));
+                        }
+                        Command::DEBUG {
+                            subcommand: DebugSubcommand::SetActiveExpire(enabled),
+                        } => {
+                            self.active_expire = enabled;
+                            let _ = response_tx.send(RespDataType::SimpleString("OK".into()));
+                        }
+                        cmd => self.databases[db].execute(cmd, response_tx),
+                    }
+
+                    // Keys the command found expired are deleted before it is replayed
+                    self.propagate_expired(db);
+                    if let Some(frame) = frame {
+                        self.propagate(db, frame);
+                    }
+                    // Elements handed to blocked clients are propagated as plain pops
+                    for key in std::mem::take(&mut self.databases[db].blocked_pops) {
+                        let lpop = RespDataType::Array(vec![
+                            RespDataType::BulkString("LPOP".into()),
+                            RespDataType::BulkString(key.into()),
+                        ]);
+                        self.propagate(db, lpop);
+                    }
+                    self.publish_events(db);
+                    if written {
+                        self.evict_to_maxmemory();
+                    }
+                }
+                StorageRequest::AddReplica(replica_tx) => {
+                    self.replicas.push(replica_tx);
+                    self.replication_db = None;
+                }
+                StorageRequest::Snapshot(response_tx) => {
+                    let entries = self
+                        .databases
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(db, keyspace)| keyspace.snapshot(db))
+                        .collect();
+                    let _ = response_tx.send(entries);
+                }
+                StorageRequest::Restore(entries, response_tx) => {
+                    for entry in entries {
+                        if let Some(keyspace) = self.databases.get_mut(entry.db) {
+                            keyspace.restore(entry);
+                        }
+                    }
+                    let _ = response_tx.send(());
+                }
+                StorageRequest::FsyncAof(response_tx) => {
+                    if let Some(Err(e)) = self.aof.as_mut().map(Aof::fsync) {
+                        error!("{:#}", e);
+                    }
+                    let _ = response_tx.send(());
+                }
+                StorageRequest::SetListMaxListpackSize(size) => {
+                    for keyspace in &mut self.databases {
+                        keyspace.list_store.set_max_listpack_size(size);
+                    }
                 }
-                Command::LPUSH { key, elements } => {
-                    let response = self.list_store.lpush(key.clone(), elements); // Clone key for pending check
-                    let _ = response_tx.send(response);
+                StorageRequest::SetMaxmemory(maxmemory, policy) => {
+                    self.maxmemory = maxmemory;
+                    self.maxmemory_policy = policy;
+                    // Candidates are ranked by the policy
+                    self.eviction_pool.clear();
+                    for keyspace in &mut self.databases {
+                        keyspace.lfu = policy == MaxmemoryPolicy::AllkeysLfu;
+                    }
+                    self.evict_to_maxmemory();
                 }
-                Command::RPUSH { key, elements } => {
-                    let response = self.list_store.rpush(key.clone(), elements); // Clone key for pending check
-                    let _ = response_tx.send(response);
+                StorageRequest::SetListMaxLen(max_len) => {
+                    for keyspace in &mut self.databases {
+                        keyspace.list_store.set_max_len(max_len);
+                    }
                 }
-                Command::LRANGE { key, start, stop } => {
-                    let response = self.list_store.lrange(&key, start, stop);
-                    let _ = response_tx.send(response);
+                StorageRequest::SetMaxmemorySamples(samples) => {
+                    self.maxmemory_samples = samples;
                 }
-                Command::LPOP { key, count } => {
-                    let response = self.list_store.left_pop(&key, count);
-                    let _ = response_tx.send(response);
+                StorageRequest::Stats(response_tx) => {
+                    let _ = response_tx.send(StorageStats {
+                        used_memory: self.databases.iter().map(Keyspace::used_memory).sum(),
+                        keyspace: self.databases.iter().map(Keyspace::key_counts).collect(),
+                    });
                 }
-                Command::BLPOP {
-                    keys: _,
-                    timeout: _,
-                } => {
-                    unimplemented!()
+                StorageRequest::Versions(keys, response_tx) => {
+                    let mut versions = Vec::with_capacity(keys.len());
+                    for (db, key) in &keys {
+                        versions.push(self.databases[*db].version(key));
+                        self.propagate_expired(*db);
+                        self.publish_events(*db);
+                    }
+                    let _ = response_tx.send(versions);
                 }
-                Command::INCR { key } => {
-                    let response = self.string_store.increment(key);
-                    let _ = response_tx.send(response);
+                StorageRequest::Watch(keys, response_tx) => {
+                    let mut versions = Vec::with_capacity(keys.len());
+                    for (db, key) in &keys {
+                        versions.push(self.databases[*db].watch(key));
+                        self.propagate_expired(*db);
+                        self.publish_events(*db);
+                    }
+                    let _ = response_tx.send(versions);
                 }
-                // Command::MULTI => {
-                //     let _ = response_tx.send(RespDataType::SimpleString("OK".into()));
-                // }
-                _ => {
-                    let _ = response_tx
-                        .send(RespDataType::SimpleError("Unsupported command".to_string()));
+                StorageRequest::Unwatch(keys) => {
+                    for (db, key) in &keys {
+                        self.databases[*db].unwatch(key);
+                    }
                 }
             }
         }
     }
 }
 
+/// Type of the value stored at a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyType {
+    String,
+    List,
+    SortedSet,
+}
+
+/// Version of a watched key, along with the number of WATCHes on it.
+#[derive(Default)]
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
+}
+
+/// The keys of a single logical database.
+#[derive(Default)]
+struct Keyspace {
+    string_store: Strings,
+    list_store: Lists,
+    zset_store: SortedSets,
+    /// Last-write version of every watched key, regardless of its type, dropped once
+    /// nobody watches it. Used by WATCH to detect concurrent writes.
+    watched: HashMap<String, WatchedKey>,
+    next_version: u64,
+    /// Keys popped on behalf of BLPOP clients by the last command, one per element
+    blocked_pops: Vec<String>,
+    /// Keys whose TTL elapsed since last propagated, waiting for their DEL
+    expired_keys: Vec<String>,
+    keyspace_events: KeyspaceEvents,
+    /// Keyspace events raised by the last command, waiting to be published
+    events: Vec<(EventClass, &'static str, String)>,
+    rng: Rng,
+    /// Whether the `maxmemory-policy` is LFU, which OBJECT FREQ requires
+    lfu: bool,
+}
+
+impl Keyspace {
+    /// Marks `key` as modified so that any client watching it aborts its EXEC.
+    fn bump_version(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            self.next_version += 1;
+            watched.version = self.next_version;
+        }
+    }
+
+    /// Records a keyspace event on `key`, if events of `class` are enabled.
+    fn notify(&mut self, class: EventClass, event: &'static str, key: &str) {
+        if self.keyspace_events.is_enabled(class) {
+            self.events.push((class, event, key.to_string()));
+        }
+    }
+
+    /// Marks `key` as modified because its TTL elapsed.
+    fn expired(&mut self, key: &str) {
+        self.expired_keys.push(key.to_string());
+        self.bump_version(key);
+        self.notify(EventClass::Expired, "expired", key);
+    }
+
+    /// Reaps up to `sample_size` expired keys of each store.
+    fn sweep_expired(&mut self, sample_size: usize) {
+        let mut reaped = self.string_store.sweep_expired(sample_size);
+        reaped.extend(self.list_store.sweep_expired(sample_size));
+        reaped.extend(self.zset_store.sweep_expired(sample_size));
+        for key in reaped {
+            self.expired(&key);
+        }
+    }
+
+    /// Reaps `key` if its TTL elapsed, so that expiring counts as a modification.
+    fn remove_if_expired(&mut self, key: &str) {
+        // Only one store holds the key, but all must be checked
+        let in_strings = self.string_store.remove_if_expired(key);
+        let in_lists = self.list_store.remove_if_expired(key);
+        let in_zsets = self.zset_store.remove_if_expired(key);
+        if in_strings || in_lists || in_zsets {
+            self.expired(key);
+        }
+    }
+
+    /// Returns the deadline of the live value at `key`, `Some(None)` if it has no TTL.
+    fn expiry(&self, key: &str) -> Option<Option<Instant>> {
+        self.string_store
+            .expiry(key)
+            .or_else(|| self.list_store.expiry(key))
+            .or_else(|| self.zset_store.expiry(key))
+    }
+
+    /// Replies with when `key` expires: its remaining TTL, or its deadline as Unix time
+    /// when `absolute`, in seconds or in milliseconds. -1 when it has no TTL and -2 when
+    /// it is missing.
+    fn expiry_reply(&mut self, key: &str, absolute: bool, millis: bool) -> RespDataType {
+        self.remove_if_expired(key);
+        let ms = match self.expiry(key) {
+            None => return RespDataType::Integer(-2),
+            Some(None) => return RespDataType::Integer(-1),
+            Some(Some(deadline)) if absolute => unix_ms(deadline),
+            Some(Some(deadline)) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis() as u64,
+        };
+        // Like Redis, a TTL in seconds is rounded to the nearest while a deadline is
+        // truncated
+        let reply = match (millis, absolute) {
+            (true, _) => ms,
+            (false, true) => ms / 1000,
+            (false, false) => (ms + 500) / 1000,
+        };
+        RespDataType::Integer(reply as i64)
+    }
+
+    /// Gives `key` a TTL of `ttl_ms` milliseconds, deleting it right away when not
+    /// positive, and replies 1, or 0 when `key` is missing. `command` names the command
+    /// in the error replied when the deadline is out of range.
+    fn expire(&mut self, key: &str, ttl_ms: i64, command: &str) -> RespDataType {
+        self.remove_if_expired(key);
+        if ttl_ms <= 0 {
+            if !self.remove(key) {
+                return RespDataType::Integer(0);
+            }
+            self.bump_version(key);
+            self.notify(EventClass::Generic, "del", key);
+            return RespDataType::Integer(1);
+        }
+
+        let Some(deadline) = Instant::now().checked_add(Duration::from_millis(ttl_ms as u64))
+        else {
+            return RespDataType::SimpleError(format!(
+                "ERR invalid expire time in '{command}' command"
+            ));
+        };
+        let set = self.string_store.set_expiry(key, Some(deadline))
+            || self.list_store.set_expiry(key, Some(deadline))
+            || self.zset_store.set_expiry(key, Some(deadline));
+        if !set {
+            return RespDataType::Integer(0);
+        }
+        self.bump_version(key);
+        self.notify(EventClass::Generic, "expire", key);
+        RespDataType::Integer(1)
+    }
+
+    /// Serves the clients blocked on `key`, recording the pops.
+    fn serve_blocked(&mut self, key: &str) {
+        let served = self.list_store.serve_blocked(key);
+        for _ in 0..served {
+            self.blocked_pops.push(key.to_string());
+            self.notify(EventClass::List, "lpop", key);
+        }
+    }
+
+    /// Returns the current version of `key`, first reaping it if its TTL elapsed so
+    /// that expiring counts as a modification. Keys nobody watches have version 0.
+    fn version(&mut self, key: &str) -> u64 {
+        self.remove_if_expired(key);
+        self.watched.get(key).map_or(0, |watched| watched.version)
+    }
+
+    /// Starts tracking the writes to `key` on behalf of one more WATCH, returning its
+    /// current version.
+    fn watch(&mut self, key: &str) -> u64 {
+        self.remove_if_expired(key);
+        let watched = self.watched.entry(key.to_string()).or_default();
+        watched.watchers += 1;
+        watched.version
+    }
+
+    /// Drops one WATCH of `key`, forgetting its version once nobody watches it.
+    fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Describes the value at `key` the way DEBUG OBJECT does.
+    fn debug_object(&self, key: &str) -> RespDataType {
+        let (value, encoding, idle) = if let Some(data) = self.string_store.peek(key) {
+            (
+                RdbValue::String(data.to_vec()),
+                self.string_store.encoding(key),
+                self.string_store.idle_time(key),
+            )
+        } else if let Some(elements) = self.list_store.peek(key) {
+            (
+                RdbValue::List(elements.iter().cloned().collect()),
+                self.list_store.encoding(key),
+                self.list_store.idle_time(key),
+            )
+        } else if let Some(members) = self.zset_store.peek(key) {
+            (
+                RdbValue::SortedSet(members),
+                self.zset_store.encoding(key),
+                self.zset_store.idle_time(key),
+            )
+        } else {
+            return RespDataType::SimpleError("ERR no such key".into());
+        };
+
+        RespDataType::SimpleString(format!(
+            "refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+            encoding.unwrap_or_default(),
+            rdb::serialized_len(&value),
+            idle.unwrap_or_default().as_secs()
+        ))
+    }
+
+    /// Returns the live keys of every store as snapshot entries of database `db`.
+    fn snapshot(&self, db: usize) -> Vec<RdbEntry> {
+        let strings = self
+            .string_store
+            .entries()
+            .map(|(key, data, expires_at)| RdbEntry {
+                db,
+                key: key.clone(),
+                value: RdbValue::String(data.to_vec()),
+                expires_at_ms: expires_at.map(unix_ms),
+            });
+        let lists = self
+            .list_store
+            .entries()
+            .map(|(key, elements, expires_at)| RdbEntry {
+                db,
+                key: key.clone(),
+                value: RdbValue::List(elements.iter().cloned().collect()),
+                expires_at_ms: expires_at.map(unix_ms),
+            });
+        let zsets = self
+            .zset_store
+            .entries()
+            .map(|(key, members, expires_at)| RdbEntry {
+                db,
+                key: key.clone(),
+                value: RdbValue::SortedSet(members),
+                expires_at_ms: expires_at.map(unix_ms),
+            });
+        strings.chain(lists).chain(zsets).collect()
+    }
+
+    /// Stores a key loaded from a snapshot, unless its deadline already passed.
+    fn restore(&mut self, entry: RdbEntry) {
+        let expires_at = match entry.expires_at_ms {
+            Some(at) => match at.checked_sub(unix_ms(Instant::now())) {
+                Some(ttl) if ttl > 0 => Some(Instant::now() + Duration::from_millis(ttl)),
+                _ => return,
+            },
+            None => None,
+        };
+        match entry.value {
+            RdbValue::String(data) => self.string_store.restore(entry.key, data, expires_at),
+            RdbValue::List(elements) => self.list_store.restore(entry.key, elements, expires_at),
+            RdbValue::SortedSet(members) => self.zset_store.restore(entry.key, members, expires_at),
+        }
+    }
+
+    /// Removes `key` from every store, returning whether it held a value.
+    fn remove(&mut self, key: &str) -> bool {
+        // Every store must be cleared, so avoid short-circuiting
+        let in_strings = self.string_store.remove(key);
+        let in_lists = self.list_store.remove(key);
+        let in_zsets = self.zset_store.remove(key);
+        in_strings || in_lists || in_zsets
+    }
+
+    /// Returns the bytes the keys are estimated to take, kept up to date by the stores.
+    /// Keys whose TTL elapsed count until they are reaped.
+    fn used_memory(&self) -> usize {
+        self.string_store.used_memory()
+            + self.list_store.used_memory()
+            + self.zset_store.used_memory()
+            + KEY_OVERHEAD * self.key_count()
+    }
+
+    /// Returns the number of keys, including those whose TTL elapsed but that were not
+    /// reaped yet.
+    fn key_count(&self) -> usize {
+        self.string_store.key_count() + self.list_store.key_count() + self.zset_store.key_count()
+    }
+
+    /// Returns the key at `position`, in `0..key_count()`, the stores being laid end to
+    /// end.
+    fn key_at(&self, position: usize) -> Option<&String> {
+        let strings = self.string_store.key_count();
+        let lists = self.list_store.key_count();
+        if position < strings {
+            self.string_store.key_at(position)
+        } else if position < strings + lists {
+            self.list_store.key_at(position - strings)
+        } else {
+            self.zset_store.key_at(position - strings - lists)
+        }
+    }
+
+    /// Picks `count` distinct keys at random, or every key when there are not that many.
+    /// Keys whose TTL elapsed may be among them.
+    fn sample_keys(&mut self, count: usize) -> Vec<String> {
+        let total = self.key_count();
+        let positions: BTreeSet<usize> = if total <= count {
+            (0..total).collect()
+        } else {
+            let mut positions = BTreeSet::new();
+            while positions.len() < count {
+                positions.insert(self.rng.below(total));
+            }
+            positions
+        };
+        positions
+            .into_iter()
+            .filter_map(|position| self.key_at(position).cloned())
+            .collect()
+    }
+
+    /// Returns the number of live keys, and of those with a TTL.
+    fn key_counts(&self) -> (usize, usize) {
+        self.string_store
+            .keys()
+            .chain(self.list_store.keys())
+            .chain(self.zset_store.keys())
+            .fold((0, 0), |(keys, expires), key| {
+                let volatile = matches!(self.expiry(key), Some(Some(_)));
+                (keys + 1, expires + volatile as usize)
+            })
+    }
+
+    /// Returns the rank of the live `key` under `policy`, the lowest being evicted
+    /// first: its last access time under LRU, and its access count under LFU, the least
+    /// recently accessed key breaking ties. Nothing is evicted under `noeviction`.
+    fn eviction_rank(&self, key: &str, policy: MaxmemoryPolicy) -> Option<(u64, Instant)> {
+        let (count, last_accessed) = self
+            .string_store
+            .access(key)
+            .or_else(|| self.list_store.access(key))
+            .or_else(|| self.zset_store.access(key))?;
+        match policy {
+            MaxmemoryPolicy::NoEviction => None,
+            MaxmemoryPolicy::AllkeysLru => Some((0, last_accessed)),
+            MaxmemoryPolicy::AllkeysLfu => Some((count, last_accessed)),
+        }
+    }
+
+    /// Evicts `key`.
+    fn evict(&mut self, key: &str) {
+        self.remove(key);
+        self.bump_version(key);
+        self.notify(EventClass::Evicted, "evicted", key);
+    }
+
+    /// Removes every key from every store, marking them as modified for WATCH.
+    fn clear_all(&mut self) {
+        let keys: Vec<String> = self
+            .string_store
+            .keys()
+            .chain(self.list_store.keys())
+            .chain(self.zset_store.keys())
+            .cloned()
+            .collect();
+        for key in &keys {
+            self.bump_version(key);
+        }
+
+        self.string_store.clear();
+        self.list_store.clear();
+        self.zset_store.clear();
+    }
+
+    /// Duplicates the value at `src` into `dst`, replying 1 on success and 0 when `src`
+    /// is missing or `dst` already exists and `replace` is not set.
+    fn copy(&mut self, src: &str, dst: &str, replace: bool) -> RespDataType {
+        if src == dst {
+            return RespDataType::SimpleError(
+                "ERR source and destination objects are the same".into(),
+            );
+        }
+
+        if self.key_type(src).is_none() || (self.key_type(dst).is_some() && !replace) {
+            return RespDataType::Integer(0);
+        }
+
+        self.remove(dst);
+        if self.list_store.copy(src, dst) {
+            self.serve_blocked(dst);
+        } else if !self.zset_store.copy(src, dst) {
+            self.string_store.copy(src, dst);
+        }
+        self.bump_version(dst);
+        self.notify(EventClass::Generic, "copy_to", dst);
+
+        RespDataType::Integer(1)
+    }
+
+    /// Returns the keys of the buckets visited from `cursor` until at least `count` keys
+    /// are collected, along with the cursor to resume from (0 once every bucket has been
+    /// visited).
+    ///
+    /// Keys are spread over a virtual table of `2^k` buckets by the low bits of their
+    /// hash, `2^k` being the number of keys rounded up to a power of two. Like in Redis,
+    /// buckets are visited in reverse-binary order: the cursor increments the high bits of
+    /// the bucket index first, so the buckets visited before the table grows or shrinks
+    /// map onto a prefix of the new order. Keys present for the whole scan are returned
+    /// exactly once while the keyspace grows, and at least once when it shrinks.
+    ///
+    /// The stores do not expose their buckets, so a call walks every key but only keeps
+    /// the buckets it returns. `pattern` only filters the keys of the batch, so a call may
+    /// return fewer keys than `count`.
+    fn scan(&self, cursor: u64, pattern: Option<&str>, count: usize) -> RespDataType {
+        let keys = || {
+            self.string_store
+                .keys()
+                .chain(self.list_store.keys())
+                .chain(self.zset_store.keys())
+        };
+        let mask = keys().count().next_power_of_two() as u64 - 1;
+        let start = (cursor & mask).reverse_bits();
+
+        // Buckets keyed by their place in the iteration order. Trailing buckets are
+        // dropped as soon as the ones before them hold `count` keys on their own.
+        let mut buckets: BTreeMap<u64, Vec<&String>> = BTreeMap::new();
+        let mut kept = 0;
+        for key in keys() {
+            let position = (scan_position(key) & mask).reverse_bits();
+            if position < start {
+                continue;
+            }
+            buckets.entry(position).or_default().push(key);
+            kept += 1;
+            while let Some(last) = buckets.last_entry() {
+                if kept - last.get().len() < count {
+                    break;
+                }
+                kept -= last.remove().len();
+            }
+        }
+
+        let next_cursor = match buckets.last_key_value() {
+            Some((last, _)) if kept >= count => {
+                (last | !mask.reverse_bits()).wrapping_add(1).reverse_bits()
+            }
+            _ => 0,
+        };
+
+        let batch = buckets
+            .into_values()
+            .flatten()
+            .filter(|key| pattern.is_none_or(|p| glob_match(p.as_bytes(), key.as_bytes())))
+            .map(|key| RespDataType::BulkString(key.to_string().into()))
+            .collect();
+
+        RespDataType::Array(vec![
+            RespDataType::BulkString(next_cursor.to_string().into()),
+            RespDataType::Array(batch),
+        ])
+    }
+
+    /// Replies with a key picked uniformly among the live keys of every store, or nil
+    /// when there is none. Picked keys whose TTL elapsed are reaped before picking again.
+    fn random_key(&mut self) -> RespDataType {
+        while self.key_count() > 0 {
+            let position = self.rng.below(self.key_count());
+            let Some(key) = self.key_at(position).cloned() else {
+                break;
+            };
+            self.remove_if_expired(&key);
+            if self.key_type(&key).is_some() {
+                return RespDataType::BulkString(key.into());
+            }
+        }
+        RespDataType::NullBulkString
+    }
+
+    /// Returns the type of the live value at `key`.
+    fn key_type(&self, key: &str) -> Option<KeyType> {
+        if self.string_store.contains(key) {
+            Some(KeyType::String)
+        } else if self.list_store.contains(key) {
+            Some(KeyType::List)
+        } else if self.zset_store.contains(key) {
+            Some(KeyType::SortedSet)
+        } else {
+            None
+        }
+    }
+
+    /// Whether one of the keys of `cmd` holds a value of another type than the one the
+    /// command operates on. Missing keys are never of the wrong type.
+    fn is_wrong_type(&self, cmd: &Command) -> bool {
+        let (expected, keys) = match cmd {
+            Command::GET { key }
+            | Command::INCR { key }
+            | Command::APPEND { key, .. }
+            | Command::STRLEN { key }
+            | Command::SETRANGE { key, .. }
+            | Command::SETBIT { key, .. }
+            | Command::GETBIT { key, .. }
+            | Command::BITCOUNT { key, .. } => (KeyType::String, std::slice::from_ref(key)),
+            Command::BITOP { sources, .. } => (KeyType::String, sources.as_slice()),
+            Command::LPUSH { key, .. }
+            | Command::RPUSH { key, .. }
+            | Command::LRANGE { key, .. }
+            | Command::LLEN { key }
+            | Command::SORT { key, .. }
+            | Command::LPOS { key, .. }
+            | Command::LPOP { key, .. } => (KeyType::List, std::slice::from_ref(key)),
+            Command::BLPOP { keys, .. } | Command::LMPOP { keys, .. } => {
+                (KeyType::List, keys.as_slice())
+            }
+            Command::ZADD { key, .. }
+            | Command::ZSCORE { key, .. }
+            | Command::ZCARD { key }
+            | Command::ZCOUNT { key, .. }
+            | Command::ZINCRBY { key, .. }
+            | Command::ZREM { key, .. }
+            | Command::ZRANGE { key, .. }
+            | Command::ZREVRANGE { key, .. }
+            | Command::ZRANGEBYSCORE { key, .. }
+            | Command::ZRANK { key, .. }
+            | Command::ZREVRANK { key, .. } => (KeyType::SortedSet, std::slice::from_ref(key)),
+            _ => return false,
+        };
+        keys.iter()
+            .any(|key| self.key_type(key).is_some_and(|found| found != expected))
+    }
+
+    /// Runs `cmd` against this database and sends its reply on `response_tx`.
+    fn execute(&mut self, cmd: Command, response_tx: oneshot::Sender<RespDataType>) {
+        if self.is_wrong_type(&cmd) {
+            let _ = response_tx.send(RespDataType::SimpleError(WRONGTYPE_ERROR.into()));
+            return;
+        }
+
+        match cmd {
+            Command::SET {
+                key,
+                val,
+                px,
+                condition,
+                get,
+                keep_ttl,
+            } => {
+                // SET overwrites a value of any type, but GET can only return a string
+                let holds_other = self
+                    .key_type(&key)
+                    .is_some_and(|found| found != KeyType::String);
+                if holds_other && get {
+                    let _ = response_tx.send(RespDataType::SimpleError(WRONGTYPE_ERROR.into()));
+                    return;
+                }
+
+                let exists = holds_other || self.string_store.contains(&key);
+                let written = match condition {
+                    Some(SetCondition::NX) => !exists,
+                    Some(SetCondition::XX) => exists,
+                    None => true,
+                };
+                if written {
+                    self.notify(EventClass::String, "set", &key);
+                    self.bump_version(&key);
+                }
+
+                let response = if !holds_other {
+                    self.string_store
+                        .set(key, val, px, condition, get, keep_ttl)
+                } else if written {
+                    self.remove(&key);
+                    self.string_store.set(key, val, px, None, false, false)
+                } else {
+                    RespDataType::NullBulkString
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::GET { key } => {
+                self.remove_if_expired(&key);
+                let response = self.string_store.get(&key);
+                let _ = response_tx.send(response);
+            }
+            Command::LLEN { key } => {
+                let response = self.list_store.get_list_len(&key);
+                let _ = response_tx.send(response);
+            }
+            Command::LPUSH { key, elements } => {
+                let response = self.list_store.lpush(key.clone(), elements); // Clone key for pending check
+                if let RespDataType::Integer(_) = response {
+                    self.bump_version(&key);
+                    self.notify(EventClass::List, "lpush", &key);
+                }
+                let _ = response_tx.send(response);
+                self.serve_blocked(&key);
+            }
+            Command::RPUSH { key, elements } => {
+                let response = self.list_store.rpush(key.clone(), elements); // Clone key for pending check
+                if let RespDataType::Integer(_) = response {
+                    self.bump_version(&key);
+                    self.notify(EventClass::List, "rpush", &key);
+                }
+                let _ = response_tx.send(response);
+                self.serve_blocked(&key);
+            }
+            Command::LPOS {
+                key,
+                element,
+                rank,
+                count,
+            } => {
+                let response = self.list_store.lpos(&key, &element, rank, count);
+                let _ = response_tx.send(response);
+            }
+            Command::SORT {
+                key,
+                alpha,
+                desc,
+                limit,
+            } => {
+                let response = self.list_store.sort(&key, alpha, desc, limit);
+                let _ = response_tx.send(response);
+            }
+            Command::LRANGE { key, start, stop } => {
+                let response = self.list_store.lrange(&key, start, stop);
+                let _ = response_tx.send(response);
+            }
+            Command::LPOP { key, count } => {
+                let response = self.list_store.left_pop(&key, count);
+                let popped = match &response {
+                    RespDataType::BulkString(_) => true,
+                    RespDataType::Array(elements) => !elements.is_empty(),
+                    _ => false,
+                };
+                if popped {
+                    self.bump_version(&key);
+                    self.notify(EventClass::List, "lpop", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::LMPOP { keys, end, count } => {
+                let response = match self.list_store.multi_pop(&keys, end, count) {
+                    Some((key, elements)) => {
+                        self.bump_version(&key);
+                        let event = match end {
+                            ListEnd::Left => "lpop",
+                            ListEnd::Right => "rpop",
+                        };
+                        self.notify(EventClass::List, event, &key);
+                        RespDataType::Array(vec![
+                            RespDataType::BulkString(key.into()),
+                            RespDataType::Array(
+                                elements
+                                    .into_iter()
+                                    .map(|element| RespDataType::BulkString(element.into()))
+                                    .collect(),
+                            ),
+                        ])
+                    }
+                    None => RespDataType::NullArray,
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::BLPOP { keys, timeout: _ } => {
+                // The timeout is enforced by the caller, which stops waiting for the reply
+                if let Some(key) = self.list_store.blocking_left_pop(keys, response_tx) {
+                    self.bump_version(&key);
+                    self.notify(EventClass::List, "lpop", &key);
+                    self.blocked_pops.push(key);
+                }
+            }
+            Command::DEBUG {
+                subcommand: DebugSubcommand::PurgeExpired,
+            } => {
+                let mut reaped = self.string_store.purge_expired();
+                reaped.extend(self.list_store.purge_expired());
+                reaped.extend(self.zset_store.purge_expired());
+                for key in &reaped {
+                    self.expired(key);
+                }
+                let _ = response_tx.send(RespDataType::Integer(reaped.len() as i64));
+            }
+            Command::DEBUG {
+                subcommand: DebugSubcommand::ListpackEntries,
+            } => {
+                let size = self.list_store.max_listpack_size();
+                let (max_entries, max_bytes) = listpack_limits(size);
+                let limit = |max: Option<usize>| {
+                    max.map_or(RespDataType::NullBulkString, |max| {
+                        RespDataType::Integer(max as i64)
+                    })
+                };
+                let _ = response_tx.send(RespDataType::Array(vec![
+                    RespDataType::BulkString("list-max-listpack-size".into()),
+                    RespDataType::Integer(size),
+                    RespDataType::BulkString("max-entries".into()),
+                    limit(max_entries),
+                    RespDataType::BulkString("max-bytes".into()),
+                    limit(max_bytes),
+                ]));
+            }
+            Command::DEBUG {
+                subcommand: DebugSubcommand::Object(key),
+            } => {
+                let _ = response_tx.send(self.debug_object(&key));
+            }
+            Command::SCAN {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let count = count.unwrap_or(DEFAULT_SCAN_COUNT);
+                let response = self.scan(cursor, pattern.as_deref(), count);
+                let _ = response_tx.send(response);
+            }
+            Command::DEL { keys } | Command::UNLINK { keys } => {
+                let mut removed = 0;
+                for key in &keys {
+                    if self.remove(key) {
+                        self.bump_version(key);
+                        self.notify(EventClass::Generic, "del", key);
+                        removed += 1;
+                    }
+                }
+                let _ = response_tx.send(RespDataType::Integer(removed));
+            }
+            Command::EXPIRE { key, seconds } => {
+                // The parser keeps the TTL within milliseconds
+                let response = self.expire(&key, seconds * 1000, "expire");
+                let _ = response_tx.send(response);
+            }
+            Command::PEXPIREAT { key, unix_ms } => {
+                let ttl_ms = unix_ms.saturating_sub(unix_ms_now() as i64);
+                let response = self.expire(&key, ttl_ms, "pexpireat");
+                let _ = response_tx.send(response);
+            }
+            Command::TTL { key } => {
+                let response = self.expiry_reply(&key, false, false);
+                let _ = response_tx.send(response);
+            }
+            Command::EXPIRETIME { key } => {
+                let response = self.expiry_reply(&key, true, false);
+                let _ = response_tx.send(response);
+            }
+            Command::PEXPIRETIME { key } => {
+                let response = self.expiry_reply(&key, true, true);
+                let _ = response_tx.send(response);
+            }
+            Command::TOUCH { keys } => {
+                let touched = keys
+                    .iter()
+                    .filter(|key| {
+                        self.string_store.mark_accessed(key)
+                            || self.list_store.mark_accessed(key)
+                            || self.zset_store.mark_accessed(key)
+                    })
+                    .count();
+                let _ = response_tx.send(RespDataType::Integer(touched as i64));
+            }
+            Command::FLUSHDB => {
+                self.clear_all();
+                let _ = response_tx.send(RespDataType::SimpleString("OK".into()));
+            }
+            Command::COPY { src, dst, replace } => {
+                let response = self.copy(&src, &dst, replace);
+                let _ = response_tx.send(response);
+            }
+            Command::OBJECT {
+                subcommand: ObjectSubcommand::Encoding(key),
+            } => {
+                let encoding = self
+                    .string_store
+                    .encoding(&key)
+                    .or_else(|| self.list_store.encoding(&key))
+                    .or_else(|| self.zset_store.encoding(&key));
+                let response = match encoding {
+                    Some(encoding) => RespDataType::BulkString(encoding.into()),
+                    None => RespDataType::SimpleError("ERR no such key".into()),
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::OBJECT {
+                subcommand: ObjectSubcommand::IdleTime(key),
+            } => {
+                let idle_time = self
+                    .string_store
+                    .idle_time(&key)
+                    .or_else(|| self.list_store.idle_time(&key))
+                    .or_else(|| self.zset_store.idle_time(&key));
+                let response = match idle_time {
+                    Some(idle) => RespDataType::Integer(idle.as_secs() as i64),
+                    None => RespDataType::SimpleError("ERR no such key".into()),
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::OBJECT {
+                subcommand: ObjectSubcommand::RefCount(key),
+            } => {
+                let response = if self.key_type(&key).is_some() {
+                    RespDataType::Integer(1)
+                } else {
+                    RespDataType::SimpleError("ERR no such key".into())
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::OBJECT {
+                subcommand: ObjectSubcommand::Freq(key),
+            } => {
+                let frequency = self
+                    .string_store
+                    .frequency(&key)
+                    .or_else(|| self.list_store.frequency(&key))
+                    .or_else(|| self.zset_store.frequency(&key));
+                let response = match frequency {
+                    _ if !self.lfu => RespDataType::SimpleError(
+                        "ERR An LFU maxmemory policy is not selected, access frequency not tracked."
+                            .into(),
+                    ),
+                    Some(count) => RespDataType::Integer(count as i64),
+                    None => RespDataType::SimpleError("ERR no such key".into()),
+                };
+                let _ = response_tx.send(response);
+            }
+            Command::RANDOMKEY => {
+                let _ = response_tx.send(self.random_key());
+            }
+            Command::DBSIZE => {
+                let size = self.string_store.keys().count()
+                    + self.list_store.keys().count()
+                    + self.zset_store.keys().count();
+                let _ = response_tx.send(RespDataType::Integer(size as i64));
+            }
+            Command::APPEND { key, value } => {
+                let response = self.string_store.append(key.clone(), &value);
+                if let RespDataType::Integer(_) = response {
+                    self.bump_version(&key);
+                    self.notify(EventClass::String, "append", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::STRLEN { key } => {
+                let response = self.string_store.len(&key);
+                let _ = response_tx.send(response);
+            }
+            Command::SETRANGE { key, offset, value } => {
+                let response = self.string_store.set_range(key.clone(), offset, &value);
+                if matches!(response, RespDataType::Integer(_)) && !value.is_empty() {
+                    self.bump_version(&key);
+                    self.notify(EventClass::String, "setrange", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::SETBIT { key, offset, value } => {
+                let response = self.string_store.set_bit(key.clone(), offset, value);
+                if let RespDataType::Integer(_) = response {
+                    self.bump_version(&key);
+                    self.notify(EventClass::String, "setbit", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::BITOP { op, dest, sources } => {
+                // Like SET, the result replaces a value of any type
+                let existed = self.key_type(&dest).is_some();
+                self.list_store.remove(&dest);
+                self.zset_store.remove(&dest);
+                let response = self.string_store.bit_op(op, dest.clone(), &sources);
+                let stored = response != RespDataType::Integer(0);
+                if stored || existed {
+                    self.bump_version(&dest);
+                }
+                if stored {
+                    self.notify(EventClass::String, "set", &dest);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::BITCOUNT { key, range } => {
+                let response = self.string_store.bit_count(&key, range);
+                let _ = response_tx.send(response);
+            }
+            Command::GETBIT { key, offset } => {
+                let response = self.string_store.get_bit(&key, offset);
+                let _ = response_tx.send(response);
+            }
+            Command::ZADD {
+                key,
+                condition,
+                comparison,
+                members,
+            } => {
+                let (response, changed) =
+                    self.zset_store
+                        .add(key.clone(), members, condition, comparison);
+                if changed {
+                    self.bump_version(&key);
+                    self.notify(EventClass::SortedSet, "zadd", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::ZINCRBY { key, delta, member } => {
+                let response = self.zset_store.increment(key.clone(), delta, member);
+                if !matches!(response, RespDataType::SimpleError(_)) {
+                    self.bump_version(&key);
+                    self.notify(EventClass::SortedSet, "zincr", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::ZREM { key, members } => {
+                let response = self.zset_store.remove_members(&key, &members);
+                if response != RespDataType::Integer(0) {
+                    self.bump_version(&key);
+                    self.notify(EventClass::SortedSet, "zrem", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            Command::ZSCORE { key, member } => {
+                let response = self.zset_store.score(&key, &member);
+                let _ = response_tx.send(response);
+            }
+            Command::ZCARD { key } => {
+                let response = self.zset_store.card(&key);
+                let _ = response_tx.send(response);
+            }
+            Command::ZCOUNT { key, min, max } => {
+                let response = self.zset_store.count(&key, min, max);
+                let _ = response_tx.send(response);
+            }
+            Command::ZRANGE {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => {
+                let response = self.zset_store.range(&key, start, stop, false, with_scores);
+                let _ = response_tx.send(response);
+            }
+            Command::ZREVRANGE {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => {
+                let response = self.zset_store.range(&key, start, stop, true, with_scores);
+                let _ = response_tx.send(response);
+            }
+            Command::ZRANGEBYSCORE {
+                key,
+                min,
+                max,
+                with_scores,
+                limit,
+            } => {
+                let response = self
+                    .zset_store
+                    .range_by_score(&key, min, max, with_scores, limit);
+                let _ = response_tx.send(response);
+            }
+            Command::ZRANK { key, member } => {
+                let response = self.zset_store.rank(&key, &member, false);
+                let _ = response_tx.send(response);
+            }
+            Command::ZREVRANK { key, member } => {
+                let response = self.zset_store.rank(&key, &member, true);
+                let _ = response_tx.send(response);
+            }
+            Command::INCR { key } => {
+                let response = self.string_store.increment(key.clone());
+                if let RespDataType::Integer(_) = response {
+                    self.bump_version(&key);
+                    self.notify(EventClass::String, "incrby", &key);
+                }
+                let _ = response_tx.send(response);
+            }
+            // Command::MULTI => {
+            //     let _ = response_tx.send(RespDataType::SimpleString("OK".into()));
+            // }
+            _ => {
+                let _ =
+                    response_tx.send(RespDataType::SimpleError("Unsupported command".to_string()));
+            }
+        }
+    }
+}
+
+/// Hash of `key` whose low bits pick its SCAN bucket.
+fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Clone)]
 pub struct StorageHandle {
-    cmd_tx: UnboundedSender<StorageCommand>,
+    cmd_tx: UnboundedSender<StorageRequest>,
 }
 
 impl Default for StorageHandle {
@@ -88,19 +1472,1113 @@ impl Default for StorageHandle {
     }
 }
 
-type StorageCommand = (Command, oneshot::Sender<RespDataType>);
+enum StorageRequest {
+    /// Run a command against the given database, along with the frame to propagate to
+    /// replicas if it turns out to be a write
+    Command(
+        usize,
+        Command,
+        Option<RespDataType>,
+        oneshot::Sender<RespDataType>,
+    ),
+    /// Start feeding a new replica with the writes executed from now on
+    AddReplica(UnboundedSender<RespDataType>),
+    /// Collect every live key of every database
+    Snapshot(oneshot::Sender<Vec<RdbEntry>>),
+    /// Store the given keys, as loaded from a snapshot
+    Restore(Vec<RdbEntry>, oneshot::Sender<()>),
+    /// Query the last-write versions of the given keys, each in its own database
+    Versions(Vec<(usize, String)>, oneshot::Sender<Vec<u64>>),
+    /// Like `Versions`, also tracking the writes to the keys until they are unwatched
+    Watch(Vec<(usize, String)>, oneshot::Sender<Vec<u64>>),
+    /// Drop one WATCH of each of the given keys
+    Unwatch(Vec<(usize, String)>),
+    /// Gather the figures INFO reports about the dataset
+    Stats(oneshot::Sender<StorageStats>),
+    /// Change the `list-max-listpack-size` of every database
+    SetListMaxListpackSize(i64),
+    /// Change the `maxmemory` and `maxmemory-policy`, evicting right away if needed
+    SetMaxmemory(u64, MaxmemoryPolicy),
+    /// Change the `list-max-len` of every database
+    SetListMaxLen(usize),
+    /// Change the `maxmemory-samples`
+    SetMaxmemorySamples(usize),
+    /// Flush the appended writes to disk, if the AOF is enabled
+    FsyncAof(oneshot::Sender<()>),
+}
 
 impl StorageHandle {
     pub fn new() -> Self {
+        Self::with_config(StorageConfig::default())
+    }
+
+    /// Starts the storage actor with the given settings.
+    pub fn with_config(config: StorageConfig) -> Self {
         let (cmd_tx, cmd_rx) = unbounded_channel();
-        let storage_actor = StorageActor::new(cmd_rx);
+        let storage_actor = StorageActor::new(cmd_rx, config);
         tokio::spawn(storage_actor.run());
         Self { cmd_tx }
     }
 
-    pub async fn send(&self, cmd: Command) -> RespDataType {
+    /// Runs `cmd` against database `db` and returns its reply.
+    pub async fn send(&self, db: usize, cmd: Command) -> RespDataType {
+        self.request(db, cmd, None).await
+    }
+
+    /// Like [`send`](Self::send) for a command received from a client. If `cmd` is a
+    /// write, `frame` (the command as received) is propagated to the replicas.
+    pub async fn send_from_client(
+        &self,
+        db: usize,
+        cmd: Command,
+        frame: RespDataType,
+    ) -> RespDataType {
+        self.request(db, cmd, Some(frame)).await
+    }
+
+    async fn request(&self, db: usize, cmd: Command, frame: Option<RespDataType>) -> RespDataType {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::Command(db, cmd, frame, resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+
+    /// Registers a replica, returning the stream of writes to forward to it.
+    pub fn add_replica(&self) -> UnboundedReceiver<RespDataType> {
+        let (replica_tx, replica_rx) = unbounded_channel();
+        self.cmd_tx
+            .send(StorageRequest::AddReplica(replica_tx))
+            .expect("Actor task failed");
+        replica_rx
+    }
+
+    /// Returns every live key of every database, grouped by database.
+    pub async fn snapshot(&self) -> Vec<RdbEntry> {
         let (resp_tx, resp_rx) = oneshot::channel();
-        self.cmd_tx.send((cmd, resp_tx)).expect("Actor task failed");
+        self.cmd_tx
+            .send(StorageRequest::Snapshot(resp_tx))
+            .expect("Actor task failed");
         resp_rx.await.expect("Actor response failed")
     }
+
+    /// Stores the keys of a snapshot. Keys whose deadline already passed are skipped.
+    pub async fn restore(&self, entries: Vec<RdbEntry>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::Restore(entries, resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+
+    /// Applies a new `list-max-listpack-size` to the lists of every database. Commands
+    /// sent afterwards see the new encoding threshold.
+    pub fn set_list_max_listpack_size(&self, size: i64) {
+        self.cmd_tx
+            .send(StorageRequest::SetListMaxListpackSize(size))
+            .expect("Actor task failed");
+    }
+
+    /// Applies a new `maxmemory` and `maxmemory-policy`.
+    pub fn set_maxmemory(&self, maxmemory: u64, policy: MaxmemoryPolicy) {
+        self.cmd_tx
+            .send(StorageRequest::SetMaxmemory(maxmemory, policy))
+            .expect("Actor task failed");
+    }
+
+    /// Applies a new `list-max-len` to the lists of every database.
+    pub fn set_list_max_len(&self, max_len: usize) {
+        self.cmd_tx
+            .send(StorageRequest::SetListMaxLen(max_len))
+            .expect("Actor task failed");
+    }
+
+    /// Applies a new `maxmemory-samples` to the next evictions.
+    pub fn set_maxmemory_samples(&self, samples: usize) {
+        self.cmd_tx
+            .send(StorageRequest::SetMaxmemorySamples(samples))
+            .expect("Actor task failed");
+    }
+
+    /// Flushes the writes appended to the AOF so far to disk. Does nothing when the AOF
+    /// is disabled.
+    pub async fn fsync_aof(&self) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::FsyncAof(resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+
+    /// Returns the last-write version of each `(db, key)` pair, in the same order as `keys`.
+    pub async fn versions(&self, keys: Vec<(usize, String)>) -> Vec<u64> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::Versions(keys, resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+
+    /// Watches each `(db, key)` pair, returning their versions like
+    /// [`versions`](Self::versions). Every call must be matched by an
+    /// [`unwatch`](Self::unwatch) of the same keys.
+    pub async fn watch(&self, keys: Vec<(usize, String)>) -> Vec<u64> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::Watch(keys, resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+
+    /// Stops watching each `(db, key)` pair.
+    pub fn unwatch(&self, keys: Vec<(usize, String)>) {
+        // The actor may be gone already when a connection is dropped at shutdown
+        let _ = self.cmd_tx.send(StorageRequest::Unwatch(keys));
+    }
+
+    /// Returns the figures INFO reports about the dataset.
+    pub async fn stats(&self) -> StorageStats {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(StorageRequest::Stats(resp_tx))
+            .expect("Actor task failed");
+        resp_rx.await.expect("Actor response failed")
+    }
+}
+
+/// Returns the current Unix time in milliseconds.
+fn unix_ms_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Builds the DEL of `key` propagated when it expires or is evicted.
+fn del_frame(key: String) -> RespDataType {
+    RespDataType::Array(vec![
+        RespDataType::BulkString("DEL".into()),
+        RespDataType::BulkString(key.into()),
+    ])
+}
+
+/// Rewrites `frame`, as received for `cmd`, so that a relative expiration becomes a
+/// deadline: `SET ... PX` turns into `SET ... PXAT` and EXPIRE into PEXPIREAT. Replayed
+/// from the AOF or by a replica later on, the key then still expires when it should.
+fn with_absolute_expiry(cmd: &Command, frame: RespDataType) -> RespDataType {
+    let bulk = |s: String| RespDataType::BulkString(s.into());
+    match (cmd, frame) {
+        (Command::SET { px: Some(px), .. }, RespDataType::Array(mut parts)) => {
+            // Options start after the key and value, and PX is the only one taking
+            // an argument
+            if let Some(i) = (3..parts.len().saturating_sub(1)).find(|&i| {
+                parts[i]
+                    .get_str()
+                    .is_ok_and(|opt| opt.eq_ignore_ascii_case("PX"))
+            }) {
+                parts[i] = bulk("PXAT".into());
+                parts[i + 1] = bulk((unix_ms_now() + px.as_millis() as u64).to_string());
+            }
+            RespDataType::Array(parts)
+        }
+        (Command::EXPIRE { key, seconds }, _) => {
+            let unix_ms = (unix_ms_now() as i64).saturating_add(seconds * 1000);
+            RespDataType::Array(vec![
+                bulk("PEXPIREAT".into()),
+                bulk(key.clone()),
+                bulk(unix_ms.to_string()),
+            ])
+        }
+        (_, frame) => frame,
+    }
+}
+
+/// Converts `instant` to Unix time in milliseconds.
+fn unix_ms(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let unix = if instant >= now {
+        unix_now + (instant - now)
+    } else {
+        unix_now.saturating_sub(now - instant)
+    };
+    unix.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn set_with_ttl(key: &str, ttl: Duration) -> Command {
+        Command::SET {
+            key: key.into(),
+            val: "v".into(),
+            px: Some(ttl),
+            condition: None,
+            get: false,
+            keep_ttl: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_get_round_trip() {
+        let storage = crate::StorageHandle::new();
+        let set = Command::SET {
+            key: "k".into(),
+            val: "v".into(),
+            px: None,
+            condition: None,
+            get: false,
+            keep_ttl: false,
+        };
+        assert_eq!(
+            storage.send(0, set).await,
+            RespDataType::SimpleString("OK".into())
+        );
+        assert_eq!(
+            storage.send(0, Command::GET { key: "k".into() }).await,
+            RespDataType::BulkString("v".into())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_on_a_list() {
+        let storage = StorageHandle::new();
+        let rpush = || Command::RPUSH {
+            key: "list".into(),
+            elements: vec!["a".into(), "b".into()],
+        };
+        let ttl = || Command::TTL { key: "list".into() };
+        let expire = |seconds| Command::EXPIRE {
+            key: "list".into(),
+            seconds,
+        };
+
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-2));
+        assert_eq!(storage.send(0, expire(10)).await, RespDataType::Integer(0));
+        storage.send(0, rpush()).await;
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-1));
+        assert_eq!(storage.send(0, expire(10)).await, RespDataType::Integer(1));
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(10));
+
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(6));
+        assert_eq!(
+            storage.send(0, Command::LLEN { key: "list".into() }).await,
+            RespDataType::Integer(2)
+        );
+
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-2));
+        assert_eq!(
+            storage.send(0, Command::LLEN { key: "list".into() }).await,
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(0)
+        );
+
+        // Pushing again creates a new list, without the old TTL
+        assert_eq!(storage.send(0, rpush()).await, RespDataType::Integer(2));
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-1));
+        assert_eq!(storage.send(0, expire(0)).await, RespDataType::Integer(1));
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_on_a_sorted_set() {
+        let storage = StorageHandle::with_config(StorageConfig {
+            sweep_interval: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let zadd = || Command::ZADD {
+            key: "zset".into(),
+            condition: None,
+            comparison: None,
+            members: vec![(1.0, "a".into())],
+        };
+        let ttl = || Command::TTL { key: "zset".into() };
+
+        storage.send(0, zadd()).await;
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-1));
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::EXPIRE {
+                        key: "zset".into(),
+                        seconds: 10,
+                    },
+                )
+                .await,
+            RespDataType::Integer(1)
+        );
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(10));
+
+        // The sweep reaps it without it being accessed
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(0)
+        );
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::DEBUG {
+                        subcommand: DebugSubcommand::PurgeExpired,
+                    },
+                )
+                .await,
+            RespDataType::Integer(0)
+        );
+
+        // Adding again creates a new sorted set, without the old TTL
+        storage.send(0, zadd()).await;
+        assert_eq!(storage.send(0, ttl()).await, RespDataType::Integer(-1));
+        assert_eq!(
+            storage.send(0, Command::ZCARD { key: "zset".into() }).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expire_far_in_the_future_keeps_the_actor_alive() {
+        let storage = StorageHandle::new();
+        storage
+            .send(0, set_with_ttl("k", Duration::from_secs(100)))
+            .await;
+
+        let reply = storage
+            .send(
+                0,
+                Command::EXPIRE {
+                    key: "k".into(),
+                    seconds: i64::MAX / 1000,
+                },
+            )
+            .await;
+        assert!(
+            matches!(
+                reply,
+                RespDataType::Integer(1) | RespDataType::SimpleError(_)
+            ),
+            "{reply:?}"
+        );
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expiretime_is_absolute() {
+        let storage = StorageHandle::new();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        storage
+            .send(0, set_with_ttl("k", Duration::from_secs(100)))
+            .await;
+        storage
+            .send(
+                0,
+                Command::RPUSH {
+                    key: "list".into(),
+                    elements: vec!["a".into()],
+                },
+            )
+            .await;
+
+        let RespDataType::Integer(ms) = storage
+            .send(0, Command::PEXPIRETIME { key: "k".into() })
+            .await
+        else {
+            panic!("expected an integer");
+        };
+        let expected = (unix_now + Duration::from_secs(100)).as_millis() as i64;
+        assert!(
+            (expected..expected + 1000).contains(&ms),
+            "{ms} vs {expected}"
+        );
+
+        // 700ms past a second, which rounding would carry over to the next one
+        let secs = unix_now.as_secs() as i64 + 100;
+        storage
+            .send(
+                0,
+                Command::PEXPIREAT {
+                    key: "k".into(),
+                    unix_ms: secs * 1000 + 700,
+                },
+            )
+            .await;
+        assert_eq!(
+            storage
+                .send(0, Command::EXPIRETIME { key: "k".into() })
+                .await,
+            RespDataType::Integer(secs)
+        );
+
+        for (key, expected) in [("list", -1), ("missing", -2)] {
+            assert_eq!(
+                storage
+                    .send(0, Command::EXPIRETIME { key: key.into() })
+                    .await,
+                RespDataType::Integer(expected)
+            );
+            assert_eq!(
+                storage
+                    .send(0, Command::PEXPIRETIME { key: key.into() })
+                    .await,
+                RespDataType::Integer(expected)
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_touch_and_unlink() {
+        let storage = StorageHandle::new();
+        storage
+            .send(0, set_with_ttl("string", Duration::from_secs(60)))
+            .await;
+        storage
+            .send(
+                0,
+                Command::RPUSH {
+                    key: "list".into(),
+                    elements: vec!["a".into()],
+                },
+            )
+            .await;
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+        let idle_time = |key: &str| Command::OBJECT {
+            subcommand: ObjectSubcommand::IdleTime(key.into()),
+        };
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::TOUCH {
+                        keys: keys(&["string", "missing", "list", "string"])
+                    }
+                )
+                .await,
+            RespDataType::Integer(3)
+        );
+        assert_eq!(
+            storage.send(0, idle_time("list")).await,
+            RespDataType::Integer(0)
+        );
+
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::UNLINK {
+                        keys: keys(&["string", "missing", "list"])
+                    }
+                )
+                .await,
+            RespDataType::Integer(2)
+        );
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lmpop_pops_from_the_first_non_empty_list() {
+        let storage = StorageHandle::new();
+        let lmpop = |end, count| Command::LMPOP {
+            keys: vec!["empty".into(), "full".into(), "other".into()],
+            end,
+            count,
+        };
+        let bulk = |value: &str| RespDataType::BulkString(value.to_string().into());
+
+        assert_eq!(
+            storage.send(0, lmpop(ListEnd::Left, 1)).await,
+            RespDataType::NullArray
+        );
+
+        for key in ["full", "other"] {
+            storage
+                .send(
+                    0,
+                    Command::RPUSH {
+                        key: key.into(),
+                        elements: vec!["a".into(), "b".into(), "c".into()],
+                    },
+                )
+                .await;
+        }
+        assert_eq!(
+            storage.send(0, lmpop(ListEnd::Left, 1)).await,
+            RespDataType::Array(vec![bulk("full"), RespDataType::Array(vec![bulk("a")])])
+        );
+        assert_eq!(
+            storage.send(0, lmpop(ListEnd::Right, 5)).await,
+            RespDataType::Array(vec![
+                bulk("full"),
+                RespDataType::Array(vec![bulk("c"), bulk("b")])
+            ])
+        );
+        // full is now empty, so the next list is popped
+        assert_eq!(
+            storage.send(0, lmpop(ListEnd::Left, 1)).await,
+            RespDataType::Array(vec![bulk("other"), RespDataType::Array(vec![bulk("a")])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_randomkey_picks_from_every_store() {
+        let storage = StorageHandle::with_config(StorageConfig {
+            random_seed: Some(7),
+            ..Default::default()
+        });
+        assert_eq!(
+            storage.send(0, Command::RANDOMKEY).await,
+            RespDataType::NullBulkString
+        );
+
+        storage
+            .send(0, set_with_ttl("string", Duration::from_secs(60)))
+            .await;
+        storage
+            .send(
+                0,
+                Command::RPUSH {
+                    key: "list".into(),
+                    elements: vec!["a".into()],
+                },
+            )
+            .await;
+        storage
+            .send(
+                0,
+                Command::ZADD {
+                    key: "zset".into(),
+                    condition: None,
+                    comparison: None,
+                    members: vec![(1.0, "a".into())],
+                },
+            )
+            .await;
+
+        let mut seen = HashSet::new();
+        for _ in 0..50 {
+            let RespDataType::BulkString(key) = storage.send(0, Command::RANDOMKEY).await else {
+                panic!("expected a key");
+            };
+            seen.insert(String::from_utf8(key.to_vec()).unwrap());
+        }
+        let expected: HashSet<String> = ["string", "list", "zset"].map(String::from).into();
+        assert_eq!(seen, expected);
+    }
+
+    #[tokio::test]
+    async fn test_randomkey_reaps_expired_keys() {
+        let storage = StorageHandle::new();
+        storage
+            .send(0, set_with_ttl("gone", Duration::from_millis(1)))
+            .await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            storage.send(0, Command::RANDOMKEY).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(0)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweep_reaps_keys_that_are_never_read() {
+        let storage = StorageHandle::with_config(StorageConfig {
+            sweep_interval: Duration::from_millis(100),
+            ..Default::default()
+        });
+        storage
+            .send(0, set_with_ttl("short", Duration::from_millis(50)))
+            .await;
+        storage
+            .send(3, set_with_ttl("other-db", Duration::from_millis(50)))
+            .await;
+        storage
+            .send(0, set_with_ttl("long", Duration::from_secs(60)))
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // Nothing is left for a full pass to reap: the sweep already removed the keys
+        let purge = Command::DEBUG {
+            subcommand: DebugSubcommand::PurgeExpired,
+        };
+        assert_eq!(
+            storage.send(0, purge.clone()).await,
+            RespDataType::Integer(0)
+        );
+        assert_eq!(storage.send(3, purge).await, RespDataType::Integer(0));
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(1)
+        );
+    }
+
+    /// Subscribes to `channel`, returning the stream of `message` frames
+    fn subscribe(pubsub: &PubSub, channel: &str) -> tokio::sync::mpsc::Receiver<Vec<RespDataType>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let killed = tokio_util::sync::CancellationToken::new();
+        pubsub.subscribe(channel.into(), "127.0.0.1:1".parse().unwrap(), tx, killed);
+        rx
+    }
+
+    /// The batch a subscriber of `channel` is handed when `payload` is published
+    fn message(channel: &str, payload: &str) -> Vec<RespDataType> {
+        vec![RespDataType::Array(vec![
+            RespDataType::BulkString("message".into()),
+            RespDataType::BulkString(channel.to_string().into()),
+            RespDataType::BulkString(payload.to_string().into()),
+        ])]
+    }
+
+    #[tokio::test]
+    async fn test_set_publishes_keyevent() {
+        let pubsub = PubSub::default();
+        let mut events = subscribe(&pubsub, "__keyevent@0__:set");
+        let mut other_db = subscribe(&pubsub, "__keyevent@1__:set");
+        let storage = StorageHandle::with_config(StorageConfig {
+            pubsub: pubsub.clone(),
+            keyspace_events: "E$".parse().unwrap(),
+            ..Default::default()
+        });
+
+        storage
+            .send(0, set_with_ttl("k", Duration::from_secs(60)))
+            .await;
+        assert_eq!(
+            events.try_recv().unwrap(),
+            message("__keyevent@0__:set", "k")
+        );
+        assert!(other_db.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry_publishes_keyspace_event() {
+        let pubsub = PubSub::default();
+        let mut events = subscribe(&pubsub, "__keyspace@0__:k");
+        let storage = StorageHandle::with_config(StorageConfig {
+            sweep_interval: Duration::from_millis(100),
+            pubsub: pubsub.clone(),
+            keyspace_events: "Kx".parse().unwrap(),
+            ..Default::default()
+        });
+
+        storage
+            .send(0, set_with_ttl("k", Duration::from_millis(50)))
+            .await;
+        // Only expired events are enabled, so the SET itself is not published
+        assert!(events.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            events.try_recv().unwrap(),
+            message("__keyspace@0__:k", "expired")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_without_active_expire_keys_expire_on_access() {
+        let pubsub = PubSub::default();
+        let mut events = subscribe(&pubsub, "__keyevent@0__:expired");
+        let storage = StorageHandle::with_config(StorageConfig {
+            sweep_interval: Duration::from_millis(100),
+            pubsub: pubsub.clone(),
+            keyspace_events: "Ex".parse().unwrap(),
+            ..Default::default()
+        });
+        let set_active_expire = |enabled| Command::DEBUG {
+            subcommand: DebugSubcommand::SetActiveExpire(enabled),
+        };
+
+        assert_eq!(
+            storage.send(0, set_active_expire(false)).await,
+            RespDataType::SimpleString("OK".into())
+        );
+        storage
+            .send(0, set_with_ttl("k", Duration::from_millis(50)))
+            .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // Still stored, only no longer visible
+        assert!(events.try_recv().is_err());
+        assert_eq!(
+            storage.send(0, Command::DBSIZE).await,
+            RespDataType::Integer(0)
+        );
+
+        assert_eq!(
+            storage.send(0, Command::GET { key: "k".into() }).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            message("__keyevent@0__:expired", "k")
+        );
+
+        storage.send(0, set_active_expire(true)).await;
+        storage
+            .send(0, set_with_ttl("other", Duration::from_millis(50)))
+            .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            events.try_recv().unwrap(),
+            message("__keyevent@0__:expired", "other")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_object_encoding() {
+        let storage = StorageHandle::new();
+        let encoding = |key: &str| Command::OBJECT {
+            subcommand: ObjectSubcommand::Encoding(key.into()),
+        };
+        let rpush = |key: &str, elements: Vec<String>| Command::RPUSH {
+            key: key.into(),
+            elements,
+        };
+
+        storage.send(0, rpush("small", vec!["a".into()])).await;
+        storage
+            .send(0, rpush("large", vec!["x".repeat(100); 100]))
+            .await;
+
+        assert_eq!(
+            storage.send(0, encoding("small")).await,
+            RespDataType::BulkString("listpack".into())
+        );
+        assert_eq!(
+            storage.send(0, encoding("large")).await,
+            RespDataType::BulkString("quicklist".into())
+        );
+        assert_eq!(
+            storage.send(0, encoding("never-set")).await,
+            RespDataType::SimpleError("ERR no such key".into())
+        );
+    }
+
+    fn set_small(key: &str) -> Command {
+        Command::SET {
+            key: key.into(),
+            val: "0123456789".into(),
+            px: None,
+            condition: None,
+            get: false,
+            keep_ttl: false,
+        }
+    }
+
+    /// Room for three keys set by `set_small`, but not four
+    const THREE_SMALL_KEYS: u64 = 3 * (1 + 10 + KEY_OVERHEAD as u64);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allkeys_lru_evicts_the_least_recently_accessed_key() {
+        let storage = StorageHandle::new();
+        storage.set_maxmemory(THREE_SMALL_KEYS, MaxmemoryPolicy::AllkeysLru);
+        let get = |key: &str| Command::GET { key: key.into() };
+
+        for key in ["a", "b", "c"] {
+            storage.send(0, set_small(key)).await;
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        storage.send(0, get("a")).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        storage.send(0, set_small("d")).await;
+        assert_eq!(
+            storage.send(0, get("b")).await,
+            RespDataType::NullBulkString
+        );
+        assert_eq!(
+            storage.send(0, get("a")).await,
+            RespDataType::BulkString("0123456789".into())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allkeys_lru_reaps_expired_keys_before_evicting() {
+        let storage = StorageHandle::new();
+        storage
+            .send(
+                0,
+                Command::DEBUG {
+                    subcommand: DebugSubcommand::SetActiveExpire(false),
+                },
+            )
+            .await;
+        storage.set_maxmemory(THREE_SMALL_KEYS, MaxmemoryPolicy::AllkeysLru);
+
+        storage.send(0, set_small("a")).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        storage
+            .send(0, set_with_ttl("b", Duration::from_secs(1)))
+            .await;
+        storage.send(0, set_small("c")).await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        // b still counts until reaped, which frees room for d without evicting a
+        storage.send(0, set_small("d")).await;
+        for key in ["a", "c", "d"] {
+            assert_eq!(
+                storage.send(0, Command::GET { key: key.into() }).await,
+                RespDataType::BulkString("0123456789".into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_noeviction_refuses_writes_over_maxmemory() {
+        let storage = StorageHandle::new();
+        storage.set_maxmemory(THREE_SMALL_KEYS, MaxmemoryPolicy::NoEviction);
+
+        // The budget is checked before writing, so the fourth key still gets in
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(
+                storage.send(0, set_small(key)).await,
+                RespDataType::SimpleString("OK".into())
+            );
+        }
+        assert_eq!(
+            storage.send(0, set_small("e")).await,
+            RespDataType::SimpleError(OOM_ERROR.into())
+        );
+        // Deleting is still allowed, and frees room
+        storage
+            .send(
+                0,
+                Command::DEL {
+                    keys: vec!["a".into(), "b".into()],
+                },
+            )
+            .await;
+        assert_eq!(
+            storage.send(0, set_small("e")).await,
+            RespDataType::SimpleString("OK".into())
+        );
+    }
+
+    #[test]
+    fn test_versions_are_only_kept_while_watched() {
+        let mut keyspace = Keyspace::default();
+        keyspace.bump_version("k");
+        assert_eq!(keyspace.watch("k"), 0);
+        assert_eq!(keyspace.watch("k"), 0);
+        keyspace.bump_version("k");
+        let version = keyspace.version("k");
+        assert_ne!(version, 0);
+
+        keyspace.unwatch("k");
+        assert_eq!(keyspace.version("k"), version);
+        keyspace.unwatch("k");
+        assert!(keyspace.watched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_used_memory_follows_every_write() {
+        let storage = StorageHandle::new();
+        let run = |parts: &[&str]| {
+            let frame = RespDataType::Array(
+                parts
+                    .iter()
+                    .map(|part| RespDataType::BulkString(part.to_string().into()))
+                    .collect(),
+            );
+            storage.send(0, Command::try_from(frame).unwrap())
+        };
+        let key = |len: usize| len + KEY_OVERHEAD;
+
+        run(&["SET", "k", "abc"]).await;
+        run(&["APPEND", "k", "de"]).await;
+        run(&["RPUSH", "l", "a", "bb"]).await;
+        run(&["LPOP", "l"]).await;
+        run(&["ZADD", "z", "1", "m", "2", "n"]).await;
+        run(&["ZREM", "z", "n"]).await;
+        let expected = key(1 + 5) + key(1 + 2) + key(1 + 1 + 8);
+        assert_eq!(storage.stats().await.used_memory, expected);
+
+        // Emptied lists are deleted
+        run(&["LPOP", "l"]).await;
+        run(&["DEL", "k"]).await;
+        assert_eq!(storage.stats().await.used_memory, key(1 + 1 + 8));
+        run(&["FLUSHALL"]).await;
+        assert_eq!(storage.stats().await.used_memory, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_eviction_samples_maxmemory_samples_keys() {
+        let storage = StorageHandle::with_config(StorageConfig {
+            random_seed: Some(7),
+            ..Default::default()
+        });
+        let keys: Vec<String> = (0..20).map(|i| format!("{:02}", i % 10)).collect();
+        // Room for every key but one, in two databases
+        let room = 20 * (2 + 10 + KEY_OVERHEAD as u64) - 1;
+        storage.set_maxmemory(room, MaxmemoryPolicy::AllkeysLru);
+        // Sampling every key finds the least recently accessed one
+        storage.set_maxmemory_samples(64);
+
+        for (i, key) in keys.iter().enumerate() {
+            storage.send(i / 10, set_small(key)).await;
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        let dbsize = |db| storage.send(db, Command::DBSIZE);
+        assert_eq!(dbsize(0).await, RespDataType::Integer(9));
+        assert_eq!(
+            storage.send(0, Command::GET { key: "00".into() }).await,
+            RespDataType::NullBulkString
+        );
+
+        // With fewer samples, some key still makes room
+        storage.set_maxmemory_samples(2);
+        storage.send(1, set_small("10")).await;
+        let total = match (dbsize(0).await, dbsize(1).await) {
+            (RespDataType::Integer(a), RespDataType::Integer(b)) => a + b,
+            sizes => panic!("unexpected DBSIZE replies {sizes:?}"),
+        };
+        assert_eq!(total, 19);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allkeys_lfu_evicts_the_least_frequently_accessed_key() {
+        let storage = StorageHandle::new();
+        let set = set_small;
+        let get = |key: &str| Command::GET { key: key.into() };
+        let freq = |key: &str| Command::OBJECT {
+            subcommand: ObjectSubcommand::Freq(key.into()),
+        };
+
+        assert!(matches!(
+            storage.send(0, freq("a")).await,
+            RespDataType::SimpleError(_)
+        ));
+        storage.set_maxmemory(THREE_SMALL_KEYS, MaxmemoryPolicy::AllkeysLfu);
+
+        for key in ["a", "b", "c"] {
+            storage.send(0, set(key)).await;
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        for _ in 0..3 {
+            storage.send(0, get("a")).await;
+            storage.send(0, get("c")).await;
+        }
+        assert_eq!(storage.send(0, freq("a")).await, RespDataType::Integer(4));
+        assert_eq!(storage.send(0, freq("b")).await, RespDataType::Integer(1));
+
+        // d is accessed as rarely as b, but more recently
+        storage.send(0, set("d")).await;
+        assert_eq!(
+            storage.send(0, get("b")).await,
+            RespDataType::NullBulkString
+        );
+        for key in ["a", "c", "d"] {
+            assert_eq!(
+                storage.send(0, get(key)).await,
+                RespDataType::BulkString("0123456789".into())
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_object_idletime_resets_on_access() {
+        let storage = StorageHandle::new();
+        let object = |subcommand| Command::OBJECT { subcommand };
+        let idletime = || object(ObjectSubcommand::IdleTime("k".into()));
+
+        storage
+            .send(0, set_with_ttl("k", Duration::from_secs(3600)))
+            .await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(storage.send(0, idletime()).await, RespDataType::Integer(10));
+        // Inspecting the key does not count as an access
+        assert_eq!(storage.send(0, idletime()).await, RespDataType::Integer(10));
+
+        storage.send(0, Command::GET { key: "k".into() }).await;
+        assert_eq!(storage.send(0, idletime()).await, RespDataType::Integer(0));
+
+        assert_eq!(
+            storage
+                .send(0, object(ObjectSubcommand::RefCount("k".into())))
+                .await,
+            RespDataType::Integer(1)
+        );
+        assert_eq!(
+            storage
+                .send(0, object(ObjectSubcommand::IdleTime("missing".into())))
+                .await,
+            RespDataType::SimpleError("ERR no such key".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_reject_keys_of_another_type() {
+        let storage = StorageHandle::new();
+        let wrongtype = RespDataType::SimpleError(WRONGTYPE_ERROR.into());
+        let rpush = |key: &str| Command::RPUSH {
+            key: key.into(),
+            elements: vec!["a".into()],
+        };
+
+        storage.send(0, rpush("list")).await;
+        assert_eq!(
+            storage.send(0, Command::GET { key: "list".into() }).await,
+            wrongtype
+        );
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::GET {
+                        key: "missing".into()
+                    }
+                )
+                .await,
+            RespDataType::NullBulkString
+        );
+
+        storage
+            .send(0, set_with_ttl("string", Duration::from_secs(60)))
+            .await;
+        assert_eq!(storage.send(0, rpush("string")).await, wrongtype);
+        assert_eq!(
+            storage
+                .send(
+                    0,
+                    Command::LLEN {
+                        key: "string".into()
+                    }
+                )
+                .await,
+            wrongtype
+        );
+
+        // SET replaces a list rather than failing
+        assert_eq!(
+            storage
+                .send(0, set_with_ttl("list", Duration::from_secs(60)))
+                .await,
+            RespDataType::SimpleString("OK".into())
+        );
+        assert_eq!(
+            storage.send(0, Command::GET { key: "list".into() }).await,
+            RespDataType::BulkString("v".into())
+        );
+    }
 }